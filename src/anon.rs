@@ -1,164 +1,638 @@
-use anyhow::Result;
+use crate::check::post_check;
+use anyhow::{Context, Result};
 use crossbeam::sync::WaitGroup;
 use dcmrig_rs::*;
 use dicom::{
     core::{DataElement, VR},
     dicom_value,
     dictionary_std::tags,
-    object::{open_file, FileDicomObject, InMemDicomObject},
+    object::{open_file, FileDicomObject, InMemDicomObject, OpenFileOptions},
 };
 use rayon::prelude::*;
 use std::{
-    collections::HashMap,
-    fs::File,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+use walkdir::DirEntry;
 
+#[allow(clippy::too_many_arguments)]
 pub fn dicom_anon(
     source_path: PathBuf,
     destination_path: PathBuf,
     anon_prefix: String,
-) -> Result<()> {
+    anon_keys: Vec<String>,
+    mapping_out: Option<PathBuf>,
+    charset: OutputCharset,
+    drop_unsafe_references: bool,
+    naming: NamingOptions,
+    run_options: RunOptions,
+) -> Result<RunSummary> {
+    let start_time = std::time::Instant::now();
     info!(
         "Anonymizing the data for >> SOURCE: {} | DESTINATION: {} | ANON PREFIX: {}",
         source_path.display(),
         destination_path.display(),
         &anon_prefix
     );
-
     // Set up required variables
-    let (all_files, total_len, pb) = preprocessing_setup(&source_path, &destination_path)?;
-    let failed_case: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let non_dcm_cases: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let anon_id_tracker: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    let RunSetup {
+        all_files,
+        total_len,
+        pb,
+        run_dirs,
+        walk_errors,
+        too_small,
+        too_large,
+        oversize,
+    } = preprocessing_setup(&source_path, &destination_path, &run_options)?;
+    let outcomes: Arc<OutcomeTracker> = Arc::new(
+        OutcomeTracker::new(RunCounts {
+            walk_errors,
+            too_small,
+            too_large,
+            oversize,
+            ..Default::default()
+        })
+        .with_run_callbacks(&run_options)
+        .with_single_file_result(&run_dirs),
+    );
+    let anon_id_tracker: Arc<Mutex<AnonIdMap>> = Arc::new(Mutex::new(HashMap::new()));
+    let keyless = AtomicU64::new(0);
+    let reference_audit = ReferenceAudit::default();
+    let unsafe_references = match drop_unsafe_references {
+        true => Some(UnsafeReferences::index(&all_files, &run_options)),
+        false => None,
+    };
     let wg = WaitGroup::new();
 
     // Main Loop
-    all_files
-        .par_iter()
-        .enumerate()
-        .for_each(|(_index, working_path)| {
-            if let Ok(dcm_obj) = open_file(working_path.path()) {
-                let anon_id_clone = Arc::clone(&anon_id_tracker);
-                anon_each_dcm_file(
-                    &dcm_obj,
-                    &destination_path,
-                    anon_id_clone,
-                    &anon_prefix,
-                    wg.clone(),
-                )
-                .unwrap_or_else(|_| {
-                    let mut map = failed_case.lock().expect("Failed to lock mutex");
-                    *map += 1;
-                    error!(
-                        "Can't ANON {:#?} Copying to FAILED_CASES directory",
-                        &working_path.file_name()
-                    );
-                    failed_case_copy(&working_path.clone().into_path(), &destination_path)
-                        .expect("Failed to copy file to FAILED_CASES directory");
-                });
-            } else {
-                let nwg = wg.clone();
-                let mut map = non_dcm_cases.lock().expect("Failed to lock mutex");
-                *map += 1;
-                copy_non_dicom_files(&working_path, &destination_path).unwrap_or_else(|_| {
-                    error!("Can't copy non dicom file {:#?}", &working_path.file_name())
-                });
-                drop(nwg);
-            }
+    let not_dispatched = for_each_source_file(&all_files, &run_options, |working_path| {
+        let Some(read) = run_options
+            .read_gate
+            .run(|| with_file_timeout(working_path.path(), &run_options, open_file))
+        else {
+            record_file_timeout(working_path, &run_dirs, &run_options, &outcomes);
             pb.inc(1);
-        });
+            return;
+        };
+        if let Ok(mut dcm_obj) = read {
+            check_dicomdir_record(&dcm_obj, working_path, &run_options);
+            fix_file_vrs(&mut dcm_obj, working_path, &run_options);
+            if skip_excluded_file(&dcm_obj, working_path, &run_dirs, &run_options, &outcomes) {
+                pb.inc(1);
+                return;
+            }
+            if skip_global_duplicate(&dcm_obj, working_path, &run_options, &outcomes) {
+                pb.inc(1);
+                return;
+            }
+            reference_audit.add(&dcm_obj, working_path.path());
+            let anon_id_clone = Arc::clone(&anon_id_tracker);
+            anon_each_dcm_file(
+                &dcm_obj,
+                working_path,
+                &destination_path,
+                anon_id_clone,
+                &anon_prefix,
+                &anon_keys,
+                &keyless,
+                &charset,
+                &run_options.vr_length,
+                &naming,
+                &run_options,
+                unsafe_references.as_ref(),
+                &run_dirs,
+                Arc::clone(&outcomes),
+                wg.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!(
+                    "Can't ANON {:?} Copying to FAILED_CASES directory",
+                    phi(working_path.file_name().to_string_lossy())
+                );
+                record_failed_case(
+                    working_path,
+                    e.to_string(),
+                    &run_dirs,
+                    &run_options,
+                    &outcomes,
+                );
+            });
+        } else if truncated_pixel_data_headers(working_path.path(), &run_options).is_some() {
+            error!(
+                "Can't ANON {:?}, the pixel data is truncated Copying to FAILED_CASES directory",
+                phi(working_path.file_name().to_string_lossy())
+            );
+            record_truncated_pixel_data(working_path, &run_dirs, &run_options, &outcomes);
+        } else {
+            let nwg = wg.clone();
+            record_unopened_file(working_path, &run_dirs, &run_options, &outcomes);
+            drop(nwg);
+        }
+        pb.inc(1);
+    });
     pb.finish();
-    print_status(
-        total_len,
-        *failed_case.lock().expect("Failed to lock mutex"),
-        *non_dcm_cases.lock().expect("Failed to lock mutex"),
-        "Anon".to_string(),
-    )?;
-    wg.wait();
+    wait_for_writes(wg);
+    reference_audit.write(&destination_path)?;
+    if let Some(unsafe_references) = &unsafe_references {
+        unsafe_references.write(&destination_path)?;
+    }
+    if let Some(mapping_path) = mapping_out {
+        write_anon_mapping(
+            &anon_id_tracker.lock().expect("Failed to lock mutex"),
+            &mapping_path,
+        )?;
+    }
     info!("DICOM Anon complete!");
-    Ok(())
+    Ok(RunSummary::new(
+        "Anon".to_string(),
+        destination_path,
+        total_len,
+        RunCounts {
+            interrupted: not_dispatched,
+            keyless: keyless.load(Ordering::Relaxed),
+            ..outcomes.counts()
+        },
+        start_time.elapsed(),
+    ))
+}
+
+// AnonIDs by the anon key and its normalized value, with the values found in the files
+// Files without a key value are listed by their source path under the keyless key
+type AnonIdMap = HashMap<(String, String), AnonIdEntry>;
+
+struct AnonIdEntry {
+    anon_id: String,
+    raw_values: BTreeSet<String>,
+}
+
+const KEYLESS: &str = "keyless";
+
+// SOPInstanceUIDs of the anonymized source files and the instances referenced by them
+// The remapped references to instances never seen in the run point at nothing
+#[derive(Default)]
+struct ReferenceAudit {
+    seen: Mutex<HashSet<String>>,
+    references: Mutex<Vec<(PathBuf, String)>>,
 }
 
+impl ReferenceAudit {
+    fn add(&self, dcm_obj: &FileDicomObject<InMemDicomObject>, source_path: &Path) {
+        if let Some(sop_instance_uid) = dcm_obj
+            .element(tags::SOP_INSTANCE_UID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+        {
+            self.seen
+                .lock()
+                .expect("Failed to lock mutex")
+                .insert(trim_uid(&sop_instance_uid).to_string());
+        }
+        let referenced = referenced_instance_uids(dcm_obj);
+        if !referenced.is_empty() {
+            self.references
+                .lock()
+                .expect("Failed to lock mutex")
+                .extend(
+                    referenced
+                        .into_iter()
+                        .map(|uid| (source_path.to_path_buf(), uid)),
+                );
+        }
+    }
+
+    // Lists the external references in external_references.csv at the destination
+    fn write(&self, destination_path: &Path) -> Result<()> {
+        let seen = self.seen.lock().expect("Failed to lock mutex");
+        let references = self.references.lock().expect("Failed to lock mutex");
+        let external: Vec<_> = references
+            .iter()
+            .filter(|(_, uid)| !seen.contains(uid))
+            .collect();
+        if external.is_empty() {
+            return Ok(());
+        }
+        let mut external_csv = "source_path,referenced_sop_instance_uid\n".to_string();
+        for (source_path, uid) in &external {
+            external_csv.push_str(&format!(
+                "{},{}\n",
+                csv_field(&source_path.display().to_string()),
+                uid
+            ));
+        }
+        let external_path = destination_path.join("external_references.csv");
+        fs::write(&external_path, external_csv)?;
+        let files: HashSet<_> = external
+            .iter()
+            .map(|(source_path, _)| source_path)
+            .collect();
+        warn!(
+            "!! {} files reference {} instances not in the run, external references written to: {}",
+            files.len(),
+            external.len(),
+            external_path.display()
+        );
+        Ok(())
+    }
+}
+
+// SOPInstanceUIDs of all files of the run, indexed before the run for --drop-unsafe-references
+// as a KO or presentation state may be processed before the images it references
+struct UnsafeReferences {
+    run_instances: HashSet<String>,
+    dropped: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl UnsafeReferences {
+    fn index(all_files: &[DirEntry], run_options: &RunOptions) -> Self {
+        info!("Indexing the SOPInstanceUIDs of the run for the unsafe references");
+        let run_instances = all_files
+            .par_iter()
+            .filter_map(|each_file| {
+                let dcm_obj = run_options
+                    .read_gate
+                    .run(|| {
+                        OpenFileOptions::new()
+                            .read_until(tags::STUDY_DATE)
+                            .open_file(each_file.path())
+                    })
+                    .ok()?;
+                let sop_instance_uid = dcm_obj
+                    .element(tags::SOP_INSTANCE_UID)
+                    .ok()?
+                    .to_str()
+                    .ok()?;
+                Some(trim_uid(&sop_instance_uid).to_string())
+            })
+            .collect();
+        UnsafeReferences {
+            run_instances,
+            dropped: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Record the file as dropped when a referenced instance is not in the run
+    fn drop_unresolved(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        source_path: &DirEntry,
+        outcomes: &OutcomeTracker,
+    ) -> bool {
+        let unresolved: Vec<String> = referenced_instance_uids(dcm_obj)
+            .into_iter()
+            .filter(|uid| !self.run_instances.contains(uid))
+            .collect();
+        if unresolved.is_empty() {
+            return false;
+        }
+        warn!(
+            "!! {:?} references {} instances not in the run, not written",
+            phi(source_path.file_name().to_string_lossy()),
+            unresolved.len()
+        );
+        outcomes.record(FileOutcome {
+            error: Some(format!("{} unresolved references", unresolved.len())),
+            ..FileOutcome::new(source_path.path(), FileStatus::UnsafeReferences)
+        });
+        self.dropped.lock().expect("Failed to lock mutex").extend(
+            unresolved
+                .into_iter()
+                .map(|uid| (source_path.path().to_path_buf(), uid)),
+        );
+        true
+    }
+
+    // Lists the dropped files with their unresolved UIDs in unsafe_references.csv
+    fn write(&self, destination_path: &Path) -> Result<()> {
+        let dropped = self.dropped.lock().expect("Failed to lock mutex");
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        let mut dropped_csv = "source_path,unresolved_sop_instance_uid\n".to_string();
+        for (source_path, uid) in dropped.iter() {
+            dropped_csv.push_str(&format!(
+                "{},{}\n",
+                csv_field(&source_path.display().to_string()),
+                uid
+            ));
+        }
+        let dropped_path = destination_path.join("unsafe_references.csv");
+        fs::write(&dropped_path, dropped_csv)?;
+        info!("Unsafe references written to: {}", dropped_path.display());
+        Ok(())
+    }
+}
+
+// The AnonID is written to SH tags like AccessionNumber and StudyID with a 16 character limit
+// and to LO and PN tags with a 64 character limit
+pub fn check_anon_prefix(anon_prefix: &str, length_policy: &LengthPolicy) {
+    let id_len = match anon_prefix.is_empty() {
+        true => gen_id().len(),
+        false => anon_prefix.chars().count() + 1 + gen_id().len(),
+    };
+    if id_len > 64 {
+        error!(
+            "ANON PREFIX is too long, the AnonID would be {} characters",
+            id_len
+        );
+        exit(1);
+    }
+    if id_len > 16 {
+        match length_policy {
+            LengthPolicy::Error => {
+                error!(
+                    "ANON PREFIX is too long, the AnonID of {} characters does not fit SH tags. Use at most {} characters or --vr-length truncate|keep",
+                    id_len,
+                    16 - 1 - gen_id().len()
+                );
+                exit(1);
+            }
+            _ => warn!(
+                "The AnonID of {} characters is over the 16 character limit of SH tags",
+                id_len
+            ),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn anon_each_dcm_file(
     dcm_obj: &FileDicomObject<InMemDicomObject>,
+    source_path: &DirEntry,
     destination_path: &PathBuf,
-    map_clone: Arc<Mutex<HashMap<std::string::String, std::string::String>>>,
+    map_clone: Arc<Mutex<AnonIdMap>>,
     anon_prefix: &String,
+    anon_keys: &[String],
+    keyless: &AtomicU64,
+    charset: &OutputCharset,
+    length_policy: &LengthPolicy,
+    naming: &NamingOptions,
+    run_options: &RunOptions,
+    unsafe_references: Option<&UnsafeReferences>,
+    run_dirs: &RunDirs,
+    outcomes: Arc<OutcomeTracker>,
     wg: WaitGroup,
 ) -> Result<()> {
-    let patient_id = dcm_obj.element_by_name("PatientID")?.to_str()?.to_string();
-    let mut map = map_clone.lock().expect("Failed to lock mutex");
-    match map.get(&patient_id) {
-        Some(_) => (),
+    // The first anon key with a value is used, the files without one get an AnonID each
+    // PatientIDs of one patient in different formats get the same AnonID with --normalize-patient-id
+    let anon_key = anon_keys.iter().find_map(|key| {
+        dcm_obj
+            .element_by_name(key)
+            .ok()
+            .and_then(|v| v.to_multi_str().ok())
+            .map(|values| collapsed_values(&values).join("\\"))
+            .filter(|v| !v.is_empty())
+            .map(|v| match key.as_str() {
+                "PatientID" => (key.clone(), run_options.patient_id.normalize(&v), v),
+                _ => (key.clone(), v.clone(), v),
+            })
+    });
+    let anon_key = match anon_key {
+        Some(anon_key) => anon_key,
         None => {
-            let anon_id: String = if anon_prefix.len() == 0 {
-                gen_id()
-            } else {
-                format!("{anon_prefix}_{}", gen_id())
+            keyless.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "No value for any anon key in {:?}, the file gets its own AnonID",
+                phi(source_path.file_name().to_string_lossy())
+            );
+            let source = source_path.path().display().to_string();
+            (KEYLESS.to_string(), source.clone(), source)
+        }
+    };
+    let (anon_key, anon_value, raw_value) = anon_key;
+    let mut map = map_clone.lock().expect("Failed to lock mutex");
+    let anon_id_entry = map
+        .entry((anon_key, anon_value))
+        .or_insert_with_key(|(key, value)| {
+            let new_id = match run_options.seed {
+                Some(seed) => seeded_id(seed, value),
+                None => gen_id(),
             };
-            map.insert(patient_id.clone(), anon_id);
-            debug!("New AnonID for: {}", patient_id);
+            debug!("New AnonID for {}: {}", key, phi(value));
+            AnonIdEntry {
+                anon_id: match anon_prefix.is_empty() {
+                    true => new_id,
+                    false => format!("{anon_prefix}_{}", new_id),
+                },
+                raw_values: BTreeSet::new(),
+            }
+        });
+    anon_id_entry.raw_values.insert(raw_value);
+    let patient_anon_id = anon_id_entry.anon_id.clone();
+    // Released before the file is modified and written, the other files wait on the map
+    drop(map);
+    let (mut new_dicom_object, mut rule_counts) = mask_tags_with_id(
+        dcm_obj.clone(),
+        patient_anon_id.clone(),
+        &DeidMethod::default(),
+        length_policy,
+        &run_options.keep,
+    )?;
+    apply_identity_policy(
+        &mut new_dicom_object,
+        dcm_obj,
+        &run_options.identity,
+        &patient_anon_id,
+        length_policy,
+        &run_options.keep,
+    )?;
+    let changed;
+    (new_dicom_object, changed) = dicom_anon_date_time(new_dicom_object, &run_options.keep)?;
+    rule_counts.insert("anon dates".to_string(), changed);
+    let changed;
+    (new_dicom_object, changed) = delete_private_tags(
+        new_dicom_object,
+        &run_options.keep,
+        &run_options.private_tag_stats,
+    )?;
+    rule_counts.insert("anon private tags".to_string(), changed);
+    let changed;
+    (new_dicom_object, changed) = anon_dicom_uids(
+        new_dicom_object,
+        &run_options.uid_root,
+        length_policy,
+        &run_options.keep,
+    )?;
+    rule_counts.insert("anon UIDs".to_string(), changed);
+    run_options.rule_stats.record(&rule_counts);
+    if let Some(unsafe_references) = unsafe_references {
+        if unsafe_references.drop_unresolved(dcm_obj, source_path, &outcomes) {
+            return Ok(());
         }
     }
-    let patient_anon_id = map
-        .get(&patient_id)
-        .expect("Failed to index Hashmap")
-        .to_string();
-    let mut new_dicom_object = mask_tags_with_id(dcm_obj.clone(), patient_anon_id)?;
-    new_dicom_object = dicom_anon_date_time(new_dicom_object)?;
-    new_dicom_object = delete_private_tags(new_dicom_object)?;
-    new_dicom_object = anon_dicom_uids(new_dicom_object)?;
-    let dicom_tags_values: HashMap<String, String> = get_sanitized_tag_values(&new_dicom_object)?;
+    new_dicom_object = normalize_charset(new_dicom_object, charset)?;
+    update_file_meta(&mut new_dicom_object, &run_options.file_meta);
+    if run_options.post_check {
+        post_check(&new_dicom_object)?;
+    }
+    if let Some(compression) = &run_options.compression {
+        compression.compress(
+            &mut new_dicom_object,
+            &source_path.file_name().to_string_lossy(),
+        );
+    }
+    let dicom_tags_values: HashMap<String, String> =
+        get_sanitized_tag_values(&new_dicom_object, naming)?;
+    // Files matching a cookbook route, tested on the source file go to its destination root
+    let destination_path = run_options.routes.destination(dcm_obj, destination_path);
 
     let dcm_obj_clone = new_dicom_object.clone();
-    let new_dp = destination_path.clone();
-    rayon::spawn(move || {
-        let file_name = generate_dicom_file_name(&dicom_tags_values, "ANON".to_string())
-            .expect("Failed to generate file Name");
-        let dir_path = generate_dicom_file_path(dicom_tags_values, &new_dp)
-            .expect("Failed to generate file path");
-        let full_path = check_if_dup_exists(format!("{}/{}", dir_path, file_name));
-        debug!("Saving file: {} to: {}", file_name, dir_path);
-        let dcm_buffer = File::create(full_path).expect("Failed to create file");
-        dcm_obj_clone
-            .write_all(dcm_buffer)
-            .expect("Failed to add dcm value to buffer");
-        drop(wg);
-    });
+    // Ordinal study directories are looked up on the original PatientID and StudyInstanceUID
+    let study_dir = match run_options.study_dir_style {
+        StudyDirStyle::Ordinal => Some(run_options.study_ordinals.study_dir(dcm_obj)),
+        StudyDirStyle::Date => None,
+    };
+    let (dir_path, file_name) = match &run_options.media_names {
+        Some(media_names) => media_names.assign(&dicom_tags_values, destination_path),
+        None => (
+            run_options.series_dirs.resolve(
+                compute_dicom_dir_path(
+                    &dicom_tags_values,
+                    destination_path,
+                    &naming.series_derived,
+                    study_dir,
+                )
+                .display()
+                .to_string(),
+                dicom_tags_values
+                    .get("SeriesInstanceUID")
+                    .expect("Failed to extract value"),
+            ),
+            generate_dicom_file_name(&dicom_tags_values, "ANON".to_string(), naming.pad_width)?,
+        ),
+    };
+    let io_retry = run_options.io_retry.clone();
+    let ensured_dirs = run_options.ensured_dirs.clone();
+    let dir_buckets = run_options.dir_buckets.clone();
+    let c_source_path = source_path.clone();
+    let failed_source_path = source_path.clone();
+    let run_dirs = run_dirs.clone();
+    let c_run_options = run_options.clone();
+    let failed_outcomes = outcomes.clone();
+    spawn_write(
+        run_options,
+        wg,
+        move || {
+            let (bucket_dir, full_path) = match dir_buckets.place(
+                &dir_path,
+                &file_name,
+                instance_order(&dicom_tags_values),
+                &|| object_content_hash(&dcm_obj_clone),
+            ) {
+                Placement::New(bucket_dir, full_path) => (bucket_dir, full_path),
+                Placement::Duplicate(existing_path) => {
+                    debug!(
+                        "{} is identical to {}",
+                        phi(c_source_path.path().display()),
+                        phi(existing_path.display())
+                    );
+                    outcomes.record(FileOutcome {
+                        destination_path: Some(existing_path),
+                        ..FileOutcome::new(c_source_path.path(), FileStatus::Duplicate)
+                    });
+                    return Ok(());
+                }
+            };
+            ensured_dirs
+                .ensure(&bucket_dir)
+                .context("Create the destination directory")?;
+            debug!("Saving file: {} to: {}", file_name, bucket_dir);
+            write_dicom_file(&dcm_obj_clone, &full_path, &io_retry)
+                .context("Write to the destination")?;
+            outcomes.record(FileOutcome {
+                destination_path: Some(PathBuf::from(full_path)),
+                tag_values: dicom_tags_values,
+                ..FileOutcome::new(c_source_path.path(), FileStatus::Written)
+            });
+            Ok(())
+        },
+        move |e| {
+            error!(
+                "Can't write {:?} Copying to FAILED_CASES directory",
+                phi(failed_source_path.file_name().to_string_lossy())
+            );
+            record_failed_case(
+                &failed_source_path,
+                format!("{:#}", e),
+                &run_dirs,
+                &c_run_options,
+                &failed_outcomes,
+            );
+        },
+    );
     Ok(())
 }
 
+// Returns the object and the number of elements changed
 fn dicom_anon_date_time(
     dcm_obj: FileDicomObject<InMemDicomObject>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
+    keep: &KeepList,
+) -> Result<(FileDicomObject<InMemDicomObject>, u64)> {
     // Setting Up primitives
 
     let time_str = "090000".to_string();
     let date_str = "19000101".to_string();
     let date_time = format!("{date_str}T{time_str}");
 
-    let dicom_date_data = dicom_vr_corrected_value(VR::DA, &date_str)?;
-    let dicom_time_data = dicom_vr_corrected_value(VR::TM, &time_str)?;
-    let dicom_date_time = dicom_vr_corrected_value(VR::DT, &date_time)?;
-
-    let date_deleted_dcm_obj = mask_all_vr(dcm_obj.clone(), VR::DA, dicom_date_data)?;
-    let time_deleted_dcm_obj = mask_all_vr(date_deleted_dcm_obj.clone(), VR::TM, dicom_time_data)?;
-    let mut datetime_deleted_dcm_obj =
-        mask_all_vr(time_deleted_dcm_obj.clone(), VR::DT, dicom_date_time)?;
-
-    datetime_deleted_dcm_obj.put(DataElement::new(
-        tags::PATIENT_AGE,
-        VR::AS,
-        dicom_value!(Strs, ["099Y".to_string()]),
-    ));
-    datetime_deleted_dcm_obj.put(DataElement::new(
-        tags::PATIENT_SEX,
-        VR::CS,
-        dicom_value!(Strs, ["O".to_string()]),
-    ));
-
-    Ok(datetime_deleted_dcm_obj)
+    let dicom_date_data = dicom_vr_corrected_value(VR::DA, &date_str, &LengthPolicy::Error)?;
+    let dicom_time_data = dicom_vr_corrected_value(VR::TM, &time_str, &LengthPolicy::Error)?;
+    let dicom_date_time = dicom_vr_corrected_value(VR::DT, &date_time, &LengthPolicy::Error)?;
+
+    let (date_deleted_dcm_obj, date_changed) =
+        mask_all_vr(dcm_obj.clone(), VR::DA, dicom_date_data, keep)?;
+    let (time_deleted_dcm_obj, time_changed) =
+        mask_all_vr(date_deleted_dcm_obj.clone(), VR::TM, dicom_time_data, keep)?;
+    let (mut datetime_deleted_dcm_obj, datetime_changed) =
+        mask_all_vr(time_deleted_dcm_obj.clone(), VR::DT, dicom_date_time, keep)?;
+    let mut changed = date_changed + time_changed + datetime_changed;
+
+    for (each_tag, each_vr, each_value) in [
+        (tags::PATIENT_AGE, VR::AS, "099Y"),
+        (tags::PATIENT_SEX, VR::CS, "O"),
+    ] {
+        if !keep.protects(each_tag, "the date pass") {
+            datetime_deleted_dcm_obj.put(DataElement::new(
+                each_tag,
+                each_vr,
+                dicom_value!(Strs, [each_value.to_string()]),
+            ));
+            changed += 1;
+        }
+    }
+    // The offset would contradict the flattened times and leak the acquisition timezone
+    if !keep.protects(tags::TIMEZONE_OFFSET_FROM_UTC, "the date pass")
+        && datetime_deleted_dcm_obj.remove_element(tags::TIMEZONE_OFFSET_FROM_UTC)
+    {
+        changed += 1;
+    }
+
+    Ok((datetime_deleted_dcm_obj, changed))
+}
+
+// One row per value found in the files, normalized_value is the value the AnonID is keyed on
+fn write_anon_mapping(anon_ids: &AnonIdMap, mapping_path: &PathBuf) -> Result<()> {
+    let mut mapping_list: Vec<_> = anon_ids.iter().collect();
+    mapping_list.sort_by(|a, b| a.0.cmp(b.0));
+    let mut mapping_csv = "anon_key,value,normalized_value,anon_id\n".to_string();
+    for ((key, normalized_value), entry) in mapping_list {
+        for value in &entry.raw_values {
+            mapping_csv.push_str(&format!(
+                "{},{},{},{}\n",
+                key,
+                csv_field(value),
+                csv_field(normalized_value),
+                entry.anon_id
+            ));
+        }
+    }
+    fs::write(mapping_path, mapping_csv)?;
+    info!("AnonID mapping written to: {}", mapping_path.display());
+    Ok(())
 }
@@ -1,7 +1,15 @@
-use clap::{Args, Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use dcmrig_rs::{
+    parse_ae_title, parse_anon_prefix, parse_derived_values, parse_file_size,
+    parse_institution_policy, parse_interval, parse_route_destination, parse_sample_fraction,
+    parse_tag_keyword, parse_uid_root, CompressionCodec, DerivedValues, InstitutionPolicy,
+    LengthPolicy, MultiValueMode, OutputCharset, Parallelism, RouteDestination, StaffNamePolicy,
+    StudyDirStyle, DEFAULT_UID_ROOT,
+};
+use serde::Serialize;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, Serialize)]
 #[clap(
     author = "Birendra Rokaha <birenrokaha1@gmail.com>",
     version,
@@ -13,9 +21,178 @@ pub struct ArgsParser {
     /// Verbose output
     #[arg(short = 'v', long = "verbose")]
     pub verbose: bool,
+    /// Exit with a non zero code if any file failed to process
+    #[arg(long = "fail-on-errors")]
+    pub fail_on_errors: bool,
+    /// Write the run summary as a JSON file to the given path
+    #[arg(long = "summary-json")]
+    pub summary_json: Option<PathBuf>,
+    /// Re-encode the uncompressed PixelData of the anon and deid outputs losslessly
+    #[arg(long = "compress", value_enum)]
+    pub compress: Option<CompressionCodec>,
+    /// Write the run summary, the report tables and the configuration as a self contained HTML file
+    #[arg(long = "html-report")]
+    pub html_report: Option<PathBuf>,
+    /// Serve the counters of the running sort, anon, deid or report as JSON over HTTP on the given port
+    #[arg(long = "status-port")]
+    pub status_port: Option<u16>,
+    /// Address the status listener of --status-port binds to, Default 127.0.0.1 so only local probes reach it
+    #[arg(long = "status-bind", default_value = "127.0.0.1")]
+    pub status_bind: IpAddr,
+    /// How multi-valued tags are used in file names and paths
+    #[arg(long = "multi-value", value_enum, default_value_t = MultiValueMode::Join)]
+    pub multi_value: MultiValueMode,
+    /// Zero pad width of the InstanceNumber in file names
+    #[arg(long = "pad-width", default_value_t = 5)]
+    pub pad_width: usize,
+    /// Derived values appended to the series directory eg ImagePlane,EchoTimeMs, or none
+    /// ImagePlane, MultiFrame, IsDerived and EchoTimeMs
+    #[arg(long = "series-derived", value_parser = parse_derived_values, default_value = "ImagePlane")]
+    pub series_derived: DerivedValues,
+    /// Write the effective configuration to the given path as JSON, or TOML with a .toml extension
+    #[arg(long = "dump-config")]
+    pub dump_config: Option<PathBuf>,
+    /// Skip files of the given SOP class UID or keyword eg SR, KO, GSPS, RTDOSE, can be repeated
+    #[arg(long = "exclude-sop-class")]
+    pub exclude_sop_class: Vec<String>,
+    /// Copy the files skipped by --exclude-sop-class to an EXCLUDED_SOP directory
+    #[arg(long = "keep-excluded")]
+    pub keep_excluded: bool,
+    /// Nest NON_DICOM, FAILED_CASES and EXCLUDED_SOP under a run timestamp directory
+    /// eg FAILED_CASES/2024-06-01T1030
+    #[arg(long = "run-subdir")]
+    pub run_subdir: bool,
+    /// Attempts for each copy or write to the destination before the file counts as failed
+    #[arg(long = "io-retries", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub io_retries: u32,
+    /// Delay before the first IO retry in milliseconds, doubled for every further retry
+    #[arg(long = "io-retry-delay-ms", default_value_t = 100)]
+    pub io_retry_delay_ms: u64,
+    /// Abandon the read of a source file after this time eg 300 or 5m, it is copied to FAILED_CASES
+    #[arg(long = "file-timeout", value_parser = parse_interval)]
+    pub file_timeout: Option<Duration>,
+    /// Abort before processing if any directory or file of the source can not be read
+    #[arg(long = "fail-on-walk-errors")]
+    pub fail_on_walk_errors: bool,
+    /// Process files in parallel, or series in parallel with the files of a series in instance order
+    #[arg(long = "parallelism", value_enum, default_value_t = Parallelism::File)]
+    pub parallelism: Parallelism,
+    /// Files of the source opened and read at the same time, Default unlimited
+    #[arg(long = "read-concurrency", value_parser = clap::value_parser!(u32).range(1..))]
+    pub read_concurrency: Option<u32>,
+    /// Files written or copied to the destination at the same time, Default unlimited
+    #[arg(long = "write-concurrency", value_parser = clap::value_parser!(u32).range(1..))]
+    pub write_concurrency: Option<u32>,
+    /// Files per destination directory, the overflow goes to numbered sub-directories 0001, 0002..
+    #[arg(long = "max-files-per-dir", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_files_per_dir: Option<u64>,
+    /// Write a series sharing its generated directory with another series to <dir>_<UID tail>
+    #[arg(long)]
+    pub strict_layout: bool,
+    /// Study directory names, date=<StudyDate>T<StudyTime>_<UID tail> of the written file
+    /// ordinal=STUDY_01, STUDY_02.. per patient in the order of the original study dates
+    #[arg(long, value_enum, default_value_t = StudyDirStyle::Date)]
+    pub study_dir_style: StudyDirStyle,
+    /// Process the directories under the source root one by one for sort, anon and deid and
+    /// log the files and failures of each directory once it is done
+    #[arg(long = "progress-by", value_enum, default_value_t = ProgressBy::None)]
+    pub progress_by: ProgressBy,
+    /// Write a DICOMDIR of the written files to the destination after sort, anon or deid
+    #[arg(long = "write-dicomdir")]
+    pub write_dicomdir: bool,
+    /// Name the written files DICOM/P0000001/S0000001/SE000001/I0000001 so they are valid on CD and DVD media
+    #[arg(long = "media-compatible")]
+    pub media_compatible: bool,
+    /// Rewrite elements with a VR other than the dictionary VR when the value converts, eg an explicit UN
+    #[arg(long = "fix-vrs")]
+    pub fix_vrs: bool,
+    /// Read the headers of files whose PixelData can't be read, sort copies them and anon and deid count them apart
+    #[arg(long = "tolerate-truncated-pixeldata")]
+    pub tolerate_truncated_pixeldata: bool,
+    /// Only process the files referenced by a DICOMDIR at the source root, if there is one
+    #[arg(long = "use-dicomdir")]
+    pub use_dicomdir: bool,
+    /// Run even if a previous run on the destination did not complete
+    #[arg(long = "force")]
+    pub force: bool,
+    /// Continue a previous run on the destination that did not complete, keeping the files it wrote
+    #[arg(long = "skip-existing", conflicts_with = "force")]
+    pub skip_existing: bool,
+    /// Take over the lock of a destination whose run crashed, once its PID is no longer running
+    #[arg(long = "force-unlock")]
+    pub force_unlock: bool,
+    /// Also copy empty files and files smaller than 132 bytes to NON_DICOM
+    #[arg(long = "copy-non-dicom-all")]
+    pub copy_non_dicom_all: bool,
+    /// Write a CSV of the empty files and files smaller than 132 bytes to the given path
+    #[arg(long = "too-small-csv")]
+    pub too_small_csv: Option<PathBuf>,
+    /// Leave out files over this size eg 2G or 500M without opening them, listed in oversize_skipped.csv
+    #[arg(long = "max-file-size", value_parser = parse_file_size)]
+    pub max_file_size: Option<u64>,
+    /// Process only the first file of each SOPInstanceUID across the source, eg a study copied under two roots
+    #[arg(long = "dedupe-global")]
+    pub dedupe_global: bool,
+    /// Values over the length limit of their VR eg 16 for SH, fail the file, truncate or keep them
+    #[arg(long = "vr-length", value_enum, default_value_t = LengthPolicy::Error)]
+    pub vr_length: LengthPolicy,
+    /// Process and write the files one by one in path order so identical runs give identical output
+    #[arg(long)]
+    pub deterministic: bool,
+    /// Seed of the generated AnonIDs and of --sample, Default 0 with --deterministic, random otherwise
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Smoke test on the first N files in path order, the destination is marked as partial
+    #[arg(long, conflicts_with = "sample", value_parser = clap::value_parser!(u64).range(1..))]
+    pub limit: Option<u64>,
+    /// Smoke test on a random fraction of the files eg 0.01, the destination is marked as partial
+    #[arg(long, value_parser = parse_sample_fraction)]
+    pub sample: Option<f64>,
+    /// Don't restrict the names and file sizes on a FAT or exFAT destination
+    #[arg(long)]
+    pub ignore_fs_limits: bool,
+    /// Institution identifiers for anon and deid: remove, keep, id or replace:<site code>
+    /// Overrides the cookbook [identity] institution
+    #[arg(long, value_parser = parse_institution_policy)]
+    pub institution: Option<InstitutionPolicy>,
+    /// Operator and physician names for anon and deid, replace writes the AnonID or DeID
+    /// Overrides the cookbook [identity] staff_names
+    #[arg(long, value_enum)]
+    pub staff_names: Option<StaffNamePolicy>,
+    /// Normalize PatientIDs before the AnonID and mapping table lookups of anon and deid
+    /// Steps from the cookbook [normalize] patient_id, Default spaces, hyphens and uppercase
+    #[arg(long)]
+    pub normalize_patient_id: bool,
+    /// Root of the anon UIDs and of the ImplementationClassUID <root>.1 of the anon and deid files
+    #[arg(long, value_parser = parse_uid_root, default_value = DEFAULT_UID_ROOT)]
+    pub uid_root: String,
+    /// SourceApplicationEntityTitle of the file meta group of the anon and deid files
+    #[arg(long, value_parser = parse_ae_title, default_value = "DCMRIG")]
+    pub source_ae_title: String,
+    /// Allow tags identifying the patient like PatientName in the cookbook [keep] list
+    #[arg(long)]
+    pub allow_risky_keep: bool,
+    /// Allow cookbook mask and delete rules on the UIDs and file meta tags every DICOM file needs
+    #[arg(long)]
+    pub allow_breaking_rules: bool,
+    /// Run the conformance checks of the check subcommand on each anon and deid file before it is written
+    #[arg(long)]
+    pub post_check: bool,
+    /// Log PatientIDs, names, match values and source paths as short hashes
+    #[arg(long)]
+    pub redact_logs: bool,
+    /// Hash to value lookup of the redacted logs, Default <destination>/log_redaction_keys.csv
+    #[arg(long, requires = "redact_logs")]
+    pub log_key_out: Option<PathBuf>,
+    /// Destination root of a cookbook route for sort, anon and deid eg derived=/data/derived, can be repeated
+    #[arg(long, value_parser = parse_route_destination)]
+    pub route: Vec<RouteDestination>,
+    /// Cookbook of deid and cookbook check, Default ~/.dcmrig/cookbook.toml
+    #[arg(long)]
+    pub cookbook: Option<PathBuf>,
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Subcommand, Serialize)]
 pub enum EntityType {
     /// Sort the given source with any combination of PatientID, PatientName or Modality
     Sort(SortCommand),
@@ -23,47 +200,247 @@ pub enum EntityType {
     Anon(AnonCommand),
     /// Deidentify the given source based on a mapping table
     Deid(DeidCommand),
-    /// [NON FUNCTIONAL] Generate a report for a sorted dataset
+    /// Generate CSV, xlsx or SQLite reports for the given source
     Report(ReportCommand),
+    /// Check the deid cookbook
+    Cookbook(CookbookCommand),
+    /// Check DICOM files against a subset of the IOD rules
+    Check(CheckCommand),
+    /// Run the steps of a runfile one after another
+    Batch(BatchCommand),
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Args, Serialize)]
 pub struct SortCommand {
     /// Sort order can be any combination of I=PatientID, N=PatientName, and M=Modality
     #[clap(short, long, default_value = "I")]
     pub sort_order: String,
+    /// Replace the PatientID and StudyInstanceUID directory names with 8 character hashes
+    #[clap(long)]
+    pub hash_dirs: bool,
+    /// Write a CSV of hash,original_value for the hashed directory names
+    #[clap(long, requires = "hash_dirs")]
+    pub hash_key_out: Option<PathBuf>,
+    /// File name of the sorted files. both appends the original name after the generated name
+    #[clap(long, value_enum, default_value_t = FilenameMode::Generated)]
+    pub filename: FilenameMode,
+    /// Keep the original file name, same as --filename original
+    #[clap(long, conflicts_with = "filename")]
+    pub keep_filename: bool,
+    /// Split the series directories by EchoNumbers, DiffusionBValue or TemporalPositionIdentifier
+    #[clap(long, value_enum)]
+    pub split_by: Option<SplitBy>,
+    /// Compare the instances written per StudyInstanceUID with a CSV of StudyInstanceUID,expected count
+    #[clap(long)]
+    pub expected_manifest: Option<PathBuf>,
     /// Source data path, All files will be recursively indexed
     pub source: PathBuf,
     /// Destination data path, the paths will be recursively created
     pub destination: PathBuf,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+pub enum FilenameMode {
+    Generated,
+    Original,
+    Both,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+pub enum SplitBy {
+    Echo,
+    Bvalue,
+    Temporal,
+}
+
+#[derive(Debug, Args, Serialize)]
 pub struct AnonCommand {
-    /// Prefix for the ANON ID, Default Blank
-    #[clap(short, long, default_value = "")]
+    /// Prefix for the ANON ID of letters, digits, underscore and hyphen, Default Blank
+    #[clap(short, long, default_value = "", value_parser = parse_anon_prefix)]
     pub prefix: String,
+    /// Tags that key the AnonID, the first one with a value is used
+    /// Files without any get an AnonID of their own
+    #[clap(long, value_delimiter = ',', default_value = "PatientID", value_parser = parse_tag_keyword)]
+    pub anon_key: Vec<String>,
+    /// Write a CSV of anon_key,value,anon_id for the AnonIDs given
+    #[clap(long)]
+    pub mapping_out: Option<PathBuf>,
+    /// SpecificCharacterSet of the output files, utf8=ISO_IR 192 latin1=ISO_IR 100
+    #[clap(long, value_enum, default_value_t = OutputCharset::Utf8)]
+    pub charset: OutputCharset,
+    /// Don't write files referencing instances not in the run like a KO of missing images
+    /// They are listed in unsafe_references.csv, Default written with a warning
+    #[clap(long)]
+    pub drop_unsafe_references: bool,
     /// Source data path, All files will be recursively indexed
     pub source: PathBuf,
     /// Destination data path, the paths will be recursively created
     pub destination: PathBuf,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Args, Serialize)]
 pub struct DeidCommand {
     /// Mapping table in the following order seperated by line DEID,PatientID eg DEID_001,U012345
+    /// An optional third column sets the PatientName eg DEID_001,U012345,SUBJECT^001
+    /// A JSON array of objects is also accepted eg [{"deid": "DEID_001", "patient_id": "U012345"}]
     #[clap(short, long)]
     pub mapping_table: PathBuf,
+    /// Key names for the DeID, PatientID and optional PatientName fields of a JSON mapping table
+    #[clap(long, default_value = "deid,patient_id,patient_name")]
+    pub map_keys: String,
+    /// Mapping level. study also matches each StudyInstanceUID against the study table
+    #[clap(long, value_enum, default_value_t = MapLevel::Patient)]
+    pub map_level: MapLevel,
+    /// Normalization of the match values, whitespace and null padding is always trimmed
+    #[clap(long, value_enum, default_value_t = MatchNormalize::Exact)]
+    pub match_normalize: MatchNormalize,
+    /// Study mapping table seperated by line LABEL,StudyInstanceUID eg V01,1.2.840.1234
+    /// The label is written to ClinicalTrialTimePointID and StudyID
+    #[clap(long)]
+    pub study_table: Option<PathBuf>,
+    /// Key names for the label and StudyInstanceUID fields of a JSON study table
+    #[clap(long, default_value = "label,study_instance_uid")]
+    pub study_map_keys: String,
+    /// Abort before processing if any cookbook add value is invalid for its VR or a tag is listed in
+    /// more than one of mask, add and delete
+    #[clap(long)]
+    pub strict_cookbook: bool,
+    /// Fail the files where PatientID, PatientName or a masked tag kept its original value
+    #[clap(long)]
+    pub strict: bool,
+    /// Write the error chain and the modified tags of each failed file next to its copy in FAILED_CASES
+    #[clap(long)]
+    pub debug_failed: bool,
+    /// Merge the entries added to the mapping table during the run at this interval eg 10m
+    #[clap(long, value_parser = parse_interval)]
+    pub reload_mapping_every: Option<Duration>,
+    /// SpecificCharacterSet of the output files, utf8=ISO_IR 192 latin1=ISO_IR 100
+    #[clap(long, value_enum, default_value_t = OutputCharset::Keep)]
+    pub charset: OutputCharset,
     /// Source data path, All files will be recursively indexed
     pub source: PathBuf,
     /// Destination data path, the paths will be recursively created
     pub destination: PathBuf,
 }
 
-#[derive(Debug, Args)]
+/// Grouping of the progress log lines
+#[derive(Debug, Clone, Default, PartialEq, ValueEnum, Serialize)]
+pub enum ProgressBy {
+    /// Only the overall progress bar
+    #[default]
+    None,
+    /// A line per directory directly under the source root once all its files are done
+    TopDir,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+pub enum MapLevel {
+    Patient,
+    Study,
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+pub enum MatchNormalize {
+    Exact,
+    CaseInsensitive,
+}
+
+#[derive(Debug, Args, Serialize)]
+#[clap(group(ArgGroup::new("section").required(true).multiple(true)))]
 pub struct ReportCommand {
     /// Source data path, All files will be recursively indexed
     pub source: PathBuf,
-    /// Destination data path for the csv file
+    /// Destination directory for the csv files
     pub destination: PathBuf,
+    /// Write devices.csv with the studies and series of each scanner
+    #[clap(long, group = "section")]
+    pub devices: bool,
+    /// Write instances.csv with a row per file, streamed as the files are read
+    #[clap(long, group = "section")]
+    pub per_instance: bool,
+    /// Columns of instances.csv, Default all
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        requires = "per_instance",
+        default_values_t = InstanceColumn::all()
+    )]
+    pub columns: Vec<InstanceColumn>,
+    /// Write distinct_<keyword>.csv with the raw values of each tag and the files carrying them
+    /// eg InstitutionName,ReferringPhysicianName,StationName
+    #[clap(long, group = "section", value_delimiter = ',', value_parser = parse_tag_keyword)]
+    pub distinct: Vec<String>,
+    /// Distinct values kept per tag, the files of the values seen after that are counted as other
+    #[clap(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub distinct_cap: u64,
+    /// Write a csv file per report section, a single report.xlsx with a sheet per section,
+    /// or a SQLite database with a table per section
+    #[clap(long, value_enum, default_value_t = ReportFormat::Csv)]
+    pub format: ReportFormat,
+    /// SQLite database of --format sqlite, Default <destination>/index.db
+    #[clap(long)]
+    pub out: Option<PathBuf>,
+    /// Add this run to an existing SQLite database instead of refusing it
+    #[clap(long)]
+    pub append: bool,
+}
+
+#[derive(Debug, Args, Serialize)]
+pub struct CookbookCommand {
+    #[clap(subcommand)]
+    pub action: CookbookAction,
+}
+
+#[derive(Debug, Args, Serialize)]
+pub struct CheckCommand {
+    /// Source data path, All files will be recursively indexed
+    #[clap(long)]
+    pub conformance: PathBuf,
+    /// CSV with a row per finding, Default <source>/conformance_findings.csv
+    #[clap(long)]
+    pub findings: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, Serialize)]
+pub struct BatchCommand {
+    /// TOML runfile with a [[step]] table per sort, anon, deid, report or cookbook run
+    pub runfile: PathBuf,
+}
+
+#[derive(Debug, Subcommand, Serialize)]
+pub enum CookbookAction {
+    /// Validate the cookbook and print the effective configuration with its includes merged
+    Check {
+        /// Fail if any cookbook add value is invalid for its VR or a tag is in more than one action
+        #[clap(long)]
+        strict_cookbook: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+pub enum InstanceColumn {
+    Path,
+    Size,
+    SopInstanceUid,
+    SeriesInstanceUid,
+    StudyInstanceUid,
+    PatientId,
+    Modality,
+    StudyDate,
+    InstanceNumber,
+    TransferSyntax,
+}
+
+impl InstanceColumn {
+    pub fn all() -> Vec<InstanceColumn> {
+        InstanceColumn::value_variants().to_vec()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+pub enum ReportFormat {
+    Csv,
+    Xlsx,
+    Sqlite,
 }
@@ -0,0 +1,247 @@
+use crate::args::{ArgsParser, EntityType};
+use anyhow::Result;
+use clap::Parser;
+use dcmrig_rs::{RunStatus, RunSummary, EXIT_INTERRUPTED};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf};
+use tracing::{error, info, warn};
+
+// Replaced with the destination of the step before
+const PREV_DESTINATION: &str = "${prev.destination}";
+
+/// Steps of a batch, run in the order of the runfile
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RunFile {
+    /// Run the next steps after a failed step
+    #[serde(default)]
+    continue_on_error: bool,
+    /// Options of every step given before the action eg ["--run-subdir"]
+    #[serde(default)]
+    options: Vec<String>,
+    /// Combined summary of the steps, Default batch_summary.json in the last destination
+    summary_json: Option<PathBuf>,
+    #[serde(rename = "step", default)]
+    steps: Vec<RunStep>,
+}
+
+/// A single sort, anon, deid, report or cookbook run
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RunStep {
+    name: Option<String>,
+    action: String,
+    source: Option<String>,
+    destination: Option<String>,
+    cookbook: Option<String>,
+    mapping_table: Option<String>,
+    /// Options given before the action, after the options of the runfile
+    #[serde(default)]
+    options: Vec<String>,
+    /// Arguments of the action eg ["--hash-dirs"]
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// Result of a step in the combined summary
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StepStatus {
+    Complete,
+    Failed,
+    Interrupted,
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+struct StepReport {
+    name: String,
+    action: String,
+    status: StepStatus,
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<RunSummary>,
+}
+
+/// Command line of a step, parsed like the arguments of a single run
+struct BatchStep {
+    name: String,
+    action: String,
+    destination: Option<PathBuf>,
+    args: ArgsParser,
+}
+
+/// Run the steps of the runfile with the given run function and write the combined summary
+/// All steps are parsed before the first one runs so a typo doesn't stop the batch halfway
+/// Returns the exit code of the first failed step
+pub fn run_batch(
+    runfile_path: &PathBuf,
+    run: fn(ArgsParser) -> Result<(i32, Option<RunSummary>)>,
+) -> Result<i32> {
+    let runfile: RunFile = toml::from_str(&fs::read_to_string(runfile_path)?).map_err(|e| {
+        anyhow::Error::msg(format!(
+            "Can't read the runfile {}: {}",
+            runfile_path.display(),
+            e
+        ))
+    })?;
+    if runfile.steps.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "The runfile {} has no [[step]]",
+            runfile_path.display()
+        )));
+    }
+    let steps = parse_steps(&runfile)?;
+    let summary_path = runfile.summary_json.clone().or_else(|| {
+        steps
+            .iter()
+            .rev()
+            .find_map(|step| step.destination.as_ref())
+            .map(|destination| destination.join("batch_summary.json"))
+    });
+
+    let step_count = steps.len();
+    let mut reports = Vec::new();
+    let mut exit_code = 0;
+    let mut stopped = false;
+    for (index, step) in steps.into_iter().enumerate() {
+        let mut report = StepReport {
+            name: step.name,
+            action: step.action,
+            status: StepStatus::Skipped,
+            exit_code: 0,
+            error: None,
+            summary: None,
+        };
+        if stopped {
+            reports.push(report);
+            continue;
+        }
+        info!(
+            "Batch step {}/{}: {} ({})",
+            index + 1,
+            step_count,
+            report.name,
+            report.action
+        );
+        match run(step.args) {
+            Ok((step_exit_code, summary)) => {
+                report.exit_code = step_exit_code;
+                report.status = match (&summary, step_exit_code) {
+                    (Some(summary), _) if summary.status == RunStatus::Interrupted => {
+                        StepStatus::Interrupted
+                    }
+                    (_, 0) => StepStatus::Complete,
+                    _ => StepStatus::Failed,
+                };
+                report.summary = summary;
+            }
+            Err(e) => {
+                error!("Batch step {} failed: {}", report.name, e);
+                report.exit_code = 1;
+                report.status = StepStatus::Failed;
+                report.error = Some(e.to_string());
+            }
+        }
+        match report.status {
+            StepStatus::Interrupted => {
+                exit_code = EXIT_INTERRUPTED;
+                stopped = true;
+            }
+            StepStatus::Failed => {
+                if exit_code == 0 {
+                    exit_code = report.exit_code;
+                }
+                if !runfile.continue_on_error {
+                    warn!("Batch stopped after the failed step {}", report.name);
+                    stopped = true;
+                }
+            }
+            _ => (),
+        }
+        reports.push(report);
+    }
+
+    if let Some(summary_path) = summary_path {
+        let batch_summary = serde_json::json!({
+            "runfile": runfile_path,
+            "exit_code": exit_code,
+            "steps": reports,
+        });
+        fs::write(&summary_path, serde_json::to_string_pretty(&batch_summary)?)?;
+        info!("Batch summary written to: {}", summary_path.display());
+    }
+    Ok(exit_code)
+}
+
+/// Build and parse the command line of every step
+/// ${prev.destination} in any value is the destination of the step before
+fn parse_steps(runfile: &RunFile) -> Result<Vec<BatchStep>> {
+    let mut steps = Vec::new();
+    let mut prev_destination: Option<String> = None;
+    for (index, step) in runfile.steps.iter().enumerate() {
+        let name = step
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{}_{}", index + 1, step.action));
+        let resolve = |value: &String| -> Result<String> {
+            match (value.contains(PREV_DESTINATION), &prev_destination) {
+                (false, _) => Ok(value.clone()),
+                (true, Some(prev_destination)) => {
+                    Ok(value.replace(PREV_DESTINATION, prev_destination))
+                }
+                (true, None) => Err(anyhow::Error::msg(format!(
+                    "Step {} uses {} without a step with a destination before it",
+                    name, PREV_DESTINATION
+                ))),
+            }
+        };
+
+        let mut argv = vec!["dcmrig".to_string()];
+        for option in runfile.options.iter().chain(&step.options) {
+            argv.push(resolve(option)?);
+        }
+        if let Some(cookbook) = &step.cookbook {
+            argv.extend(["--cookbook".to_string(), resolve(cookbook)?]);
+        }
+        argv.extend(step.action.split_whitespace().map(|a| a.to_string()));
+        if let Some(mapping_table) = &step.mapping_table {
+            argv.extend(["--mapping-table".to_string(), resolve(mapping_table)?]);
+        }
+        for arg in &step.args {
+            argv.push(resolve(arg)?);
+        }
+        let destination = step.destination.as_ref().map(resolve).transpose()?;
+        for path in [
+            step.source.as_ref().map(resolve).transpose()?,
+            destination.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            argv.push(path);
+        }
+
+        let args = ArgsParser::try_parse_from(&argv).map_err(|e| {
+            anyhow::Error::msg(format!("Invalid batch step {}: {}", name, e.render()))
+        })?;
+        if let EntityType::Batch(_) = args.action_type {
+            return Err(anyhow::Error::msg(format!(
+                "Batch step {} can't run another batch",
+                name
+            )));
+        }
+        if destination.is_some() {
+            prev_destination = destination.clone();
+        }
+        steps.push(BatchStep {
+            name,
+            action: step.action.clone(),
+            destination: destination.map(PathBuf::from),
+            args,
+        });
+    }
+    Ok(steps)
+}
@@ -0,0 +1,439 @@
+use anyhow::Result;
+use dcmrig_rs::*;
+use dicom::{
+    core::{header::Header, VR},
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, OpenFileOptions, Tag},
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tracing::{debug, info, warn};
+
+/// Presence rule of an attribute, Type 1 needs a value and Type 2 only has to be present
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AttributeType {
+    Type1,
+    Type2,
+}
+
+use AttributeType::{Type1, Type2};
+
+/// Attributes of a module with their type
+type ModuleAttributes = &'static [(Tag, AttributeType)];
+
+/// Attributes of the patient, study, series, equipment and SOP common modules of every
+/// storage SOP class
+static COMMON_ATTRIBUTES: &[(Tag, AttributeType)] = &[
+    (tags::SOP_CLASS_UID, Type1),
+    (tags::SOP_INSTANCE_UID, Type1),
+    (tags::STUDY_INSTANCE_UID, Type1),
+    (tags::SERIES_INSTANCE_UID, Type1),
+    (tags::MODALITY, Type1),
+    (tags::PATIENT_NAME, Type2),
+    (tags::PATIENT_ID, Type2),
+    (tags::PATIENT_BIRTH_DATE, Type2),
+    (tags::PATIENT_SEX, Type2),
+    (tags::STUDY_DATE, Type2),
+    (tags::STUDY_TIME, Type2),
+    (tags::REFERRING_PHYSICIAN_NAME, Type2),
+    (tags::STUDY_ID, Type2),
+    (tags::ACCESSION_NUMBER, Type2),
+    (tags::SERIES_NUMBER, Type2),
+    (tags::MANUFACTURER, Type2),
+    (tags::INSTANCE_NUMBER, Type2),
+];
+
+/// Image pixel module of the image SOP classes below
+static IMAGE_PIXEL_ATTRIBUTES: &[(Tag, AttributeType)] = &[
+    (tags::SAMPLES_PER_PIXEL, Type1),
+    (tags::PHOTOMETRIC_INTERPRETATION, Type1),
+    (tags::ROWS, Type1),
+    (tags::COLUMNS, Type1),
+    (tags::BITS_ALLOCATED, Type1),
+    (tags::BITS_STORED, Type1),
+    (tags::HIGH_BIT, Type1),
+    (tags::PIXEL_REPRESENTATION, Type1),
+];
+
+/// SOP class UID, name and the attributes of its IOD specific modules
+/// Only a pragmatic subset of the IODs, conditional attributes are left out
+static SOP_CLASS_RULES: &[(&str, &str, ModuleAttributes)] = &[
+    (
+        "1.2.840.10008.5.1.4.1.1.2",
+        "CT Image",
+        &[
+            (tags::IMAGE_TYPE, Type1),
+            (tags::RESCALE_INTERCEPT, Type1),
+            (tags::RESCALE_SLOPE, Type1),
+            (tags::KVP, Type2),
+            (tags::ACQUISITION_NUMBER, Type2),
+        ],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.4",
+        "MR Image",
+        &[
+            (tags::IMAGE_TYPE, Type1),
+            (tags::SCANNING_SEQUENCE, Type1),
+            (tags::SEQUENCE_VARIANT, Type1),
+            (tags::SCAN_OPTIONS, Type2),
+            (tags::MR_ACQUISITION_TYPE, Type2),
+            (tags::ECHO_TIME, Type2),
+            (tags::ECHO_TRAIN_LENGTH, Type2),
+        ],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.6.1",
+        "US Image",
+        &[(tags::IMAGE_TYPE, Type2)],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.3.1",
+        "US Multi-frame Image",
+        &[(tags::IMAGE_TYPE, Type2), (tags::NUMBER_OF_FRAMES, Type1)],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.7",
+        "Secondary Capture Image",
+        &[(tags::CONVERSION_TYPE, Type1)],
+    ),
+];
+
+/// Errors break the conformance of the file, warnings are worth a look
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A single rule a file breaks
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    pub keyword: String,
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, tag: Tag, rule: &'static str, message: String) -> Self {
+        Finding {
+            severity,
+            keyword: tag_keyword(tag),
+            rule,
+            message,
+        }
+    }
+}
+
+pub fn dicom_check(
+    source_path: PathBuf,
+    findings_path: Option<PathBuf>,
+    run_options: RunOptions,
+) -> Result<RunSummary> {
+    let start_time = std::time::Instant::now();
+    info!(
+        "Checking the conformance of >> SOURCE: {}",
+        source_path.display()
+    );
+
+    // The checked files are only read, the findings go next to them unless a path is given
+    let findings_path =
+        findings_path.unwrap_or_else(|| source_path.join("conformance_findings.csv"));
+    let IndexSetup {
+        all_files,
+        too_small,
+        oversize,
+        total_len,
+        pb,
+        walk_errors,
+    } = index_setup(&source_path, &run_options)?;
+    let outcomes = OutcomeTracker::new(RunCounts {
+        walk_errors,
+        too_small: too_small.len() as u64,
+        oversize: oversize.len() as u64,
+        ..Default::default()
+    })
+    .with_run_callbacks(&run_options);
+    let mut findings_csv = BufWriter::new(File::create(&findings_path)?);
+    writeln!(findings_csv, "path,severity,keyword,rule,message")?;
+    let findings_csv = Mutex::new(findings_csv);
+    let warnings = AtomicU64::new(0);
+
+    // Main Loop
+    let not_dispatched = for_each_source_file(&all_files, &run_options, |working_path| {
+        match run_options.read_gate.run(|| {
+            OpenFileOptions::new()
+                .read_until(tags::PIXEL_DATA)
+                .open_file(working_path.path())
+        }) {
+            Ok(dcm_obj) => {
+                let mut findings = conformance_findings(&dcm_obj);
+                findings.extend(preamble_finding(working_path.path()));
+                write_findings(working_path.path(), &findings, &findings_csv);
+                let errors: Vec<_> = findings
+                    .iter()
+                    .filter(|finding| finding.severity == Severity::Error)
+                    .collect();
+                warnings.fetch_add((findings.len() - errors.len()) as u64, Ordering::Relaxed);
+                if !errors.is_empty() {
+                    outcomes.record(FileOutcome {
+                        error: Some(format!("{} conformance errors", errors.len())),
+                        ..FileOutcome::new(working_path.path(), FileStatus::Failed)
+                    });
+                }
+            }
+            Err(_) => {
+                debug!(
+                    "Not a DICOM file {:?}",
+                    phi(working_path.file_name().to_string_lossy())
+                );
+                outcomes.record(FileOutcome::new(working_path.path(), FileStatus::NonDicom));
+            }
+        }
+        pb.inc(1);
+    });
+    pb.finish();
+    findings_csv
+        .into_inner()
+        .expect("Failed to lock mutex")
+        .flush()?;
+    let counts = outcomes.counts();
+    match counts.failed {
+        0 => info!("No conformance errors found"),
+        failed => warn!("{} files with conformance errors", failed),
+    }
+    info!(
+        "{} conformance warnings, findings written to {}",
+        warnings.into_inner(),
+        findings_path.display()
+    );
+    Ok(RunSummary::new(
+        "Check".to_string(),
+        findings_path,
+        total_len,
+        RunCounts {
+            interrupted: not_dispatched,
+            ..counts
+        },
+        start_time.elapsed(),
+    ))
+}
+
+fn write_findings(file_path: &Path, findings: &[Finding], findings_csv: &Mutex<BufWriter<File>>) {
+    if findings.is_empty() {
+        return;
+    }
+    let path = csv_field(&file_path.display().to_string());
+    let mut findings_csv = findings_csv.lock().expect("Failed to lock mutex");
+    for finding in findings {
+        if let Err(e) = writeln!(
+            findings_csv,
+            "{},{},{},{},{}",
+            path,
+            finding.severity,
+            finding.keyword,
+            finding.rule,
+            csv_field(&finding.message)
+        ) {
+            warn!(
+                "Can't write the findings of {}: {}",
+                phi(file_path.display()),
+                e
+            );
+            return;
+        }
+    }
+}
+
+/// Fail a file written by anon or deid when it breaks any conformance rule, for --post-check
+pub fn post_check(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<()> {
+    let errors: Vec<String> = conformance_findings(dcm_obj)
+        .into_iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .map(|finding| format!("{} {}", finding.keyword, finding.message))
+        .collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow::Error::msg(format!(
+            "Post check failed: {}",
+            errors.join("; ")
+        ))),
+    }
+}
+
+/// Findings of the file meta group, the attribute presence rules of the SOP class and the
+/// format of the UIDs, dates and times of the dataset
+pub fn conformance_findings(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Vec<Finding> {
+    let mut findings = meta_findings(dcm_obj);
+    let sop_class_uid = text_value(dcm_obj, tags::SOP_CLASS_UID).unwrap_or_default();
+    let sop_class_rules = SOP_CLASS_RULES
+        .iter()
+        .find(|(uid, _, _)| *uid == sop_class_uid);
+    let mut attributes: Vec<(Tag, AttributeType)> = COMMON_ATTRIBUTES.to_vec();
+    match sop_class_rules {
+        Some((_, _, iod_attributes)) => {
+            attributes.extend_from_slice(IMAGE_PIXEL_ATTRIBUTES);
+            attributes.extend_from_slice(iod_attributes);
+        }
+        None => debug!("No IOD rules for SOP class {}", sop_class_uid),
+    }
+    for (tag, attribute_type) in attributes {
+        match (text_value(dcm_obj, tag), attribute_type) {
+            (None, Type1) => findings.push(Finding::new(
+                Severity::Error,
+                tag,
+                "type1",
+                "Type 1 attribute is missing".to_string(),
+            )),
+            (Some(value), Type1) if value.is_empty() => findings.push(Finding::new(
+                Severity::Error,
+                tag,
+                "type1",
+                "Type 1 attribute is empty".to_string(),
+            )),
+            (None, Type2) => findings.push(Finding::new(
+                Severity::Warning,
+                tag,
+                "type2",
+                "Type 2 attribute is missing".to_string(),
+            )),
+            _ => (),
+        }
+    }
+    for element in dcm_obj.iter() {
+        let vr = element.vr();
+        if !matches!(vr, VR::UI | VR::DA | VR::TM | VR::DT) {
+            continue;
+        }
+        let Ok(values) = element.to_multi_str() else {
+            continue;
+        };
+        for value in values.iter().map(|v| trim_uid(v)) {
+            let (valid, rule) = match vr {
+                VR::UI => (value.is_empty() || is_valid_uid(value), "uid"),
+                _ => (is_valid_vr_value(vr, value), "date_time"),
+            };
+            if !valid {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    element.tag(),
+                    rule,
+                    format!("Invalid {} value {:?}", vr, value),
+                ));
+            }
+        }
+    }
+    findings
+}
+
+/// Group 0002 has the UIDs of the dataset and a valid transfer syntax and implementation UID
+fn meta_findings(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Vec<Finding> {
+    let meta = dcm_obj.meta();
+    let mut findings = Vec::new();
+    for (tag, uid) in [
+        (
+            tags::MEDIA_STORAGE_SOP_CLASS_UID,
+            trim_uid(&meta.media_storage_sop_class_uid),
+        ),
+        (
+            tags::MEDIA_STORAGE_SOP_INSTANCE_UID,
+            trim_uid(&meta.media_storage_sop_instance_uid),
+        ),
+        (tags::TRANSFER_SYNTAX_UID, trim_uid(&meta.transfer_syntax)),
+        (
+            tags::IMPLEMENTATION_CLASS_UID,
+            trim_uid(&meta.implementation_class_uid),
+        ),
+    ] {
+        if !is_valid_uid(uid) {
+            findings.push(Finding::new(
+                Severity::Error,
+                tag,
+                "meta",
+                format!("Missing or invalid file meta UID {:?}", uid),
+            ));
+        }
+    }
+    for (meta_tag, meta_uid, dataset_tag) in [
+        (
+            tags::MEDIA_STORAGE_SOP_CLASS_UID,
+            trim_uid(&meta.media_storage_sop_class_uid),
+            tags::SOP_CLASS_UID,
+        ),
+        (
+            tags::MEDIA_STORAGE_SOP_INSTANCE_UID,
+            trim_uid(&meta.media_storage_sop_instance_uid),
+            tags::SOP_INSTANCE_UID,
+        ),
+    ] {
+        if let Some(dataset_uid) = text_value(dcm_obj, dataset_tag) {
+            if dataset_uid != meta_uid {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    meta_tag,
+                    "meta",
+                    format!("Differs from {} of the dataset", tag_keyword(dataset_tag)),
+                ));
+            }
+        }
+    }
+    findings
+}
+
+/// A preamble that isn't all zeros may hold vendor data or the header of a TIFF or BMP
+/// the file doubles as, only ever read from the source files
+fn preamble_finding(file_path: &Path) -> Option<Finding> {
+    let mut preamble = [0u8; DICOM_PREAMBLE_LEN];
+    File::open(file_path)
+        .and_then(|mut f| f.read_exact(&mut preamble))
+        .ok()?;
+    let non_zero = preamble.iter().filter(|b| **b != 0).count();
+    match non_zero {
+        0 => None,
+        _ => Some(Finding {
+            severity: Severity::Warning,
+            keyword: "Preamble".to_string(),
+            rule: "preamble",
+            message: format!("{} non-zero bytes in the preamble", non_zero),
+        }),
+    }
+}
+
+/// Value with the padding removed, None when the attribute is missing
+fn text_value(dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag) -> Option<String> {
+    let element = dcm_obj.element(tag).ok()?;
+    Some(
+        element
+            .to_str()
+            .map(|v| trim_uid(&v).to_string())
+            .unwrap_or_default(),
+    )
+}
+
+/// Dot separated numbers without leading zeros, at most 64 characters
+fn is_valid_uid(uid: &str) -> bool {
+    !uid.is_empty()
+        && uid.len() <= 64
+        && uid.split('.').all(|component| {
+            !component.is_empty()
+                && component.chars().all(|c| c.is_ascii_digit())
+                && (component == "0" || !component.starts_with('0'))
+        })
+}
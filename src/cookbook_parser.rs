@@ -1,25 +1,199 @@
 use anyhow::Result;
+use dcmrig_rs::{
+    dicom_vr_corrected_value, extract_tag_vr_from_str, normalize_cs_value,
+    parse_institution_policy, parse_tag_stage, resolve_tag_name, tag_name_hint, vr_value_format,
+    AddMode, DeidMethod, IdentityPolicy, KeepList, LengthPolicy, MethodCode, PatientIdStep,
+    RoutePredicate, RouteRule, StaffNamePolicy, TagStage, DEFAULT_TAG_STAGE_ORDER,
+};
 use dicom::core::dictionary::DataDictionaryEntryRef;
-use dicom::core::{DataDictionary, VR};
+use dicom::core::{DataDictionary, Tag, VR};
 use dicom::object::StandardDataDictionary;
 use home::{self, home_dir};
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::io::Write;
 use std::str::FromStr;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::{self, canonicalize, create_dir_all, File},
+    path::{Path, PathBuf},
     process::exit,
 };
-use toml;
 use tracing::{error, info, warn};
 
+// Validated cookbook configuration used by deid
+// Serialized as the effective configuration for logging and --dump-config
+#[derive(Debug, Clone, Serialize)]
+pub struct CookbookConfig {
+    pub path: String,
+    // Included base cookbooks, the first one is loaded first
+    pub includes: Vec<String>,
+    // SHA-256 of the content of the cookbook and its included files
+    #[serde(rename = "sha256")]
+    pub hash: String,
+    #[serde(serialize_with = "serialize_tag_name")]
+    pub match_id: DataDictionaryEntryRef<'static>,
+    #[serde(serialize_with = "serialize_tag_names")]
+    pub mask_tags: Vec<DataDictionaryEntryRef<'static>>,
+    #[serde(serialize_with = "serialize_vrs")]
+    pub mask_vrs: Vec<VR>,
+    // Masked tags that take the PatientName value of the mapping table
+    #[serde(serialize_with = "serialize_tag_names")]
+    pub mask_name_tags: Vec<DataDictionaryEntryRef<'static>>,
+    pub add: HashMap<String, String>,
+    // Added tags only set when missing, keyed on the keyword, the others are overwritten
+    pub add_modes: HashMap<String, AddMode>,
+    #[serde(serialize_with = "serialize_tag_names")]
+    pub delete: Vec<DataDictionaryEntryRef<'static>>,
+    pub private_tags: bool,
+    // SOP class UIDs or keywords of the files to skip
+    pub exclude_sop_classes: Vec<String>,
+    // DeidentificationMethod written after the tag actions, the add values apply when unset
+    pub deid_method: Option<DeidMethod>,
+    // Institution and staff name handling, the command line options take precedence
+    pub identity: IdentityPolicy,
+    // Routing rules in cookbook order, used with --route
+    pub routes: Vec<RouteRule>,
+    // PatientID normalization steps of --normalize-patient-id, the default steps when unset
+    pub patient_id_steps: Option<Vec<PatientIdStep>>,
+    // Values of the masked AccessionNumber and StudyID
+    pub study_ids: StudyIdsConfig,
+    // Tags no rule of anon or deid may alter
+    #[serde(serialize_with = "serialize_tag_names")]
+    pub keep_tags: Vec<DataDictionaryEntryRef<'static>>,
+    #[serde(skip)]
+    pub keep: KeepList,
+    // Effective configuration of the [modality.<Modality>] sections, keyed on the uppercase Modality
+    pub modalities: BTreeMap<String, CookbookConfig>,
+    // Tag names read as another keyword or not valid, listed by cookbook check
+    pub tag_name_issues: Vec<String>,
+    // Order of the tag action stages, the private tags stage only runs with private_tags
+    pub order: Vec<TagStage>,
+}
+
+// Sections a [modality.<Modality>] override may hold
+static MODALITY_SECTIONS: [&str; 4] = ["mask", "add", "delete", "keep"];
+
+// Tags a DICOM file can't be read or imported without, masking or deleting them needs
+// --allow-breaking-rules
+static REQUIRED_TAGS: [&str; 7] = [
+    "SOPClassUID",
+    "SOPInstanceUID",
+    "StudyInstanceUID",
+    "SeriesInstanceUID",
+    "MediaStorageSOPClassUID",
+    "MediaStorageSOPInstanceUID",
+    "TransferSyntaxUID",
+];
+
+// Tags identifying the patient, keeping them needs --allow-risky-keep
+static IDENTITY_CRITICAL_TAGS: [&str; 9] = [
+    "PatientName",
+    "PatientID",
+    "PatientBirthDate",
+    "OtherPatientIDs",
+    "OtherPatientIDsSequence",
+    "OtherPatientNames",
+    "PatientAddress",
+    "PatientTelephoneNumbers",
+    "PatientMotherBirthName",
+];
+
+// Value written to the masked AccessionNumber and StudyID
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessionPolicy {
+    // The DeID of the patient, shared by all studies
+    #[default]
+    Deid,
+    // Salted hash of the original AccessionNumber, stable across runs with the same salt
+    Hash,
+    // <DeID>_<n> numbered in the order the studies of the patient are first seen
+    SequentialPerPatient,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StudyIdsConfig {
+    #[serde(default)]
+    pub accession: AccessionPolicy,
+    // Only written as <redacted>, the salt would let the hashed values be recomputed
+    #[serde(serialize_with = "serialize_redacted")]
+    pub salt: Option<String>,
+}
+
+fn serialize_tag_name<S: serde::Serializer>(
+    tag: &DataDictionaryEntryRef<'static>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(tag.alias)
+}
+
+fn serialize_tag_names<S: serde::Serializer>(
+    tag_list: &[DataDictionaryEntryRef<'static>],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(tag_list.iter().map(|t| t.alias))
+}
+
+fn serialize_vrs<S: serde::Serializer>(
+    vr_list: &[VR],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(vr_list.iter().map(|v| v.to_string()))
+}
+
+fn serialize_redacted<S: serde::Serializer>(
+    value: &Option<String>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl CookbookConfig {
+    // Tag name issues of the base and of every modality, each listed once
+    pub fn all_tag_name_issues(&self) -> Vec<String> {
+        let mut issues = self.tag_name_issues.clone();
+        for config in self.modalities.values() {
+            for issue in &config.tag_name_issues {
+                if !issues.contains(issue) {
+                    issues.push(issue.clone());
+                }
+            }
+        }
+        issues
+    }
+
+    // Configuration of a file, the override of its Modality or the base configuration
+    pub fn for_modality(&self, modality: &str) -> &CookbookConfig {
+        match self.modalities.is_empty() {
+            true => self,
+            false => self
+                .modalities
+                .get(&modality.trim().to_uppercase())
+                .unwrap_or(self),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct CookBook {
     matchid: Option<MatchIDTag>,
     mask: Option<MaskTags>,
     delete: Option<DelTags>,
     add: Option<AddTags>,
+    filter: Option<FilterConfig>,
+    deid: Option<DeidConfig>,
+    identity: Option<IdentityConfig>,
+    routes: Option<Vec<RouteConfig>>,
+    normalize: Option<NormalizeConfig>,
+    study_ids: Option<StudyIdsConfig>,
+    keep: Option<KeepTags>,
+    order: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,8 +203,15 @@ struct MatchIDTag {
 
 #[derive(Debug, Deserialize, Clone)]
 struct MaskTags {
+    #[serde(default)]
     tags: Vec<String>,
+    #[serde(default)]
     vrs: Vec<String>,
+    #[serde(default)]
+    name_tags: Vec<String>,
+    // UI in vrs masks every UID of the file, only with allow_ui = true
+    #[serde(default)]
+    allow_ui: bool,
 }
 
 impl MaskTags {
@@ -38,13 +219,29 @@ impl MaskTags {
         MaskTags {
             tags: Vec::new(),
             vrs: Vec::new(),
+            name_tags: Vec::new(),
+            allow_ui: false,
         }
     }
 }
 
+// VRs of sequences and binary values, masking them wholesale writes broken files
+static UNMASKABLE_VRS: [VR; 8] = [
+    VR::SQ,
+    VR::OB,
+    VR::OW,
+    VR::OF,
+    VR::OD,
+    VR::OL,
+    VR::OV,
+    VR::UN,
+];
+
 #[derive(Debug, Deserialize, Clone)]
 struct DelTags {
+    #[serde(default)]
     tags: Vec<String>,
+    #[serde(default)]
     private_tags: bool,
 }
 
@@ -59,7 +256,30 @@ impl DelTags {
 
 #[derive(Debug, Deserialize)]
 struct AddTags {
-    tags: std::collections::HashMap<String, String>,
+    tags: std::collections::HashMap<String, AddValue>,
+}
+
+// A plain value overwrites, an entry with if_missing = true only sets a missing tag
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AddValue {
+    Plain(String),
+    Entry(AddEntry),
+}
+
+// Present with an empty value counts as missing unless empty_is_missing = false
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddEntry {
+    value: String,
+    #[serde(default)]
+    if_missing: bool,
+    #[serde(default = "empty_is_missing_default")]
+    empty_is_missing: bool,
+}
+
+fn empty_is_missing_default() -> bool {
+    true
 }
 
 impl AddTags {
@@ -70,10 +290,67 @@ impl AddTags {
     }
 }
 
+#[derive(Debug, Deserialize, Default)]
+struct FilterConfig {
+    #[serde(default)]
+    exclude_sop_classes: Vec<String>,
+}
+
+// institution is remove, keep, id or replace:<site code>
+#[derive(Debug, Deserialize)]
+struct IdentityConfig {
+    institution: Option<String>,
+    staff_names: Option<StaffNamePolicy>,
+}
+
+// Tags protected from every modification
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeepTags {
+    tags: Vec<String>,
+}
+
+// patient_id is a list of spaces, hyphens, uppercase and leading_zeros
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NormalizeConfig {
+    patient_id: Vec<PatientIdStep>,
+}
+
+// Files where the tag equals, contains or matches the regex go to the route
+// Exactly one of equals, contains and regex is set
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    route: String,
+    tag: String,
+    equals: Option<String>,
+    contains: Option<String>,
+    regex: Option<String>,
+}
+
+// method can be a single string or a list for a multi-valued DeidentificationMethod
+#[derive(Debug, Deserialize)]
+struct DeidConfig {
+    method: Option<MethodValues>,
+    #[serde(default)]
+    method_codes: Vec<MethodCode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MethodValues {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
 fn create_default_cookbook(cookbook_file_path: &String) -> Result<String> {
     warn!("Cookbook file not found, Creating a default cookbook file");
     let default_cookbook_raw = r#"#The chain of application is mask > add > delete
-# The tags are case sensitive. They should match the DICOM standard dictionary specification
+# A top level order list changes it, every stage listed once
+# order = ["delete_private", "mask", "add", "delete"]
+# The tags should be the keywords of the DICOM standard dictionary eg PatientID
+# Other cases, spaces and the (0010,0020) form are read as the keyword with a warning
 # Mask and delete only work with the tags already present in the dicom file
 
 # Tags are case sensitive. Need to follow the DICOM Stadndard dictionary
@@ -82,36 +359,116 @@ fn create_default_cookbook(cookbook_file_path: &String) -> Result<String> {
 tag = "PatientID"
 
 # List of tags and VRs that will be masked by the DeID
-# Only PN VR recommended
+# Only PN VR recommended, LO, SH, ST, LT and UT also work. SQ and the binary VRs are dropped
+# UI masks every UID including SOPClassUID and needs allow_ui = true and --allow-breaking-rules
 [mask]
 tags = ["PatientID", "PatientName", "InstitutionName", "InstitutionAddress", "StudyID", "AccessionNumber"]
 vrs = ["PN"]
+# Masked tags that take the PatientName column of the mapping table when it is present
+# Defaults to the masked tags and VRs with PN VR
+# name_tags = ["PatientName"]
 
 # List of tags that will be deleted
+# The free text descriptions and comments often hold the patient name or accession
 [delete]
-tags = []
+tags = ["CommentsOnThePerformedProcedureStep", "AcquisitionDeviceProcessingDescription", "RequestedProcedureDescription", "AdmittingDiagnosesDescription", "ImageComments"]
 private_tags = false
 
 # Dictionary of tags to be added along with their values
 # Date should follow YYYYMMDD format >> 19900101
 # Time should follow HHMMSS format >> 090000
 # DateTime should floolw YYYYMMDDTHHMMSS format >> 19900101T090000
+# DateTime may end with a UTC offset >> 19900101T090000+0200
 [add]
-tags.PatientIdentityRemoved = "Yes"
+tags.PatientIdentityRemoved = "YES"
 tags.DeidentificationMethod = "DCMRig"
 tags.ClinicalTrialSponsorName = "TrialName"
+# An entry with if_missing = true keeps the value of the file, empty values count as missing
+# unless empty_is_missing = false
+# tags.ClinicalTrialProtocolID = { value = "Protocol", if_missing = true }
 # Timepoint is a special field which follows the following pattern
 # PatientID_StudyDateTStudyTime_Modality
 tags.ClinicalTrialTimePointID = "PatientID_StudyDateTStudyTime_Modality"
+
+# DeidentificationMethod text and optional DeidentificationMethodCodeSequence items
+# Written after all other actions. Each method is limited to 64 characters
+# [deid]
+# method = ["Per protocol ABC-123 v2.1", "DCMRig"]
+# method_codes = [{ value = "113100", scheme = "DCM", meaning = "Basic Application Confidentiality Profile" }]
+
+# A base cookbook can be included at the top of this file, before any table
+# The base is loaded first and this file is applied on top of it
+# Values and [add] tags override the base per key, lists replace the base lists
+# unless merge_lists = true which appends them
+# include = "/path/to/base_cookbook.toml"
+# merge_lists = true
+
+# Institution and staff name identifiers, applied after the other actions
+# institution = "remove" | "keep" | "id" | "replace:<site code>", id writes the DeID
+# staff_names = "remove" | "empty" | "replace" | "keep" for OperatorsName, PerformingPhysicianName,
+# PhysiciansOfRecord and ReferringPhysicianName, replace writes the DeID
+# Unset values leave these tags to the mask, add and delete actions
+# [identity]
+# institution = "replace:SITE01"
+# staff_names = "remove"
+
+# Routing of the written files to the destination roots given with --route NAME=PATH
+# The rules are tested in this order and the first match wins, files without a match
+# go to the destination of the command. Each rule has one of equals, contains or regex,
+# multi-valued tags like ImageType are tested as their values joined with a backslash
+# [[routes]]
+# route = "derived"
+# tag = "ImageType"
+# regex = "DERIVED|SECONDARY"
+
+# Values of the masked AccessionNumber and StudyID, PatientID and PatientName keep the DeID
+# accession = "deid" writes the DeID to every study of the patient
+# "hash" writes a salted hash of the original AccessionNumber, stable across runs with the same salt
+# "sequential-per-patient" writes <DeID>_1, <DeID>_2.. in the order the studies are first seen
+# [study_ids]
+# accession = "hash"
+# salt = "a long random project secret"
+
+# Normalization of the PatientIDs with --normalize-patient-id, applied in this order
+# before the AnonID and mapping table lookups. Defaults to spaces, hyphens and uppercase
+# [normalize]
+# patient_id = ["spaces", "hyphens", "uppercase", "leading_zeros"]
+
+# Overrides of mask, add, delete and keep for the files of a Modality, merged over the base
+# Lists are appended and add values set per key, a tag named by the override is taken out
+# of every action of the base so the override wins
+# [modality.MG]
+# keep.tags = ["ViewPosition", "ImageLaterality"]
+# delete.tags = ["DetectorID"]
+
+# SOP classes to skip, as UIDs or keywords SR, DOSESR, KO, GSPS, RTDOSE, RTSTRUCT, RTPLAN, SC, PDF
+# [filter]
+# exclude_sop_classes = ["SR", "1.2.840.10008.5.1.4.1.1.11.1"]
 "#;
     let mut file_to_save =
         File::create(cookbook_file_path).expect("Failed to create cookbook path");
-    write!(file_to_save, "{}", default_cookbook_raw.to_string())?;
+    write!(file_to_save, "{}", default_cookbook_raw)?;
     info!("Default cookbook created: {}", cookbook_file_path);
     Ok(default_cookbook_raw.to_string())
 }
 
-fn check_for_cookbook() -> Result<String> {
+// Returns the cookbook path and its content
+// A given cookbook must exist, the default one is created on the first run
+fn check_for_cookbook(cookbook_path: Option<&PathBuf>) -> Result<(String, String)> {
+    if let Some(cookbook_path) = cookbook_path {
+        let file_content = fs::read_to_string(cookbook_path).map_err(|e| {
+            anyhow::Error::msg(format!(
+                "Can't read the cookbook {}: {}",
+                cookbook_path.display(),
+                e
+            ))
+        })?;
+        info!(
+            "Reading from the cookbook toml file at {}",
+            cookbook_path.display()
+        );
+        return Ok((cookbook_path.display().to_string(), file_content));
+    }
     let home_path = home_dir().expect("Home path not found");
     let cookbook_home = format!("{}/.dcmrig", home_path.display());
     let cookbook_file_path = format!("{}/cookbook.toml", cookbook_home);
@@ -135,15 +492,150 @@ fn check_for_cookbook() -> Result<String> {
         Err(_) => create_default_cookbook(&cookbook_file_path)?,
     };
 
-    Ok(file_content)
+    Ok((cookbook_file_path, file_content))
+}
+
+// Cookbook table with all includes merged, and the files it was read from in load order
+struct MergedCookbook {
+    table: toml::Table,
+    includes: Vec<String>,
+    contents: Vec<String>,
 }
 
-fn check_valid_tag_vec(tag_vec: Vec<String>) -> Vec<DataDictionaryEntryRef<'static>> {
+// Resolve the include of a cookbook, the included base is merged first
+// include_chain holds the files being resolved to reject circular includes
+fn resolve_cookbook_includes(
+    cookbook_path: &Path,
+    file_content: &str,
+    include_chain: &mut Vec<PathBuf>,
+) -> Result<MergedCookbook> {
+    let canonical_path = canonicalize(cookbook_path).unwrap_or(cookbook_path.to_path_buf());
+    if include_chain.contains(&canonical_path) {
+        let chain: Vec<String> = include_chain
+            .iter()
+            .chain([&canonical_path])
+            .map(|p| p.display().to_string())
+            .collect();
+        error!("Circular cookbook include: {}", chain.join(" > "));
+        exit(1)
+    }
+    let mut table: toml::Table = toml::from_str(file_content).unwrap_or_else(|e| {
+        error!(
+            "Failed to parse cookbook {}: {}",
+            cookbook_path.display(),
+            e
+        );
+        exit(1)
+    });
+    let include = table.remove("include");
+    let merge_lists = match table.remove("merge_lists") {
+        Some(toml::Value::Boolean(merge_lists)) => merge_lists,
+        Some(_) => {
+            error!(
+                "merge_lists must be true or false in {}",
+                cookbook_path.display()
+            );
+            exit(1)
+        }
+        None => false,
+    };
+    let include_path = match include {
+        Some(toml::Value::String(include_path)) => cookbook_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(include_path),
+        Some(_) => {
+            error!("include must be a path in {}", cookbook_path.display());
+            exit(1)
+        }
+        None => {
+            return Ok(MergedCookbook {
+                table,
+                includes: vec![],
+                contents: vec![file_content.to_string()],
+            })
+        }
+    };
+
+    let include_content = fs::read_to_string(&include_path).unwrap_or_else(|e| {
+        error!(
+            "Can't read the cookbook {} included by {}: {}",
+            include_path.display(),
+            cookbook_path.display(),
+            e
+        );
+        exit(1)
+    });
+    info!("Including base cookbook {}", include_path.display());
+    include_chain.push(canonical_path);
+    let mut merged = resolve_cookbook_includes(&include_path, &include_content, include_chain)?;
+    include_chain.pop();
+    merge_cookbook_tables(&mut merged.table, table, merge_lists);
+    merged.includes.push(include_path.display().to_string());
+    merged.contents.push(file_content.to_string());
+    Ok(merged)
+}
+
+// Apply the local cookbook table on top of the base
+// Tables like [add] merge per key, lists are replaced or appended with merge_lists, other values override
+fn merge_cookbook_tables(base: &mut toml::Table, local: toml::Table, merge_lists: bool) {
+    for (key, local_value) in local {
+        let merged_value = match (base.remove(&key), local_value) {
+            // An [add] entry with a value replaces the base entry as a whole
+            (Some(toml::Value::Table(mut base_table)), toml::Value::Table(local_table))
+                if !local_table.contains_key("value") =>
+            {
+                merge_cookbook_tables(&mut base_table, local_table, merge_lists);
+                toml::Value::Table(base_table)
+            }
+            // The stage order is always replaced, appending would keep the base order
+            (Some(toml::Value::Array(mut base_list)), toml::Value::Array(local_list))
+                if merge_lists && key != "order" =>
+            {
+                for each in local_list {
+                    if !base_list.contains(&each) {
+                        base_list.push(each);
+                    }
+                }
+                toml::Value::Array(base_list)
+            }
+            (_, local_value) => local_value,
+        };
+        base.insert(key, merged_value);
+    }
+}
+
+// Tag names resolve in any case, with spaces or as (gggg,eeee), the names read as another
+// keyword and the ones that don't resolve are added to the issues of the cookbook
+fn check_tag_name(
+    tag_name: &str,
+    tag_name_issues: &mut Vec<String>,
+) -> Option<DataDictionaryEntryRef<'static>> {
+    match resolve_tag_name(tag_name) {
+        Some(entry) => {
+            if entry.alias != tag_name {
+                warn!("!! Tag {:?} is read as {}", tag_name, entry.alias);
+                tag_name_issues.push(format!("{:?} is read as {}", tag_name, entry.alias));
+            }
+            Some(entry)
+        }
+        None => {
+            let hint = tag_name_hint(tag_name);
+            warn!("!! Tag {} is not valid and is ignored.{}", tag_name, hint);
+            tag_name_issues.push(format!("{:?} is not valid.{}", tag_name, hint));
+            None
+        }
+    }
+}
+
+fn check_valid_tag_vec(
+    tag_vec: Vec<String>,
+    tag_name_issues: &mut Vec<String>,
+) -> Vec<DataDictionaryEntryRef<'static>> {
     let mut std_tag_list = Vec::new();
     for each in tag_vec {
-        match DataDictionary::by_name(&StandardDataDictionary, &each) {
-            Some(tag) => std_tag_list.push(tag.to_owned()),
-            None => warn!("Tag {} is not valid", each),
+        if let Some(tag) = check_tag_name(&each, tag_name_issues) {
+            std_tag_list.push(tag);
         }
     }
     // tags_vec
@@ -162,96 +654,417 @@ fn check_valid_vr_vec(vrs_vec: Vec<String>) -> Vec<VR> {
     std_vr_list
 }
 
-fn check_valid_tag_hashmap(tag_hash: HashMap<String, String>) -> HashMap<String, String> {
-    let mut tags_hash_m = tag_hash.clone();
+// The valid add tags keyed on their dictionary keyword
+fn check_valid_tag_hashmap(
+    tag_hash: HashMap<String, String>,
+    tag_name_issues: &mut Vec<String>,
+) -> HashMap<String, String> {
+    let mut tags_hash_m = HashMap::new();
     for each in tag_hash {
-        match DataDictionary::by_name(&StandardDataDictionary, &each.0) {
-            Some(_) => (),
-            None => {
-                tags_hash_m.remove(&each.0);
-                warn!("Tag {} is not valid", each.0)
+        if let Some(tag) = check_tag_name(&each.0, tag_name_issues) {
+            tags_hash_m.insert(tag.alias.to_string(), each.1);
+        }
+    }
+    tags_hash_m
+}
+
+// Validate each add value against the VR of its tag once
+// Invalid entries are dropped, or abort the run in strict mode
+fn check_valid_add_values(
+    tag_hash: HashMap<String, String>,
+    strict_cookbook: bool,
+    length_policy: &LengthPolicy,
+) -> HashMap<String, String> {
+    let mut tags_hash_m = tag_hash.clone();
+    for (each_tag, each_value) in tag_hash {
+        let (_, each_vr) = match extract_tag_vr_from_str(&each_tag) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        // CS values are normalized once here instead of for every file
+        let each_value = match each_vr == VR::CS {
+            true => {
+                let cs_value = normalize_cs_value(&each_value);
+                if cs_value != each_value {
+                    if strict_cookbook {
+                        error!(
+                            "!! Add tag {} value \"{}\" is not a valid CS, it would be {:?}",
+                            each_tag, each_value, cs_value
+                        );
+                        error!("Invalid add value in the cookbook with --strict-cookbook");
+                        exit(1);
+                    }
+                    warn!(
+                        "Add tag {} value \"{}\" normalized to CS {:?}",
+                        each_tag, each_value, cs_value
+                    );
+                    tags_hash_m.insert(each_tag.clone(), cs_value.clone());
+                }
+                cs_value
+            }
+            false => each_value,
+        };
+        if let Err(e) = dicom_vr_corrected_value(each_vr, &each_value, length_policy) {
+            tags_hash_m.remove(&each_tag);
+            warn!(
+                "!! Add tag {} value \"{}\" is not valid for VR {}, expected format {} | {}",
+                each_tag,
+                each_value,
+                each_vr,
+                vr_value_format(each_vr),
+                e
+            );
+            if strict_cookbook {
+                error!("Invalid add value in the cookbook with --strict-cookbook");
+                exit(1);
             }
         }
     }
     tags_hash_m
 }
 
-fn check_tag_list(action: &str, tag_list: Vec<String>) -> Vec<DataDictionaryEntryRef<'static>> {
+fn check_tag_list(
+    action: &str,
+    tag_list: Vec<String>,
+    tag_name_issues: &mut Vec<String>,
+) -> Vec<DataDictionaryEntryRef<'static>> {
     match tag_list.is_empty() {
         true => {
             warn!("The {} cookbook is empty or corrupted", action);
-            return vec![];
+            vec![]
         }
         false => {
             info!("Checking Mask list");
-            let tag_list: Vec<DataDictionaryEntryRef<'_>> = check_valid_tag_vec(tag_list);
+            let tag_list: Vec<DataDictionaryEntryRef<'_>> =
+                check_valid_tag_vec(tag_list, tag_name_issues);
             tag_list
                 .iter()
-                .enumerate()
-                .for_each(|(_i, v)| info!("Tags to {} {}", action, v.alias));
-            return tag_list;
+                .for_each(|v| info!("Tags to {} {}", action, v.alias));
+            tag_list
+        }
+    }
+}
+
+// Sequence and binary VRs are dropped and UI only kept with allow_ui, or abort the run in
+// strict mode. The text VRs PN, LO, SH, ST, LT and UT are the ones worth masking
+fn check_mask_vrs(vr_list: Vec<VR>, allow_ui: bool, strict_cookbook: bool) -> Vec<VR> {
+    let mut mask_vrs = Vec::new();
+    for vr in vr_list {
+        let dropped = match vr {
+            vr if UNMASKABLE_VRS.contains(&vr) => {
+                warn!(
+                    "!! [mask] VR {} can't be masked, masking every {} element writes broken files. It is dropped",
+                    vr, vr
+                );
+                true
+            }
+            VR::UI if !allow_ui => {
+                warn!("!! [mask] VR UI would mask every UID of the file including SOPClassUID. It is dropped, set allow_ui = true to mask it anyway");
+                true
+            }
+            VR::UI => {
+                warn!("!! [mask] VR UI masks every UID of the file including SOPClassUID, the files may not be readable by any viewer");
+                false
+            }
+            _ => false,
+        };
+        match dropped {
+            true if strict_cookbook => {
+                error!("Invalid mask VR in the cookbook with --strict-cookbook");
+                exit(1);
+            }
+            true => (),
+            false => mask_vrs.push(vr),
         }
     }
+    mask_vrs
 }
 
-fn check_vr_list(vr_list: Vec<String>) -> Vec<VR> {
+fn check_vr_list(vr_list: Vec<String>, allow_ui: bool, strict_cookbook: bool) -> Vec<VR> {
     match vr_list.is_empty() {
         true => {
             warn!("The Mask VR cookbook is empty or corrupted");
-            return vec![];
+            vec![]
         }
         false => {
             info!("Checking Mask list");
-            let vr_list = check_valid_vr_vec(vr_list);
+            let vr_list = check_mask_vrs(check_valid_vr_vec(vr_list), allow_ui, strict_cookbook);
             // info!("Tags to mask {:?}", mask_list);
+            vr_list.iter().for_each(|v| info!("VR to mask {}", v));
             vr_list
-                .iter()
-                .enumerate()
-                .for_each(|(_i, v)| info!("VR to mask {}", v));
-            return vr_list;
         }
     }
 }
 
-pub fn parse_toml_cookbook() -> Result<(
-    DataDictionaryEntryRef<'static>,
-    Vec<DataDictionaryEntryRef<'static>>,
-    Vec<VR>,
-    HashMap<String, String>,
-    Vec<DataDictionaryEntryRef<'static>>,
-    bool,
-)> {
-    let file_content = check_for_cookbook()?;
-    let toml_des: CookBook =
-        toml::from_str(&file_content).expect("Failed to deserialize Cargo.toml");
+// Enforce the VR length limits of the DeidentificationMethod and the code sequence items
+fn check_deid_method(deid_config: DeidConfig) -> DeidMethod {
+    let methods = match deid_config.method {
+        Some(MethodValues::Single(method)) => vec![method],
+        Some(MethodValues::Multiple(methods)) => methods,
+        None => DeidMethod::default().methods,
+    };
+    let mut issues = Vec::new();
+    for each in &methods {
+        if each.chars().count() > 64 || each.contains('\\') {
+            issues.push(format!(
+                "method {:?} is over 64 characters or has a '\\'",
+                each
+            ));
+        }
+    }
+    for each in &deid_config.method_codes {
+        for (name, value, max_len) in [
+            ("value", &each.value, 16),
+            ("scheme", &each.scheme, 16),
+            ("meaning", &each.meaning, 64),
+        ] {
+            if value.is_empty() || value.chars().count() > max_len {
+                issues.push(format!(
+                    "method code {} {:?} must be 1 to {} characters",
+                    name, value, max_len
+                ));
+            }
+        }
+    }
+    if !issues.is_empty() {
+        for each in issues {
+            error!("!! [deid] {}", each);
+        }
+        exit(1)
+    }
+    info!("DeidentificationMethod > {}", methods.join("\\"));
+    DeidMethod {
+        methods,
+        codes: deid_config.method_codes,
+    }
+}
+
+pub fn parse_toml_cookbook(
+    strict_cookbook: bool,
+    allow_risky_keep: bool,
+    allow_breaking_rules: bool,
+    length_policy: &LengthPolicy,
+    cookbook_path: Option<&PathBuf>,
+) -> Result<CookbookConfig> {
+    let (cookbook_path, file_content) = check_for_cookbook(cookbook_path)?;
+    parse_cookbook_str(
+        &cookbook_path,
+        &file_content,
+        strict_cookbook,
+        allow_risky_keep,
+        allow_breaking_rules,
+        length_policy,
+    )
+}
+
+// Parse the content of a cookbook read from cookbook_path, its includes are resolved from there
+fn parse_cookbook_str(
+    cookbook_path: &str,
+    file_content: &str,
+    strict_cookbook: bool,
+    allow_risky_keep: bool,
+    allow_breaking_rules: bool,
+    length_policy: &LengthPolicy,
+) -> Result<CookbookConfig> {
+    let MergedCookbook {
+        mut table,
+        includes,
+        contents,
+    } = resolve_cookbook_includes(Path::new(cookbook_path), file_content, &mut vec![])?;
+    let hash: String = Sha256::digest(contents.concat().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let modality_tables = match table.remove("modality") {
+        Some(toml::Value::Table(modality_tables)) => modality_tables,
+        Some(_) => {
+            error!("!! [modality] must hold tables like [modality.MG]");
+            exit(1)
+        }
+        None => toml::Table::new(),
+    };
+    let mut modalities = BTreeMap::new();
+    for (modality, modality_table) in modality_tables {
+        let toml::Value::Table(modality_table) = modality_table else {
+            error!("!! [modality.{}] must be a table", modality);
+            exit(1)
+        };
+        if let Some(section) = modality_table
+            .keys()
+            .find(|section| !MODALITY_SECTIONS.contains(&section.as_str()))
+        {
+            error!(
+                "!! [modality.{}] {} can't be set per modality, only {}",
+                modality,
+                section,
+                MODALITY_SECTIONS.join(", ")
+            );
+            exit(1)
+        }
+        info!("Checking the cookbook of modality {}", modality);
+        let mut config = parse_cookbook_table(
+            merge_modality_override(&table, modality_table),
+            strict_cookbook,
+            allow_risky_keep,
+            allow_breaking_rules,
+            length_policy,
+        )?;
+        config.path = cookbook_path.to_string();
+        config.includes = includes.clone();
+        config.hash = hash.clone();
+        modalities.insert(modality.trim().to_uppercase(), config);
+    }
+    let mut config = parse_cookbook_table(
+        table,
+        strict_cookbook,
+        allow_risky_keep,
+        allow_breaking_rules,
+        length_policy,
+    )?;
+    config.path = cookbook_path.to_string();
+    config.includes = includes;
+    config.hash = hash;
+    config.modalities = modalities;
+    Ok(config)
+}
+
+// A [modality.<Modality>] section merged over the base cookbook
+// Its lists are appended to the base lists and its add values set per key. A tag named by any
+// action of the override is first taken out of every action of the base, so the override wins
+// eg a base [delete] of ViewPosition and a [modality.MG.keep] of it keep ViewPosition for MG
+fn merge_modality_override(base: &toml::Table, modality_table: toml::Table) -> toml::Table {
+    let mut named: Vec<String> = Vec::new();
+    for section in ["mask", "delete", "keep"] {
+        if let Some(toml::Value::Array(tag_list)) =
+            modality_table.get(section).and_then(|s| s.get("tags"))
+        {
+            named.extend(
+                tag_list
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(str::to_string),
+            );
+        }
+    }
+    if let Some(toml::Value::Table(add_tags)) =
+        modality_table.get("add").and_then(|s| s.get("tags"))
+    {
+        named.extend(add_tags.keys().cloned());
+    }
+    let mut merged = base.clone();
+    for section in ["mask", "delete", "keep"] {
+        if let Some(toml::Value::Array(tag_list)) =
+            merged.get_mut(section).and_then(|s| s.get_mut("tags"))
+        {
+            tag_list.retain(|v| {
+                !v.as_str()
+                    .is_some_and(|name| named.iter().any(|n| n == name))
+            });
+        }
+    }
+    if let Some(toml::Value::Table(add_tags)) =
+        merged.get_mut("add").and_then(|s| s.get_mut("tags"))
+    {
+        *add_tags = add_tags
+            .iter()
+            .filter(|(name, _)| !named.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+    }
+    merge_cookbook_tables(&mut merged, modality_table, true);
+    merged
+}
+
+// Validate a merged cookbook table, the path, includes and hash are set by the caller
+fn parse_cookbook_table(
+    table: toml::Table,
+    strict_cookbook: bool,
+    allow_risky_keep: bool,
+    allow_breaking_rules: bool,
+    length_policy: &LengthPolicy,
+) -> Result<CookbookConfig> {
+    let toml_des: CookBook = toml::Value::Table(table)
+        .try_into()
+        .expect("Failed to deserialize the cookbook");
+    let mut tag_name_issues = Vec::new();
 
     // Setting up variables
     let matchid = toml_des.matchid.unwrap_or_else(|| MatchIDTag {
         tag: "PatientID".to_string(),
     });
-    let mask_list = toml_des
+    let mask_list = toml_des.mask.clone().unwrap_or_else(MaskTags::default).tags;
+    let mask_vrs_list = toml_des.mask.clone().unwrap_or_else(MaskTags::default).vrs;
+    let mask_name_list = toml_des
         .mask
         .clone()
-        .unwrap_or_else(|| MaskTags::default())
-        .tags;
-    let mask_vrs_list = toml_des
+        .unwrap_or_else(MaskTags::default)
+        .name_tags;
+    let allow_ui = toml_des
         .mask
         .clone()
-        .unwrap_or_else(|| MaskTags::default())
-        .vrs;
+        .unwrap_or_else(MaskTags::default)
+        .allow_ui;
 
-    let add_list = toml_des.add.unwrap_or_else(|| AddTags::default()).tags;
+    let mut add_modes = HashMap::new();
+    let add_list: HashMap<String, String> = toml_des
+        .add
+        .unwrap_or_else(AddTags::default)
+        .tags
+        .into_iter()
+        .map(|(name, add_value)| match add_value {
+            AddValue::Plain(value) => (name, value),
+            AddValue::Entry(entry) => {
+                if entry.if_missing {
+                    add_modes.insert(
+                        name.clone(),
+                        AddMode::IfMissing {
+                            empty_is_missing: entry.empty_is_missing,
+                        },
+                    );
+                }
+                (name, entry.value)
+            }
+        })
+        .collect();
 
     let delete_list = toml_des
         .delete
         .clone()
-        .unwrap_or_else(|| DelTags::default())
+        .unwrap_or_else(DelTags::default)
         .tags;
     let private_tags_del = toml_des
         .delete
-        .unwrap_or_else(|| DelTags::default())
+        .unwrap_or_else(DelTags::default)
         .private_tags;
 
+    let exclude_sop_list = toml_des.filter.unwrap_or_default().exclude_sop_classes;
+
+    let order = check_stage_order(toml_des.order);
+    let deid_method = toml_des.deid.map(check_deid_method);
+    let routes = check_routes(toml_des.routes.unwrap_or_default());
+    let patient_id_steps = toml_des.normalize.map(|normalize| normalize.patient_id);
+    let study_ids = toml_des.study_ids.unwrap_or_default();
+    // An unsalted hash of an AccessionNumber can be reversed by hashing the known accessions
+    if study_ids.accession == AccessionPolicy::Hash
+        && study_ids.salt.as_deref().unwrap_or("").is_empty()
+    {
+        error!("!! [study_ids] accession = \"hash\" needs a salt");
+        exit(1);
+    }
+
+    let identity = match toml_des.identity {
+        Some(identity_config) => IdentityPolicy {
+            institution: identity_config.institution.map(|policy| {
+                parse_institution_policy(&policy).unwrap_or_else(|e| {
+                    error!("!! [identity] {}", e);
+                    exit(1)
+                })
+            }),
+            staff_names: identity_config.staff_names,
+        },
+        None => IdentityPolicy::default(),
+    };
+
     // Validating the lists
     info!("Checking MatchID tag");
     let matchid = match matchid.tag.as_str() {
@@ -267,34 +1080,554 @@ pub fn parse_toml_cookbook() -> Result<(
     };
     info!("MatchID > {}", matchid.alias);
 
-    let mask_tag_list = check_tag_list("mask", mask_list);
-    let delete_tag_list = check_tag_list("delete", delete_list);
+    let keep_tag_list = match toml_des.keep {
+        Some(keep) if !keep.tags.is_empty() => check_keep_tags(
+            check_tag_list("keep", keep.tags, &mut tag_name_issues),
+            matchid,
+            allow_risky_keep,
+        ),
+        _ => vec![],
+    };
 
-    let mask_vr_list = check_vr_list(mask_vrs_list);
+    let mask_tag_list = check_tag_list("mask", mask_list, &mut tag_name_issues);
+    let delete_tag_list = check_tag_list("delete", delete_list, &mut tag_name_issues);
+
+    let mask_vr_list = check_vr_list(mask_vrs_list, allow_ui, strict_cookbook);
+    let mask_name_tag_list = match mask_name_list.is_empty() {
+        true => vec![],
+        false => check_tag_list(
+            "mask with PatientName",
+            mask_name_list,
+            &mut tag_name_issues,
+        ),
+    };
 
     let add_list = match add_list.is_empty() {
         true => {
             warn!("The Add cookbook is empty or corrupted");
-            AddTags::default().tags
+            HashMap::new()
         }
         false => {
             info!("Checking Add list");
-            let add_list = check_valid_tag_hashmap(add_list);
+            let add_list = check_valid_tag_hashmap(add_list, &mut tag_name_issues);
+            let add_list = check_valid_add_values(add_list, strict_cookbook, length_policy);
             // info!("Tags to add {:?}", add_list);
             add_list
                 .iter()
-                .enumerate()
-                .for_each(|(_i, v)| info!("Tags to add {} > {}", v.0, v.1));
+                .for_each(|v| info!("Tags to add {} > {}", v.0, v.1));
             add_list
         }
     };
 
-    Ok((
-        matchid.to_owned(),
-        mask_tag_list,
-        mask_vr_list,
-        add_list,
-        delete_tag_list,
-        private_tags_del,
-    ))
+    // Keyed on the keyword like the checked add list
+    let add_modes: HashMap<String, AddMode> = add_modes
+        .into_iter()
+        .filter_map(|(name, mode)| {
+            resolve_tag_name(&name).map(|entry| (entry.alias.to_string(), mode))
+        })
+        .collect();
+    for (name, mode) in &add_modes {
+        info!("Tag to add {} only when missing > {:?}", name, mode);
+    }
+
+    check_breaking_rules(
+        &mask_tag_list,
+        &mask_name_tag_list,
+        &mask_vr_list,
+        &delete_tag_list,
+        allow_breaking_rules,
+    );
+
+    check_action_overlaps(
+        &mask_tag_list,
+        &mask_vr_list,
+        &add_list,
+        &delete_tag_list,
+        &order,
+        strict_cookbook,
+    );
+
+    Ok(CookbookConfig {
+        path: String::new(),
+        includes: vec![],
+        hash: String::new(),
+        match_id: matchid.to_owned(),
+        mask_tags: mask_tag_list,
+        mask_vrs: mask_vr_list,
+        mask_name_tags: mask_name_tag_list,
+        add: add_list,
+        add_modes,
+        delete: delete_tag_list,
+        private_tags: private_tags_del,
+        exclude_sop_classes: exclude_sop_list,
+        deid_method,
+        identity,
+        routes,
+        patient_id_steps,
+        study_ids,
+        keep: KeepList::new(keep_tag_list.iter().map(|t| t.tag.inner())),
+        keep_tags: keep_tag_list,
+        modalities: BTreeMap::new(),
+        tag_name_issues,
+        order,
+    })
+}
+
+// Every stage listed once, a stage left out would silently skip its tags
+fn check_stage_order(order_list: Option<Vec<String>>) -> Vec<TagStage> {
+    let Some(order_list) = order_list else {
+        return DEFAULT_TAG_STAGE_ORDER.to_vec();
+    };
+    let mut order = Vec::new();
+    for each_name in &order_list {
+        let stage = parse_tag_stage(each_name).unwrap_or_else(|e| {
+            error!("!! [order] {}", e);
+            exit(1)
+        });
+        if order.contains(&stage) {
+            error!("!! [order] {} is listed more than once", each_name);
+            exit(1);
+        }
+        order.push(stage);
+    }
+    let missing: Vec<&str> = DEFAULT_TAG_STAGE_ORDER
+        .iter()
+        .filter(|stage| !order.contains(stage))
+        .map(|stage| stage.name())
+        .collect();
+    if !missing.is_empty() {
+        error!(
+            "!! [order] {} missing, every stage must be listed",
+            missing.join(", ")
+        );
+        exit(1);
+    }
+    info!("Stage order > {}", order_list.join(" > "));
+    order
+}
+
+// Keeping a tag that identifies the patient, or the MatchID, leaves it in every output file
+fn check_keep_tags(
+    keep_tags: Vec<DataDictionaryEntryRef<'static>>,
+    matchid: &DataDictionaryEntryRef<'static>,
+    allow_risky_keep: bool,
+) -> Vec<DataDictionaryEntryRef<'static>> {
+    let risky: Vec<_> = keep_tags
+        .iter()
+        .filter(|each_tag| {
+            IDENTITY_CRITICAL_TAGS.contains(&each_tag.alias)
+                || each_tag.tag.inner() == matchid.tag.inner()
+        })
+        .collect();
+    for each_tag in &risky {
+        match allow_risky_keep {
+            true => warn!(
+                "!! [keep] {} identifies the patient and is kept",
+                each_tag.alias
+            ),
+            false => error!(
+                "!! [keep] {} identifies the patient, use --allow-risky-keep to keep it",
+                each_tag.alias
+            ),
+        }
+    }
+    if !risky.is_empty() && !allow_risky_keep {
+        exit(1);
+    }
+    keep_tags
+}
+
+// Masking or deleting a tag every file needs writes files no archive or viewer imports
+// The UIDs are changed by the UID anonymization of anon, not by cookbook rules
+fn check_breaking_rules(
+    mask_tags: &[DataDictionaryEntryRef<'static>],
+    mask_name_tags: &[DataDictionaryEntryRef<'static>],
+    mask_vrs: &[VR],
+    delete_tags: &[DataDictionaryEntryRef<'static>],
+    allow_breaking_rules: bool,
+) {
+    let mut breaking: Vec<String> = Vec::new();
+    for (section, tag_list) in [
+        ("mask", mask_tags),
+        ("mask name_tags", mask_name_tags),
+        ("delete", delete_tags),
+    ] {
+        breaking.extend(
+            tag_list
+                .iter()
+                .filter(|each_tag| REQUIRED_TAGS.contains(&each_tag.alias))
+                .map(|each_tag| format!("[{}] {}", section, each_tag.alias)),
+        );
+    }
+    // Every required tag is a UID
+    if mask_vrs.contains(&VR::UI) {
+        breaking.push(format!("[mask] vrs UI masks {}", REQUIRED_TAGS.join(", ")));
+    }
+    for each_rule in &breaking {
+        match allow_breaking_rules {
+            true => warn!(
+                "!! {} alters a tag every DICOM file needs, the files may not import anywhere",
+                each_rule
+            ),
+            false => error!(
+                "!! {} alters a tag every DICOM file needs, use --allow-breaking-rules to keep the rule",
+                each_rule
+            ),
+        }
+    }
+    if !breaking.is_empty() && !allow_breaking_rules {
+        exit(1);
+    }
+}
+
+// Tags listed in more than one of mask, add and delete
+// The stages run in the cookbook order and the last one wins, a VR of [mask] vrs
+// masks the tags of [mask] tags with that VR. Ambiguous cookbooks abort in strict mode
+fn check_action_overlaps(
+    mask_tags: &[DataDictionaryEntryRef<'static>],
+    mask_vrs: &[VR],
+    add: &HashMap<String, String>,
+    delete: &[DataDictionaryEntryRef<'static>],
+    order: &[TagStage],
+    strict_cookbook: bool,
+) {
+    let mut actions: BTreeMap<Tag, (String, Vec<&str>)> = BTreeMap::new();
+    let mut list_tag = |tag: Tag, name: &str, action: &'static str| {
+        let entry = actions
+            .entry(tag)
+            .or_insert_with(|| (name.to_string(), Vec::new()));
+        if !entry.1.contains(&action) {
+            entry.1.push(action);
+        }
+    };
+    for each_tag in mask_tags {
+        list_tag(each_tag.tag.inner(), each_tag.alias, "mask");
+        if mask_vrs.contains(&each_tag.vr.relaxed()) {
+            list_tag(each_tag.tag.inner(), each_tag.alias, "mask vrs");
+        }
+    }
+    for each_tag in add.keys() {
+        if let Ok((tag, _)) = extract_tag_vr_from_str(each_tag) {
+            list_tag(tag, each_tag, "add");
+        }
+    }
+    for each_tag in delete {
+        list_tag(each_tag.tag.inner(), each_tag.alias, "delete");
+    }
+
+    let stage_position = |action: &&str| {
+        let stage = match *action {
+            "add" => TagStage::Add,
+            "delete" => TagStage::Delete,
+            _ => TagStage::Mask,
+        };
+        order.iter().position(|s| *s == stage)
+    };
+    for (_, listed) in actions.values_mut() {
+        listed.sort_by_key(stage_position);
+    }
+    let overlaps: Vec<_> = actions
+        .values()
+        .filter(|(_, listed)| listed.len() > 1)
+        .collect();
+    for (name, listed) in &overlaps {
+        let outcome = match listed.last() {
+            Some(&"delete") => "it is deleted",
+            Some(&"add") => "it is set to the add value",
+            _ => "it is masked with the value of its VR",
+        };
+        warn!(
+            "!! [{}] {} is listed in {}, {}",
+            listed.join("] ["),
+            name,
+            listed.join(" and "),
+            outcome
+        );
+    }
+    if strict_cookbook && !overlaps.is_empty() {
+        error!("Tags listed in more than one cookbook action with --strict-cookbook");
+        exit(1);
+    }
+}
+
+// Invalid routes abort the run, a file must never be routed by a rule that was dropped
+fn check_routes(route_list: Vec<RouteConfig>) -> Vec<RouteRule> {
+    route_list
+        .into_iter()
+        .map(|route| {
+            let tag = match resolve_tag_name(&route.tag) {
+                Some(entry) => entry.tag.inner(),
+                None => {
+                    error!(
+                        "!! [routes] Unknown tag {} of route {}.{}",
+                        route.tag,
+                        route.route,
+                        tag_name_hint(&route.tag)
+                    );
+                    exit(1)
+                }
+            };
+            let predicate = match (route.equals, route.contains, route.regex) {
+                (Some(value), None, None) => RoutePredicate::Equals(value),
+                (None, Some(value), None) => RoutePredicate::Contains(value),
+                (None, None, Some(pattern)) => match Regex::new(&pattern) {
+                    Ok(regex) => RoutePredicate::Regex(regex),
+                    Err(e) => {
+                        error!("!! [routes] Invalid regex of route {}: {}", route.route, e);
+                        exit(1)
+                    }
+                },
+                _ => {
+                    error!(
+                        "!! [routes] Route {} needs exactly one of equals, contains or regex",
+                        route.route
+                    );
+                    exit(1)
+                }
+            };
+            info!("Route {} > {}", route.tag, route.route);
+            RouteRule {
+                route: route.route,
+                tag,
+                tag_name: route.tag,
+                predicate,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dcmrig_rs::{AddMode, InstitutionPolicy, PatientIdStep, StaffNamePolicy};
+
+    fn parse(file_content: &str) -> CookbookConfig {
+        parse_cookbook_str(
+            "/tmp/dcmrig_test_cookbook.toml",
+            file_content,
+            false,
+            false,
+            false,
+            &LengthPolicy::default(),
+        )
+        .expect("Failed to parse the cookbook")
+    }
+
+    fn aliases(tag_list: &[DataDictionaryEntryRef<'static>]) -> Vec<&'static str> {
+        tag_list.iter().map(|t| t.alias).collect()
+    }
+
+    #[test]
+    fn parses_a_full_featured_cookbook() {
+        let config = parse(
+            r#"
+order = ["mask", "add", "delete", "delete_private"]
+
+[matchid]
+tag = "PatientName"
+
+[mask]
+tags = ["PatientID", "InstitutionName"]
+vrs = ["PN", "LO"]
+name_tags = ["PatientName"]
+
+[delete]
+tags = ["ImageComments", "StudyComments"]
+private_tags = true
+
+[add]
+tags.PatientIdentityRemoved = "YES"
+tags.ClinicalTrialSponsorName = { value = "TrialName", if_missing = true }
+
+[filter]
+exclude_sop_classes = ["SR"]
+
+[deid]
+method = ["Per protocol ABC-123", "DCMRig"]
+method_codes = [{ value = "113100", scheme = "DCM", meaning = "Basic Application Confidentiality Profile" }]
+
+[identity]
+institution = "replace:SITE01"
+staff_names = "remove"
+
+[[routes]]
+route = "derived"
+tag = "ImageType"
+regex = "DERIVED|SECONDARY"
+
+[normalize]
+patient_id = ["spaces", "uppercase"]
+
+[study_ids]
+accession = "hash"
+salt = "a project secret"
+
+[keep]
+tags = ["ViewPosition"]
+
+[modality.MG]
+delete.tags = ["DetectorID"]
+"#,
+        );
+
+        assert_eq!(config.match_id.alias, "PatientName");
+        assert_eq!(aliases(&config.mask_tags), ["PatientID", "InstitutionName"]);
+        assert_eq!(config.mask_vrs, [VR::PN, VR::LO]);
+        assert_eq!(aliases(&config.mask_name_tags), ["PatientName"]);
+        assert_eq!(aliases(&config.delete), ["ImageComments", "StudyComments"]);
+        assert!(config.private_tags);
+        assert_eq!(config.add["PatientIdentityRemoved"], "YES");
+        assert_eq!(config.add["ClinicalTrialSponsorName"], "TrialName");
+        assert_eq!(
+            config.add_modes["ClinicalTrialSponsorName"],
+            AddMode::IfMissing {
+                empty_is_missing: true
+            }
+        );
+        assert!(!config.add_modes.contains_key("PatientIdentityRemoved"));
+        assert_eq!(config.exclude_sop_classes, ["SR"]);
+        let deid_method = config.deid_method.as_ref().expect("deid method is set");
+        assert_eq!(deid_method.methods, ["Per protocol ABC-123", "DCMRig"]);
+        assert_eq!(deid_method.codes[0].value, "113100");
+        assert_eq!(
+            config.identity.institution,
+            Some(InstitutionPolicy::Replace("SITE01".to_string()))
+        );
+        assert_eq!(config.identity.staff_names, Some(StaffNamePolicy::Remove));
+        assert_eq!(config.routes.len(), 1);
+        assert_eq!(config.routes[0].route, "derived");
+        assert_eq!(config.routes[0].tag_name, "ImageType");
+        assert_eq!(
+            config.patient_id_steps,
+            Some(vec![PatientIdStep::Spaces, PatientIdStep::Uppercase])
+        );
+        assert_eq!(config.study_ids.accession, AccessionPolicy::Hash);
+        assert_eq!(config.study_ids.salt.as_deref(), Some("a project secret"));
+        assert_eq!(aliases(&config.keep_tags), ["ViewPosition"]);
+        assert_eq!(
+            config.order,
+            [
+                TagStage::Mask,
+                TagStage::Add,
+                TagStage::Delete,
+                TagStage::DeletePrivate
+            ]
+        );
+        assert_eq!(config.path, "/tmp/dcmrig_test_cookbook.toml");
+        assert_eq!(config.hash.len(), 64);
+
+        let mammo = config.for_modality("mg");
+        assert_eq!(
+            aliases(&mammo.delete),
+            ["ImageComments", "StudyComments", "DetectorID"]
+        );
+        assert_eq!(aliases(&config.for_modality("CT").delete).len(), 2);
+    }
+
+    #[test]
+    fn parses_the_plain_and_if_missing_add_entries() {
+        let config = parse(
+            r#"
+[add]
+tags.PatientIdentityRemoved = "YES"
+tags.ClinicalTrialSponsorName = { value = "TrialName", if_missing = true }
+tags.ClinicalTrialProtocolID = { value = "Protocol", if_missing = true, empty_is_missing = false }
+tags.ClinicalTrialSiteID = { value = "SITE01" }
+"#,
+        );
+        assert_eq!(config.add.len(), 4);
+        assert_eq!(config.add["ClinicalTrialProtocolID"], "Protocol");
+        assert_eq!(
+            config.add_modes["ClinicalTrialSponsorName"],
+            AddMode::IfMissing {
+                empty_is_missing: true
+            }
+        );
+        assert_eq!(
+            config.add_modes["ClinicalTrialProtocolID"],
+            AddMode::IfMissing {
+                empty_is_missing: false
+            }
+        );
+        // A table without if_missing overwrites like a plain value
+        assert!(!config.add_modes.contains_key("ClinicalTrialSiteID"));
+        assert!(!config.add_modes.contains_key("PatientIdentityRemoved"));
+    }
+
+    #[test]
+    fn parses_an_empty_add_list() {
+        let config = parse("[add]\ntags = {}\n");
+        assert!(config.add.is_empty());
+        assert!(config.add_modes.is_empty());
+    }
+
+    // Log lines written while f runs
+    fn logged(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for LogBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0
+                    .lock()
+                    .expect("Failed to lock mutex")
+                    .extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let log = buffer.0.lock().expect("Failed to lock mutex").clone();
+        String::from_utf8(log).expect("Log is not UTF-8")
+    }
+
+    #[test]
+    fn drops_the_sequence_binary_and_ui_mask_vrs_with_a_warning() {
+        let mut config = None;
+        let log = logged(|| {
+            config = Some(parse(
+                "[mask]\nvrs = [\"PN\", \"SQ\", \"OB\", \"UN\", \"UI\", \"LO\"]\n",
+            ))
+        });
+        assert_eq!(config.expect("Cookbook parsed").mask_vrs, [VR::PN, VR::LO]);
+        for vr in ["SQ", "OB", "UN"] {
+            assert!(
+                log.contains(&format!("VR {} can't be masked", vr)),
+                "no warning for {}",
+                vr
+            );
+        }
+        assert!(log.contains("VR UI would mask every UID"));
+    }
+
+    #[test]
+    fn keeps_the_ui_mask_vr_with_allow_ui() {
+        let config = parse_cookbook_str(
+            "/tmp/dcmrig_test_cookbook.toml",
+            "[mask]\nvrs = [\"PN\", \"UI\"]\nallow_ui = true\n",
+            false,
+            false,
+            true,
+            &LengthPolicy::default(),
+        )
+        .expect("Failed to parse the cookbook");
+        assert_eq!(config.mask_vrs, [VR::PN, VR::UI]);
+    }
+
+    #[test]
+    fn parses_an_empty_cookbook_with_the_defaults() {
+        let config = parse("");
+        assert_eq!(config.match_id.alias, "PatientID");
+        assert!(config.mask_tags.is_empty());
+        assert!(config.add.is_empty());
+        assert!(!config.private_tags);
+        assert_eq!(config.order, DEFAULT_TAG_STAGE_ORDER);
+    }
 }
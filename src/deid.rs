@@ -1,29 +1,48 @@
-use crate::cookbook_parser::parse_toml_cookbook;
-use anyhow::Result;
+use crate::args::{MapLevel, MatchNormalize};
+use crate::check::post_check;
+use crate::cookbook_parser::{AccessionPolicy, CookbookConfig, StudyIdsConfig};
+use anyhow::{Context, Result};
 use crossbeam::sync::WaitGroup;
 use dcmrig_rs::*;
 
 use dicom::{
-    core::{dictionary::DataDictionaryEntryRef, VR},
-    object::{FileDicomObject, InMemDicomObject},
+    core::{DataDictionary, Tag, VR},
+    dicom_value,
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, StandardDataDictionary},
 };
 
-use rayon::prelude::*;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 use tracing::{debug, error, info, warn};
+use walkdir::DirEntry;
 
+#[allow(clippy::too_many_arguments)]
 pub fn dicom_deid(
     source_path: PathBuf,
     destination_path: PathBuf,
     mapping_table: PathBuf,
-) -> Result<()> {
+    map_keys: String,
+    map_level: MapLevel,
+    match_normalize: MatchNormalize,
+    study_table: Option<PathBuf>,
+    study_map_keys: String,
+    cookbook: CookbookConfig,
+    charset: OutputCharset,
+    strict: bool,
+    debug_failed: bool,
+    reload_mapping_every: Option<Duration>,
+    naming: NamingOptions,
+    run_options: RunOptions,
+) -> Result<RunSummary> {
+    let start_time = std::time::Instant::now();
     info!(
         "Deidentifying the data for >> SOURCE: {} | DESTINATION: {} | MappingTable: {}",
         source_path.display(),
@@ -31,185 +50,1137 @@ pub fn dicom_deid(
         mapping_table.display(),
     );
 
-    // Get cookbook configs
-    let (
-        match_id,
-        mask_tag_config,
-        mask_vr_config,
-        add_config,
-        delete_tag_config,
-        private_tags_del,
-    ) = parse_toml_cookbook()?;
-
     // Set up required variables
-    let (all_files, total_len, pb) = preprocessing_setup(&source_path, &destination_path)?;
-    let failed_case: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let non_dcm_cases: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let mapping_dict = generate_mapping_dict(&mapping_table).unwrap_or_else(|_| {
-        error!("Can't open the mapping table: {}", mapping_table.display());
+    let RunSetup {
+        all_files,
+        total_len,
+        pb,
+        run_dirs,
+        walk_errors,
+        too_small,
+        too_large,
+        oversize,
+    } = preprocessing_setup(&source_path, &destination_path, &run_options)?;
+    let outcomes: Arc<OutcomeTracker> = Arc::new(
+        OutcomeTracker::new(RunCounts {
+            walk_errors,
+            too_small,
+            too_large,
+            oversize,
+            ..Default::default()
+        })
+        .with_run_callbacks(&run_options)
+        .with_single_file_result(&run_dirs),
+    );
+    // PatientIDs are only normalized when the files are matched on them
+    let patient_id = match (
+        cookbook.match_id.alias,
+        run_options.patient_id.steps.is_empty(),
+    ) {
+        ("PatientID", _) | (_, true) => run_options.patient_id.clone(),
+        (match_id, false) => {
+            warn!(
+                "The files are matched on {}, --normalize-patient-id is not used",
+                match_id
+            );
+            PatientIdNormalizer::default()
+        }
+    };
+    let mapping_dict = MappingTable::load(
+        mapping_table,
+        map_keys.clone(),
+        match_normalize,
+        patient_id,
+        reload_mapping_every,
+    )
+    .unwrap_or_else(|e| {
+        error!("Can't read the mapping table: {}", e);
         exit(1);
     });
+    let normalized_matches: Arc<Mutex<HashMap<String, String>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let study_ids = StudyIds::new(&cookbook.study_ids);
+    let deid_usage: Arc<Mutex<DeidUsage>> = Arc::new(Mutex::new(DeidUsage::default()));
+    let study_mapping_dict = match map_level {
+        MapLevel::Patient => None,
+        MapLevel::Study => {
+            let study_table = study_table.unwrap_or_else(|| {
+                error!("A study table is required with --map-level study");
+                exit(1);
+            });
+            info!("Study mapping table: {}", study_table.display());
+            Some(
+                generate_mapping_dict(&study_table, &study_map_keys)
+                    .and_then(|dict| {
+                        normalize_mapping_dict(
+                            dict,
+                            &MatchNormalize::Exact,
+                            &PatientIdNormalizer::default(),
+                        )
+                    })
+                    .unwrap_or_else(|e| {
+                        error!(
+                            "Can't read the study mapping table: {}\n{}",
+                            study_table.display(),
+                            e
+                        );
+                        exit(1);
+                    }),
+            )
+        }
+    };
     let wg = WaitGroup::new();
 
     // Main Loop
-    all_files
-        .par_iter()
-        .enumerate()
-        .for_each(|(_index, working_path)| {
-            if let Ok(dcm_obj) = dicom::object::OpenFileOptions::new()
-                .read_all()
-                .open_file(working_path.path())
-            {
-                deid_each_dcm_file(
-                    &dcm_obj,
-                    &destination_path,
-                    mapping_dict.clone(),
-                    match_id.clone(),
-                    mask_tag_config.clone(),
-                    mask_vr_config.clone(),
-                    delete_tag_config.clone(),
-                    add_config.clone(),
-                    private_tags_del.clone(),
-                    wg.clone(),
-                )
-                .unwrap_or_else(|_| {
-                    let mut map = failed_case.lock().expect("Failed to lock mutex");
-                    *map += 1;
+    let not_dispatched = for_each_source_file(&all_files, &run_options, |working_path| {
+        mapping_dict.reload_if_due();
+        let Some(read) = run_options.read_gate.run(|| {
+            with_file_timeout(working_path.path(), &run_options, |path| {
+                dicom::object::OpenFileOptions::new()
+                    .read_all()
+                    .open_file(path)
+            })
+        }) else {
+            record_file_timeout(working_path, &run_dirs, &run_options, &outcomes);
+            pb.inc(1);
+            return;
+        };
+        if let Ok(mut dcm_obj) = read {
+            check_dicomdir_record(&dcm_obj, working_path, &run_options);
+            fix_file_vrs(&mut dcm_obj, working_path, &run_options);
+            if skip_excluded_file(&dcm_obj, working_path, &run_dirs, &run_options, &outcomes) {
+                pb.inc(1);
+                return;
+            }
+            if skip_global_duplicate(&dcm_obj, working_path, &run_options, &outcomes) {
+                pb.inc(1);
+                return;
+            }
+            let mut progress = DeidProgress::default();
+            match deid_each_dcm_file(
+                &dcm_obj,
+                working_path,
+                &destination_path,
+                &mapping_dict,
+                study_mapping_dict.as_ref(),
+                Arc::clone(&normalized_matches),
+                &study_ids,
+                Arc::clone(&deid_usage),
+                &cookbook,
+                &charset,
+                strict,
+                debug_failed,
+                &mut progress,
+                &run_options.vr_length,
+                &naming,
+                &run_dirs,
+                &run_options,
+                Arc::clone(&outcomes),
+                wg.clone(),
+            ) {
+                Ok(DeidOutcome::DeIdentified) => (),
+                Ok(DeidOutcome::Unmapped) => {
+                    outcomes.record(FileOutcome::new(working_path.path(), FileStatus::Skipped))
+                }
+                Err(e) => {
                     error!(
-                        "Can't DeID {:#?} Copying to FAILED_CASES directory",
-                        &working_path.file_name()
+                        "Can't DeID {:?} Copying to FAILED_CASES directory",
+                        phi(working_path.file_name().to_string_lossy())
+                    );
+                    record_deid_failure(
+                        working_path,
+                        e.context(format!("DeID failed at the {} stage", progress.stage)),
+                        progress.modified.as_ref(),
+                        debug_failed,
+                        &run_dirs,
+                        &run_options,
+                        &outcomes,
                     );
-                    failed_case_copy(&working_path.clone().into_path(), &destination_path)
-                        .expect("Failed to copy file to FAILED_CASES directory");
-                });
-            } else {
-                let nwg = wg.clone();
-                let mut map = non_dcm_cases.lock().expect("Failed to lock mutex");
-                *map += 1;
-                copy_non_dicom_files(&working_path, &destination_path).unwrap_or_else(|_| {
-                    error!("Can't copy non dicom file {:#?}", &working_path.file_name());
-                });
-                drop(nwg);
+                }
             }
-            pb.inc(1);
-        });
+        } else if truncated_pixel_data_headers(working_path.path(), &run_options).is_some() {
+            error!(
+                "Can't DeID {:?}, the pixel data is truncated Copying to FAILED_CASES directory",
+                phi(working_path.file_name().to_string_lossy())
+            );
+            record_truncated_pixel_data(working_path, &run_dirs, &run_options, &outcomes);
+        } else {
+            let nwg = wg.clone();
+            record_unopened_file(working_path, &run_dirs, &run_options, &outcomes);
+            drop(nwg);
+        }
+        pb.inc(1);
+    });
     pb.finish();
-    print_status(
-        total_len,
-        *failed_case.lock().expect("Failed to lock mutex"),
-        *non_dcm_cases.lock().expect("Failed to lock mutex"),
-        "DeID".to_string(),
-    )?;
+    report_normalized_matches(&normalized_matches.lock().expect("Failed to lock mutex"));
     info!("Waiting for all threads to complete");
-    wg.wait();
+    wait_for_writes(wg);
+    write_deid_usage(
+        &destination_path,
+        &mapping_dict,
+        &deid_usage.lock().expect("Failed to lock mutex"),
+    )?;
     info!("DICOM DeID complete!");
-    Ok(())
+    Ok(RunSummary::new(
+        "DeID".to_string(),
+        destination_path,
+        total_len,
+        RunCounts {
+            interrupted: not_dispatched,
+            ..outcomes.counts()
+        },
+        start_time.elapsed(),
+    ))
+}
+
+/// A single row of the mapping table
+/// PatientName is optional, the DeID is used in its place when missing
+#[derive(Debug, Clone)]
+struct MappingEntry {
+    deid: String,
+    patient_name: Option<String>,
+    match_value: String,
+}
+
+/// AccessionNumber and StudyID values of the studies with [study_ids] accession
+/// A study is its original AccessionNumber, or its StudyInstanceUID without one, so all
+/// files of a study get the same value
+struct StudyIds {
+    policy: AccessionPolicy,
+    salt: String,
+    /// Studies of each DeID in the order they were first seen
+    sequences: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl StudyIds {
+    fn new(config: &StudyIdsConfig) -> Self {
+        if config.accession != AccessionPolicy::Deid {
+            info!("AccessionNumber and StudyID: {:?}", config.accession);
+        }
+        StudyIds {
+            policy: config.accession.clone(),
+            salt: config.salt.clone().unwrap_or_default(),
+            sequences: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Value of the study of the file, None to keep the DeID
+    fn value(
+        &self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        patient_deid: &str,
+    ) -> Option<String> {
+        if self.policy == AccessionPolicy::Deid {
+            return None;
+        }
+        let study_value = |tag: Tag| {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|e| e.to_str().ok())
+                .map(|v| trim_uid(&v).to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let study_key = study_value(tags::ACCESSION_NUMBER)
+            .or_else(|| study_value(tags::STUDY_INSTANCE_UID))
+            .unwrap_or_default();
+        match self.policy {
+            AccessionPolicy::Deid => None,
+            // 16 hex characters fit the SH limit of AccessionNumber and StudyID
+            AccessionPolicy::Hash => Some(
+                Sha256::digest(format!("{}\0{}", self.salt, study_key).as_bytes())
+                    .iter()
+                    .take(8)
+                    .map(|b| format!("{:02X}", b))
+                    .collect(),
+            ),
+            AccessionPolicy::SequentialPerPatient => {
+                let mut sequences = self.sequences.lock().expect("Failed to lock mutex");
+                let studies = sequences.entry(patient_deid.to_string()).or_default();
+                let position = match studies.iter().position(|s| *s == study_key) {
+                    Some(position) => position,
+                    None => {
+                        studies.push(study_key);
+                        studies.len() - 1
+                    }
+                };
+                Some(format!("{}_{}", patient_deid, position + 1))
+            }
+        }
+    }
+}
+
+/// Mapping table of a run keyed on the normalized match values
+/// With --reload-mapping-every the entries added to the file while the run is in flight are
+/// merged in, existing entries are never removed or changed so a patient keeps a single DeID
+struct MappingTable {
+    path: PathBuf,
+    map_keys: String,
+    match_normalize: MatchNormalize,
+    patient_id: PatientIdNormalizer,
+    entries: RwLock<HashMap<String, MappingEntry>>,
+    reload_every: Option<Duration>,
+    last_load: Mutex<Instant>,
+}
+
+impl MappingTable {
+    fn load(
+        path: PathBuf,
+        map_keys: String,
+        match_normalize: MatchNormalize,
+        patient_id: PatientIdNormalizer,
+        reload_every: Option<Duration>,
+    ) -> Result<Self> {
+        let entries = read_mapping_table(&path, &map_keys, &match_normalize, &patient_id)?;
+        if let Some(reload_every) = reload_every {
+            info!(
+                "Mapping table is reloaded every {} seconds",
+                reload_every.as_secs()
+            );
+        }
+        Ok(MappingTable {
+            path,
+            map_keys,
+            match_normalize,
+            patient_id,
+            entries: RwLock::new(entries),
+            reload_every,
+            last_load: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Key of a value of the match tag in the entries
+    fn match_key(&self, value: &str) -> String {
+        normalize_match_value(value, &self.match_normalize, &self.patient_id)
+    }
+
+    fn get(&self, match_key: &str) -> Option<MappingEntry> {
+        self.entries
+            .read()
+            .expect("Failed to lock mutex")
+            .get(match_key)
+            .cloned()
+    }
+
+    /// Merge the new entries of the table once the interval has passed since the last load
+    /// Files found unmapped before stay unmapped, only the files after the reload see the entries
+    fn reload_if_due(&self) {
+        let Some(reload_every) = self.reload_every else {
+            return;
+        };
+        // A single worker reloads, the others go on with the current entries
+        let Ok(mut last_load) = self.last_load.try_lock() else {
+            return;
+        };
+        if last_load.elapsed() < reload_every {
+            return;
+        }
+        *last_load = Instant::now();
+        let reloaded = match read_mapping_table(
+            &self.path,
+            &self.map_keys,
+            &self.match_normalize,
+            &self.patient_id,
+        ) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!(
+                    "Can't reload the mapping table, the current entries are kept: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let mut entries = self.entries.write().expect("Failed to lock mutex");
+        let mut added = 0;
+        let mut changed = 0;
+        for (match_key, entry) in reloaded {
+            match entries.get(&match_key) {
+                Some(existing)
+                    if existing.deid != entry.deid
+                        || existing.patient_name != entry.patient_name =>
+                {
+                    changed += 1
+                }
+                Some(_) => (),
+                None => {
+                    entries.insert(match_key, entry);
+                    added += 1;
+                }
+            }
+        }
+        info!(
+            "Mapping table reloaded: {} entries added, {} in total",
+            added,
+            entries.len()
+        );
+        if changed > 0 {
+            warn!(
+                "{} entries changed in the mapping table are ignored until the next run",
+                changed
+            );
+        }
+    }
+}
+
+/// Read and normalize a mapping table
+fn read_mapping_table(
+    mapping_table: &PathBuf,
+    map_keys: &str,
+    match_normalize: &MatchNormalize,
+    patient_id: &PatientIdNormalizer,
+) -> Result<HashMap<String, MappingEntry>> {
+    generate_mapping_dict(mapping_table, map_keys)
+        .and_then(|dict| normalize_mapping_dict(dict, match_normalize, patient_id))
+        .map_err(|e| anyhow::Error::msg(format!("{}\n{}", mapping_table.display(), e)))
+}
+
+/// Files, series and studies written for a single mapping entry
+#[derive(Debug, Default)]
+struct EntryUsage {
+    files: u64,
+    series: HashSet<String>,
+    studies: HashSet<String>,
+    /// Files written with an identity tag that kept its original value, and those tags
+    files_unchanged: u64,
+    unchanged_tags: BTreeSet<String>,
+}
+
+/// Mapping table usage of a run
+/// used is keyed on the normalized match value, unmapped on the value found in the file
+/// which is the StudyInstanceUID for a file missing from the study table
+#[derive(Debug, Default)]
+struct DeidUsage {
+    used: HashMap<String, EntryUsage>,
+    unmapped: HashMap<String, u64>,
+}
+
+/// Steps of the DeID of a single file, the step a failed file reached is part of its error
+#[derive(Debug, Default, Clone, Copy)]
+enum DeidStage {
+    /// Match value, mapping table and study label lookup
+    #[default]
+    Match,
+    /// Cookbook tag actions, identity policy and charset
+    Modify,
+    /// Identity tags left unchanged
+    Validate,
+    /// Destination path and file name from the modified tags
+    Path,
+    Write,
+}
+
+impl std::fmt::Display for DeidStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stage = match self {
+            DeidStage::Match => "match",
+            DeidStage::Modify => "modify",
+            DeidStage::Validate => "validate",
+            DeidStage::Path => "path",
+            DeidStage::Write => "write",
+        };
+        write!(f, "{}", stage)
+    }
+}
+
+/// Stage reached by a file, and its modified dataset with --debug-failed
+#[derive(Default)]
+struct DeidProgress {
+    stage: DeidStage,
+    modified: Option<FileDicomObject<InMemDicomObject>>,
+}
+
+/// Copy a failed file to FAILED_CASES with its error chain as the reason
+/// With --debug-failed the error chain and the modified tags are also written next to the copy
+fn record_deid_failure(
+    source_path: &DirEntry,
+    error: anyhow::Error,
+    modified: Option<&FileDicomObject<InMemDicomObject>>,
+    debug_failed: bool,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) {
+    let copy_path = record_failed_case(
+        source_path,
+        format!("{:#}", error),
+        run_dirs,
+        run_options,
+        outcomes,
+    );
+    if let (true, Some(copy_path)) = (debug_failed, copy_path) {
+        match write_failed_dump(&copy_path, modified.map(|m| &**m), &error) {
+            Ok(dump_path) => debug!("Failed case tags written to: {}", phi(dump_path.display())),
+            Err(e) => warn!("Can't write the tags of the failed case: {}", e),
+        }
+    }
+}
+
+/// Result of a single file that did not fail
+/// Unmapped files are not written to the destination
+enum DeidOutcome {
+    DeIdentified,
+    Unmapped,
 }
 
 /// Deidentify each file based on the mapping dict
 /// Generate filename and path based on DICOM tags
 /// Save the file to the necessary directory
 /// All Destination directories will be created recursively
+#[allow(clippy::too_many_arguments)]
 fn deid_each_dcm_file(
     dcm_obj: &FileDicomObject<InMemDicomObject>,
+    source_path: &DirEntry,
     destination_path: &PathBuf,
-    mapping_dict: HashMap<String, String>,
-    match_id: DataDictionaryEntryRef<'static>,
-    mask_tag_config_list: Vec<DataDictionaryEntryRef<'static>>,
-    mask_vr_config_list: Vec<VR>,
-    delete_tag_config_list: Vec<DataDictionaryEntryRef<'static>>,
-    add_config_list: HashMap<String, String>,
-    private_tags_del: bool,
+    mapping_dict: &MappingTable,
+    study_mapping_dict: Option<&HashMap<String, MappingEntry>>,
+    normalized_matches: Arc<Mutex<HashMap<String, String>>>,
+    study_ids: &StudyIds,
+    deid_usage: Arc<Mutex<DeidUsage>>,
+    cookbook: &CookbookConfig,
+    charset: &OutputCharset,
+    strict: bool,
+    debug_failed: bool,
+    progress: &mut DeidProgress,
+    length_policy: &LengthPolicy,
+    naming: &NamingOptions,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: Arc<OutcomeTracker>,
     wg: WaitGroup,
-) -> Result<()> {
-    let tag_to_match = dcm_obj.element(match_id.tag.inner())?.to_str()?.to_string();
-    let patient_deid = match mapping_dict.get(&tag_to_match) {
-        Some(deid) => deid.to_string(),
-        None => "".to_string(),
+) -> Result<DeidOutcome> {
+    // Files without the match tag can't be mapped, they are not processing errors
+    // A PatientName matches on its alphabetic group
+    let tag_to_match = match dcm_obj.element(cookbook.match_id.tag.inner()) {
+        Ok(element) if element.vr() == VR::PN => PersonName::parse(&element.to_str()?).alphabetic(),
+        Ok(element) => element.to_str()?.to_string(),
+        Err(_) => {
+            warn!(
+                "Match tag {} not found, file skipped",
+                cookbook.match_id.alias
+            );
+            return Ok(DeidOutcome::Unmapped);
+        }
+    };
+    let match_key = mapping_dict.match_key(&tag_to_match);
+    let mapping_entry = match mapping_dict.get(&match_key) {
+        Some(entry) => entry,
+        None => {
+            debug!("DeID for {} is not found", phi(&tag_to_match));
+            let mut usage = deid_usage.lock().expect("Failed to lock mutex");
+            *usage.unmapped.entry(tag_to_match).or_insert(0) += 1;
+            return Ok(DeidOutcome::Unmapped);
+        }
     };
+    if mapping_entry.match_value != tag_to_match {
+        let mut map = normalized_matches.lock().expect("Failed to lock mutex");
+        map.insert(tag_to_match.clone(), mapping_entry.match_value.clone());
+    }
+    let patient_deid = mapping_entry.deid;
+    // Without a PatientName column every masked tag takes the DeID
+    let patient_name = mapping_entry
+        .patient_name
+        .unwrap_or_else(|| patient_deid.clone());
 
-    if patient_deid.is_empty() {
-        debug!("DeID for {tag_to_match} is not found");
-        return Ok(());
+    // Study level label keyed on the StudyInstanceUID
+    // A file without a StudyInstanceUID keeps the patient mapping only
+    let study_uid = dcm_obj
+        .element(tags::STUDY_INSTANCE_UID)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|v| v.to_string());
+    let study_label = match (study_mapping_dict, study_uid) {
+        (Some(study_dict), Some(study_uid)) => match study_dict.get(&normalize_match_value(
+            &study_uid,
+            &MatchNormalize::Exact,
+            &PatientIdNormalizer::default(),
+        )) {
+            Some(entry) => Some(entry.deid.clone()),
+            None => {
+                debug!("Study label for {} is not found", phi(&study_uid));
+                let mut usage = deid_usage.lock().expect("Failed to lock mutex");
+                *usage.unmapped.entry(study_uid).or_insert(0) += 1;
+                return Ok(DeidOutcome::Unmapped);
+            }
+        },
+        (Some(_), None) => {
+            debug!("No StudyInstanceUID, the study label is not written");
+            None
+        }
+        (None, _) => None,
+    };
+
+    progress.stage = DeidStage::Modify;
+    // The [modality.<Modality>] override of the cookbook, resolved once for the file
+    let modality = dcm_obj
+        .element(tags::MODALITY)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+    let cookbook = cookbook.for_modality(&modality);
+    // Resolve the tag actions for this file and apply them in a single pass
+    // Masked tags listed in name_tags, or with PN VR by default, take the PatientName value
+    // A masked AccessionNumber and StudyID take the value of the study with [study_ids]
+    let study_id = study_ids.value(dcm_obj, &patient_deid);
+    let mut mask_tags = HashMap::new();
+    for each_tag in &cookbook.mask_tags {
+        let each_vr = each_tag.vr.relaxed();
+        let is_name_tag = match cookbook.mask_name_tags.is_empty() {
+            true => each_vr == VR::PN,
+            false => cookbook
+                .mask_name_tags
+                .iter()
+                .any(|name_tag| name_tag.tag.inner() == each_tag.tag.inner()),
+        };
+        let is_study_tag = [tags::ACCESSION_NUMBER, tags::STUDY_ID].contains(&each_tag.tag.inner());
+        let value = match (&study_id, is_study_tag, is_name_tag) {
+            (Some(study_id), true, _) => {
+                dicom_vr_corrected_value(each_vr, study_id, length_policy)?
+            }
+            (_, _, true) => dicom_vr_corrected_value(each_vr, &patient_name, length_policy)?,
+            (_, _, false) => dicom_vr_corrected_value(each_vr, &patient_deid, length_policy)?,
+        };
+        mask_tags.insert(each_tag.tag.inner(), (each_vr, value));
     }
 
-    let mut new_dicom_object = dcm_obj.clone();
+    let mut mask_vrs = HashMap::new();
+    for each_vr in &cookbook.mask_vrs {
+        let value = match *each_vr == VR::PN {
+            true => dicom_value!(Strs, [single_group_pn(&patient_name)]),
+            false => dicom_value!(Strs, [patient_deid.clone()]),
+        };
+        mask_vrs.insert(*each_vr, value);
+    }
 
-    if private_tags_del {
-        new_dicom_object = delete_private_tags(new_dicom_object)?
+    let mut add = HashMap::new();
+    let mut add_modes = HashMap::new();
+    for (each_tag_name, each_value) in &cookbook.add {
+        let (each_tag, each_vr) = extract_tag_vr_from_str(each_tag_name)?;
+        let value = dicom_vr_corrected_value(each_vr, each_value, length_policy)?;
+        add.insert(each_tag, (each_vr, value));
+        if let Some(add_mode) = cookbook.add_modes.get(each_tag_name) {
+            add_modes.insert(each_tag, *add_mode);
+        }
+    }
+    // Study label is applied after the cookbook add values
+    if let Some(label) = study_label {
+        for (each_tag, each_vr) in [
+            (tags::CLINICAL_TRIAL_TIME_POINT_ID, VR::LO),
+            (tags::STUDY_ID, VR::SH),
+        ] {
+            let value = dicom_vr_corrected_value(each_vr, &label, length_policy)?;
+            add.insert(each_tag, (each_vr, value));
+            add_modes.remove(&each_tag);
+        }
     }
 
-    let new_dicom_object = match mask_tag_config_list.is_empty() {
-        true => new_dicom_object,
-        false => tags_to_mask(
-            new_dicom_object.clone(),
-            patient_deid.clone(),
-            mask_tag_config_list,
-        )?,
+    let order = cookbook
+        .order
+        .iter()
+        .filter(|stage| cookbook.private_tags || **stage != TagStage::DeletePrivate)
+        .copied()
+        .collect();
+
+    let tag_actions = TagActions {
+        order,
+        mask_tags,
+        mask_vrs,
+        add,
+        add_modes,
+        delete: cookbook.delete.iter().map(|t| t.tag.inner()).collect(),
+        keep: cookbook.keep.clone(),
     };
+    let (mut new_dicom_object, rule_counts) =
+        apply_tag_actions(dcm_obj.clone(), &tag_actions).context("Cookbook tag actions")?;
+    run_options.rule_stats.record(&rule_counts);
+    if cookbook.private_tags {
+        run_options
+            .private_tag_stats
+            .record(dcm_obj, &cookbook.keep);
+    }
+    apply_identity_policy(
+        &mut new_dicom_object,
+        dcm_obj,
+        &run_options.identity,
+        &patient_deid,
+        length_policy,
+        &cookbook.keep,
+    )
+    .context("Identity policy")?;
+    if let Some(deid_method) = &cookbook.deid_method {
+        put_deid_method(&mut new_dicom_object, deid_method);
+    }
+    let mut new_dicom_object =
+        normalize_charset(new_dicom_object, charset).context("Output charset")?;
+    update_file_meta(&mut new_dicom_object, &run_options.file_meta);
+    progress.stage = DeidStage::Validate;
+    if debug_failed {
+        progress.modified = Some(new_dicom_object.clone());
+    }
+
+    // A DeID equal to the original value leaves the file identifiable
+    let unchanged_tags = unchanged_identity_tags(dcm_obj, &new_dicom_object, cookbook);
+    if !unchanged_tags.is_empty() {
+        if strict {
+            return Err(anyhow::Error::msg(format!(
+                "Identity tags unchanged by the DeID: {}",
+                unchanged_tags.join(", ")
+            )));
+        }
+        warn!(
+            "!! {:?} still holds the original value of {} after the DeID",
+            phi(source_path.file_name().to_string_lossy()),
+            unchanged_tags.join(", ")
+        );
+    }
+    if run_options.post_check {
+        post_check(&new_dicom_object).context("Post check")?;
+    }
+    if let Some(compression) = &run_options.compression {
+        compression.compress(
+            &mut new_dicom_object,
+            &source_path.file_name().to_string_lossy(),
+        );
+    }
+
+    progress.stage = DeidStage::Path;
+    let dicom_tags_values =
+        get_sanitized_tag_values(&new_dicom_object, naming).context("Tag values of the path")?;
+    // Files matching a cookbook route, tested on the source file go to its destination root
+    let destination_path = run_options.routes.destination(dcm_obj, destination_path);
 
-    let new_dicom_object = match mask_vr_config_list.is_empty() {
-        true => new_dicom_object,
-        false => mask_vr(new_dicom_object, mask_vr_config_list, patient_deid.clone())?,
+    let dcm_obj_clone = Arc::new(new_dicom_object.clone());
+    let io_retry = run_options.io_retry.clone();
+    // A missing UID is counted under the NoValue placeholder of the tag values
+    let uid_or_placeholder = |tag: Tag, name: &str| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format!("NoValue_{}", name))
     };
+    let study_uid = uid_or_placeholder(tags::STUDY_INSTANCE_UID, "StudyInstanceUID");
+    let series_uid = uid_or_placeholder(tags::SERIES_INSTANCE_UID, "SeriesInstanceUID");
+    let c_source_path = source_path.clone();
 
-    let new_dicom_object = match add_config_list.is_empty() {
-        true => new_dicom_object,
-        false => tags_to_add(new_dicom_object.clone(), add_config_list)?,
+    // Ordinal study directories are looked up on the original PatientID and StudyInstanceUID
+    let study_dir = match run_options.study_dir_style {
+        StudyDirStyle::Ordinal => Some(run_options.study_ordinals.study_dir(dcm_obj)),
+        StudyDirStyle::Date => None,
     };
+    let (dir_path, file_name) = match &run_options.media_names {
+        Some(media_names) => media_names.assign(&dicom_tags_values, destination_path),
+        None => (
+            run_options.series_dirs.resolve(
+                compute_dicom_dir_path(
+                    &dicom_tags_values,
+                    destination_path,
+                    &naming.series_derived,
+                    study_dir,
+                )
+                .display()
+                .to_string(),
+                dicom_tags_values
+                    .get("SeriesInstanceUID")
+                    .expect("Failed to extract value"),
+            ),
+            generate_dicom_file_name(&dicom_tags_values, "DeID".to_string(), naming.pad_width)?,
+        ),
+    };
+    let ensured_dirs = run_options.ensured_dirs.clone();
+    let dir_buckets = run_options.dir_buckets.clone();
+    let run_dirs = run_dirs.clone();
+    let c_run_options = run_options.clone();
+    let failed_outcomes = outcomes.clone();
+
+    // Only kept for the dump of a failed write
+    let failed_obj = debug_failed.then(|| dcm_obj_clone.clone());
+    let failed_source_path = source_path.clone();
 
-    let new_dicom_object = match delete_tag_config_list.is_empty() {
-        true => new_dicom_object,
-        false => tags_to_delete(new_dicom_object.clone(), delete_tag_config_list)?,
+    spawn_write(
+        run_options,
+        wg,
+        move || {
+            let (bucket_dir, full_path) = match dir_buckets.place(
+                &dir_path,
+                &file_name,
+                instance_order(&dicom_tags_values),
+                &|| object_content_hash(&dcm_obj_clone),
+            ) {
+                Placement::New(bucket_dir, full_path) => (bucket_dir, full_path),
+                Placement::Duplicate(existing_path) => {
+                    debug!(
+                        "{} is identical to {}",
+                        phi(c_source_path.path().display()),
+                        phi(existing_path.display())
+                    );
+                    outcomes.record(FileOutcome {
+                        destination_path: Some(existing_path),
+                        ..FileOutcome::new(c_source_path.path(), FileStatus::Duplicate)
+                    });
+                    return Ok(());
+                }
+            };
+            ensured_dirs
+                .ensure(&bucket_dir)
+                .context("Create the destination directory")?;
+            debug!("Saving file: {} to: {}", file_name, bucket_dir);
+            write_dicom_file(&dcm_obj_clone, &full_path, &io_retry)?;
+            let mut usage = deid_usage.lock().expect("Failed to lock mutex");
+            let entry_usage = usage.used.entry(match_key).or_default();
+            entry_usage.files += 1;
+            entry_usage.series.insert(trim_uid(&series_uid).to_string());
+            entry_usage.studies.insert(trim_uid(&study_uid).to_string());
+            if !unchanged_tags.is_empty() {
+                entry_usage.files_unchanged += 1;
+                entry_usage.unchanged_tags.extend(unchanged_tags);
+            }
+            drop(usage);
+            outcomes.record(FileOutcome {
+                destination_path: Some(PathBuf::from(full_path)),
+                tag_values: dicom_tags_values,
+                ..FileOutcome::new(c_source_path.path(), FileStatus::Written)
+            });
+            Ok(())
+        },
+        move |e| {
+            error!(
+                "Can't write {:?} Copying to FAILED_CASES directory",
+                phi(failed_source_path.file_name().to_string_lossy())
+            );
+            record_deid_failure(
+                &failed_source_path,
+                e.context(format!("DeID failed at the {} stage", DeidStage::Write)),
+                failed_obj.as_deref(),
+                debug_failed,
+                &run_dirs,
+                &c_run_options,
+                &failed_outcomes,
+            );
+        },
+    );
+    Ok(DeidOutcome::DeIdentified)
+}
+
+/// Generate a dictionary based on the Mapping table
+/// Eg DeID001,U012345 >> {"U012345"; "DeID001"}
+/// Eg DeID001,U012345,SUBJECT^001 >> {"U012345"; "DeID001", "SUBJECT^001"}
+/// JSON tables are detected by the .json extension or a leading '['
+fn generate_mapping_dict(
+    mapping_table: &PathBuf,
+    map_keys: &str,
+) -> Result<HashMap<String, MappingEntry>> {
+    let is_json = match mapping_table.extension() {
+        Some(ext) => ext.eq_ignore_ascii_case("json"),
+        None => false,
     };
+    let file_content = match fs::read_to_string(mapping_table) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to open file {}: {}",
+                mapping_table.display(),
+                e
+            )))
+        }
+    };
+    if is_json || file_content.trim_start().starts_with('[') {
+        generate_mapping_dict_json(&file_content, map_keys)
+    } else {
+        generate_mapping_dict_csv(&file_content)
+    }
+}
 
-    let dicom_tags_values = get_sanitized_tag_values(&new_dicom_object)?;
+/// All lines that dont follow DeID,PatientID[,PatientName] pattern will be ignored
+/// A PatientID mapped more than once is rejected
+fn generate_mapping_dict_csv(file_content: &str) -> Result<HashMap<String, MappingEntry>> {
+    let mut data_map: HashMap<String, MappingEntry> = HashMap::new();
+    for line in file_content.lines() {
+        let parts: Vec<&str> = line.split(',').collect();
+        if parts.len() == 2 || parts.len() == 3 {
+            if parts[0].is_empty() || parts[1].is_empty() {
+                continue;
+            }
+            let key = parts[1].trim().to_string();
+            let value = MappingEntry {
+                deid: parts[0].trim().to_string(),
+                patient_name: parts
+                    .get(2)
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty()),
+                match_value: key.clone(),
+            };
+            insert_mapping(&mut data_map, key, value, line)?;
+        } else {
+            warn!("Invalid line: {}", phi(line));
+        }
+    }
+    Ok(data_map)
+}
 
-    let new_dp = destination_path.clone();
-    let dcm_obj_clone = new_dicom_object.clone();
+/// JSON array of objects Eg [{"deid": "DeID001", "patient_id": "U012345"}]
+/// The key names are given as DEID_KEY,PATIENTID_KEY[,PATIENTNAME_KEY]
+fn generate_mapping_dict_json(
+    file_content: &str,
+    map_keys: &str,
+) -> Result<HashMap<String, MappingEntry>> {
+    let keys: Vec<&str> = map_keys.split(',').map(|k| k.trim()).collect();
+    if !(keys.len() == 2 || keys.len() == 3) || keys.iter().any(|k| k.is_empty()) {
+        return Err(anyhow::Error::msg(format!(
+            "Invalid map keys: {}, expected DEID_KEY,PATIENTID_KEY[,PATIENTNAME_KEY]",
+            map_keys
+        )));
+    }
+    let (deid_key, patient_id_key) = (keys[0], keys[1]);
 
-    rayon::spawn(move || {
-        let file_name = generate_dicom_file_name(&dicom_tags_values, "DeID".to_string())
-            .expect("Failed to generate file name");
-        let dir_path = generate_dicom_file_path(dicom_tags_values, &new_dp)
-            .expect("Failed to generate DIR path");
+    let entries: Vec<serde_json::Value> = serde_json::from_str(file_content)?;
+    let mut data_map: HashMap<String, MappingEntry> = HashMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let patient_name = match keys.get(2) {
+            Some(name_key) if entry.get(*name_key).is_some() => {
+                Some(json_mapping_field(entry, name_key, index)?)
+            }
+            _ => None,
+        };
+        let key = json_mapping_field(entry, patient_id_key, index)?;
+        let value = MappingEntry {
+            deid: json_mapping_field(entry, deid_key, index)?,
+            patient_name,
+            match_value: key.clone(),
+        };
+        insert_mapping(&mut data_map, key, value, &format!("index {}", index))?;
+    }
+    Ok(data_map)
+}
 
-        let full_path = check_if_dup_exists(format!("{}/{}", dir_path, file_name));
-        debug!("Saving file: {} to: {}", file_name, dir_path);
-        let dcm_buffer = File::create(full_path).expect("Failed to create file");
-        dcm_obj_clone
-            .write_all(dcm_buffer)
-            .expect("Failed to add dcm value to buffer");
-        drop(wg);
-    });
+fn json_mapping_field(entry: &serde_json::Value, field: &str, index: usize) -> Result<String> {
+    let value = match entry.get(field) {
+        Some(serde_json::Value::String(v)) => v.trim().to_string(),
+        Some(serde_json::Value::Number(v)) => v.to_string(),
+        Some(_) => {
+            return Err(anyhow::Error::msg(format!(
+                "Mapping table index {}: \"{}\" is not a string",
+                index, field
+            )))
+        }
+        None => {
+            return Err(anyhow::Error::msg(format!(
+                "Mapping table index {}: \"{}\" is missing",
+                index, field
+            )))
+        }
+    };
+    if value.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "Mapping table index {}: \"{}\" is empty",
+            index, field
+        )));
+    }
+    Ok(value)
+}
+
+fn insert_mapping(
+    data_map: &mut HashMap<String, MappingEntry>,
+    key: String,
+    value: MappingEntry,
+    location: &str,
+) -> Result<()> {
+    if data_map.contains_key(&key) {
+        return Err(anyhow::Error::msg(format!(
+            "Duplicate PatientID {} in mapping table at {}",
+            phi(&key),
+            location
+        )));
+    }
+    data_map.insert(key, value);
     Ok(())
 }
 
-/// Generate a dictionary based on the Mapping table
-/// Eg DeID001,U012345 >> {"U012345"; "DeID001"}
-/// All lines that dont follow DeID,PatientID pattern will be ignored
-fn generate_mapping_dict(mapping_table: &PathBuf) -> Result<HashMap<String, String>> {
-    let mut data_map: HashMap<String, String> = HashMap::new();
-    if let Ok(file) = File::open(&mapping_table) {
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                let parts: Vec<&str> = line.split(',').collect();
-                if parts.len() == 2 {
-                    if parts[0].is_empty() || parts[1].is_empty() {
-                        continue;
-                    }
-                    let key = parts[1].trim().to_string();
-                    let value = parts[0].trim().to_string();
-                    data_map.insert(key, value);
-                } else {
-                    warn!("Invalid line: {}", line);
-                }
-            }
+/// Trim whitespace and null padding, and uppercase for case insensitive matching
+/// PatientIDs are normalized first with --normalize-patient-id
+fn normalize_match_value(
+    value: &str,
+    match_normalize: &MatchNormalize,
+    patient_id: &PatientIdNormalizer,
+) -> String {
+    let value = patient_id.normalize(value);
+    let trimmed = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    match match_normalize {
+        MatchNormalize::Exact => trimmed.to_string(),
+        MatchNormalize::CaseInsensitive => trimmed.to_uppercase(),
+    }
+}
+
+/// Re-key the mapping dict on the normalized match values
+/// Entries that collapse onto the same normalized value are rejected
+fn normalize_mapping_dict(
+    mapping_dict: HashMap<String, MappingEntry>,
+    match_normalize: &MatchNormalize,
+    patient_id: &PatientIdNormalizer,
+) -> Result<HashMap<String, MappingEntry>> {
+    let mut data_map: HashMap<String, MappingEntry> = HashMap::new();
+    for (key, value) in mapping_dict {
+        let normalized_key = normalize_match_value(&key, match_normalize, patient_id);
+        if let Some(existing) = data_map.get(&normalized_key) {
+            return Err(anyhow::Error::msg(format!(
+                "PatientIDs {} and {} are the same after normalization",
+                phi(&existing.match_value),
+                phi(&key)
+            )));
         }
-    } else {
-        error!("Failed to open file {}", &mapping_table.display());
-        exit(1);
+        data_map.insert(normalized_key, value);
     }
     Ok(data_map)
 }
+
+/// Write deid_usage.csv to the destination once all writers are complete
+/// Every mapping entry is listed, entries without files as unused
+/// followed by the match values found in the files without a mapping entry
+/// normalized_value is the value after --match-normalize and --normalize-patient-id
+fn write_deid_usage(
+    destination_path: &Path,
+    mapping_dict: &MappingTable,
+    deid_usage: &DeidUsage,
+) -> Result<()> {
+    let entries = mapping_dict.entries.read().expect("Failed to lock mutex");
+    let mut mapping_list: Vec<_> = entries.iter().collect();
+    mapping_list.sort_by(|a, b| a.1.deid.cmp(&b.1.deid));
+    let mut usage_csv =
+        "DeID,match_value,files_written,series_seen,studies_seen,files_unmapped,status,files_unchanged,unchanged_tags,normalized_value\n"
+            .to_string();
+    let mut unused = 0;
+    let mut unchanged = 0;
+    for (match_key, entry) in mapping_list {
+        let line = match deid_usage.used.get(match_key) {
+            Some(usage) => {
+                if usage.files_unchanged > 0 {
+                    unchanged += 1;
+                }
+                format!(
+                    "{},{},{},{},{},0,used,{},{},{}\n",
+                    csv_field(&entry.deid),
+                    csv_field(&entry.match_value),
+                    usage.files,
+                    usage.series.len(),
+                    usage.studies.len(),
+                    usage.files_unchanged,
+                    csv_field(
+                        &usage
+                            .unchanged_tags
+                            .iter()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    ),
+                    csv_field(match_key)
+                )
+            }
+            None => {
+                unused += 1;
+                format!(
+                    "{},{},0,0,0,0,unused,0,,{}\n",
+                    csv_field(&entry.deid),
+                    csv_field(&entry.match_value),
+                    csv_field(match_key)
+                )
+            }
+        };
+        usage_csv.push_str(&line);
+    }
+    let mut unmapped_list: Vec<_> = deid_usage.unmapped.iter().collect();
+    unmapped_list.sort();
+    for (match_value, files) in &unmapped_list {
+        usage_csv.push_str(&format!(
+            ",{},0,0,0,{},unmapped,0,,{}\n",
+            csv_field(match_value),
+            files,
+            csv_field(&mapping_dict.match_key(match_value))
+        ));
+    }
+    let usage_path = destination_path.join("deid_usage.csv");
+    fs::write(&usage_path, usage_csv)?;
+    info!(
+        "Mapping usage written to: {} | {} unused entries | {} unmapped values",
+        usage_path.display(),
+        unused,
+        unmapped_list.len()
+    );
+    if unchanged > 0 {
+        warn!(
+            "{} mapping entries wrote files with unchanged identity tags, see files_unchanged in {}",
+            unchanged,
+            usage_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Identity tags that still hold their non empty original value after the deidentification
+/// Compared per tag: PatientID, PatientName, the masked tags and the elements of the masked VRs
+/// so the tags that are not changed on purpose like Modality are left out
+fn unchanged_identity_tags(
+    original: &FileDicomObject<InMemDicomObject>,
+    deidentified: &FileDicomObject<InMemDicomObject>,
+    cookbook: &CookbookConfig,
+) -> Vec<String> {
+    let mut identity_tags: Vec<Tag> = vec![tags::PATIENT_ID, tags::PATIENT_NAME];
+    identity_tags.extend(cookbook.mask_tags.iter().map(|t| t.tag.inner()));
+    identity_tags.extend(
+        original
+            .iter()
+            .filter(|element| cookbook.mask_vrs.contains(&element.vr()))
+            .map(|element| element.header().tag),
+    );
+    // The tags of the [keep] list are unchanged on purpose
+    identity_tags.retain(|tag| {
+        !cookbook
+            .keep_tags
+            .iter()
+            .any(|keep_tag| keep_tag.tag.inner() == *tag)
+    });
+    identity_tags.sort();
+    identity_tags.dedup();
+    let value = |dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|element| element.to_str().ok())
+            .map(|v| v.trim_end_matches(['\0', ' ']).trim().to_string())
+    };
+    identity_tags
+        .into_iter()
+        .filter(
+            |tag| match (value(original, *tag), value(deidentified, *tag)) {
+                (Some(original_value), Some(new_value)) => {
+                    !original_value.is_empty() && original_value == new_value
+                }
+                _ => false,
+            },
+        )
+        .map(|tag| match StandardDataDictionary.by_tag(tag) {
+            Some(entry) => entry.alias.to_string(),
+            None => tag.to_string(),
+        })
+        .collect()
+}
+
+/// List the match values that were only found after normalization
+/// so that the mapping table can be fixed
+fn report_normalized_matches(normalized_matches: &HashMap<String, String>) {
+    if normalized_matches.is_empty() {
+        return;
+    }
+    warn!(
+        "{} match values were only mapped after normalization",
+        normalized_matches.len()
+    );
+    for (file_value, table_value) in normalized_matches {
+        warn!(
+            "File value {:?} matched mapping table {:?}",
+            phi(file_value),
+            phi(table_value)
+        );
+    }
+}
@@ -1,36 +1,59 @@
 use nanoid::nanoid;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Write,
     fs::{self, canonicalize, copy, create_dir_all},
-    path::PathBuf,
+    net::IpAddr,
+    path::{Path, PathBuf},
     process::exit,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
+use crossbeam::{channel::RecvTimeoutError, sync::WaitGroup};
 use dicom::{
     core::{
-        chrono::NaiveDate,
-        dictionary::DataDictionaryEntryRef,
+        chrono::{FixedOffset, NaiveDate},
+        dictionary::{DataDictionaryEntryRef, VirtualVr},
         header::Header,
-        value::{DicomDate, DicomDateTime, DicomTime},
+        value::{
+            DataSetSequence, DicomDate, DicomDateTime, DicomTime, PixelFragmentSequence, Value,
+        },
         DataDictionary, DataElement, PrimitiveValue, VR,
     },
     dicom_value,
     dictionary_std::tags::{self, ORIGINAL_ATTRIBUTES_SEQUENCE},
-    object::{FileDicomObject, InMemDicomObject, StandardDataDictionary, Tag},
-};
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::{
-    current_num_threads,
-    iter::{ParallelBridge, ParallelIterator},
+    encoding::TransferSyntaxIndex,
+    object::{
+        mem::InMemElement, FileDicomObject, FileMetaTable, FileMetaTableBuilder, InMemDicomObject,
+        StandardDataDictionary, Tag,
+    },
+    transfer_syntax::{
+        entries::{
+            DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN, EXPLICIT_VR_BIG_ENDIAN, EXPLICIT_VR_LITTLE_ENDIAN,
+            IMPLICIT_VR_LITTLE_ENDIAN, RLE_LOSSLESS,
+        },
+        TransferSyntaxRegistry,
+    },
 };
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::{current_num_threads, prelude::*};
 use regex::Regex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tracing::{debug, error, info, warn};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use walkdir::{DirEntry, WalkDir};
 
-// Tags to get data for
-static DICOM_TAGS_SANITIZED: [&str; 10] = [
+#[cfg(feature = "test-support")]
+pub mod test_support;
+
+// Tags of the destination layouts and file names, always extracted
+pub static DEFAULT_SANITIZED_TAGS: [&str; 10] = [
     "PatientID",
     "PatientName",
     "Modality",
@@ -54,6 +77,15 @@ static DICOM_TAGS_CHANGE: [(Tag, VR); 7] = [
     (tags::PATIENT_COMMENTS, VR::LT),
 ];
 
+// Free text that often holds the patient name or accession, emptied by anon when present
+pub static FREE_TEXT_TAGS: [Tag; 5] = [
+    tags::COMMENTS_ON_THE_PERFORMED_PROCEDURE_STEP,
+    tags::ACQUISITION_DEVICE_PROCESSING_DESCRIPTION,
+    tags::REQUESTED_PROCEDURE_DESCRIPTION,
+    tags::ADMITTING_DIAGNOSES_DESCRIPTION,
+    tags::IMAGE_COMMENTS,
+];
+
 // Logo
 pub fn print_logo() {
     let app_version = env!("CARGO_PKG_VERSION");
@@ -73,463 +105,6567 @@ pub fn print_logo() {
     println!("{} Ver: {}", art, app_version);
 }
 
+// Files and state prepared before starting the action
+pub struct RunSetup {
+    pub all_files: Vec<DirEntry>,
+    pub total_len: u64,
+    pub pb: ProgressBar,
+    pub run_dirs: RunDirs,
+    // Entries of the source tree that could not be read
+    pub walk_errors: u64,
+    // Files too small to be DICOM, part of the total but not of all_files
+    pub too_small: u64,
+    // Files over the file size limit of the destination filesystem, never opened
+    pub too_large: u64,
+    // Files over --max-file-size, part of the total but not of all_files
+    pub oversize: u64,
+}
+
+// Preamble and DICM marker, any smaller file can't be a DICOM file
+static MIN_DICOM_FILE_SIZE: u64 = 132;
+
+// Bytes before the DICM marker, free for the application that wrote the file
+pub const DICOM_PREAMBLE_LEN: usize = 128;
+
+// Selection of the files of a smoke test run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartialSelection {
+    // The first files in path order
+    Limit(u64),
+    // A random fraction of the files, the same seed selects the same files
+    Sample { fraction: f64, seed: u64 },
+}
+
+impl std::fmt::Display for PartialSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartialSelection::Limit(limit) => write!(f, "--limit {}", limit),
+            PartialSelection::Sample { fraction, seed } => {
+                write!(f, "--sample {} with seed {}", fraction, seed)
+            }
+        }
+    }
+}
+
+// Partial run of --limit or --sample, the files left out are counted for the summary
+#[derive(Debug, Clone)]
+pub struct PartialRun {
+    pub selection: PartialSelection,
+    not_selected: Arc<AtomicU64>,
+}
+
+// Partial run in the summary and the destination marker
+#[derive(Debug, Clone, Serialize)]
+pub struct PartialRunSummary {
+    pub selection: String,
+    pub not_selected: u64,
+}
+
+impl PartialRun {
+    pub fn new(selection: PartialSelection) -> Self {
+        PartialRun {
+            selection,
+            not_selected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Selected files in their indexed order, the selection doesn't depend on the walk order
+    fn select(&self, mut all_files: Vec<DirEntry>, too_small: usize) -> Vec<DirEntry> {
+        let found = all_files.len() + too_small;
+        let selected: Vec<DirEntry> = match &self.selection {
+            PartialSelection::Limit(limit) => {
+                all_files.sort_by(|a, b| a.path().cmp(b.path()));
+                all_files.truncate(*limit as usize);
+                all_files
+            }
+            PartialSelection::Sample { fraction, seed } => {
+                let count = ((all_files.len() as f64 * fraction).round() as usize)
+                    .clamp(1, all_files.len().max(1));
+                // Files ranked on a seeded hash of their path
+                let mut ranked: Vec<(u64, usize)> = all_files
+                    .iter()
+                    .enumerate()
+                    .map(|(index, each)| {
+                        let mut hasher = Sha256::new();
+                        hasher.update(seed.to_le_bytes());
+                        hasher.update(each.path().to_string_lossy().as_bytes());
+                        let digest = hasher.finalize();
+                        let rank = u64::from_le_bytes(digest[..8].try_into().unwrap_or_default());
+                        (rank, index)
+                    })
+                    .collect();
+                ranked.sort();
+                let picked: HashSet<usize> = ranked
+                    .into_iter()
+                    .take(count)
+                    .map(|(_, index)| index)
+                    .collect();
+                all_files
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| picked.contains(index))
+                    .map(|(_, each)| each)
+                    .collect()
+            }
+        };
+        let not_selected = (found - selected.len()) as u64;
+        self.not_selected.store(not_selected, Ordering::Relaxed);
+        warn!(
+            "Partial run with {}, {} of {} files are processed",
+            self.selection,
+            selected.len(),
+            found
+        );
+        selected
+    }
+
+    pub fn summary(&self) -> PartialRunSummary {
+        PartialRunSummary {
+            selection: self.selection.to_string(),
+            not_selected: self.not_selected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Parse the fraction of --sample, over 0 and at most 1
+pub fn parse_sample_fraction(fraction: &str) -> Result<f64> {
+    match fraction.trim().parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+        _ => Err(anyhow::Error::msg(format!(
+            "Invalid sample {:?}, expected a fraction over 0 and at most 1 eg 0.01",
+            fraction
+        ))),
+    }
+}
+
 // Initial setup before starting the action
 pub fn preprocessing_setup(
     source_path: &PathBuf,
     destination_path: &PathBuf,
-) -> Result<(Vec<DirEntry>, u64, ProgressBar)> {
-    check_given_path_exists(&source_path, &destination_path)?;
+    run_options: &RunOptions,
+) -> Result<RunSetup> {
+    check_given_path_exists(source_path, destination_path)?;
+    let run_dirs = RunDirs::create(source_path, destination_path, run_options)?;
+    let IndexSetup {
+        mut all_files,
+        too_small,
+        oversize,
+        total_len,
+        pb,
+        walk_errors,
+    } = index_setup(source_path, run_options)?;
+    if !too_small.is_empty() {
+        handle_too_small_files(&too_small, &run_dirs, run_options)?;
+    }
+    if !oversize.is_empty() {
+        write_oversize_csv(&oversize, &destination_path.join(OVERSIZE_CSV_NAME))?;
+    }
+    let mut too_large = 0;
+    if let Some(max_file_size) = run_options.fs_limits.max_file_size {
+        let (fitting, over_limit): (Vec<DirEntry>, Vec<DirEntry>) = all_files
+            .into_iter()
+            .partition(|each| each.metadata().map(|m| m.len()).unwrap_or(0) <= max_file_size);
+        for each in &over_limit {
+            error!(
+                "{} is over the {} byte file size limit of the destination filesystem and is not processed",
+                phi(each.path().display()),
+                max_file_size
+            );
+        }
+        too_large = over_limit.len() as u64;
+        all_files = fitting;
+        pb.set_length(all_files.len() as u64);
+    }
+    if run_options.study_dir_style == StudyDirStyle::Ordinal {
+        run_options
+            .study_ordinals
+            .index(&all_files, &run_options.read_gate);
+    }
+    Ok(RunSetup {
+        all_files,
+        total_len,
+        pb,
+        run_dirs,
+        walk_errors,
+        too_small: too_small.len() as u64,
+        too_large,
+        oversize: oversize.len() as u64,
+    })
+}
+
+// Indexed source files without any of the destination directories, used as is by report
+pub struct IndexSetup {
+    pub all_files: Vec<DirEntry>,
+    // Files too small to be DICOM, part of the total but not of all_files
+    pub too_small: Vec<DirEntry>,
+    // Files over --max-file-size, part of the total but not of all_files
+    pub oversize: Vec<DirEntry>,
+    pub total_len: u64,
+    pub pb: ProgressBar,
+    // Entries of the source tree that could not be read
+    pub walk_errors: u64,
+}
+
+// Index the source and set up the progress bar
+pub fn index_setup(source_path: &PathBuf, run_options: &RunOptions) -> Result<IndexSetup> {
     info!("Indexing files from: {}", source_path.display());
-    let all_files: Vec<_> = WalkDir::new(source_path)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .par_bridge()
-        .filter(|entry| entry.file_type().is_file())
-        .collect();
-    let total_len: u64 = all_files.len() as u64;
-    info!("Total files found: {} | Starting deid", total_len);
-    let pb = ProgressBar::new(total_len);
+    let dicomdir_path = match run_options.use_dicomdir {
+        true => find_dicomdir(source_path),
+        false => None,
+    };
+    let SourceIndex {
+        mut all_files,
+        mut too_small,
+        walk_errors,
+    } = match &dicomdir_path {
+        Some(dicomdir_path) => index_dicomdir_files(dicomdir_path, &run_options.dicomdir_records)?,
+        None if source_path.is_file() => index_single_file(source_path)?,
+        None => {
+            if run_options.use_dicomdir {
+                info!("No DICOMDIR at the source root, walking the source");
+            }
+            index_source_files(source_path)?
+        }
+    };
+    // The walk order depends on the filesystem
+    if run_options.deterministic {
+        all_files.sort_by(|a, b| a.path().cmp(b.path()));
+        too_small.sort_by(|a, b| a.path().cmp(b.path()));
+    }
+    // A partial run leaves the too small files out with the files that were not selected
+    if let Some(partial_run) = &run_options.partial_run {
+        all_files = partial_run.select(all_files, too_small.len());
+        too_small.clear();
+    }
+    // Corrupted files of several GB would hold a worker and their size in memory for minutes
+    let mut oversize = Vec::new();
+    if let Some(max_file_size) = run_options.max_file_size {
+        (all_files, oversize) = all_files
+            .into_iter()
+            .partition(|each| each.metadata().map(|m| m.len()).unwrap_or(0) <= max_file_size);
+    }
+    if !walk_errors.is_empty() {
+        warn!(
+            "{} entries of the source could not be read and are not processed",
+            walk_errors.len()
+        );
+        for each in walk_errors.iter().take(10) {
+            warn!("Walk error: {}", phi(each));
+        }
+        walk_errors
+            .iter()
+            .skip(10)
+            .for_each(|e| debug!("Walk error: {}", phi(e)));
+        if run_options.fail_on_walk_errors {
+            error!("Aborting, the source tree is not fully readable");
+            exit(1)
+        }
+    }
+    if !too_small.is_empty() {
+        info!(
+            "{} files are empty or smaller than {} bytes and are not opened",
+            too_small.len(),
+            MIN_DICOM_FILE_SIZE
+        );
+    }
+    if !oversize.is_empty() {
+        warn!(
+            "{} files are over the {} bytes of --max-file-size and are not opened",
+            oversize.len(),
+            run_options.max_file_size.unwrap_or(0)
+        );
+        for each in &oversize {
+            debug!("Over --max-file-size: {}", phi(each.path().display()));
+        }
+    }
+    let total_len: u64 = (all_files.len() + too_small.len() + oversize.len()) as u64;
+    info!("Total files found: {}", total_len);
+    let pb = ProgressBar::new(all_files.len() as u64);
     pb.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} {percent}% [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({pos}/{len}, ETA {eta})",
         )?,
     );
+    if source_path.is_file() {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    if let Some(status_board) = &run_options.status_board {
+        status_board.track(&pb);
+    }
     info!("Current number of threads: {}", current_num_threads());
-    Ok((all_files, total_len, pb))
+    Ok(IndexSetup {
+        all_files,
+        too_small,
+        oversize,
+        total_len,
+        pb,
+        walk_errors: walk_errors.len() as u64,
+    })
 }
 
-fn check_given_path_exists(src_path: &PathBuf, dest_path: &PathBuf) -> Result<()> {
-    // Source Path
-    match canonicalize(src_path) {
-        Ok(_) => (),
-        Err(e) => {
-            error!(
-                "Given source Path doesnot exist: {}\n{}",
-                src_path.display(),
-                e
-            );
-            exit(1)
+// List the too small files and only copy them to NON_DICOM with --copy-non-dicom-all
+fn handle_too_small_files(
+    too_small: &Vec<DirEntry>,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+) -> Result<()> {
+    if let Some(csv_path) = &run_options.too_small_csv {
+        let mut too_small_csv = String::from("path,size\n");
+        for each in too_small {
+            let size = fs::metadata(each.path()).map(|m| m.len()).unwrap_or(0);
+            writeln!(too_small_csv, "{},{}", each.path().display(), size)?;
         }
+        fs::write(csv_path, too_small_csv)?;
+        info!("Too small files written to: {}", csv_path.display());
     }
-    // Destination Path
-    match canonicalize(dest_path) {
-        Ok(_) => (),
-        Err(_) => create_dir_all(dest_path).unwrap_or_else(|_| {
-            error!("Can't create dir: {}", dest_path.display());
-            exit(1)
-        }),
+    if run_options.copy_non_dicom_all && !run_dirs.single_file {
+        let copy_each = |each: &DirEntry| {
+            if copy_non_dicom_files(each, &run_dirs.non_dicom, run_options).is_err() {
+                error!(
+                    "Can't copy non dicom file {:?}",
+                    phi(each.file_name().to_string_lossy())
+                );
+            }
+        };
+        match run_options.deterministic {
+            true => too_small.iter().for_each(copy_each),
+            false => too_small.par_iter().for_each(copy_each),
+        }
     }
     Ok(())
 }
 
-// For all non DICOM files, Copy them to a NON_DICOM directory in the destination path
-pub fn copy_non_dicom_files(each_file: &DirEntry, destination_path: &PathBuf) -> Result<()> {
-    let non_dicom_path: PathBuf =
-        PathBuf::from(format!("{}/NON_DICOM", &destination_path.to_string_lossy()));
-    if !non_dicom_path.exists() {
-        create_dir_all(&non_dicom_path)?;
-    }
-    let non_dicom_file_path = PathBuf::from(format!(
-        "{}/NON_DICOM/{}",
-        &destination_path.to_string_lossy(),
-        &each_file
-            .file_name()
-            .to_str()
-            .expect("Failed to extract filename")
-    ));
-    copy(each_file.clone().into_path(), non_dicom_file_path)?;
-    Ok(())
-}
+// Name of the CSV of the files over --max-file-size, written to the destination
+pub const OVERSIZE_CSV_NAME: &str = "oversize_skipped.csv";
 
-pub fn failed_case_copy(source_path: &PathBuf, dest_path: &PathBuf) -> Result<()> {
-    let failed_cases_path = format!("{}/FAILED_CASES", dest_path.display());
-    match canonicalize(failed_cases_path.clone()) {
-        Ok(_) => (),
-        Err(_) => create_dir_all(&failed_cases_path).unwrap_or_else(|_| {
-            error!("Can't create dir: {}", failed_cases_path);
-            exit(1)
-        }),
+// List the files over --max-file-size with their size
+pub fn write_oversize_csv(oversize: &[DirEntry], csv_path: &Path) -> Result<()> {
+    let mut oversize_csv = String::from("path,size\n");
+    for each in oversize {
+        let size = each.metadata().map(|m| m.len()).unwrap_or(0);
+        writeln!(
+            oversize_csv,
+            "{},{}",
+            csv_field(&each.path().display().to_string()),
+            size
+        )?;
     }
-    let failed_cases_full_name = format!(
-        "{}/{}",
-        failed_cases_path,
-        source_path
-            .file_name()
-            .expect("Failed to extract file name")
-            .to_str()
-            .expect("Failed to convert filename to str")
+    fs::write(csv_path, oversize_csv)?;
+    info!(
+        "Files over --max-file-size written to: {}",
+        csv_path.display()
     );
-
-    let final_failed_path = check_if_dup_exists(failed_cases_full_name);
-    fs::copy(source_path, final_failed_path)?;
     Ok(())
 }
-// Replace all non_alphanumeric characters with an underscore '_'
-pub fn replace_non_alphanumeric(input: &str) -> String {
-    let re = Regex::new(r"[^a-zA-Z0-9]+").expect("Failed to set up Regex");
-    let modified_chars: String = input
-        .chars()
-        .map(|c| if re.is_match(&c.to_string()) { '_' } else { c })
-        .collect();
-    re.replace_all(&modified_chars, "_").to_string()
+
+struct SourceIndex {
+    all_files: Vec<DirEntry>,
+    too_small: Vec<DirEntry>,
+    walk_errors: Vec<String>,
 }
 
-// For a given list of tags. Get the sanitized values.
-// Removes all unnecessary characters and adds NoValue_ if value is not found for the tag
-pub fn get_sanitized_tag_values(
-    dcm_obj: &FileDicomObject<InMemDicomObject>,
-) -> Result<HashMap<String, String>> {
-    let mut dicom_tags_values = HashMap::new();
-    for each_tag in DICOM_TAGS_SANITIZED {
-        match dcm_obj.element_by_name(each_tag) {
-            Ok(tv) => {
-                dicom_tags_values.insert(
-                    each_tag.to_string(),
-                    tv.to_str()?.to_string().replace(&['-', ':'][..], ""),
-                );
+// A single file source, indexed without the walk and its spinner
+fn index_single_file(source_path: &PathBuf) -> Result<SourceIndex> {
+    let entry = WalkDir::new(source_path)
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::Error::msg(format!("Can't read {}", source_path.display())))??;
+    let size = fs::metadata(entry.path())?.len();
+    let (all_files, too_small) = match size < MIN_DICOM_FILE_SIZE {
+        true => (Vec::new(), vec![entry]),
+        false => (vec![entry], Vec::new()),
+    };
+    Ok(SourceIndex {
+        all_files,
+        too_small,
+        walk_errors: Vec::new(),
+    })
+}
+
+// Walk the source tree with a running count
+// Returns the files, the files too small to be DICOM
+// and the entries that could not be read eg permission denied or broken symlinks
+fn index_source_files(source_path: &PathBuf) -> Result<SourceIndex> {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::with_template(
+        "{spinner:.green} [{elapsed_precise}] Indexing {pos} files {msg}",
+    )?);
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let mut all_files = Vec::new();
+    let mut too_small = Vec::new();
+    let mut walk_errors = Vec::new();
+    for entry in WalkDir::new(source_path) {
+        match entry {
+            Ok(entry) if entry.file_type().is_file() => {
+                match fs::metadata(entry.path()) {
+                    Ok(metadata) if metadata.len() < MIN_DICOM_FILE_SIZE => too_small.push(entry),
+                    _ => all_files.push(entry),
+                }
+                spinner.inc(1);
             }
-            Err(_) => {
-                warn!("No value for {}", each_tag);
-                let final_value = format!("NoValue_{}", each_tag);
-                dicom_tags_values.insert(each_tag.to_string(), final_value);
+            Ok(_) => (),
+            Err(e) => {
+                walk_errors.push(match e.path() {
+                    Some(path) => format!("{}: {}", path.display(), e),
+                    None => e.to_string(),
+                });
+                spinner.set_message(format!("| {} unreadable", walk_errors.len()));
             }
         }
     }
-    dicom_tags_values.insert("ImagePlane".to_string(), determine_plane(&dcm_obj)?);
-    Ok(dicom_tags_values)
+    spinner.finish_and_clear();
+    Ok(SourceIndex {
+        all_files,
+        too_small,
+        walk_errors,
+    })
 }
 
-// Check if the target directory exists and create a new one recursively if it does not exist
-pub fn create_target_dir(dir_path: &String) -> Result<()> {
-    if !PathBuf::from(dir_path).exists() {
-        create_dir_all(PathBuf::from(dir_path))?
-    }
-    Ok(())
+// DICOMDIR at the root of the source, media file names are often upper or lower case only
+fn find_dicomdir(source_path: &PathBuf) -> Option<PathBuf> {
+    fs::read_dir(source_path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| {
+            entry.file_name().eq_ignore_ascii_case("DICOMDIR")
+                && entry.file_type().map(|t| t.is_file()).unwrap_or(false)
+        })
+        .map(|entry| entry.path())
 }
 
-// Check if a file already exist and add ~ to end of the file if it does recursively.
-pub fn check_if_dup_exists(full_path: String) -> String {
-    let new_path = full_path;
-    if PathBuf::from(&new_path).exists() {
-        let change_path = format!("{}~", new_path.clone());
-        check_if_dup_exists(change_path)
-    } else {
-        return new_path;
-    }
+// PatientID and StudyInstanceUID of the DICOMDIR records above a referenced file
+#[derive(Debug, Clone, Default)]
+pub struct DicomdirRecord {
+    pub patient_id: String,
+    pub study_instance_uid: String,
 }
 
-// Change certain tags to the given ID and add deidentified tags.
-// Returns a cloned dicom object with modified values
-pub fn mask_tags_with_id(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    patient_deid: String,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    let p_value = dicom_vr_corrected_value(VR::PN, &patient_deid)?;
-    // Mask all PN values with the given ID
-    dcm_obj = mask_all_vr(dcm_obj.clone(), VR::PN, p_value.clone())?;
-
-    for each_v in DICOM_TAGS_CHANGE {
-        let p_value = dicom_vr_corrected_value(each_v.1, &patient_deid)?;
-        dcm_obj.put(DataElement::new(each_v.0, each_v.1, p_value.clone()));
-    }
-    // Add deidentified Info
-    dcm_obj.put(DataElement::new(
-        tags::DEIDENTIFICATION_METHOD,
-        VR::LO,
-        p_value.clone(),
-    ));
-    dcm_obj.put(DataElement::new(
-        tags::PATIENT_IDENTITY_REMOVED,
-        VR::CS,
-        p_value,
-    ));
-    Ok(dcm_obj)
+// Records of the files referenced by the DICOMDIR, set once when the source is indexed
+#[derive(Debug, Clone, Default)]
+pub struct DicomdirRecords {
+    records: Arc<OnceLock<HashMap<PathBuf, DicomdirRecord>>>,
 }
 
-pub fn tags_to_mask(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    patient_deid: String,
-    mask_config_list: Vec<DataDictionaryEntryRef<'static>>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_tag in mask_config_list {
-        let each_tag_tag = each_tag.tag.inner();
-        let each_tag_vr: VR = each_tag.vr.relaxed();
-        let value = dicom_vr_corrected_value(each_tag_vr, &patient_deid)?;
-        match dcm_obj.put(DataElement::new(each_tag_tag, each_tag_vr, value.clone())) {
-            Some(_) => (),
-            None => error!("Mask Tag : Failed to mask tag {:?}", each_tag_tag),
-        }
-
-        fn mask_sq_vrs(
-            data_element: &DataElement<InMemDicomObject>,
-            // dcm_obj: &mut FileDicomObject<InMemDicomObject>,
-            value: PrimitiveValue,
-            tag_to_check: Tag,
-        ) {
-            for each_sq_element in data_element.items().into_iter() {
-                for each_element in each_sq_element.into_iter() {
-                    for sq_inner_element in each_element.to_owned() {
-                        if sq_inner_element.vr() == VR::SQ {
-                            mask_sq_vrs(
-                                &sq_inner_element,
-                                // mut dcm_obj,
-                                value.clone(),
-                                tag_to_check,
-                            );
-                        }
-                        if sq_inner_element.tag() == tag_to_check {
-                            // sq_inner_element.update_value(|e| {
-                            //     e.primitive_mut().unwrap().truncate(0);
-                            // });
-                            // TODO
+// Index only the files referenced by the DICOMDIR
+// The records are read in the order of the DirectoryRecordSequence, where each image level
+// record follows its PATIENT and STUDY records as written by media creators
+// Referenced files that don't exist are counted as walk errors
+fn index_dicomdir_files(
+    dicomdir_path: &PathBuf,
+    dicomdir_records: &DicomdirRecords,
+) -> Result<SourceIndex> {
+    info!(
+        "Indexing the files referenced by: {}",
+        dicomdir_path.display()
+    );
+    let dicomdir = dicom::object::open_file(dicomdir_path)?;
+    let media_root = dicomdir_path
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    let record_text = |record: &InMemDicomObject, tag: Tag| -> String {
+        record
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|v| trim_uid(&v).to_string())
+            .unwrap_or_default()
+    };
+    let mut all_files = Vec::new();
+    let mut too_small = Vec::new();
+    let mut walk_errors = Vec::new();
+    let mut records: HashMap<PathBuf, DicomdirRecord> = HashMap::new();
+    let mut current = DicomdirRecord::default();
+    let directory_records = dicomdir
+        .element(tags::DIRECTORY_RECORD_SEQUENCE)?
+        .items()
+        .ok_or_else(|| anyhow::Error::msg("DICOMDIR has no directory records"))?;
+    for record in directory_records {
+        match record_text(record, tags::DIRECTORY_RECORD_TYPE).as_str() {
+            "PATIENT" => {
+                current = DicomdirRecord {
+                    patient_id: record_text(record, tags::PATIENT_ID),
+                    study_instance_uid: String::new(),
+                }
+            }
+            "STUDY" => current.study_instance_uid = record_text(record, tags::STUDY_INSTANCE_UID),
+            _ => {
+                // Only records that reference a file
+                let Some(file_id) = record
+                    .element(tags::REFERENCED_FILE_ID)
+                    .ok()
+                    .and_then(|e| e.to_multi_str().ok())
+                    .map(|c| {
+                        c.iter()
+                            .map(|c| trim_uid(c).to_string())
+                            .collect::<Vec<_>>()
+                    })
+                else {
+                    continue;
+                };
+                let entry = resolve_file_id(&media_root, &file_id)
+                    .and_then(|file_path| WalkDir::new(file_path).into_iter().next())
+                    .and_then(|entry| entry.ok());
+                match entry {
+                    Some(entry) if !records.contains_key(entry.path()) => {
+                        records.insert(entry.path().to_path_buf(), current.clone());
+                        match fs::metadata(entry.path()) {
+                            Ok(metadata) if metadata.len() < MIN_DICOM_FILE_SIZE => {
+                                too_small.push(entry)
+                            }
+                            _ => all_files.push(entry),
                         }
                     }
+                    Some(_) => (),
+                    None => walk_errors.push(format!(
+                        "{}: referenced by the DICOMDIR but not found",
+                        file_id.join("\\")
+                    )),
                 }
             }
         }
-
-        for data_element in &dcm_obj {
-            if data_element.vr() == VR::SQ {
-                mask_sq_vrs(data_element, value.clone(), each_tag_tag);
-            }
-        }
     }
+    info!("Files referenced by the DICOMDIR: {}", records.len());
+    let _ = dicomdir_records.records.set(records);
+    Ok(SourceIndex {
+        all_files,
+        too_small,
+        walk_errors,
+    })
+}
 
-    Ok(dcm_obj)
+// Path of a ReferencedFileID, the components are matched case insensitively
+// as the media may be mounted with lower case names
+fn resolve_file_id(media_root: &Path, file_id: &[String]) -> Option<PathBuf> {
+    let exact_path = file_id
+        .iter()
+        .fold(media_root.to_path_buf(), |path, component| {
+            path.join(component)
+        });
+    if exact_path.is_file() {
+        return Some(exact_path);
+    }
+    let mut file_path = media_root.to_path_buf();
+    for component in file_id {
+        file_path = fs::read_dir(&file_path)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().eq_ignore_ascii_case(component))?
+            .path();
+    }
+    file_path.is_file().then_some(file_path)
 }
 
-pub fn tags_to_add(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    add_config_list: HashMap<String, String>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_element in add_config_list {
-        let config_tag = each_element.0;
-        let config_value = each_element.1;
-        let (each_tag, each_vr) = extract_tag_vr_from_str(&config_tag)?;
-        let value = dicom_vr_corrected_value(each_vr, &config_value)?;
-        dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+// Warn when the PatientID or StudyInstanceUID of a file differ from its DICOMDIR records
+pub fn check_dicomdir_record(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    each_file: &DirEntry,
+    run_options: &RunOptions,
+) {
+    let Some(record) = run_options
+        .dicomdir_records
+        .records
+        .get()
+        .and_then(|records| records.get(each_file.path()))
+    else {
+        return;
+    };
+    let file_value = |tag: Tag| -> String {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|v| trim_uid(&v).to_string())
+            .unwrap_or_default()
+    };
+    let patient_id = file_value(tags::PATIENT_ID);
+    if !record.patient_id.is_empty() && patient_id != record.patient_id {
+        warn!(
+            "PatientID {} of {} does not match the DICOMDIR patient record {}",
+            phi(&patient_id),
+            phi(each_file.path().display()),
+            phi(&record.patient_id)
+        );
+    }
+    let study_instance_uid = file_value(tags::STUDY_INSTANCE_UID);
+    if !record.study_instance_uid.is_empty() && study_instance_uid != record.study_instance_uid {
+        warn!(
+            "StudyInstanceUID {} of {} does not match the DICOMDIR study record {}",
+            study_instance_uid,
+            phi(each_file.path().display()),
+            record.study_instance_uid
+        );
     }
-    Ok(dcm_obj)
 }
 
-pub fn tags_to_delete(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    delete_config_list: Vec<DataDictionaryEntryRef<'static>>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    for each_tag in delete_config_list {
-        match dcm_obj.remove_element(each_tag.tag.inner()) {
-            true => (),
-            false => debug!("Delete Tag: {:?} not valid/found", each_tag.tag.inner()),
+// Media storage SOP class of a DICOMDIR
+const MEDIA_STORAGE_DIRECTORY_STORAGE: &str = "1.2.840.10008.1.3.10";
+
+// ISO 9660 safe names for --media-compatible, 8 character upper case components
+// eg DICOM/P0000001/S0000001/SE000001/I0000001 numbered in the order the files are written
+#[derive(Debug, Clone, Default)]
+pub struct MediaNames {
+    names: Arc<Mutex<MediaNameState>>,
+}
+
+#[derive(Debug, Default)]
+struct MediaNameState {
+    // Component of each key under its parent directory
+    assigned: HashMap<(String, String), String>,
+    // Components given out so far in each directory
+    used: HashMap<String, u32>,
+}
+
+impl MediaNameState {
+    fn component(&mut self, parent: &str, key: Option<&str>, prefix: &str) -> String {
+        if let Some(component) =
+            key.and_then(|key| self.assigned.get(&(parent.to_string(), key.to_string())))
+        {
+            return component.clone();
         }
+        let used = self.used.entry(parent.to_string()).or_insert(0);
+        *used += 1;
+        let component = format!("{}{:0>width$}", prefix, used, width = 8 - prefix.len());
+        if let Some(key) = key {
+            self.assigned
+                .insert((parent.to_string(), key.to_string()), component.clone());
+        }
+        component
     }
-    Ok(dcm_obj)
 }
 
-pub fn delete_private_tags(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    fn is_private(tag: Tag) -> bool {
-        return tag.group() % 2 == 1;
+impl MediaNames {
+    // Sanitized tags the names are assigned on
+    pub const TAGS: [&'static str; 3] = ["PatientID", "StudyInstanceUID", "SeriesInstanceUID"];
+
+    // Directory and file name of a file, a directory per patient, study and series
+    pub fn assign(
+        &self,
+        dicom_tags_values: &HashMap<String, String>,
+        destination_path: &Path,
+    ) -> (String, String) {
+        let tag_value = |name: &str| dicom_tags_values.get(name).cloned().unwrap_or_default();
+        let mut names = self.names.lock().expect("Failed to lock mutex");
+        let patient = names.component("", Some(&tag_value("PatientID")), "P");
+        let study = names.component(&patient, Some(&tag_value("StudyInstanceUID")), "S");
+        let study_dir = format!("{}/{}", patient, study);
+        let series = names.component(&study_dir, Some(&tag_value("SeriesInstanceUID")), "SE");
+        let series_dir = format!("{}/{}", study_dir, series);
+        let image = names.component(&series_dir, None, "I");
+        (
+            format!("{}/DICOM/{}", destination_path.display(), series_dir),
+            image,
+        )
     }
+}
 
-    let mut private_tags: Vec<Tag> = vec![];
-    for each_element in dcm_obj.clone() {
-        collect_tags(each_element, &mut private_tags);
+// Images of a series as the padded InstanceNumber and the written path
+type SeriesImages<'a> = Vec<(String, &'a PathBuf)>;
+
+// Series per study per patient of the DICOMDIR, keyed on PatientID and the UIDs
+type DicomdirHierarchy<'a> =
+    BTreeMap<&'a str, BTreeMap<&'a str, BTreeMap<&'a str, SeriesImages<'a>>>>;
+
+// Files written by a run for --write-dicomdir, collected from the written outcomes
+#[derive(Debug, Clone, Default)]
+pub struct DicomdirBuilder {
+    files: Arc<Mutex<Vec<DicomdirFile>>>,
+}
+
+#[derive(Debug, Clone)]
+struct DicomdirFile {
+    patient_id: String,
+    study_instance_uid: String,
+    series_instance_uid: String,
+    instance_number: String,
+    file_path: PathBuf,
+}
+
+// Next record on the same level and first record of the level below, as record indices
+#[derive(Debug, Default)]
+struct RecordLinks {
+    next: Option<usize>,
+    lower: Option<usize>,
+}
+
+// Attributes of each record type copied from the first file below the record
+static PATIENT_RECORD_KEYS: [(Tag, VR); 3] = [
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS),
+    (tags::PATIENT_NAME, VR::PN),
+    (tags::PATIENT_ID, VR::LO),
+];
+static STUDY_RECORD_KEYS: [(Tag, VR); 7] = [
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS),
+    (tags::STUDY_DATE, VR::DA),
+    (tags::STUDY_TIME, VR::TM),
+    (tags::STUDY_DESCRIPTION, VR::LO),
+    (tags::STUDY_INSTANCE_UID, VR::UI),
+    (tags::STUDY_ID, VR::SH),
+    (tags::ACCESSION_NUMBER, VR::SH),
+];
+static SERIES_RECORD_KEYS: [(Tag, VR); 4] = [
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS),
+    (tags::MODALITY, VR::CS),
+    (tags::SERIES_INSTANCE_UID, VR::UI),
+    (tags::SERIES_NUMBER, VR::IS),
+];
+static IMAGE_RECORD_KEYS: [(Tag, VR); 2] = [
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS),
+    (tags::INSTANCE_NUMBER, VR::IS),
+];
+
+impl DicomdirBuilder {
+    // Sanitized tags of the written outcomes the records are ordered on
+    pub const TAGS: [&'static str; 4] = [
+        "PatientID",
+        "StudyInstanceUID",
+        "SeriesInstanceUID",
+        "InstanceNumber",
+    ];
+
+    // Outcome callback that keeps the written files and their sanitized tag values
+    pub fn callback(&self) -> OutcomeCallback {
+        let files = Arc::clone(&self.files);
+        Arc::new(move |outcome: &FileOutcome| {
+            let (FileStatus::Written, Some(file_path)) =
+                (&outcome.status, &outcome.destination_path)
+            else {
+                return;
+            };
+            let tag_value = |name: &str| outcome.tag_values.get(name).cloned().unwrap_or_default();
+            files
+                .lock()
+                .expect("Failed to lock mutex")
+                .push(DicomdirFile {
+                    patient_id: tag_value("PatientID"),
+                    study_instance_uid: tag_value("StudyInstanceUID"),
+                    series_instance_uid: tag_value("SeriesInstanceUID"),
+                    instance_number: tag_value("InstanceNumber"),
+                    file_path: file_path.clone(),
+                });
+        })
     }
 
-    fn collect_tags(data_element: DataElement<InMemDicomObject>, private_tags: &mut Vec<Tag>) {
-        let tag = data_element.tag();
-        if is_private(tag) {
-            private_tags.push(tag);
-        };
+    // Write <destination>/DICOMDIR with PATIENT, STUDY, SERIES and IMAGE records
+    // The records are ordered on PatientID, StudyInstanceUID, SeriesInstanceUID and InstanceNumber
+    // and take their attributes from the written files
+    pub fn write(&self, destination_path: &PathBuf, run_options: &RunOptions) -> Result<()> {
+        let files = self.files.lock().expect("Failed to lock mutex");
+        let mut hierarchy: DicomdirHierarchy = BTreeMap::new();
+        // Files of other --route destinations are not on this media
+        for file in files
+            .iter()
+            .filter(|file| file.file_path.starts_with(destination_path))
+        {
+            hierarchy
+                .entry(&file.patient_id)
+                .or_default()
+                .entry(&file.study_instance_uid)
+                .or_default()
+                .entry(&file.series_instance_uid)
+                .or_default()
+                .push((pad_number(&file.instance_number, 12), &file.file_path));
+        }
 
-        if data_element.vr() == VR::SQ {
-            for each_sq_element in data_element.items().into_iter() {
-                for each_element in each_sq_element.into_iter() {
-                    for each_tag in each_element {
-                        collect_tags(each_tag.to_owned(), private_tags)
+        let mut records: Vec<InMemDicomObject> = Vec::new();
+        let mut links: Vec<RecordLinks> = Vec::new();
+        let read_header = |file_path: &PathBuf| -> Result<FileDicomObject<InMemDicomObject>> {
+            dicom::object::OpenFileOptions::new()
+                .read_until(tags::PIXEL_DATA)
+                .open_file(file_path)
+                .map_err(|e| {
+                    anyhow::Error::msg(format!(
+                        "Can't read {} for the DICOMDIR: {}",
+                        phi(file_path.display()),
+                        e
+                    ))
+                })
+        };
+        let mut invalid_file_ids = 0;
+        let mut patient_list = Vec::new();
+        for studies in hierarchy.values() {
+            let mut study_list = Vec::new();
+            for series_map in studies.values() {
+                let mut series_list = Vec::new();
+                for images in series_map.values() {
+                    let mut images = images.clone();
+                    images.sort();
+                    let mut image_list = Vec::new();
+                    for (_, file_path) in images {
+                        let header = read_header(file_path)?;
+                        // The first image of a patient, study or series adds their records
+                        if series_list.is_empty() && image_list.is_empty() {
+                            if study_list.is_empty() {
+                                patient_list.push(push_record(
+                                    &mut records,
+                                    &mut links,
+                                    dicomdir_record("PATIENT", &PATIENT_RECORD_KEYS, &header),
+                                ));
+                            }
+                            study_list.push(push_record(
+                                &mut records,
+                                &mut links,
+                                dicomdir_record("STUDY", &STUDY_RECORD_KEYS, &header),
+                            ));
+                        }
+                        if image_list.is_empty() {
+                            series_list.push(push_record(
+                                &mut records,
+                                &mut links,
+                                dicomdir_record("SERIES", &SERIES_RECORD_KEYS, &header),
+                            ));
+                        }
+                        let image_index = push_record(
+                            &mut records,
+                            &mut links,
+                            dicomdir_record("IMAGE", &IMAGE_RECORD_KEYS, &header),
+                        );
+                        let file_id: Vec<String> = file_path
+                            .strip_prefix(destination_path)
+                            .unwrap_or(file_path)
+                            .components()
+                            .map(|c| c.as_os_str().to_string_lossy().to_string())
+                            .collect();
+                        if !file_id.iter().all(|c| is_media_file_id_component(c)) {
+                            invalid_file_ids += 1;
+                        }
+                        let meta = header.meta();
+                        for (tag, vr, value) in [
+                            (
+                                tags::REFERENCED_FILE_ID,
+                                VR::CS,
+                                PrimitiveValue::Strs(file_id.into()),
+                            ),
+                            (
+                                tags::REFERENCED_SOP_CLASS_UID_IN_FILE,
+                                VR::UI,
+                                dicom_value!(Str, trim_uid(meta.media_storage_sop_class_uid())),
+                            ),
+                            (
+                                tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE,
+                                VR::UI,
+                                dicom_value!(Str, trim_uid(meta.media_storage_sop_instance_uid())),
+                            ),
+                            (
+                                tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE,
+                                VR::UI,
+                                dicom_value!(Str, trim_uid(meta.transfer_syntax())),
+                            ),
+                        ] {
+                            records[image_index].put(DataElement::new(tag, vr, value));
+                        }
+                        image_list.push(image_index);
                     }
+                    link_records(&mut links, series_list.last().copied(), &image_list);
                 }
+                link_records(&mut links, study_list.last().copied(), &series_list);
             }
+            link_records(&mut links, patient_list.last().copied(), &study_list);
+        }
+        link_records(&mut links, None, &patient_list);
+        if invalid_file_ids > 0 {
+            warn!(
+                "{} files of the DICOMDIR have paths that are not valid on media, use --media-compatible",
+                invalid_file_ids
+            );
         }
-    }
 
-    for each in private_tags {
-        dcm_obj.remove_element(each);
+        // The offsets are only known once encoded, the offsets have a fixed size
+        // so the second encoding with the real offsets has the same layout
+        let placeholder = encode_dicomdir(&records, 0, 0)?;
+        let offsets = dicomdir_record_offsets(&placeholder, records.len())?;
+        for (record, record_links) in records.iter_mut().zip(&links) {
+            for (tag, link) in [
+                (tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD, record_links.next),
+                (
+                    tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY,
+                    record_links.lower,
+                ),
+            ] {
+                let offset = link.map(|i| offsets[i]).unwrap_or(0);
+                record.put(DataElement::new(tag, VR::UL, dicom_value!(U32, [offset])));
+            }
+        }
+        let first_patient = patient_list.first().map(|i| offsets[*i]).unwrap_or(0);
+        let last_patient = patient_list.last().map(|i| offsets[*i]).unwrap_or(0);
+        let dicomdir = encode_dicomdir(&records, first_patient, last_patient)?;
+        if dicomdir.len() != placeholder.len() {
+            return Err(anyhow::Error::msg(
+                "DICOMDIR layout changed when setting the offsets",
+            ));
+        }
+        let dicomdir_path = destination_path.join("DICOMDIR");
+        retry_io(
+            &run_options.io_retry,
+            &dicomdir_path.display().to_string(),
+            || fs::write(&dicomdir_path, &dicomdir),
+        )?;
+        info!(
+            "DICOMDIR with {} patients and {} files written to: {}",
+            patient_list.len(),
+            files.len(),
+            dicomdir_path.display()
+        );
+        Ok(())
     }
+}
 
-    dcm_obj.remove_element(ORIGINAL_ATTRIBUTES_SEQUENCE);
-
-    Ok(dcm_obj)
+// Directory record with zero offsets and the keys of the given file, missing keys are empty
+fn dicomdir_record(
+    record_type: &str,
+    keys: &[(Tag, VR)],
+    header: &InMemDicomObject,
+) -> InMemDicomObject {
+    let mut record = InMemDicomObject::from_element_iter([
+        DataElement::new(
+            tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD,
+            VR::UL,
+            dicom_value!(U32, [0]),
+        ),
+        DataElement::new(
+            tags::RECORD_IN_USE_FLAG,
+            VR::US,
+            dicom_value!(U16, [0xFFFF]),
+        ),
+        DataElement::new(
+            tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY,
+            VR::UL,
+            dicom_value!(U32, [0]),
+        ),
+        DataElement::new(
+            tags::DIRECTORY_RECORD_TYPE,
+            VR::CS,
+            dicom_value!(Str, record_type),
+        ),
+    ]);
+    for (tag, vr) in keys {
+        match header.element(*tag) {
+            Ok(element) => record.put(element.clone()),
+            // SpecificCharacterSet is only needed when the file has one
+            Err(_) if *tag == tags::SPECIFIC_CHARACTER_SET => None,
+            Err(_) => record.put(DataElement::new(*tag, *vr, PrimitiveValue::Empty)),
+        };
+    }
+    record
 }
 
-pub fn anon_dicom_uids(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    let uid_tag_list = [
-        "SOPInstanceUID".to_string(),
-        "StudyInstanceUID".to_string(),
-        "SeriesInstanceUID".to_string(),
-        "FrameOfReferenceUID".to_string(),
-    ];
-    let anon_uid_prefix: Vec<_> = "1.2.999.999999.9999.9.9.9.9999".split(".").collect();
+fn push_record(
+    records: &mut Vec<InMemDicomObject>,
+    links: &mut Vec<RecordLinks>,
+    record: InMemDicomObject,
+) -> usize {
+    records.push(record);
+    links.push(RecordLinks::default());
+    records.len() - 1
+}
 
-    for each_uid in uid_tag_list {
-        let (each_tag, each_vr) = extract_tag_vr_from_str(&each_uid)?;
-        let org_uid_val = dcm_obj.element(each_tag)?.to_str()?;
-        let org_uid_vec: Vec<_> = org_uid_val.split(".").collect();
-        let mut new_uid_parts = anon_uid_prefix.clone();
-        new_uid_parts.extend_from_slice(&org_uid_vec[8..]);
-        let new_uid_val = new_uid_parts.join(".");
-        let value = dicom_vr_corrected_value(each_vr, &new_uid_val)?;
-        dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+// Point the parent record to the first child and every child to the one after it
+fn link_records(links: &mut [RecordLinks], parent: Option<usize>, children: &[usize]) {
+    if let Some(parent) = parent {
+        links[parent].lower = children.first().copied();
+    }
+    for pair in children.windows(2) {
+        links[pair[0]].next = Some(pair[1]);
     }
-    Ok(dcm_obj)
 }
 
+// File ID components on media are 1 to 8 upper case letters, digits or underscores
+fn is_media_file_id_component(component: &str) -> bool {
+    !component.is_empty()
+        && component.len() <= 8
+        && component
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+// Encode the DICOMDIR as Explicit VR Little Endian, the SOP instance UID follows from the records
+fn encode_dicomdir(
+    records: &[InMemDicomObject],
+    first_record_offset: u32,
+    last_record_offset: u32,
+) -> Result<Vec<u8>> {
+    let mut record_hash = Sha256::new();
+    for record in records {
+        if let Ok(sop_instance_uid) = record.element(tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE) {
+            record_hash.update(sop_instance_uid.to_str()?.as_bytes());
+        }
+    }
+    let digest = record_hash.finalize();
+    let instance_uid = format!(
+        "2.25.{}",
+        u128::from_be_bytes(digest[..16].try_into().expect("Digest is 32 bytes"))
+    );
+    let dataset = InMemDicomObject::from_element_iter([
+        DataElement::new(tags::FILE_SET_ID, VR::CS, dicom_value!(Str, "DCMRIG")),
+        DataElement::new(
+            tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+            VR::UL,
+            dicom_value!(U32, [first_record_offset]),
+        ),
+        DataElement::new(
+            tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+            VR::UL,
+            dicom_value!(U32, [last_record_offset]),
+        ),
+        DataElement::new(
+            tags::FILE_SET_CONSISTENCY_FLAG,
+            VR::US,
+            dicom_value!(U16, [0]),
+        ),
+        DataElement::new(
+            tags::DIRECTORY_RECORD_SEQUENCE,
+            VR::SQ,
+            Value::Sequence(DataSetSequence::from(records.to_vec())),
+        ),
+    ]);
+    let dicomdir = dataset.with_meta(
+        FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(MEDIA_STORAGE_DIRECTORY_STORAGE)
+            .media_storage_sop_instance_uid(instance_uid)
+            .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN.uid()),
+    )?;
+    let mut dicomdir_buffer = Vec::new();
+    dicomdir.write_all(&mut dicomdir_buffer)?;
+    Ok(dicomdir_buffer)
+}
+
+// Tag, header length and value length of the element at pos of an Explicit VR Little Endian dataset
+fn explicit_vr_le_header(bytes: &[u8], pos: usize) -> Option<((u16, u16), usize, u32)> {
+    let u16_at = |p: usize| Some(u16::from_le_bytes(bytes.get(p..p + 2)?.try_into().ok()?));
+    let u32_at = |p: usize| Some(u32::from_le_bytes(bytes.get(p..p + 4)?.try_into().ok()?));
+    let tag = (u16_at(pos)?, u16_at(pos + 2)?);
+    // Items and delimiters have no VR
+    if tag.0 == 0xFFFE {
+        return Some((tag, 8, u32_at(pos + 4)?));
+    }
+    match bytes.get(pos + 4..pos + 6)? {
+        b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN" | b"UR"
+        | b"UT" | b"UV" => Some((tag, 12, u32_at(pos + 8)?)),
+        _ => Some((tag, 8, u16_at(pos + 6)? as u32)),
+    }
+}
+
+// Byte offsets of the DirectoryRecordSequence items from the start of the encoded file
+// The records hold no sequences, so an item of undefined length ends at the first delimiter
+fn dicomdir_record_offsets(bytes: &[u8], record_count: usize) -> Result<Vec<u32>> {
+    let layout_error = || anyhow::Error::msg("Unexpected DICOMDIR layout");
+    // After the preamble and DICM, the file meta group is part of the walk
+    let mut pos = 132;
+    loop {
+        let (tag, header_len, value_len) =
+            explicit_vr_le_header(bytes, pos).ok_or_else(layout_error)?;
+        pos += header_len;
+        if tag == (0x0004, 0x1220) {
+            break;
+        }
+        if value_len == u32::MAX {
+            return Err(layout_error());
+        }
+        pos += value_len as usize;
+    }
+    let mut offsets = Vec::with_capacity(record_count);
+    while offsets.len() < record_count {
+        let (tag, _, item_len) = explicit_vr_le_header(bytes, pos).ok_or_else(layout_error)?;
+        if tag != (0xFFFE, 0xE000) {
+            return Err(layout_error());
+        }
+        offsets.push(pos as u32);
+        pos += 8;
+        if item_len != u32::MAX {
+            pos += item_len as usize;
+            continue;
+        }
+        loop {
+            let (tag, header_len, value_len) =
+                explicit_vr_le_header(bytes, pos).ok_or_else(layout_error)?;
+            pos += header_len;
+            if tag == (0xFFFE, 0xE00D) {
+                break;
+            }
+            pos += value_len as usize;
+        }
+    }
+    Ok(offsets)
+}
+
+// Directories for the files that are not processed, created once at the start of a run
+#[derive(Debug, Clone)]
+pub struct RunDirs {
+    pub non_dicom: PathBuf,
+    pub failed_cases: PathBuf,
+    // Only set with --keep-excluded
+    pub excluded: Option<PathBuf>,
+    // Source root the FAILED_CASES copies are named after
+    pub source_root: PathBuf,
+    // failed_index.csv of FAILED_CASES, a row is appended per copy
+    failed_index: Option<Arc<Mutex<fs::File>>>,
+    // A single file source, nothing is copied to NON_DICOM, FAILED_CASES or EXCLUDED_SOP
+    pub single_file: bool,
+}
+
+impl RunDirs {
+    pub fn create(
+        source_path: &Path,
+        destination_path: &Path,
+        run_options: &RunOptions,
+    ) -> Result<Self> {
+        // Nest each directory under a run timestamp so reruns don't mix
+        let run_dir = |name: &str| -> PathBuf {
+            let dir_path = destination_path.join(name);
+            match run_options.run_subdir {
+                true => dir_path.join(&run_options.run_id),
+                false => dir_path,
+            }
+        };
+        let non_dicom = run_dir("NON_DICOM");
+        let failed_cases = run_dir("FAILED_CASES");
+        let single_file = source_path.is_file();
+        if single_file {
+            return Ok(RunDirs {
+                non_dicom,
+                failed_cases,
+                excluded: None,
+                source_root: source_path.parent().unwrap_or(source_path).to_path_buf(),
+                failed_index: None,
+                single_file,
+            });
+        }
+        let excluded = match run_options.keep_excluded {
+            true => Some(run_dir("EXCLUDED_SOP")),
+            false => None,
+        };
+        for dir_path in [&non_dicom, &failed_cases]
+            .into_iter()
+            .chain(excluded.as_ref())
+        {
+            create_dir_all(dir_path).map_err(|e| {
+                anyhow::Error::msg(format!(
+                    "Can't create dir: {}, check the permissions of the destination\n{}",
+                    dir_path.display(),
+                    e
+                ))
+            })?;
+        }
+        // A rerun into the same FAILED_CASES adds its rows to the index
+        let index_path = failed_cases.join("failed_index.csv");
+        let mut failed_index = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)
+            .map_err(|e| {
+                anyhow::Error::msg(format!("Can't create {}\n{}", index_path.display(), e))
+            })?;
+        if failed_index.metadata()?.len() == 0 {
+            std::io::Write::write_all(&mut failed_index, b"copied_name,source_path,error\n")?;
+        }
+        Ok(RunDirs {
+            non_dicom,
+            failed_cases,
+            excluded,
+            source_root: source_path.to_path_buf(),
+            failed_index: Some(Arc::new(Mutex::new(failed_index))),
+            single_file,
+        })
+    }
+
+    // Append the copy of a failed case to failed_index.csv with the absolute source path
+    fn index_failed_case(&self, copy_path: &std::path::Path, source_path: &PathBuf, error: &str) {
+        let Some(failed_index) = &self.failed_index else {
+            return;
+        };
+        let source_path = canonicalize(source_path).unwrap_or_else(|_| source_path.clone());
+        let row = format!(
+            "{},{},{}\n",
+            csv_field(&copy_path.file_name().unwrap_or_default().to_string_lossy()),
+            csv_field(&source_path.display().to_string()),
+            csv_field(error)
+        );
+        let mut failed_index = failed_index.lock().expect("Failed to lock mutex");
+        if let Err(e) = std::io::Write::write_all(&mut *failed_index, row.as_bytes()) {
+            warn!(
+                "Can't add {} to failed_index.csv: {}",
+                phi(copy_path.display()),
+                e
+            );
+        }
+    }
+}
+
+pub fn check_given_path_exists(src_path: &PathBuf, dest_path: &PathBuf) -> Result<()> {
+    // Source Path
+    match canonicalize(src_path) {
+        Ok(_) => (),
+        Err(e) => {
+            error!(
+                "Given source Path doesnot exist: {}\n{}",
+                src_path.display(),
+                e
+            );
+            exit(1)
+        }
+    }
+    // Destination Path
+    match canonicalize(dest_path) {
+        Ok(_) => (),
+        Err(_) => create_dir_all(dest_path).unwrap_or_else(|_| {
+            error!("Can't create dir: {}", dest_path.display());
+            exit(1)
+        }),
+    }
+    // A destination that can't be written would fail every file of the run
+    if let Err(e) = probe_writable(dest_path) {
+        let reason = match e.kind() {
+            std::io::ErrorKind::PermissionDenied => {
+                "the current user has no write permission on it".to_string()
+            }
+            std::io::ErrorKind::ReadOnlyFilesystem => "it is on a read-only filesystem".to_string(),
+            _ => e.to_string(),
+        };
+        error!(
+            "Destination is not writable: {}, {}",
+            dest_path.display(),
+            reason
+        );
+        exit(1)
+    }
+    Ok(())
+}
+
+// Create and remove a probe file in the directory
+fn probe_writable(dir_path: &Path) -> std::io::Result<()> {
+    let probe_path = dir_path.join(format!(".dcmrig_write_probe_{}", std::process::id()));
+    fs::write(&probe_path, b"")?;
+    fs::remove_file(&probe_path)
+}
+
+// For all non DICOM files, Copy them to the NON_DICOM directory of the run
+pub fn copy_non_dicom_files(
+    each_file: &DirEntry,
+    non_dicom_dir: &Path,
+    run_options: &RunOptions,
+) -> Result<PathBuf> {
+    copy_to_run_dir(
+        &each_file.path().to_path_buf(),
+        &each_file.file_name().to_string_lossy(),
+        non_dicom_dir,
+        run_options,
+    )
+}
+
+// For files excluded by SOP class, Copy them to the EXCLUDED_SOP directory of the run
+pub fn copy_excluded_files(
+    each_file: &DirEntry,
+    excluded_dir: &Path,
+    run_options: &RunOptions,
+) -> Result<PathBuf> {
+    copy_to_run_dir(
+        &each_file.path().to_path_buf(),
+        &each_file.file_name().to_string_lossy(),
+        excluded_dir,
+        run_options,
+    )
+}
+
+// Files of different series often share a name like IM0001, so the copy is named
+// <hash of the path under the source root>_<original name> eg 1a2b3c4d_IM0001
+pub fn failed_case_copy(
+    source_path: &PathBuf,
+    source_root: &PathBuf,
+    failed_cases_dir: &Path,
+    run_options: &RunOptions,
+) -> Result<PathBuf> {
+    let relative_path = source_path.strip_prefix(source_root).unwrap_or(source_path);
+    let file_name = format!(
+        "{}_{}",
+        short_hash(&relative_path.to_string_lossy()),
+        source_path
+            .file_name()
+            .expect("Failed to extract file name")
+            .to_string_lossy()
+    );
+    copy_to_run_dir(source_path, &file_name, failed_cases_dir, run_options)
+}
+
+// Copy a file that could not be processed to FAILED_CASES and record its outcome
+// Returns the path of the copy, None for a single file source which is not copied
+pub fn record_failed_case(
+    each_file: &DirEntry,
+    error: String,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) -> Option<PathBuf> {
+    let copy_path = match run_dirs.single_file {
+        true => None,
+        false => {
+            let copy_path = failed_case_copy(
+                &each_file.path().to_path_buf(),
+                &run_dirs.source_root,
+                &run_dirs.failed_cases,
+                run_options,
+            )
+            .expect("Failed to copy file to FAILED_CASES directory");
+            run_dirs.index_failed_case(&copy_path, &each_file.path().to_path_buf(), &error);
+            Some(copy_path)
+        }
+    };
+    outcomes.record(FileOutcome {
+        destination_path: copy_path.clone(),
+        error: Some(error),
+        ..FileOutcome::new(each_file.path(), FileStatus::Failed)
+    });
+    copy_path
+}
+
+// Write the error chain and the elements of the modified dataset next to the copy of a failed case
+// as <copy name>.tags.txt, the pixel data and other bulk values are only listed by their length
+// Returns the path of the dump
+pub fn write_failed_dump(
+    copy_path: &Path,
+    modified: Option<&InMemDicomObject>,
+    error: &anyhow::Error,
+) -> Result<PathBuf> {
+    let mut dump = String::new();
+    writeln!(dump, "Error:")?;
+    for (index, cause) in error.chain().enumerate() {
+        match index {
+            0 => writeln!(dump, "  {}", cause)?,
+            _ => writeln!(dump, "  caused by: {}", cause)?,
+        }
+    }
+    match modified {
+        Some(dcm_obj) => {
+            writeln!(dump, "\nModified dataset:")?;
+            visit_elements(dcm_obj, |element, depth| {
+                let value = match element.value() {
+                    Value::Sequence(sequence) => format!("{} items", sequence.items().len()),
+                    Value::PixelSequence(_) => "encapsulated pixel data".to_string(),
+                    Value::Primitive(value) => match element.vr() {
+                        VR::OB | VR::OW | VR::OF | VR::OD | VR::OL | VR::OV | VR::UN => {
+                            format!("{} bytes", value.calculate_byte_len())
+                        }
+                        _ => value.to_str().to_string(),
+                    },
+                };
+                let _ = writeln!(
+                    dump,
+                    "{}{} {} {} = {}",
+                    "  ".repeat(depth + 1),
+                    element.tag(),
+                    element.vr(),
+                    tag_keyword(element.tag()),
+                    value
+                );
+                true
+            });
+        }
+        None => writeln!(dump, "\nThe file failed before its tags were modified")?,
+    }
+    let mut dump_name = copy_path.file_name().unwrap_or_default().to_os_string();
+    dump_name.push(".tags.txt");
+    let dump_path = copy_path.with_file_name(dump_name);
+    fs::write(&dump_path, dump)?;
+    Ok(dump_path)
+}
+
+// Headers of a file that failed to read when only the PixelData and what follows it are
+// unreadable, eg from an exporter that cut the file short. None without --tolerate-truncated-pixeldata
+pub fn truncated_pixel_data_headers(
+    file_path: &std::path::Path,
+    run_options: &RunOptions,
+) -> Option<FileDicomObject<InMemDicomObject>> {
+    if !run_options.tolerate_truncated_pixeldata {
+        return None;
+    }
+    let headers = dicom::object::OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(file_path)
+        .ok()?;
+    warn!(
+        "The pixel data of {:?} is truncated, only the headers are read",
+        phi(file_path.file_name().unwrap_or_default().to_string_lossy())
+    );
+    Some(headers)
+}
+
+// Copy a file with truncated pixel data to FAILED_CASES, counted apart from the other failures
+pub fn record_truncated_pixel_data(
+    each_file: &DirEntry,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) {
+    let error = "Truncated pixel data".to_string();
+    let copy_path = match run_dirs.single_file {
+        true => None,
+        false => {
+            let copy_path = failed_case_copy(
+                &each_file.path().to_path_buf(),
+                &run_dirs.source_root,
+                &run_dirs.failed_cases,
+                run_options,
+            )
+            .expect("Failed to copy file to FAILED_CASES directory");
+            run_dirs.index_failed_case(&copy_path, &each_file.path().to_path_buf(), &error);
+            Some(copy_path)
+        }
+    };
+    outcomes.record(FileOutcome {
+        destination_path: copy_path,
+        error: Some(error),
+        ..FileOutcome::new(each_file.path(), FileStatus::TruncatedPixelData)
+    });
+}
+
+// Copy a non DICOM file to NON_DICOM and record its outcome
+pub fn record_non_dicom(
+    each_file: &DirEntry,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) {
+    let mut outcome = FileOutcome::new(each_file.path(), FileStatus::NonDicom);
+    if run_dirs.single_file {
+        outcomes.record(outcome);
+        return;
+    }
+    match copy_non_dicom_files(each_file, &run_dirs.non_dicom, run_options) {
+        Ok(copy_path) => outcome.destination_path = Some(copy_path),
+        Err(e) => {
+            error!(
+                "Can't copy non dicom file {:?}",
+                phi(each_file.file_name().to_string_lossy())
+            );
+            outcome.error = Some(e.to_string());
+        }
+    }
+    outcomes.record(outcome);
+}
+
+// Copy a file that failed to open to FAILED_CASES when its transfer syntax can't be read,
+// any other file that failed to open is copied to NON_DICOM
+pub fn record_unopened_file(
+    each_file: &DirEntry,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) {
+    match unreadable_transfer_syntax(each_file.path()) {
+        Some(transfer_syntax) => {
+            error!(
+                "Can't open {:?} with transfer syntax {} Copying to FAILED_CASES directory",
+                phi(each_file.file_name().to_string_lossy()),
+                transfer_syntax
+            );
+            record_failed_case(
+                each_file,
+                format!("Unsupported transfer syntax {}", transfer_syntax),
+                run_dirs,
+                run_options,
+                outcomes,
+            );
+        }
+        None => record_non_dicom(each_file, run_dirs, run_options, outcomes),
+    }
+}
+
+// Copy a file into one of the prepared run directories as file_name, keeping both files on a
+// name collision unless they are identical. Returns the path of the copy or the identical file
+fn copy_to_run_dir(
+    source_path: &PathBuf,
+    file_name: &str,
+    run_dir: &Path,
+    run_options: &RunOptions,
+) -> Result<PathBuf> {
+    let (bucket_dir, target_path) = match run_options.dir_buckets.place(
+        &run_dir.display().to_string(),
+        &fs_safe_name(file_name, &run_options.fs_limits),
+        None,
+        &|| file_content_hash(source_path),
+    ) {
+        Placement::New(bucket_dir, target_path) => (bucket_dir, target_path),
+        Placement::Duplicate(existing_path) => return Ok(existing_path),
+    };
+    run_options.ensured_dirs.ensure(&bucket_dir)?;
+    run_options.write_gate.run(|| {
+        retry_io(&run_options.io_retry, &target_path, || {
+            copy(source_path, &target_path)
+        })
+    })?;
+    Ok(PathBuf::from(target_path))
+}
+
+// Restrictions of the destination filesystem on the written names and file sizes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsLimits {
+    // Replace the characters FAT does not allow and trim trailing dots and spaces
+    pub fat_names: bool,
+    pub max_file_size: Option<u64>,
+}
+
+// FAT32 can't hold a file of 4 GiB or more
+static FAT32_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+
+// Probe the filesystem type of the destination from /proc/mounts
+// Returns no restrictions where the type can't be found
+pub fn probe_destination_fs(destination_path: &Path) -> FsLimits {
+    let fs_type = match destination_fs_type(destination_path) {
+        Some(fs_type) => fs_type,
+        None => {
+            debug!("Filesystem of {} not found", destination_path.display());
+            return FsLimits::default();
+        }
+    };
+    let fs_limits = match fs_type.as_str() {
+        "vfat" | "msdos" | "fat" => FsLimits {
+            fat_names: true,
+            max_file_size: Some(FAT32_MAX_FILE_SIZE),
+        },
+        "exfat" => FsLimits {
+            fat_names: true,
+            max_file_size: None,
+        },
+        _ => FsLimits::default(),
+    };
+    if fs_limits.fat_names {
+        warn!(
+            "The destination is on a {} filesystem, these restrictions are in effect:",
+            fs_type
+        );
+        warn!("- The characters \" * / : < > ? \\ | are replaced with _ in original file names");
+        warn!("- Trailing dots and spaces are removed from original file names");
+        if let Some(max_file_size) = fs_limits.max_file_size {
+            warn!(
+                "- Files over {} bytes are not processed and counted as too large",
+                max_file_size
+            );
+        }
+        warn!("Use --ignore-fs-limits to turn these off");
+    }
+    fs_limits
+}
+
+// Type of the longest mount point holding the path, the path may not exist yet
+fn destination_fs_type(destination_path: &Path) -> Option<String> {
+    let existing_path = destination_path
+        .ancestors()
+        .find_map(|each| canonicalize(each).ok())?;
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let mount_point = parts.nth(1)?.replace("\\040", " ");
+            let fs_type = parts.next()?.to_string();
+            Some((PathBuf::from(mount_point), fs_type))
+        })
+        .filter(|(mount_point, _)| existing_path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .map(|(_, fs_type)| fs_type)
+}
+
+// File name that the destination filesystem can hold
+pub fn fs_safe_name(name: &str, fs_limits: &FsLimits) -> String {
+    if !fs_limits.fat_names {
+        return name.to_string();
+    }
+    let safe_name: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let safe_name = safe_name.trim_end_matches(['.', ' ']);
+    match safe_name.is_empty() {
+        true => "_".to_string(),
+        false => safe_name.to_string(),
+    }
+}
+
+// Retry policy for copies and writes to the destination
+#[derive(Debug, Clone, Serialize)]
+pub struct IoRetryPolicy {
+    // Total attempts including the first one
+    pub attempts: u32,
+    // Delay before the first retry, doubled for every further retry
+    pub delay_ms: u64,
+}
+
+impl Default for IoRetryPolicy {
+    fn default() -> Self {
+        IoRetryPolicy {
+            attempts: 3,
+            delay_ms: 100,
+        }
+    }
+}
+
+// Errors that won't go away by trying again eg a full disk or a missing source
+fn is_permanent_io_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    // ENOSPC, EROFS and EDQUOT
+    if let Some(28 | 30 | 122) = e.raw_os_error() {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::Unsupported
+    )
+}
+
+// Run an IO operation on the target path with retries and exponential backoff
+pub fn retry_io<T>(
+    io_retry: &IoRetryPolicy,
+    target_path: &str,
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= io_retry.attempts || is_permanent_io_error(&e) => return Err(e),
+            Err(e) => {
+                warn!(
+                    "IO attempt {}/{} failed for {}: {}, Retrying",
+                    attempt,
+                    io_retry.attempts,
+                    phi(target_path),
+                    e
+                );
+                std::thread::sleep(Duration::from_millis(
+                    io_retry.delay_ms.saturating_mul(1 << (attempt - 1).min(16)),
+                ));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Serialize a DICOM object in memory and write it to the given path with retries
+// The file is written to a .part file first and renamed, so a failed write leaves no partial file
+pub fn write_dicom_file(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    full_path: &str,
+    io_retry: &IoRetryPolicy,
+) -> Result<()> {
+    let dcm_buffer = encode_dicom_file(dcm_obj)?;
+    let part_path = format!("{}.part", full_path);
+    retry_io(io_retry, full_path, || {
+        fs::write(&part_path, &dcm_buffer)?;
+        fs::rename(&part_path, full_path)
+    })
+    .inspect_err(|_| {
+        let _ = fs::remove_file(&part_path);
+    })?;
+    Ok(())
+}
+
+// Bytes of the file written for a DICOM object
+// Deflated and big endian files are written as Explicit VR Little Endian
+pub fn encode_dicom_file(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<Vec<u8>> {
+    let transfer_syntax = trim_uid(dcm_obj.meta().transfer_syntax()).to_string();
+    let mut dcm_buffer: Vec<u8> = Vec::new();
+    let written = match transfer_syntax == DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN.uid()
+        || transfer_syntax == EXPLICIT_VR_BIG_ENDIAN.uid()
+    {
+        true => {
+            let mut dcm_obj = dcm_obj.clone();
+            dcm_obj
+                .meta_mut()
+                .set_transfer_syntax(&EXPLICIT_VR_LITTLE_ENDIAN);
+            debug!(
+                "Re-encoding {} as {}",
+                transfer_syntax_name(&transfer_syntax),
+                EXPLICIT_VR_LITTLE_ENDIAN.name()
+            );
+            dcm_obj.write_all(&mut dcm_buffer)
+        }
+        false => dcm_obj.write_all(&mut dcm_buffer),
+    };
+    written.map_err(|e| {
+        anyhow::Error::msg(format!(
+            "Can't encode with transfer syntax {}: {}",
+            transfer_syntax_name(&transfer_syntax),
+            e
+        ))
+    })?;
+    // dicom-rs writes a zeroed preamble, it is cleared again so no vendor data or dual format
+    // header of the source can ever reach the written file
+    let preamble_len = DICOM_PREAMBLE_LEN.min(dcm_buffer.len());
+    dcm_buffer[..preamble_len].fill(0);
+    Ok(dcm_buffer)
+}
+
+// Lossless codec of --compress
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, Serialize)]
+pub enum CompressionCodec {
+    // RLE Lossless, single sample images of 8, 16 or 32 bits allocated
+    Rle,
+}
+
+// Re-encodes the native PixelData of the anon and deid outputs after the tag changes
+// Files the codec can't encode are written uncompressed with a warning, never failed
+#[derive(Debug, Clone)]
+pub struct PixelCompression {
+    pub codec: CompressionCodec,
+    counts: Arc<CompressionCounts>,
+}
+
+#[derive(Debug, Default)]
+struct CompressionCounts {
+    compressed: AtomicU64,
+    uncompressed: AtomicU64,
+    bytes_saved: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompressionSummary {
+    pub codec: CompressionCodec,
+    pub files_compressed: u64,
+    pub files_uncompressed: u64,
+    // PixelData bytes of the compressed files less their encapsulated size
+    pub bytes_saved: u64,
+}
+
+impl PixelCompression {
+    pub fn new(codec: CompressionCodec) -> Self {
+        PixelCompression {
+            codec,
+            counts: Arc::new(CompressionCounts::default()),
+        }
+    }
+
+    // Files without PixelData or already encapsulated are left alone and not counted
+    pub fn compress(&self, dcm_obj: &mut FileDicomObject<InMemDicomObject>, source_name: &str) {
+        match rle_encode_pixel_data(dcm_obj) {
+            Ok(Some(bytes_saved)) => {
+                self.counts.compressed.fetch_add(1, Ordering::Relaxed);
+                self.counts
+                    .bytes_saved
+                    .fetch_add(bytes_saved, Ordering::Relaxed);
+            }
+            Ok(None) => (),
+            Err(e) => {
+                warn!("{} written uncompressed: {}", phi(source_name), e);
+                self.counts.uncompressed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn summary(&self) -> CompressionSummary {
+        CompressionSummary {
+            codec: self.codec.clone(),
+            files_compressed: self.counts.compressed.load(Ordering::Relaxed),
+            files_uncompressed: self.counts.uncompressed.load(Ordering::Relaxed),
+            bytes_saved: self.counts.bytes_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Segment count and the offsets of up to 15 segments
+const RLE_HEADER_LEN: usize = 64;
+
+// Replace native PixelData with RLE Lossless fragments, one per frame, and set the transfer syntax
+// Returns the bytes saved, None when there is nothing to encode. The dataset is only changed
+// once the whole PixelData is encoded and smaller than the original
+fn rle_encode_pixel_data(dcm_obj: &mut FileDicomObject<InMemDicomObject>) -> Result<Option<u64>> {
+    let transfer_syntax = trim_uid(dcm_obj.meta().transfer_syntax()).to_string();
+    let Ok(pixel_data) = dcm_obj.element(tags::PIXEL_DATA) else {
+        return Ok(None);
+    };
+    if let Value::PixelSequence(_) = pixel_data.value() {
+        return Ok(None);
+    }
+    if transfer_syntax != IMPLICIT_VR_LITTLE_ENDIAN.uid()
+        && transfer_syntax != EXPLICIT_VR_LITTLE_ENDIAN.uid()
+        && transfer_syntax != DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN.uid()
+    {
+        return Err(anyhow::Error::msg(format!(
+            "{} is not supported",
+            transfer_syntax_name(&transfer_syntax)
+        )));
+    }
+    let int_value = |tag: Tag| -> Option<usize> {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_int::<u32>().ok())
+            .map(|v| v as usize)
+    };
+    let (Some(rows), Some(columns), Some(bits_allocated)) = (
+        int_value(tags::ROWS),
+        int_value(tags::COLUMNS),
+        int_value(tags::BITS_ALLOCATED),
+    ) else {
+        return Err(anyhow::Error::msg("Rows, Columns or BitsAllocated missing"));
+    };
+    let samples_per_pixel = int_value(tags::SAMPLES_PER_PIXEL).unwrap_or(1);
+    let frames = int_value(tags::NUMBER_OF_FRAMES).unwrap_or(1).max(1);
+    if samples_per_pixel != 1 {
+        return Err(anyhow::Error::msg(format!(
+            "{} samples per pixel, only single sample images are encoded",
+            samples_per_pixel
+        )));
+    }
+    if ![8, 16, 32].contains(&bits_allocated) {
+        return Err(anyhow::Error::msg(format!(
+            "{} bits allocated, only 8, 16 and 32 are encoded",
+            bits_allocated
+        )));
+    }
+    let sample_len = bits_allocated / 8;
+    let pixel_bytes = pixel_data.to_bytes()?;
+    let frame_len = rows * columns * sample_len;
+    if frame_len == 0 || pixel_bytes.len() < frame_len * frames {
+        return Err(anyhow::Error::msg(format!(
+            "PixelData of {} bytes is shorter than {} frames of {} bytes",
+            pixel_bytes.len(),
+            frames,
+            frame_len
+        )));
+    }
+
+    let mut fragments: Vec<Vec<u8>> = Vec::with_capacity(frames);
+    for each_frame in pixel_bytes.chunks_exact(frame_len).take(frames) {
+        fragments.push(rle_encode_frame(each_frame, rows, columns, sample_len));
+    }
+    let mut offset_table: Vec<u32> = Vec::with_capacity(frames);
+    let mut encoded_len = 0;
+    for each_fragment in &fragments {
+        offset_table.push(encoded_len as u32);
+        // Item tag and length of each fragment
+        encoded_len += 8 + each_fragment.len();
+    }
+    // Basic offset table item and the sequence delimiter
+    let encoded_len = encoded_len + 8 + 4 * frames + 8;
+    let original_len = pixel_bytes.len();
+    if encoded_len >= original_len {
+        return Err(anyhow::Error::msg(format!(
+            "RLE takes {} bytes, not less than the {} bytes of the original",
+            encoded_len, original_len
+        )));
+    }
+
+    dcm_obj.put(DataElement::new(
+        tags::PIXEL_DATA,
+        VR::OB,
+        Value::PixelSequence(PixelFragmentSequence::new(offset_table, fragments)),
+    ));
+    dcm_obj.meta_mut().set_transfer_syntax(&RLE_LOSSLESS);
+    dcm_obj.meta_mut().update_information_group_length();
+    Ok(Some((original_len - encoded_len) as u64))
+}
+
+// A frame of little endian samples as RLE segments, most significant byte first
+// Every row of a segment is encoded on its own and segments are padded to an even length
+fn rle_encode_frame(frame: &[u8], rows: usize, columns: usize, sample_len: usize) -> Vec<u8> {
+    let mut header = vec![0u8; RLE_HEADER_LEN];
+    header[..4].copy_from_slice(&(sample_len as u32).to_le_bytes());
+    let mut segments: Vec<u8> = Vec::new();
+    let mut row_bytes: Vec<u8> = Vec::with_capacity(columns);
+    for each_segment in 0..sample_len {
+        let offset = (RLE_HEADER_LEN + segments.len()) as u32;
+        header[4 + each_segment * 4..8 + each_segment * 4].copy_from_slice(&offset.to_le_bytes());
+        let byte_index = sample_len - 1 - each_segment;
+        for each_row in 0..rows {
+            row_bytes.clear();
+            let row_start = each_row * columns * sample_len;
+            row_bytes.extend(
+                (0..columns).map(|column| frame[row_start + column * sample_len + byte_index]),
+            );
+            rle_encode_row(&row_bytes, &mut segments);
+        }
+        if segments.len() % 2 == 1 {
+            segments.push(0);
+        }
+    }
+    header.extend(segments);
+    header
+}
+
+// PackBits: a run of 2 to 128 equal bytes is written as 1 - n and the byte,
+// other bytes as n - 1 followed by up to 128 literal bytes
+fn rle_encode_row(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let mut run = 1;
+        while i + run < row.len() && run < 128 && row[i + run] == row[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push((257 - run) as u8);
+            out.push(row[i]);
+            i += run;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        while i < row.len() && i - start < 128 && !(i + 1 < row.len() && row[i] == row[i + 1]) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&row[start..i]);
+    }
+}
+
+// Root of the UIDs of anon and of the ImplementationClassUID, see --uid-root
+pub const DEFAULT_UID_ROOT: &str = "1.2.999.999999.9999.9.9.9.9999";
+
+// Identifiers of dcmrig written to the file meta group of the anon and deid files
+#[derive(Debug, Clone)]
+pub struct FileMetaIdentity {
+    pub implementation_class_uid: String,
+    pub implementation_version_name: String,
+    pub source_ae_title: String,
+}
+
+impl FileMetaIdentity {
+    pub fn new(uid_root: &str, source_ae_title: &str) -> Self {
+        FileMetaIdentity {
+            implementation_class_uid: format!("{}.1", uid_root),
+            implementation_version_name: format!("DCMRIG_{}", env!("CARGO_PKG_VERSION")),
+            source_ae_title: source_ae_title.to_string(),
+        }
+    }
+}
+
+impl Default for FileMetaIdentity {
+    fn default() -> Self {
+        FileMetaIdentity::new(DEFAULT_UID_ROOT, "DCMRIG")
+    }
+}
+
+// Replace the implementation identifiers and AE title of the original vendor with dcmrig
+// so the written files don't point downstream support at the wrong software
+pub fn update_file_meta(
+    dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+    identity: &FileMetaIdentity,
+) {
+    // Values of odd length are padded, UIDs with NUL and text with a space
+    let even = |value: &str, pad: char| match value.len() % 2 {
+        0 => value.to_string(),
+        _ => format!("{}{}", value, pad),
+    };
+    // The media storage UIDs follow the dataset, anon replaces the SOP instance UID
+    let dataset_uid = |tag: Tag| {
+        dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|uid| even(trim_uid(&uid), '\0'))
+    };
+    let sop_class_uid = dataset_uid(tags::SOP_CLASS_UID);
+    let sop_instance_uid = dataset_uid(tags::SOP_INSTANCE_UID);
+    let meta = dcm_obj.meta_mut();
+    if let Some(sop_class_uid) = sop_class_uid {
+        meta.media_storage_sop_class_uid = sop_class_uid;
+    }
+    if let Some(sop_instance_uid) = sop_instance_uid {
+        meta.media_storage_sop_instance_uid = sop_instance_uid;
+    }
+    meta.implementation_class_uid = even(&identity.implementation_class_uid, '\0');
+    meta.implementation_version_name = Some(even(&identity.implementation_version_name, ' '));
+    meta.source_application_entity_title = Some(even(&identity.source_ae_title, ' '));
+    meta.update_information_group_length();
+}
+
+// Name and UID of a transfer syntax eg "Explicit VR Little Endian (1.2.840.10008.1.2.1)"
+pub fn transfer_syntax_name(uid: &str) -> String {
+    match TransferSyntaxRegistry.get(trim_uid(uid)) {
+        Some(ts) => format!("{} ({})", ts.name(), trim_uid(uid)),
+        None => format!("Unknown ({})", trim_uid(uid)),
+    }
+}
+
+// Transfer syntax of a file that has a readable file meta group but could not be opened
+// Such files are DICOM with an unsupported encoding rather than non DICOM files
+// None when the registry can read the syntax, the data set itself is then what failed
+pub fn unreadable_transfer_syntax(file_path: &std::path::Path) -> Option<String> {
+    let mut file = fs::File::open(file_path).ok()?;
+    std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(128)).ok()?;
+    let meta = FileMetaTable::from_reader(std::io::BufReader::new(file)).ok()?;
+    match TransferSyntaxRegistry.get(trim_uid(meta.transfer_syntax())) {
+        Some(ts) if !ts.is_unsupported() => None,
+        _ => Some(transfer_syntax_name(meta.transfer_syntax())),
+    }
+}
+// Replace all non_alphanumeric characters with an underscore '_'
+// Visually identical inputs give the same result, see transliterate_to_ascii
+pub fn replace_non_alphanumeric(input: &str) -> String {
+    let re = Regex::new(r"[^a-zA-Z0-9]+").expect("Failed to set up Regex");
+    re.replace_all(&transliterate_to_ascii(input), "_")
+        .to_string()
+}
+
+// NFC normalize, drop invisible characters, turn the unicode spaces into a plain space
+// and strip the accents eg "Séries\u{a0}T1\u{200b}" >> "Series T1"
+// Anything else outside of ASCII is kept for the caller to replace
+fn transliterate_to_ascii(input: &str) -> String {
+    input
+        .nfc()
+        .filter_map(|c| match c {
+            '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}' => None,
+            '\u{00A0}'
+            | '\u{1680}'
+            | '\u{2000}'..='\u{200A}'
+            | '\u{202F}'
+            | '\u{205F}'
+            | '\u{3000}' => Some(' '),
+            c => Some(c),
+        })
+        .flat_map(|c| match c.is_ascii() {
+            true => vec![c],
+            false => {
+                let base: Vec<char> = c
+                    .to_string()
+                    .nfkd()
+                    .filter(|d| !is_combining_mark(*d))
+                    .collect();
+                match base.iter().all(|d| d.is_ascii()) {
+                    true => base,
+                    false => vec![c],
+                }
+            }
+        })
+        .collect()
+}
+
+// How multi-valued elements are turned into a single value for names and paths
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, Serialize)]
+pub enum MultiValueMode {
+    // Join all values with an underscore
+    Join,
+    // Only keep the first value
+    First,
+}
+
+// Options shared by the file handling of all commands
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    // SOPClassUIDs of the files to skip
+    pub exclude_sop_classes: HashSet<String>,
+    // Copy the excluded files to EXCLUDED_SOP
+    pub keep_excluded: bool,
+    // Nest NON_DICOM, FAILED_CASES and EXCLUDED_SOP under a run_id directory
+    pub run_subdir: bool,
+    // Start time of the run eg 2024-06-01T1030
+    pub run_id: String,
+    pub io_retry: IoRetryPolicy,
+    // Time a source file may take to read with --file-timeout
+    pub file_timeout: Option<Duration>,
+    // Abort if any entry of the source can not be read
+    pub fail_on_walk_errors: bool,
+    pub parallelism: Parallelism,
+    // Limits on the source files read and destination files written at the same time
+    pub read_gate: IoGate,
+    pub write_gate: IoGate,
+    // Destination directories created so far, shared by the writers
+    pub ensured_dirs: EnsuredDirs,
+    // Numbered sub-buckets for the files over --max-files-per-dir
+    pub dir_buckets: DirBuckets,
+    // Series sharing a generated series directory, diverted with --strict-layout
+    pub series_dirs: SeriesDirs,
+    // Study directory names, the ordinals are indexed by preprocessing_setup
+    pub study_dir_style: StudyDirStyle,
+    pub study_ordinals: StudyOrdinals,
+    // Destination roots of the files matching the cookbook routes
+    pub routes: Routes,
+    // Private tags removed by anon and deid, per tag and private creator
+    pub private_tag_stats: PrivateTagStats,
+    // Failure reasons and the written files per patient for the run summary
+    pub outcome_details: OutcomeDetails,
+    // Files and elements changed per rule of anon and deid
+    pub rule_stats: RuleStats,
+    // --compress, re-encodes the PixelData of the anon and deid outputs
+    pub compression: Option<PixelCompression>,
+    // Tags of the cookbook [keep] list, anon only reads it from a given --cookbook
+    pub keep: KeepList,
+    // Root of the anon UIDs, Default DEFAULT_UID_ROOT
+    pub uid_root: String,
+    // Implementation identifiers and AE title of the anon and deid files
+    pub file_meta: FileMetaIdentity,
+    // Read the headers of the files whose PixelData can't be read
+    pub tolerate_truncated_pixeldata: bool,
+    // Fail the anon and deid files breaking the conformance checks of --post-check
+    pub post_check: bool,
+    // Rewrite elements with a VR other than the dictionary VR, and the log of the corrections
+    pub fix_vrs: bool,
+    pub vr_fix_log: VrFixLog,
+    // Live counters served by --status-port
+    pub status_board: Option<StatusBoard>,
+    // Log each directory under the source root as it is done, with --progress-by top-dir
+    pub top_dir_progress: Option<TopDirProgress>,
+    // Called with the outcome of each file as it completes, for crates running dcmrig as a library
+    pub on_outcome: Option<OutcomeSink>,
+    // Only process the files referenced by a DICOMDIR at the source root
+    pub use_dicomdir: bool,
+    pub dicomdir_records: DicomdirRecords,
+    // 8 character upper case path components that are valid on CD and DVD media
+    pub media_names: Option<MediaNames>,
+    // Collects the written files for the DICOMDIR of --write-dicomdir
+    pub dicomdir: Option<DicomdirBuilder>,
+    // Also copy the empty and too small files to NON_DICOM
+    pub copy_non_dicom_all: bool,
+    // Values over the length limit of their VR
+    pub vr_length: LengthPolicy,
+    // CSV listing the empty and too small files
+    pub too_small_csv: Option<PathBuf>,
+    // Files over this size in bytes are left out at indexing and never opened, unlimited when unset
+    pub max_file_size: Option<u64>,
+    // SOPInstanceUIDs seen in the run with --dedupe-global, later files of an instance are skipped
+    pub global_dedupe: Option<GlobalDedupe>,
+    // Process and write the files one by one in path order for reproducible output
+    pub deterministic: bool,
+    // Seed of the generated IDs, random IDs when unset
+    pub seed: Option<u64>,
+    // Only process the files selected by --limit or --sample
+    pub partial_run: Option<PartialRun>,
+    // Restrictions of the destination filesystem
+    pub fs_limits: FsLimits,
+    // Institution and staff name handling of anon and deid
+    pub identity: IdentityPolicy,
+    // PatientID normalization of --normalize-patient-id for anon and deid
+    pub patient_id: PatientIdNormalizer,
+}
+
+// Live counters of a run for --status-port, the position, ETA and throughput come from
+// the progress bar and the outcomes from a callback of the outcome tracker
+#[derive(Clone, Default)]
+pub struct StatusBoard {
+    action: Arc<Mutex<String>>,
+    pb: Arc<Mutex<Option<ProgressBar>>>,
+    failed: Arc<AtomicU64>,
+    non_dicom: Arc<AtomicU64>,
+    skipped: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for StatusBoard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatusBoard").finish_non_exhaustive()
+    }
+}
+
+impl StatusBoard {
+    pub fn new(action: &str) -> Self {
+        StatusBoard {
+            action: Arc::new(Mutex::new(action.to_string())),
+            ..Default::default()
+        }
+    }
+
+    // Follow the progress bar of the run, set once the source is indexed
+    pub fn track(&self, pb: &ProgressBar) {
+        *self.pb.lock().expect("Failed to lock mutex") = Some(pb.clone());
+    }
+
+    pub fn callback(&self) -> OutcomeCallback {
+        let board = self.clone();
+        Arc::new(move |outcome: &FileOutcome| {
+            let counter = match outcome.status {
+                FileStatus::Failed | FileStatus::TruncatedPixelData => &board.failed,
+                FileStatus::NonDicom => &board.non_dicom,
+                FileStatus::Skipped
+                | FileStatus::Filtered
+                | FileStatus::UnsafeReferences
+                | FileStatus::Duplicate
+                | FileStatus::GlobalDuplicate => &board.skipped,
+                FileStatus::Written => return,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let action = self.action.lock().expect("Failed to lock mutex").clone();
+        let pb = self.pb.lock().expect("Failed to lock mutex");
+        let Some(pb) = pb.as_ref() else {
+            return serde_json::json!({ "action": action, "state": "indexing" });
+        };
+        let total = pb.length().unwrap_or(0);
+        let position = pb.position();
+        let failed = self.failed.load(Ordering::Relaxed);
+        let non_dicom = self.non_dicom.load(Ordering::Relaxed);
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        serde_json::json!({
+            "action": action,
+            "state": match pb.is_finished() {
+                true => "finishing",
+                false => "running",
+            },
+            "total": total,
+            "done": position,
+            "processed": position.saturating_sub(failed + non_dicom + skipped),
+            "failed": failed,
+            "non_dicom": non_dicom,
+            "skipped": skipped,
+            "percent": match total {
+                0 => 100.0,
+                _ => (position as f64 * 1000.0 / total as f64).round() / 10.0,
+            },
+            "elapsed_secs": pb.elapsed().as_secs(),
+            "eta_secs": pb.eta().as_secs(),
+            "files_per_sec": (pb.per_sec() * 10.0).round() / 10.0,
+        })
+    }
+}
+
+// HTTP listener of --status-port answering every request with the status board as JSON
+// Stops when dropped, also when the run ends with an error
+pub struct StatusServer {
+    server: Arc<tiny_http::Server>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl StatusServer {
+    pub fn start(bind: IpAddr, port: u16, board: StatusBoard) -> Result<Self> {
+        let server = Arc::new(tiny_http::Server::http((bind, port)).map_err(|e| {
+            anyhow::Error::msg(format!(
+                "Can't listen for the status on {}:{}: {}",
+                bind, port, e
+            ))
+        })?);
+        let listener = Arc::clone(&server);
+        let handle = std::thread::spawn(move || {
+            let header = tiny_http::Header::from_bytes("Content-Type", "application/json")
+                .expect("Valid header");
+            for request in listener.incoming_requests() {
+                let response = tiny_http::Response::from_string(board.to_json().to_string())
+                    .with_header(header.clone());
+                if let Err(e) = request.respond(response) {
+                    debug!("Status request failed: {}", e);
+                }
+            }
+        });
+        info!("Serving the run status on {}:{}", bind, port);
+        Ok(StatusServer {
+            server,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Limit on the IO operations running at the same time, unlimited by default
+// Clones share the permits and the wait time of the operations that had to queue
+#[derive(Debug, Clone, Default)]
+pub struct IoGate {
+    state: Option<Arc<IoGateState>>,
+}
+
+#[derive(Debug)]
+struct IoGateState {
+    limit: u32,
+    in_use: Mutex<u32>,
+    released: Condvar,
+    operations: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+// Permit of a running operation, released when dropped so a panic does not leak it
+struct IoPermit<'a>(&'a IoGateState);
+
+impl Drop for IoPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.in_use.lock().expect("Failed to lock mutex") -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+impl IoGate {
+    pub fn new(limit: Option<u32>) -> Self {
+        IoGate {
+            state: limit.map(|limit| {
+                Arc::new(IoGateState {
+                    limit,
+                    in_use: Mutex::new(0),
+                    released: Condvar::new(),
+                    operations: AtomicU64::new(0),
+                    wait_micros: AtomicU64::new(0),
+                })
+            }),
+        }
+    }
+
+    // Run the operation once a permit is free
+    pub fn run<T>(&self, operation: impl FnOnce() -> T) -> T {
+        let Some(state) = &self.state else {
+            return operation();
+        };
+        let wait_start = Instant::now();
+        let mut in_use = state.in_use.lock().expect("Failed to lock mutex");
+        while *in_use >= state.limit {
+            in_use = state.released.wait(in_use).expect("Failed to lock mutex");
+        }
+        *in_use += 1;
+        drop(in_use);
+        state.operations.fetch_add(1, Ordering::Relaxed);
+        state
+            .wait_micros
+            .fetch_add(wait_start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        let _permit = IoPermit(state);
+        operation()
+    }
+
+    // Average queue wait per operation, None when unlimited
+    pub fn average_wait_ms(&self) -> Option<f64> {
+        let state = self.state.as_ref()?;
+        let operations = state.operations.load(Ordering::Relaxed);
+        match operations {
+            0 => Some(0.0),
+            _ => {
+                Some(state.wait_micros.load(Ordering::Relaxed) as f64 / operations as f64 / 1000.0)
+            }
+        }
+    }
+}
+
+// Directories of the destination that already exist
+// The writers create a directory once before the first file lands in it
+// instead of checking the filesystem for every file
+#[derive(Debug, Clone, Default)]
+pub struct EnsuredDirs {
+    dirs: Arc<Mutex<HashSet<String>>>,
+}
+
+impl EnsuredDirs {
+    pub fn ensure(&self, dir_path: &String) -> Result<()> {
+        if self
+            .dirs
+            .lock()
+            .expect("Failed to lock mutex")
+            .contains(dir_path)
+        {
+            return Ok(());
+        }
+        // Creating a directory that exists is not an error, two writers may race here
+        create_dir_all(dir_path)?;
+        self.dirs
+            .lock()
+            .expect("Failed to lock mutex")
+            .insert(dir_path.clone());
+        Ok(())
+    }
+}
+
+// Series directories tracked per run, 16 bytes of hashes each
+static SERIES_DIR_LIMIT: usize = 1_000_000;
+
+// SeriesInstanceUID first seen in each generated series directory, to catch different series
+// whose sanitized numbers and descriptions give the same directory and would interleave
+// Only hashes are kept and no new directories are tracked past SERIES_DIR_LIMIT
+#[derive(Debug, Clone, Default)]
+pub struct SeriesDirs {
+    // Divert the later series to <dir>_<UID tail> with --strict-layout
+    strict: bool,
+    state: Arc<Mutex<SeriesDirState>>,
+}
+
+#[derive(Debug, Default)]
+struct SeriesDirState {
+    first_series: HashMap<u64, u64>,
+    // Directory and series pairs already warned about
+    warned: HashSet<(u64, u64)>,
+    full: bool,
+}
+
+impl SeriesDirs {
+    pub fn new(strict: bool) -> Self {
+        SeriesDirs {
+            strict,
+            state: Arc::new(Mutex::new(SeriesDirState::default())),
+        }
+    }
+
+    // Directory of a file of the series, dir_path unless another series got there first
+    pub fn resolve(&self, dir_path: String, series_uid: &str) -> String {
+        let hash = |value: &str| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            std::hash::Hash::hash(value, &mut hasher);
+            std::hash::Hasher::finish(&hasher)
+        };
+        let series_uid = trim_uid(series_uid);
+        let (dir_hash, series_hash) = (hash(&dir_path), hash(series_uid));
+        let mut state = self.state.lock().expect("Failed to lock mutex");
+        let first_series = match state.first_series.get(&dir_hash) {
+            Some(first_series) => *first_series,
+            None if state.first_series.len() >= SERIES_DIR_LIMIT => {
+                if !state.full {
+                    state.full = true;
+                    warn!(
+                        "{} series directories tracked, series sharing a directory are no longer detected",
+                        SERIES_DIR_LIMIT
+                    );
+                }
+                return dir_path;
+            }
+            None => {
+                state.first_series.insert(dir_hash, series_hash);
+                return dir_path;
+            }
+        };
+        if first_series == series_hash {
+            return dir_path;
+        }
+        let diverted = format!("{}_{}", dir_path, uid_tail(series_uid));
+        if state.warned.len() < SERIES_DIR_LIMIT && state.warned.insert((dir_hash, series_hash)) {
+            match self.strict {
+                true => warn!(
+                    "!! Series {} has the directory of another series {}, diverted to {}",
+                    phi(series_uid),
+                    phi(&dir_path),
+                    phi(&diverted)
+                ),
+                false => warn!(
+                    "!! Series {} has the directory of another series {}, the files are mixed",
+                    phi(series_uid),
+                    phi(&dir_path)
+                ),
+            }
+        }
+        match self.strict {
+            true => diverted,
+            false => dir_path,
+        }
+    }
+}
+
+// Last 5 digits of the last component of a UID, as in the study directory names
+// Counted in characters so a malformed value can't split one
+fn uid_tail(uid: &str) -> String {
+    let last = uid.split(".").last().unwrap_or_default();
+    last.chars()
+        .skip(last.chars().count().saturating_sub(5))
+        .collect()
+}
+
+// Tail of the StudyInstanceUID in the study directory names
+// A missing, empty or non numeric UID takes 5 characters of a hash of the SeriesInstanceUID,
+// or of the PatientID, StudyDate and StudyTime without one, instead of the NoValue placeholder
+pub fn study_uid_tail(dicom_tags_values: &HashMap<String, String>) -> String {
+    let usable = |name: &str| {
+        dicom_tags_values
+            .get(name)
+            .map(|v| trim_uid(v))
+            .filter(|v| !v.is_empty() && !v.starts_with("NoValue_"))
+    };
+    if let Some(study_uid) = usable("StudyInstanceUID") {
+        let tail = uid_tail(study_uid);
+        if !tail.is_empty() && tail.chars().all(|c| c.is_ascii_digit()) {
+            return tail;
+        }
+    }
+    let fallback = match usable("SeriesInstanceUID") {
+        Some(series_uid) => series_uid.to_string(),
+        None => ["PatientID", "StudyDate", "StudyTime"]
+            .into_iter()
+            .filter_map(usable)
+            .collect::<Vec<_>>()
+            .join("_"),
+    };
+    debug!("No usable StudyInstanceUID, the study directory takes a hash instead");
+    short_hash(&fallback)[..5].to_string()
+}
+
+// Files per destination directory with --max-files-per-dir
+// A directory holds the first bucket of files, the overflow goes to numbered
+// sub-buckets 0001, 0002.. of it. DICOM files are bucketed by InstanceNumber
+// so the files of a series stay in contiguous buckets in instance order
+#[derive(Debug, Clone, Default)]
+pub struct DirBuckets {
+    max_files: Option<u64>,
+    dirs: Arc<Mutex<HashMap<String, BucketedDir>>>,
+    // With --skip-existing a file an earlier run wrote under the name is kept as it is
+    skip_existing: bool,
+    // Paths placed by this run, only tracked with skip_existing
+    placed: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+// Files in each bucket of a directory and the names used across all buckets with their paths
+#[derive(Debug, Default)]
+struct BucketedDir {
+    files: BTreeMap<u64, u64>,
+    names: HashMap<String, PathBuf>,
+}
+
+// Where a file goes, or the file already written with its name and content
+#[derive(Debug)]
+pub enum Placement {
+    // Bucket directory and full path of the file to write
+    New(String, String),
+    // Nothing is written, the file is a duplicate of this one
+    Duplicate(PathBuf),
+}
+
+impl DirBuckets {
+    pub fn new(max_files: Option<u64>, skip_existing: bool) -> Self {
+        DirBuckets {
+            max_files,
+            dirs: Arc::new(Mutex::new(HashMap::new())),
+            skip_existing,
+            placed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    // A file left under the name by an earlier run, which skip_existing keeps
+    // The files written by this run still go through the duplicate check
+    fn kept_file(&self, path: PathBuf) -> Option<PathBuf> {
+        if !self.skip_existing || !path.exists() {
+            return None;
+        }
+        match self
+            .placed
+            .lock()
+            .expect("Failed to lock mutex")
+            .contains(&path)
+        {
+            true => None,
+            false => Some(path),
+        }
+    }
+
+    fn record_placed(&self, full_path: &str) {
+        if self.skip_existing {
+            self.placed
+                .lock()
+                .expect("Failed to lock mutex")
+                .insert(PathBuf::from(full_path));
+        }
+    }
+
+    // Bucket directory and full path of a file written to dir_path
+    // The name is made unique across the directory and its buckets with unique_name, the
+    // content_hash of the file is only called on a name collision
+    // Without a limit this is the dup check of the directory itself
+    pub fn place(
+        &self,
+        dir_path: &String,
+        file_name: &str,
+        instance_number: Option<u64>,
+        content_hash: &dyn Fn() -> Option<String>,
+    ) -> Placement {
+        let max_files = match self.max_files {
+            Some(max_files) => max_files,
+            None => {
+                if let Some(existing_path) =
+                    self.kept_file(PathBuf::from(format!("{}/{}", dir_path, file_name)))
+                {
+                    return Placement::Duplicate(existing_path);
+                }
+                let taken = |name: &str| {
+                    let path = PathBuf::from(format!("{}/{}", dir_path, name));
+                    path.exists().then_some(path)
+                };
+                return match unique_name(file_name, taken, content_hash) {
+                    Ok(name) => {
+                        let full_path = format!("{}/{}", dir_path, name);
+                        self.record_placed(&full_path);
+                        Placement::New(dir_path.clone(), full_path)
+                    }
+                    Err(existing_path) => Placement::Duplicate(existing_path),
+                };
+            }
+        };
+        let mut dirs = self.dirs.lock().expect("Failed to lock mutex");
+        let bucketed = dirs
+            .entry(dir_path.clone())
+            .or_insert_with(|| BucketedDir::scan(dir_path));
+        if let Some(existing_path) = bucketed
+            .names
+            .get(file_name)
+            .cloned()
+            .and_then(|path| self.kept_file(path))
+        {
+            return Placement::Duplicate(existing_path);
+        }
+        // A full bucket spills into the next one, eg with repeated InstanceNumbers
+        let mut bucket = instance_number.unwrap_or(1).saturating_sub(1) / max_files;
+        while bucketed.files.get(&bucket).copied().unwrap_or(0) >= max_files {
+            bucket += 1;
+        }
+        let bucket_dir = match bucket {
+            0 => dir_path.clone(),
+            _ => format!("{}/{:04}", dir_path, bucket),
+        };
+        let taken = |name: &str| {
+            let path = PathBuf::from(format!("{}/{}", bucket_dir, name));
+            match bucketed.names.get(name) {
+                Some(named_path) => Some(named_path.clone()),
+                None => path.exists().then_some(path),
+            }
+        };
+        let name = match unique_name(file_name, taken, content_hash) {
+            Ok(name) => name,
+            Err(existing_path) => return Placement::Duplicate(existing_path),
+        };
+        *bucketed.files.entry(bucket).or_insert(0) += 1;
+        let full_path = format!("{}/{}", bucket_dir, name);
+        bucketed.names.insert(name, PathBuf::from(&full_path));
+        self.record_placed(&full_path);
+        Placement::New(bucket_dir, full_path)
+    }
+}
+
+// Name of a file written where file_name may be taken, taken gives the path of a file
+// already holding a name. The file is a duplicate of a file with its name and content. A file
+// with other content takes a short hash of its content, eg IMG_a3f9.dcm, so a colliding file
+// gets the same name on every run whatever the order the files are processed in
+// Unreadable content and a taken hashed name fall back to appending ~
+fn unique_name(
+    file_name: &str,
+    taken: impl Fn(&str) -> Option<PathBuf>,
+    content_hash: &dyn Fn() -> Option<String>,
+) -> std::result::Result<String, PathBuf> {
+    if taken(file_name).is_none() {
+        return Ok(file_name.to_string());
+    }
+    let content_hash = content_hash();
+    let mut name = file_name.to_string();
+    let mut hashed = false;
+    while let Some(existing_path) = taken(&name) {
+        if content_hash.is_some() && file_content_hash(&existing_path) == content_hash {
+            return Err(existing_path);
+        }
+        match (&content_hash, hashed) {
+            (Some(content_hash), false) => {
+                name = hash_suffixed_name(file_name, &content_hash[..CONTENT_SUFFIX_LEN]);
+                hashed = true;
+            }
+            _ => name.push('~'),
+        }
+    }
+    Ok(name)
+}
+
+// Hex characters of the content hash in the name of a colliding file
+const CONTENT_SUFFIX_LEN: usize = 4;
+
+// IMG.dcm and a3f9 give IMG_a3f9.dcm
+fn hash_suffixed_name(file_name: &str, suffix: &str) -> String {
+    match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}_{}.{}", stem, suffix, extension)
+        }
+        _ => format!("{}_{}", file_name, suffix),
+    }
+}
+
+// SHA-256 of the bytes of a file
+pub fn file_content_hash(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|bytes| content_hash(&bytes))
+}
+
+// SHA-256 of the bytes write_dicom_file writes for the object
+pub fn object_content_hash(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Option<String> {
+    encode_dicom_file(dcm_obj)
+        .ok()
+        .map(|bytes| content_hash(&bytes))
+}
+
+fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl BucketedDir {
+    // Files of an earlier run in the directory and its buckets
+    fn scan(dir_path: &String) -> Self {
+        let mut bucketed = BucketedDir::default();
+        let entries = match fs::read_dir(dir_path) {
+            Ok(entries) => entries,
+            Err(_) => return bucketed,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let bucket = match name.len() == 4 && name.chars().all(|c| c.is_ascii_digit()) {
+                true => name.parse::<u64>().ok().filter(|b| *b > 0),
+                false => None,
+            };
+            match (bucket, entry.path().is_dir()) {
+                (Some(bucket), true) => {
+                    for bucket_entry in fs::read_dir(entry.path()).into_iter().flatten().flatten() {
+                        bucketed.names.insert(
+                            bucket_entry.file_name().to_string_lossy().to_string(),
+                            bucket_entry.path(),
+                        );
+                        *bucketed.files.entry(bucket).or_insert(0) += 1;
+                    }
+                }
+                (_, false) => {
+                    bucketed.names.insert(name, entry.path());
+                    *bucketed.files.entry(0).or_insert(0) += 1;
+                }
+                _ => (),
+            }
+        }
+        bucketed
+    }
+}
+
+// Test of a routing rule on the value of a tag
+// A multi-valued tag like ImageType is tested as its values joined with a backslash
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutePredicate {
+    // The value or one of the values
+    Equals(String),
+    Contains(String),
+    #[serde(serialize_with = "serialize_regex")]
+    Regex(Regex),
+}
+
+fn serialize_regex<S: serde::Serializer>(
+    regex: &Regex,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(regex.as_str())
+}
+
+// Files with a matching tag go to the destination of the route
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteRule {
+    pub route: String,
+    #[serde(skip)]
+    pub tag: Tag,
+    #[serde(rename = "tag")]
+    pub tag_name: String,
+    #[serde(flatten)]
+    pub predicate: RoutePredicate,
+}
+
+impl RouteRule {
+    pub fn matches(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> bool {
+        let values: Vec<String> = match dcm_obj.element_opt(self.tag) {
+            Ok(Some(element)) => match element.to_multi_str() {
+                Ok(values) => collapsed_values(&values),
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+        match &self.predicate {
+            RoutePredicate::Equals(expected) => values.iter().any(|v| v == expected),
+            RoutePredicate::Contains(text) => values.join("\\").contains(text.as_str()),
+            RoutePredicate::Regex(regex) => regex.is_match(&values.join("\\")),
+        }
+    }
+}
+
+// Destination root of a route given as NAME=PATH
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteDestination {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+pub fn parse_route_destination(route: &str) -> Result<RouteDestination> {
+    match route.split_once('=') {
+        Some((name, path)) if !name.trim().is_empty() && !path.trim().is_empty() => {
+            Ok(RouteDestination {
+                name: name.trim().to_string(),
+                path: PathBuf::from(path.trim()),
+            })
+        }
+        _ => Err(anyhow::Error::msg(format!(
+            "Invalid route {:?}, expected NAME=PATH eg derived=/data/derived",
+            route
+        ))),
+    }
+}
+
+// Routing of the written files to the destination roots of --route
+// Rules are tested in cookbook order and the first match wins,
+// files without a match go to the destination of the command
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    rules: Vec<RouteRule>,
+    destinations: HashMap<String, PathBuf>,
+    routed: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl Routes {
+    // Every route of the rules needs a destination
+    pub fn new(rules: Vec<RouteRule>, destinations: &[RouteDestination]) -> Result<Self> {
+        let destinations: HashMap<String, PathBuf> = destinations
+            .iter()
+            .map(|d| (d.name.clone(), d.path.clone()))
+            .collect();
+        if let Some(rule) = rules
+            .iter()
+            .find(|rule| !destinations.contains_key(&rule.route))
+        {
+            return Err(anyhow::Error::msg(format!(
+                "Route {} of the cookbook has no destination, add --route {}=<PATH>",
+                rule.route, rule.route
+            )));
+        }
+        for name in destinations.keys() {
+            if !rules.iter().any(|rule| &rule.route == name) {
+                warn!("No cookbook rule routes files to --route {}", name);
+            }
+        }
+        Ok(Routes {
+            rules,
+            destinations,
+            routed: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    // Destination root of a file, the default destination without a matching rule
+    pub fn destination<'a>(
+        &'a self,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        default_destination: &'a PathBuf,
+    ) -> &'a PathBuf {
+        if self.rules.is_empty() {
+            return default_destination;
+        }
+        let rule = self.rules.iter().find(|rule| rule.matches(dcm_obj));
+        let route = rule.map(|rule| rule.route.as_str()).unwrap_or("default");
+        *self
+            .routed
+            .lock()
+            .expect("Failed to lock mutex")
+            .entry(route.to_string())
+            .or_insert(0) += 1;
+        match rule {
+            Some(rule) => &self.destinations[&rule.route],
+            None => default_destination,
+        }
+    }
+
+    // Files routed per route, empty without routing
+    pub fn counts(&self) -> BTreeMap<String, u64> {
+        self.routed.lock().expect("Failed to lock mutex").clone()
+    }
+}
+
+// InstanceNumber of the sanitized tag values, orders the files in the buckets of a directory
+pub fn instance_order(dicom_tags_values: &HashMap<String, String>) -> Option<u64> {
+    dicom_tags_values
+        .get("InstanceNumber")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+}
+
+// Name of the study directories
+#[derive(Debug, Clone, Default, PartialEq, clap::ValueEnum, Serialize)]
+pub enum StudyDirStyle {
+    // <StudyDate>T<StudyTime>_<UID tail> of the written file, the flattened date with anon
+    #[default]
+    Date,
+    // STUDY_01, STUDY_02.. per patient in the order of the original study dates
+    Ordinal,
+}
+
+// Study directories of --study-dir-style ordinal, numbered per PatientID on the original
+// StudyDate, StudyTime and StudyInstanceUID of the files indexed before the run
+#[derive(Debug, Clone, Default)]
+pub struct StudyOrdinals {
+    ordinals: Arc<OnceLock<HashMap<(String, String), usize>>>,
+}
+
+impl StudyOrdinals {
+    pub fn index(&self, all_files: &[DirEntry], read_gate: &IoGate) {
+        info!("Ordering the studies of {} files", all_files.len());
+        let text = |dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag| {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|e| e.to_str().ok())
+                .map(|v| trim_uid(&v).to_string())
+                .unwrap_or_default()
+        };
+        let studies: BTreeSet<(String, String, String, String)> = all_files
+            .par_iter()
+            .filter_map(|each| {
+                let dcm_obj = read_gate
+                    .run(|| {
+                        dicom::object::OpenFileOptions::new()
+                            .read_until(tags::SERIES_INSTANCE_UID)
+                            .open_file(each.path())
+                    })
+                    .ok()?;
+                Some((
+                    text(&dcm_obj, tags::PATIENT_ID),
+                    text(&dcm_obj, tags::STUDY_DATE),
+                    text(&dcm_obj, tags::STUDY_TIME),
+                    text(&dcm_obj, tags::STUDY_INSTANCE_UID),
+                ))
+            })
+            .collect();
+        let mut ordinals = HashMap::new();
+        let mut studies_per_patient: HashMap<String, usize> = HashMap::new();
+        // A study with files of different dates is numbered on its earliest one
+        for (patient_id, _, _, study_uid) in studies {
+            if ordinals.contains_key(&(patient_id.clone(), study_uid.clone())) {
+                continue;
+            }
+            let count = studies_per_patient.entry(patient_id.clone()).or_insert(0);
+            *count += 1;
+            ordinals.insert((patient_id, study_uid), *count);
+        }
+        let _ = self.ordinals.set(ordinals);
+    }
+
+    // Directory name of the study of an original source file, STUDY_NA when it wasn't indexed
+    pub fn study_dir(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> String {
+        let text = |tag: Tag| {
+            dcm_obj
+                .element(tag)
+                .ok()
+                .and_then(|e| e.to_str().ok())
+                .map(|v| trim_uid(&v).to_string())
+                .unwrap_or_default()
+        };
+        let key = (text(tags::PATIENT_ID), text(tags::STUDY_INSTANCE_UID));
+        match self.ordinals.get().and_then(|ordinals| ordinals.get(&key)) {
+            Some(ordinal) => format!("STUDY_{:0>2}", ordinal),
+            None => "STUDY_NA".to_string(),
+        }
+    }
+}
+
+// Unit of work that is processed in parallel
+#[derive(Debug, Clone, Default, PartialEq, clap::ValueEnum, Serialize)]
+pub enum Parallelism {
+    // Every file on its own
+    #[default]
+    File,
+    // Series in parallel, the files of a series one after another in instance order
+    Series,
+}
+
+// Run the action on every source file with the given parallelism
+// With --deterministic the files are processed one after another in the indexed path order
+// Once the run is interrupted no further files are dispatched, returns the number left out
+pub fn for_each_source_file<F>(
+    all_files: &Vec<DirEntry>,
+    run_options: &RunOptions,
+    action: F,
+) -> u64
+where
+    F: Fn(&DirEntry) + Send + Sync,
+{
+    let not_dispatched = AtomicU64::new(0);
+    let dispatch = |each: &DirEntry| match interrupted() {
+        true => {
+            not_dispatched.fetch_add(1, Ordering::Relaxed);
+        }
+        false => action(each),
+    };
+    let dispatch_files = |files: &Vec<DirEntry>| {
+        if run_options.deterministic {
+            files.iter().for_each(dispatch);
+        } else {
+            match run_options.parallelism {
+                Parallelism::File => files.par_iter().for_each(dispatch),
+                Parallelism::Series => group_files_by_series(files, &run_options.read_gate)
+                    .par_iter()
+                    .for_each(|series| series.iter().for_each(|i| dispatch(&files[*i]))),
+            }
+        }
+    };
+    // With --progress-by top-dir the directories under the source root are processed one by one
+    match &run_options.top_dir_progress {
+        Some(top_dir_progress) => {
+            for (index, group_files) in top_dir_progress.group(all_files).iter().enumerate() {
+                dispatch_files(group_files);
+                top_dir_progress.dispatched(index);
+            }
+        }
+        None => dispatch_files(all_files),
+    }
+    let not_dispatched = not_dispatched.into_inner();
+    if not_dispatched > 0 {
+        warn!(
+            "Run interrupted, {} files were not processed",
+            not_dispatched
+        );
+    }
+    not_dispatched
+}
+
+// Progress of each directory directly under the source root for --progress-by top-dir
+// A directory is done once all its files are dispatched and have an outcome
+#[derive(Clone, Default)]
+pub struct TopDirProgress {
+    state: Arc<Mutex<TopDirState>>,
+}
+
+#[derive(Default)]
+struct TopDirState {
+    groups: Vec<TopDirGroup>,
+    group_of: HashMap<PathBuf, usize>,
+}
+
+struct TopDirGroup {
+    name: String,
+    files: u64,
+    recorded: u64,
+    failed: u64,
+    dispatched: bool,
+    logged: bool,
+}
+
+impl TopDirGroup {
+    fn log_if_done(&mut self) {
+        if self.dispatched && self.recorded >= self.files && !self.logged {
+            self.logged = true;
+            info!(
+                "{}: {} files, {} failed",
+                phi(&self.name),
+                with_thousands(self.files),
+                with_thousands(self.failed)
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for TopDirProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TopDirProgress").finish_non_exhaustive()
+    }
+}
+
+impl TopDirProgress {
+    // Split the files on their first path component under the source root, in name order
+    // Files directly in the source root or taken from a DICOMDIR are the group "."
+    fn group(&self, all_files: &[DirEntry]) -> Vec<Vec<DirEntry>> {
+        let mut by_name: BTreeMap<String, Vec<DirEntry>> = BTreeMap::new();
+        for each in all_files {
+            let name = match each.depth() {
+                0 | 1 => None,
+                depth => each
+                    .path()
+                    .ancestors()
+                    .nth(depth - 1)
+                    .and_then(|p| p.file_name()),
+            };
+            by_name
+                .entry(name.map_or(".".to_string(), |n| n.to_string_lossy().to_string()))
+                .or_default()
+                .push(each.clone());
+        }
+        let mut state = self.state.lock().expect("Failed to lock mutex");
+        for (index, (name, group_files)) in by_name.iter().enumerate() {
+            for each in group_files {
+                state.group_of.insert(each.path().to_path_buf(), index);
+            }
+            state.groups.push(TopDirGroup {
+                name: name.clone(),
+                files: group_files.len() as u64,
+                recorded: 0,
+                failed: 0,
+                dispatched: false,
+                logged: false,
+            });
+        }
+        info!(
+            "Processing {} top level directories in order",
+            by_name.len()
+        );
+        by_name.into_values().collect()
+    }
+
+    fn dispatched(&self, index: usize) {
+        let mut state = self.state.lock().expect("Failed to lock mutex");
+        if let Some(group) = state.groups.get_mut(index) {
+            group.dispatched = !interrupted();
+            group.log_if_done();
+        }
+    }
+
+    pub fn callback(&self) -> OutcomeCallback {
+        let progress = self.clone();
+        Arc::new(move |outcome: &FileOutcome| {
+            let mut state = progress.state.lock().expect("Failed to lock mutex");
+            let Some(index) = state.group_of.get(&outcome.source_path).copied() else {
+                return;
+            };
+            let group = &mut state.groups[index];
+            group.recorded += 1;
+            if matches!(
+                outcome.status,
+                FileStatus::Failed | FileStatus::TruncatedPixelData
+            ) {
+                group.failed += 1;
+            }
+            group.log_if_done();
+        })
+    }
+
+    // Log the directories not logged yet once the run ended, an interrupted run can be
+    // resumed from the first directory that was not fully dispatched
+    pub fn finish(&self) {
+        let mut state = self.state.lock().expect("Failed to lock mutex");
+        if let Some(first) = state.groups.iter().find(|group| !group.dispatched) {
+            warn!(
+                "{} is the first top level directory not fully processed",
+                phi(&first.name)
+            );
+        }
+        for group in state.groups.iter_mut().filter(|group| !group.logged) {
+            group.logged = true;
+            match group.dispatched {
+                true => info!(
+                    "{}: {} files, {} failed",
+                    phi(&group.name),
+                    with_thousands(group.files),
+                    with_thousands(group.failed)
+                ),
+                false => warn!(
+                    "{}: {} of {} files processed, {} failed",
+                    phi(&group.name),
+                    with_thousands(group.recorded),
+                    with_thousands(group.files),
+                    with_thousands(group.failed)
+                ),
+            }
+        }
+    }
+}
+
+// Count with a comma between the thousands eg 1,234
+fn with_thousands(count: u64) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+// Exit code of an interrupted run, also used when a second Ctrl-C forces the exit
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+// How long an interrupted run waits for the writes in flight
+const INTERRUPT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED_AT: OnceLock<Instant> = OnceLock::new();
+static INTERRUPT_HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// Stop dispatching files on the first SIGINT or SIGTERM, exit right away on the second
+pub fn install_interrupt_handler() -> Result<()> {
+    // The steps of a batch share the handler of the first step
+    if INTERRUPT_HANDLER_INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            error!("Interrupted again, exiting without waiting for the writes in flight");
+            exit(EXIT_INTERRUPTED);
+        }
+        INTERRUPTED_AT.get_or_init(Instant::now);
+        warn!("Interrupted, finishing the writes in flight. Press Ctrl-C again to exit now");
+    })?;
+    Ok(())
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Read a source file on a thread of its own with --file-timeout, None once the time is up
+// A parse that never ends can't be cancelled, its thread is left behind and the run moves on
+pub fn with_file_timeout<T: Send + 'static>(
+    file_path: &std::path::Path,
+    run_options: &RunOptions,
+    read: impl FnOnce(PathBuf) -> T + Send + 'static,
+) -> Option<T> {
+    let Some(file_timeout) = run_options.file_timeout else {
+        return Some(read(file_path.to_path_buf()));
+    };
+    let (read_sender, read_receiver) = crossbeam::channel::bounded(1);
+    let thread_path = file_path.to_path_buf();
+    std::thread::spawn(move || {
+        // Nobody is waiting any more once the file timed out
+        let _ = read_sender.send(read(thread_path));
+    });
+    match read_receiver.recv_timeout(file_timeout) {
+        Ok(read) => Some(read),
+        Err(RecvTimeoutError::Timeout) => {
+            error!(
+                "!! Reading {} did not finish within {} seconds, the file is abandoned",
+                phi(file_path.display()),
+                file_timeout.as_secs()
+            );
+            None
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            error!(
+                "!! Reading {} stopped with a panic",
+                phi(file_path.display())
+            );
+            None
+        }
+    }
+}
+
+// Copy a file abandoned by --file-timeout to FAILED_CASES
+pub fn record_file_timeout(
+    each_file: &DirEntry,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) {
+    let file_timeout = run_options.file_timeout.unwrap_or_default();
+    record_failed_case(
+        each_file,
+        format!("Timeout after {} seconds", file_timeout.as_secs()),
+        run_dirs,
+        run_options,
+        outcomes,
+    );
+}
+
+// Wait for the spawned writes of a run
+// After an interrupt the wait is limited, files still being written are left behind
+pub fn wait_for_writes(wg: WaitGroup) {
+    let (done_sender, done_receiver) = crossbeam::channel::bounded::<()>(1);
+    std::thread::spawn(move || {
+        wg.wait();
+        let _ = done_sender.send(());
+    });
+    loop {
+        match done_receiver.recv_timeout(Duration::from_millis(200)) {
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(interrupted_at) = INTERRUPTED_AT.get() {
+                    if interrupted_at.elapsed() > INTERRUPT_DRAIN_TIMEOUT {
+                        error!(
+                            "Writes in flight did not finish within {} seconds, the last files may be incomplete",
+                            INTERRUPT_DRAIN_TIMEOUT.as_secs()
+                        );
+                        return;
+                    }
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+// Series grouped per run for --parallelism series, 8 bytes of hash each
+static SERIES_GROUP_LIMIT: usize = 1_000_000;
+
+// Group the indices of the files by SeriesInstanceUID sorted by InstanceNumber
+// Only the header up to the instance level is read and a hash of the UID is kept per file
+// Non DICOM files and files without a SeriesInstanceUID are a group of their own, so are the
+// files of the series found past SERIES_GROUP_LIMIT
+fn group_files_by_series(all_files: &Vec<DirEntry>, read_gate: &IoGate) -> Vec<Vec<usize>> {
+    info!("Grouping {} files by series", all_files.len());
+    let series_keys: Vec<Option<(u64, i64)>> = all_files
+        .par_iter()
+        .map(|each| {
+            let dcm_obj = read_gate
+                .run(|| {
+                    dicom::object::OpenFileOptions::new()
+                        .read_until(tags::IMAGE_POSITION_PATIENT)
+                        .open_file(each.path())
+                })
+                .ok()?;
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            std::hash::Hash::hash(
+                trim_uid(
+                    &dcm_obj
+                        .element(tags::SERIES_INSTANCE_UID)
+                        .ok()?
+                        .to_str()
+                        .ok()?,
+                ),
+                &mut hasher,
+            );
+            let instance_number = dcm_obj
+                .element(tags::INSTANCE_NUMBER)
+                .ok()
+                .and_then(|e| e.to_int::<i64>().ok())
+                .unwrap_or(i64::MAX);
+            Some((std::hash::Hasher::finish(&hasher), instance_number))
+        })
+        .collect();
+
+    let mut series_index: HashMap<u64, usize> = HashMap::new();
+    let mut groups: Vec<Vec<(i64, usize)>> = Vec::new();
+    let mut full = false;
+    for (file_index, series_key) in series_keys.into_iter().enumerate() {
+        match series_key {
+            Some((series_hash, instance_number)) => match series_index.get(&series_hash) {
+                Some(group_index) => groups[*group_index].push((instance_number, file_index)),
+                None if series_index.len() >= SERIES_GROUP_LIMIT => {
+                    if !full {
+                        full = true;
+                        warn!(
+                            "{} series grouped, the files of further series are processed on their own",
+                            SERIES_GROUP_LIMIT
+                        );
+                    }
+                    groups.push(vec![(0, file_index)]);
+                }
+                None => {
+                    series_index.insert(series_hash, groups.len());
+                    groups.push(vec![(instance_number, file_index)]);
+                }
+            },
+            None => groups.push(vec![(0, file_index)]),
+        }
+    }
+    info!("Series found: {}", series_index.len());
+    groups
+        .into_iter()
+        .map(|mut group| {
+            group.sort();
+            group
+                .into_iter()
+                .map(|(_, file_index)| file_index)
+                .collect()
+        })
+        .collect()
+}
+
+// SOP classes for the keywords accepted by the SOP class filter
+static SOP_CLASS_KEYWORDS: [(&str, &[&str]); 9] = [
+    (
+        "SR",
+        &[
+            "1.2.840.10008.5.1.4.1.1.88.11",
+            "1.2.840.10008.5.1.4.1.1.88.22",
+            "1.2.840.10008.5.1.4.1.1.88.33",
+            "1.2.840.10008.5.1.4.1.1.88.34",
+            "1.2.840.10008.5.1.4.1.1.88.40",
+            "1.2.840.10008.5.1.4.1.1.88.50",
+            "1.2.840.10008.5.1.4.1.1.88.65",
+            "1.2.840.10008.5.1.4.1.1.88.67",
+            "1.2.840.10008.5.1.4.1.1.88.68",
+            "1.2.840.10008.5.1.4.1.1.88.69",
+            "1.2.840.10008.5.1.4.1.1.88.70",
+            "1.2.840.10008.5.1.4.1.1.88.71",
+            "1.2.840.10008.5.1.4.1.1.88.72",
+            "1.2.840.10008.5.1.4.1.1.88.73",
+            "1.2.840.10008.5.1.4.1.1.88.76",
+        ],
+    ),
+    (
+        "DOSESR",
+        &[
+            "1.2.840.10008.5.1.4.1.1.88.67",
+            "1.2.840.10008.5.1.4.1.1.88.68",
+            "1.2.840.10008.5.1.4.1.1.88.73",
+            "1.2.840.10008.5.1.4.1.1.88.76",
+        ],
+    ),
+    ("KO", &["1.2.840.10008.5.1.4.1.1.88.59"]),
+    ("GSPS", &["1.2.840.10008.5.1.4.1.1.11.1"]),
+    ("RTDOSE", &["1.2.840.10008.5.1.4.1.1.481.2"]),
+    ("RTSTRUCT", &["1.2.840.10008.5.1.4.1.1.481.3"]),
+    ("RTPLAN", &["1.2.840.10008.5.1.4.1.1.481.5"]),
+    ("SC", &["1.2.840.10008.5.1.4.1.1.7"]),
+    ("PDF", &["1.2.840.10008.5.1.4.1.1.104.1"]),
+];
+
+// Resolve SOP class keywords like SR, KO or RTDOSE and plain UIDs to a set of UIDs
+pub fn resolve_sop_classes(sop_class_list: &Vec<String>) -> HashSet<String> {
+    let mut sop_class_uids = HashSet::new();
+    for each in sop_class_list {
+        let each = each.trim();
+        if !each.is_empty() && each.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            sop_class_uids.insert(each.to_string());
+            continue;
+        }
+        match SOP_CLASS_KEYWORDS
+            .iter()
+            .find(|(keyword, _)| keyword.eq_ignore_ascii_case(&each.replace(['_', ' '], "")))
+        {
+            Some((_, uids)) => sop_class_uids.extend(uids.iter().map(|uid| uid.to_string())),
+            None => warn!("SOP class {} is not a valid UID or keyword", each),
+        }
+    }
+    sop_class_uids
+}
+
+// Check the SOPClassUID of the file against the excluded SOP classes
+pub fn is_excluded_sop_class(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    exclude_sop_classes: &HashSet<String>,
+) -> bool {
+    if exclude_sop_classes.is_empty() {
+        return false;
+    }
+    match dcm_obj.element(tags::SOP_CLASS_UID) {
+        Ok(element) => match element.to_str() {
+            Ok(sop_class) => exclude_sop_classes.contains(trim_uid(&sop_class)),
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+// Skip the file if its SOP class is excluded, Copies it to EXCLUDED_SOP with --keep-excluded
+pub fn skip_excluded_file(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    each_file: &DirEntry,
+    run_dirs: &RunDirs,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) -> bool {
+    if !is_excluded_sop_class(dcm_obj, &run_options.exclude_sop_classes) {
+        return false;
+    }
+    debug!(
+        "Skipping excluded SOP class {:?}",
+        phi(each_file.file_name().to_string_lossy())
+    );
+    let mut outcome = FileOutcome::new(each_file.path(), FileStatus::Filtered);
+    if let Some(excluded_dir) = &run_dirs.excluded {
+        match copy_excluded_files(each_file, excluded_dir, run_options) {
+            Ok(copy_path) => outcome.destination_path = Some(copy_path),
+            Err(_) => error!(
+                "Can't copy excluded file {:?}",
+                phi(each_file.file_name().to_string_lossy())
+            ),
+        }
+    }
+    outcomes.record(outcome);
+    true
+}
+
+// Skip a file whose SOPInstanceUID was already seen with --dedupe-global
+// Listed in global_duplicates.csv with the path of the file that was processed
+pub fn skip_global_duplicate(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    each_file: &DirEntry,
+    run_options: &RunOptions,
+    outcomes: &OutcomeTracker,
+) -> bool {
+    let Some(global_dedupe) = &run_options.global_dedupe else {
+        return false;
+    };
+    let Some(sop_instance_uid) = dcm_obj
+        .element(tags::SOP_INSTANCE_UID)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|uid| trim_uid(&uid).to_string())
+        .filter(|uid| !uid.is_empty())
+    else {
+        return false;
+    };
+    let kept_path = match global_dedupe.claim(&sop_instance_uid, each_file.path()) {
+        Ok(Some(kept_path)) => kept_path,
+        Ok(None) => return false,
+        Err(e) => {
+            warn!(
+                "Can't check {:?} for global duplicates, it is processed: {}",
+                phi(each_file.file_name().to_string_lossy()),
+                e
+            );
+            return false;
+        }
+    };
+    debug!(
+        "Skipping {} already processed from {}",
+        phi(each_file.path().display()),
+        phi(&kept_path)
+    );
+    global_dedupe.list(each_file.path(), &kept_path);
+    outcomes.record(FileOutcome::new(
+        each_file.path(),
+        FileStatus::GlobalDuplicate,
+    ));
+    true
+}
+
+// SOPInstanceUIDs of --dedupe-global with the path of the first file of each instance
+// Kept in a SQLite file in the destination so the set doesn't grow in memory on archives of
+// hundreds of millions of instances, a later run into the destination shares the set
+#[derive(Debug, Clone)]
+pub struct GlobalDedupe {
+    instances: Arc<Mutex<rusqlite::Connection>>,
+    duplicates_csv: Arc<Mutex<fs::File>>,
+}
+
+impl GlobalDedupe {
+    pub fn open(destination_path: &Path) -> Result<Self> {
+        let db_path = destination_path.join(GLOBAL_DEDUPE_DB_NAME);
+        let instances = rusqlite::Connection::open(&db_path).map_err(|e| {
+            anyhow::Error::msg(format!(
+                "Can't open the instances of --dedupe-global {}: {}",
+                db_path.display(),
+                e
+            ))
+        })?;
+        // The set is rebuilt by a rerun, a lost write only makes it process the file again
+        instances.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = OFF;
+            CREATE TABLE IF NOT EXISTS instances (
+                sop_instance_uid TEXT PRIMARY KEY,
+                path TEXT NOT NULL
+            ) WITHOUT ROWID;",
+        )?;
+        let csv_path = destination_path.join(GLOBAL_DUPLICATES_CSV_NAME);
+        let new_csv = !csv_path.exists();
+        let mut duplicates_csv = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&csv_path)?;
+        if new_csv {
+            std::io::Write::write_all(&mut duplicates_csv, b"path,kept_path\n")?;
+        }
+        info!(
+            "Global duplicates by SOPInstanceUID in: {}",
+            db_path.display()
+        );
+        Ok(GlobalDedupe {
+            instances: Arc::new(Mutex::new(instances)),
+            duplicates_csv: Arc::new(Mutex::new(duplicates_csv)),
+        })
+    }
+
+    // The path of the first file of the instance when it isn't this file, or record this file
+    // The same path again, eg a rerun on the same source, is processed as before
+    pub fn claim(&self, sop_instance_uid: &str, source_path: &Path) -> Result<Option<String>> {
+        let source_path = source_path.display().to_string();
+        let instances = self.instances.lock().expect("Failed to lock mutex");
+        let inserted = instances.execute(
+            "INSERT OR IGNORE INTO instances (sop_instance_uid, path) VALUES (?1, ?2)",
+            rusqlite::params![sop_instance_uid, source_path],
+        )?;
+        if inserted == 1 {
+            return Ok(None);
+        }
+        let kept_path: String = instances.query_row(
+            "SELECT path FROM instances WHERE sop_instance_uid = ?1",
+            rusqlite::params![sop_instance_uid],
+            |row| row.get(0),
+        )?;
+        Ok((kept_path != source_path).then_some(kept_path))
+    }
+
+    fn list(&self, source_path: &Path, kept_path: &str) {
+        let row = format!(
+            "{},{}\n",
+            csv_field(&source_path.display().to_string()),
+            csv_field(kept_path)
+        );
+        let mut duplicates_csv = self.duplicates_csv.lock().expect("Failed to lock mutex");
+        if let Err(e) = std::io::Write::write_all(&mut *duplicates_csv, row.as_bytes()) {
+            error!("Can't list a global duplicate: {}", e);
+        }
+    }
+}
+
+// Instances and global duplicates of --dedupe-global, written to the destination
+pub const GLOBAL_DEDUPE_DB_NAME: &str = "dedupe_global.db";
+pub const GLOBAL_DUPLICATES_CSV_NAME: &str = "global_duplicates.csv";
+
+// Options shared by the file name and path generation of all commands
+#[derive(Debug, Clone)]
+pub struct NamingOptions {
+    pub multi_value: MultiValueMode,
+    // Zero pad width of the InstanceNumber in file names
+    pub pad_width: usize,
+    // Derived values appended to the series directory, only these are computed
+    pub series_derived: DerivedValues,
+    // Tags extracted in the sanitized tag values of each file
+    pub sanitized_tags: SanitizedTags,
+}
+
+// Names of the tags in the sanitized tag values, the defaults and the tags declared by the
+// consumers of the values like the media names and the DICOMDIR, extracted in one pass
+#[derive(Debug, Clone)]
+pub struct SanitizedTags(Vec<String>);
+
+impl Default for SanitizedTags {
+    fn default() -> Self {
+        SanitizedTags(DEFAULT_SANITIZED_TAGS.map(|name| name.to_string()).to_vec())
+    }
+}
+
+impl SanitizedTags {
+    // Add the tags a consumer needs, unknown tag names are refused
+    pub fn with(mut self, tag_names: &[&str]) -> Result<Self> {
+        for tag_name in tag_names {
+            extract_tag_vr_from_str(&tag_name.to_string()).map_err(|_| {
+                anyhow::Error::msg(format!("Unknown tag {:?} in the sanitized tags", tag_name))
+            })?;
+            if !self.0.iter().any(|name| name == tag_name) {
+                self.0.push(tag_name.to_string());
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.0
+    }
+}
+
+// Values computed from a file for names and paths
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum DerivedValue {
+    // AX, COR, SAG or NA from ImageOrientationPatient
+    ImagePlane,
+    // MF with more than one frame, SF otherwise
+    MultiFrame,
+    // DERIVED or ORIGINAL from the first value of ImageType, NA without one
+    IsDerived,
+    // EchoTime rounded to whole milliseconds eg TE30, NA without one
+    EchoTimeMs,
+}
+
+const DERIVED_VALUES: [DerivedValue; 4] = [
+    DerivedValue::ImagePlane,
+    DerivedValue::MultiFrame,
+    DerivedValue::IsDerived,
+    DerivedValue::EchoTimeMs,
+];
+
+impl DerivedValue {
+    // Key of the value in the sanitized tag values
+    pub fn name(&self) -> &'static str {
+        match self {
+            DerivedValue::ImagePlane => "ImagePlane",
+            DerivedValue::MultiFrame => "MultiFrame",
+            DerivedValue::IsDerived => "IsDerived",
+            DerivedValue::EchoTimeMs => "EchoTimeMs",
+        }
+    }
+
+    fn compute(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<String> {
+        let value = match self {
+            DerivedValue::ImagePlane => determine_plane(dcm_obj)?,
+            DerivedValue::MultiFrame => match dcm_obj
+                .element_opt(tags::NUMBER_OF_FRAMES)?
+                .and_then(|v| v.to_int::<u32>().ok())
+            {
+                Some(frames) if frames > 1 => "MF".to_string(),
+                _ => "SF".to_string(),
+            },
+            DerivedValue::IsDerived => match dcm_obj
+                .element_opt(tags::IMAGE_TYPE)?
+                .and_then(|v| v.to_multi_str().ok().map(|v| v.to_vec()))
+                .and_then(|v| v.first().map(|t| t.trim().to_uppercase()))
+            {
+                Some(image_type) if image_type == "DERIVED" || image_type == "ORIGINAL" => {
+                    image_type
+                }
+                _ => "NA".to_string(),
+            },
+            DerivedValue::EchoTimeMs => match dcm_obj
+                .element_opt(tags::ECHO_TIME)?
+                .and_then(|v| v.to_float64().ok())
+            {
+                Some(echo_time) if echo_time.is_finite() => {
+                    format!("TE{}", echo_time.round() as i64)
+                }
+                _ => "NA".to_string(),
+            },
+        };
+        Ok(value)
+    }
+}
+
+// The derived values of a layout, ImagePlane by default
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DerivedValues(pub Vec<DerivedValue>);
+
+impl Default for DerivedValues {
+    fn default() -> Self {
+        DerivedValues(vec![DerivedValue::ImagePlane])
+    }
+}
+
+impl DerivedValues {
+    // Directory name suffix eg _AX or _AX_TE30, empty without derived values
+    pub fn suffix(&self, dicom_tags_values: &HashMap<String, String>) -> String {
+        self.0
+            .iter()
+            .map(|derived| {
+                format!(
+                    "_{}",
+                    dicom_tags_values
+                        .get(derived.name())
+                        .expect("Failed to extract value")
+                        .trim()
+                )
+            })
+            .collect()
+    }
+}
+
+// Parse a comma separated list of derived value names eg ImagePlane,EchoTimeMs, or none
+pub fn parse_derived_values(values: &str) -> Result<DerivedValues> {
+    if values.trim().eq_ignore_ascii_case("none") {
+        return Ok(DerivedValues(Vec::new()));
+    }
+    let mut derived_values = Vec::new();
+    for name in values.split(',').map(|name| name.trim()) {
+        match DERIVED_VALUES
+            .iter()
+            .find(|derived| derived.name().eq_ignore_ascii_case(name))
+        {
+            Some(derived) if !derived_values.contains(derived) => derived_values.push(*derived),
+            Some(_) => (),
+            None => {
+                return Err(anyhow::Error::msg(format!(
+                    "Unknown derived value {:?}, expected none or any of {}",
+                    name,
+                    DERIVED_VALUES.map(|derived| derived.name()).join(", ")
+                )))
+            }
+        }
+    }
+    Ok(DerivedValues(derived_values))
+}
+
+// Sanitized values of the tags of the naming options
+// The derived values of the naming options are added under their name
+pub fn get_sanitized_tag_values(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    naming: &NamingOptions,
+) -> Result<HashMap<String, String>> {
+    let mut dicom_tags_values =
+        sanitized_tag_values(dcm_obj, naming.sanitized_tags.names(), naming)?;
+    for derived in &naming.series_derived.0 {
+        dicom_tags_values.insert(derived.name().to_string(), derived.compute(dcm_obj)?);
+    }
+    Ok(dicom_tags_values)
+}
+
+// For a given list of tags. Get the sanitized values.
+// Removes all unnecessary characters and adds NoValue_ if value is not found for the tag
+pub fn sanitized_tag_values(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    tag_names: &[String],
+    naming: &NamingOptions,
+) -> Result<HashMap<String, String>> {
+    let mut dicom_tags_values = HashMap::new();
+    for each_tag in tag_names {
+        match dcm_obj.element_by_name(each_tag) {
+            Ok(tv) => {
+                let mut multi_str = tv.to_multi_str()?.to_vec();
+                // Only the alphabetic group of a name is used in paths
+                if tv.vr() == VR::PN {
+                    multi_str = multi_str
+                        .iter()
+                        .map(|v| PersonName::parse(v).alphabetic())
+                        .collect();
+                }
+                // The raw value is kept next to the collapsed one for the audit of the outcomes
+                if COLLAPSED_VALUE_TAGS.contains(&each_tag.as_str()) {
+                    let collapsed = collapsed_values(&multi_str);
+                    if collapsed != multi_str {
+                        let raw_value = multi_str.join("\\");
+                        debug!(
+                            "{} {:?} read as {:?}",
+                            each_tag,
+                            phi(&raw_value),
+                            phi(collapsed.join("\\"))
+                        );
+                        dicom_tags_values.insert(format!("{}_raw", each_tag), raw_value);
+                        multi_str = collapsed;
+                    }
+                }
+                let tag_value = match naming.multi_value {
+                    MultiValueMode::Join => multi_str
+                        .iter()
+                        .map(|v| trim_padding(v))
+                        .filter(|v| !v.is_empty())
+                        .collect::<Vec<_>>()
+                        .join("_"),
+                    MultiValueMode::First => match multi_str.first() {
+                        Some(v) => trim_padding(v).to_string(),
+                        None => "".to_string(),
+                    },
+                };
+                dicom_tags_values
+                    .insert(each_tag.to_string(), tag_value.replace(&['-', ':'][..], ""));
+            }
+            Err(_) => {
+                warn!("No value for {}", each_tag);
+                let final_value = format!("NoValue_{}", each_tag);
+                dicom_tags_values.insert(each_tag.to_string(), final_value);
+            }
+        }
+    }
+    Ok(dicom_tags_values)
+}
+
+// Identity and routing tags whose repeated values are read as one, eg a Modality of MR\MR
+pub static COLLAPSED_VALUE_TAGS: [&str; 3] = ["PatientID", "PatientName", "Modality"];
+
+// Values without their NUL and space padding, empty values dropped and repeated values
+// collapsed to the first, so padded or duplicated values give one directory name and AnonID
+pub fn collapsed_values(values: &[String]) -> Vec<String> {
+    let mut collapsed: Vec<String> = Vec::new();
+    for each_value in values {
+        let each_value = trim_padding(each_value);
+        if !each_value.is_empty() && !collapsed.iter().any(|v| v == each_value) {
+            collapsed.push(each_value.to_string());
+        }
+    }
+    collapsed
+}
+
+// Text values are padded with a space, some writers pad with NUL
+pub fn trim_padding(value: &str) -> &str {
+    value.trim_matches(|c: char| c == '\0' || c.is_whitespace())
+}
+
+// Run a destination write on the rayon pool, or right away with --deterministic
+// so the file keeping the name of a name collision follows the source path order
+// and with --parallelism series so the files of a series are written one after another
+// The write waits for a permit of --write-concurrency, its error goes to failed once the permit
+// is released as recording a failed case copies to FAILED_CASES through the same gate
+// The wait group is released after both
+pub fn spawn_write<W, F>(run_options: &RunOptions, wg: WaitGroup, write: W, failed: F)
+where
+    W: FnOnce() -> Result<()> + Send + 'static,
+    F: FnOnce(anyhow::Error) + Send + 'static,
+{
+    let write_gate = run_options.write_gate.clone();
+    let gated_write = move || {
+        if let Err(e) = write_gate.run(write) {
+            failed(e);
+        }
+        drop(wg);
+    };
+    match run_options.deterministic || run_options.parallelism == Parallelism::Series {
+        true => gated_write(),
+        false => rayon::spawn(gated_write),
+    }
+}
+
+// Check if the target directory exists and create a new one recursively if it does not exist
+pub fn create_target_dir(dir_path: &String) -> Result<()> {
+    if !PathBuf::from(dir_path).exists() {
+        create_dir_all(PathBuf::from(dir_path))?
+    }
+    Ok(())
+}
+
+// DeidentificationMethod values and DeidentificationMethodCodeSequence items
+#[derive(Debug, Clone, Serialize)]
+pub struct DeidMethod {
+    pub methods: Vec<String>,
+    pub codes: Vec<MethodCode>,
+}
+
+impl Default for DeidMethod {
+    fn default() -> Self {
+        DeidMethod {
+            methods: vec!["DCMRig".to_string()],
+            codes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct MethodCode {
+    pub value: String,
+    pub scheme: String,
+    pub meaning: String,
+}
+
+// Write DeidentificationMethod as a multi-valued LO and the code sequence when codes are given
+pub fn put_deid_method(dcm_obj: &mut FileDicomObject<InMemDicomObject>, deid_method: &DeidMethod) {
+    dcm_obj.put(DataElement::new(
+        tags::DEIDENTIFICATION_METHOD,
+        VR::LO,
+        PrimitiveValue::Strs(deid_method.methods.iter().cloned().collect()),
+    ));
+    if deid_method.codes.is_empty() {
+        return;
+    }
+    let items: Vec<InMemDicomObject> = deid_method
+        .codes
+        .iter()
+        .map(|code| {
+            InMemDicomObject::from_element_iter([
+                DataElement::new(
+                    tags::CODE_VALUE,
+                    VR::SH,
+                    dicom_value!(Str, code.value.clone()),
+                ),
+                DataElement::new(
+                    tags::CODING_SCHEME_DESIGNATOR,
+                    VR::SH,
+                    dicom_value!(Str, code.scheme.clone()),
+                ),
+                DataElement::new(
+                    tags::CODE_MEANING,
+                    VR::LO,
+                    dicom_value!(Str, code.meaning.clone()),
+                ),
+            ])
+        })
+        .collect();
+    dcm_obj.put(DataElement::new(
+        tags::DEIDENTIFICATION_METHOD_CODE_SEQUENCE,
+        VR::SQ,
+        Value::Sequence(DataSetSequence::from(items)),
+    ));
+}
+
+// Change certain tags to the given ID and add deidentified tags.
+// Returns a cloned dicom object with modified values and the elements changed per pass
+pub fn mask_tags_with_id(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    patient_deid: String,
+    deid_method: &DeidMethod,
+    length_policy: &LengthPolicy,
+    keep: &KeepList,
+) -> Result<(FileDicomObject<InMemDicomObject>, RuleCounts)> {
+    let mut rule_counts = RuleCounts::new();
+    let p_value = dicom_vr_corrected_value(VR::PN, &patient_deid, length_policy)?;
+    // Mask all PN values with the given ID
+    let pn_changed;
+    (dcm_obj, pn_changed) = mask_all_vr(dcm_obj.clone(), VR::PN, p_value.clone(), keep)?;
+    rule_counts.insert("anon PN values".to_string(), pn_changed);
+
+    let mut id_changed = 0;
+    for each_v in DICOM_TAGS_CHANGE {
+        if keep.protects(each_v.0, "the AnonID") {
+            continue;
+        }
+        let p_value = dicom_vr_corrected_value(each_v.1, &patient_deid, length_policy)?;
+        dcm_obj.put(DataElement::new(each_v.0, each_v.1, p_value.clone()));
+        id_changed += 1;
+    }
+    rule_counts.insert("anon AnonID tags".to_string(), id_changed);
+    let mut free_text_changed = 0;
+    for each_tag in FREE_TEXT_TAGS {
+        let Ok(element) = dcm_obj.element(each_tag) else {
+            continue;
+        };
+        if keep.protects(each_tag, "the free text emptying") {
+            continue;
+        }
+        let each_vr = element.vr();
+        dcm_obj.put(DataElement::new(each_tag, each_vr, PrimitiveValue::Empty));
+        free_text_changed += 1;
+    }
+    rule_counts.insert("anon free text".to_string(), free_text_changed);
+    // Add deidentified Info
+    put_deid_method(&mut dcm_obj, deid_method);
+    dcm_obj.put(DataElement::new(
+        tags::PATIENT_IDENTITY_REMOVED,
+        VR::CS,
+        dicom_value!(Strs, ["YES".to_string()]),
+    ));
+    Ok((dcm_obj, rule_counts))
+}
+
+// Institution identifiers, InstitutionName takes the site code with replace
+static INSTITUTION_TAGS: [(Tag, VR); 3] = [
+    (tags::INSTITUTION_NAME, VR::LO),
+    (tags::INSTITUTION_ADDRESS, VR::ST),
+    (tags::INSTITUTIONAL_DEPARTMENT_NAME, VR::LO),
+];
+
+// Names of the staff involved in the study
+static STAFF_NAME_TAGS: [Tag; 4] = [
+    tags::OPERATORS_NAME,
+    tags::PERFORMING_PHYSICIAN_NAME,
+    tags::PHYSICIANS_OF_RECORD,
+    tags::REFERRING_PHYSICIAN_NAME,
+];
+
+// What to do with the institution identifiers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum InstitutionPolicy {
+    Remove,
+    // Site code for InstitutionName, the address and department are removed
+    Replace(String),
+    Keep,
+    // The AnonID or DeID, the anon behaviour without a policy
+    Id,
+}
+
+// Parse remove, keep, id or replace:<site code>
+pub fn parse_institution_policy(policy: &str) -> Result<InstitutionPolicy> {
+    match policy.trim() {
+        "remove" => Ok(InstitutionPolicy::Remove),
+        "keep" => Ok(InstitutionPolicy::Keep),
+        "id" => Ok(InstitutionPolicy::Id),
+        other => match other.strip_prefix("replace:") {
+            Some(site_code)
+                if !site_code.is_empty()
+                    && site_code.chars().count() <= 64
+                    && !site_code.contains('\\') =>
+            {
+                Ok(InstitutionPolicy::Replace(site_code.to_string()))
+            }
+            _ => Err(anyhow::Error::msg(format!(
+                "Invalid institution policy {:?}, expected remove, keep, id or replace:<site code of 1 to 64 characters>",
+                policy
+            ))),
+        },
+    }
+}
+
+// What to do with OperatorsName, PerformingPhysicianName, PhysiciansOfRecord and ReferringPhysicianName
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaffNamePolicy {
+    Remove,
+    // Present but zero length
+    Empty,
+    // The AnonID or DeID, the anon behaviour without a policy
+    Replace,
+    Keep,
+}
+
+// Identity policy of anon and deid, unset parts keep the behaviour of the command
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IdentityPolicy {
+    pub institution: Option<InstitutionPolicy>,
+    pub staff_names: Option<StaffNamePolicy>,
+}
+
+// Apply the identity policy after the other tag actions
+// Only the identifiers present in the original file are touched, keep restores their original values
+pub fn apply_identity_policy(
+    dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+    original_obj: &FileDicomObject<InMemDicomObject>,
+    identity: &IdentityPolicy,
+    patient_id: &str,
+    length_policy: &LengthPolicy,
+    keep: &KeepList,
+) -> Result<()> {
+    if let Some(institution) = &identity.institution {
+        for (each_tag, each_vr) in INSTITUTION_TAGS {
+            let original = match original_obj.element(each_tag) {
+                Ok(element) => element.clone(),
+                Err(_) => continue,
+            };
+            if keep.protects(each_tag, "the institution policy") {
+                dcm_obj.put(original);
+                continue;
+            }
+            match (institution, each_tag == tags::INSTITUTION_NAME) {
+                (InstitutionPolicy::Keep, _) => {
+                    dcm_obj.put(original);
+                }
+                (InstitutionPolicy::Id, _) => {
+                    let value =
+                        dicom_vr_corrected_value(each_vr, &patient_id.to_string(), length_policy)?;
+                    dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+                }
+                (InstitutionPolicy::Replace(site_code), true) => {
+                    let value = dicom_vr_corrected_value(each_vr, site_code, length_policy)?;
+                    dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+                }
+                (InstitutionPolicy::Replace(_), false) | (InstitutionPolicy::Remove, _) => {
+                    dcm_obj.remove_element(each_tag);
+                }
+            }
+        }
+    }
+    if let Some(staff_names) = &identity.staff_names {
+        for each_tag in STAFF_NAME_TAGS {
+            let original = match original_obj.element(each_tag) {
+                Ok(element) => element.clone(),
+                Err(_) => continue,
+            };
+            if keep.protects(each_tag, "the staff name policy") {
+                dcm_obj.put(original);
+                continue;
+            }
+            match staff_names {
+                StaffNamePolicy::Keep => {
+                    dcm_obj.put(original);
+                }
+                StaffNamePolicy::Replace => {
+                    let value =
+                        dicom_vr_corrected_value(VR::PN, &patient_id.to_string(), length_policy)?;
+                    dcm_obj.put(DataElement::new(each_tag, VR::PN, value));
+                }
+                StaffNamePolicy::Empty => {
+                    dcm_obj.put(DataElement::new(each_tag, VR::PN, PrimitiveValue::Empty));
+                }
+                StaffNamePolicy::Remove => {
+                    dcm_obj.remove_element(each_tag);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// When an add value is written, the plain cookbook value always overwrites
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddMode {
+    #[default]
+    Overwrite,
+    // Only when the tag is absent, or present without a value with empty_is_missing
+    IfMissing {
+        empty_is_missing: bool,
+    },
+}
+
+impl AddMode {
+    pub fn applies(&self, element: Option<&InMemElement>) -> bool {
+        match (self, element) {
+            (AddMode::Overwrite, _) | (AddMode::IfMissing { .. }, None) => true,
+            (AddMode::IfMissing { empty_is_missing }, Some(each_element)) => {
+                *empty_is_missing && element_is_empty(each_element)
+            }
+        }
+    }
+}
+
+// Zero length or only padding, a sequence without items
+fn element_is_empty(element: &InMemElement) -> bool {
+    match element.value() {
+        Value::Primitive(value) => value.to_str().trim_matches([' ', '\0']).is_empty(),
+        Value::Sequence(sequence) => sequence.items().is_empty(),
+        Value::PixelSequence(_) => false,
+    }
+}
+
+// Stages of the tag modification pipeline, applied in order to each element
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagStage {
+    DeletePrivate,
+    Mask,
+    Add,
+    Delete,
+}
+
+// Order of the stages unless the cookbook sets one
+pub static DEFAULT_TAG_STAGE_ORDER: [TagStage; 4] = [
+    TagStage::DeletePrivate,
+    TagStage::Mask,
+    TagStage::Add,
+    TagStage::Delete,
+];
+
+impl TagStage {
+    // Name of the stage in the cookbook order list
+    pub fn name(&self) -> &'static str {
+        match self {
+            TagStage::DeletePrivate => "delete_private",
+            TagStage::Mask => "mask",
+            TagStage::Add => "add",
+            TagStage::Delete => "delete",
+        }
+    }
+}
+
+pub fn parse_tag_stage(stage_name: &str) -> Result<TagStage> {
+    DEFAULT_TAG_STAGE_ORDER
+        .iter()
+        .find(|stage| stage.name() == stage_name)
+        .copied()
+        .ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "Unknown stage {:?}, expected one of {}",
+                stage_name,
+                DEFAULT_TAG_STAGE_ORDER
+                    .iter()
+                    .map(|stage| stage.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })
+}
+
+// Tag modifications resolved once per file and applied by apply_tag_actions
+// A later stage in the order wins over an earlier one for the same element
+pub struct TagActions {
+    pub order: Vec<TagStage>,
+    // Tags masked with their dictionary VR, missing tags are added
+    pub mask_tags: HashMap<Tag, (VR, PrimitiveValue)>,
+    // Every element with the VR is masked
+    pub mask_vrs: HashMap<VR, PrimitiveValue>,
+    pub add: HashMap<Tag, (VR, PrimitiveValue)>,
+    // Added tags that are only written when missing, the others are overwritten
+    pub add_modes: HashMap<Tag, AddMode>,
+    pub delete: HashSet<Tag>,
+    // Tags left as they are by every stage
+    pub keep: KeepList,
+}
+
+impl TagActions {
+    // Every rule of the actions with no element changed yet, named as in the rule table
+    fn rule_counts(&self) -> RuleCounts {
+        let mut rule_counts = RuleCounts::new();
+        if self.order.contains(&TagStage::DeletePrivate) {
+            rule_counts.insert(TagStage::DeletePrivate.name().to_string(), 0);
+        }
+        for tag in self.mask_tags.keys() {
+            rule_counts.insert(format!("mask {}", tag_keyword(*tag)), 0);
+        }
+        for vr in self.mask_vrs.keys() {
+            rule_counts.insert(format!("mask vrs {}", vr), 0);
+        }
+        for tag in self.add.keys() {
+            rule_counts.insert(format!("add {}", tag_keyword(*tag)), 0);
+        }
+        for tag in &self.delete {
+            rule_counts.insert(format!("delete {}", tag_keyword(*tag)), 0);
+        }
+        rule_counts
+    }
+}
+
+// Tags of the cookbook [keep] list, the modification passes never alter them
+#[derive(Debug, Clone, Default)]
+pub struct KeepList {
+    tags: Arc<HashSet<Tag>>,
+}
+
+impl KeepList {
+    pub fn new(tags: impl IntoIterator<Item = Tag>) -> Self {
+        KeepList {
+            tags: Arc::new(tags.into_iter().collect()),
+        }
+    }
+
+    pub fn contains(&self, tag: Tag) -> bool {
+        self.tags.contains(&tag)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    // True when the tag is kept, the rule that would have changed it is logged
+    pub fn protects(&self, tag: Tag, rule: &str) -> bool {
+        let kept = self.contains(tag);
+        if kept {
+            info!(
+                "[keep] {} is kept, {} is suppressed",
+                tag_keyword(tag),
+                rule
+            );
+        }
+        kept
+    }
+}
+
+// Apply mask, add and delete actions in a single pass over the dataset
+// Each element goes through the stages in order, sequences are only visited to delete private tags
+// Returns the elements changed per rule, every rule of the actions is listed
+pub fn apply_tag_actions(
+    dcm_obj: FileDicomObject<InMemDicomObject>,
+    actions: &TagActions,
+) -> Result<(FileDicomObject<InMemDicomObject>, RuleCounts)> {
+    let meta = dcm_obj.meta().clone();
+    let mut present_tags: HashSet<Tag> = HashSet::new();
+    let mut new_elements: Vec<InMemElement> = vec![];
+    let mut rule_counts = actions.rule_counts();
+
+    for each_element in dcm_obj.into_inner() {
+        let tag = each_element.tag();
+        present_tags.insert(tag);
+        if let Some(new_element) =
+            apply_tag_stages(tag, Some(each_element), actions, &mut rule_counts)
+        {
+            new_elements.push(new_element);
+        }
+    }
+
+    // Masked and added tags that are not in the dataset yet
+    let mut missing_tags: Vec<Tag> = actions
+        .mask_tags
+        .keys()
+        .chain(actions.add.keys())
+        .filter(|tag| !present_tags.contains(tag))
+        .copied()
+        .collect();
+    missing_tags.sort();
+    missing_tags.dedup();
+    for each_tag in missing_tags {
+        if let Some(new_element) = apply_tag_stages(each_tag, None, actions, &mut rule_counts) {
+            debug!("Tag {:?} not found, added", each_tag);
+            new_elements.push(new_element);
+        }
+    }
+
+    Ok((
+        InMemDicomObject::from_element_iter(new_elements).with_exact_meta(meta),
+        rule_counts,
+    ))
+}
+
+fn apply_tag_stages(
+    tag: Tag,
+    mut element: Option<InMemElement>,
+    actions: &TagActions,
+    rule_counts: &mut RuleCounts,
+) -> Option<InMemElement> {
+    let mut count = |rule: String| *rule_counts.entry(rule).or_insert(0) += 1;
+    if actions.keep.contains(tag) {
+        // Only logged when one of the actions would have changed the tag
+        let targeted = actions.mask_tags.contains_key(&tag)
+            || actions.add.contains_key(&tag)
+            || actions.delete.contains(&tag)
+            || element
+                .as_ref()
+                .is_some_and(|each_element| actions.mask_vrs.contains_key(&each_element.vr()))
+            || (tag == ORIGINAL_ATTRIBUTES_SEQUENCE
+                && actions.order.contains(&TagStage::DeletePrivate));
+        if targeted {
+            actions.keep.protects(tag, "the cookbook tag actions");
+        }
+        return element;
+    }
+    for each_stage in &actions.order {
+        element = match each_stage {
+            // Also inside the sequence items, the separate private tag pass before the single
+            // pass pipeline only removed the top level ones
+            TagStage::DeletePrivate => {
+                if is_private_tag(tag) || tag == ORIGINAL_ATTRIBUTES_SEQUENCE {
+                    if element.is_some() {
+                        count(TagStage::DeletePrivate.name().to_string());
+                    }
+                    None
+                } else {
+                    element.map(remove_private_in_sequence)
+                }
+            }
+            TagStage::Mask => {
+                let element = match actions.mask_tags.get(&tag) {
+                    Some((vr, value)) => {
+                        count(format!("mask {}", tag_keyword(tag)));
+                        Some(DataElement::new(tag, *vr, value.clone()))
+                    }
+                    None => element,
+                };
+                match element {
+                    Some(each_element) => match actions.mask_vrs.get(&each_element.vr()) {
+                        Some(value) => {
+                            count(format!("mask vrs {}", each_element.vr()));
+                            Some(DataElement::new(tag, each_element.vr(), value.clone()))
+                        }
+                        None => Some(each_element),
+                    },
+                    None => None,
+                }
+            }
+            TagStage::Add => match actions.add.get(&tag) {
+                Some((vr, value))
+                    if actions
+                        .add_modes
+                        .get(&tag)
+                        .copied()
+                        .unwrap_or_default()
+                        .applies(element.as_ref()) =>
+                {
+                    count(format!("add {}", tag_keyword(tag)));
+                    Some(DataElement::new(tag, *vr, value.clone()))
+                }
+                _ => element,
+            },
+            TagStage::Delete => match actions.delete.contains(&tag) {
+                true => {
+                    if element.is_some() {
+                        count(format!("delete {}", tag_keyword(tag)));
+                    }
+                    None
+                }
+                false => element,
+            },
+        };
+    }
+    element
+}
+
+// Private tags have an odd group number
+pub fn is_private_tag(tag: Tag) -> bool {
+    tag.group() % 2 == 1
+}
+
+// SpecificCharacterSet of the written files
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, Serialize)]
+pub enum OutputCharset {
+    // Leave the SpecificCharacterSet of the source
+    Keep,
+    // ISO_IR 192
+    Utf8,
+    // ISO_IR 100, falls back to UTF-8 for text outside of Latin-1
+    Latin1,
+}
+
+// Declare the output charset on the object
+// Text values are decoded on read, the encoder writes them in the declared SpecificCharacterSet
+pub fn normalize_charset(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    charset: &OutputCharset,
+) -> Result<FileDicomObject<InMemDicomObject>> {
+    let charset_term = match charset {
+        OutputCharset::Keep => return Ok(dcm_obj),
+        OutputCharset::Utf8 => "ISO_IR 192",
+        OutputCharset::Latin1 => match text_fits_latin1(&dcm_obj) {
+            true => "ISO_IR 100",
+            false => {
+                warn!("Text can't be represented in ISO_IR 100, Using ISO_IR 192");
+                "ISO_IR 192"
+            }
+        },
+    };
+    dcm_obj.put(DataElement::new(
+        tags::SPECIFIC_CHARACTER_SET,
+        VR::CS,
+        dicom_value!(Strs, [charset_term.to_string()]),
+    ));
+    Ok(dcm_obj)
+}
+
+// Check every text value including the ones in sequences
+fn text_fits_latin1(dcm_obj: &InMemDicomObject) -> bool {
+    visit_elements(dcm_obj, |element, _depth| match element.value() {
+        Value::Primitive(_) => match element.vr() {
+            VR::SH | VR::LO | VR::ST | VR::LT | VR::UT | VR::PN | VR::UC => element
+                .to_str()
+                .map(|text| text.chars().all(|c| (c as u32) <= 0xFF))
+                .unwrap_or(true),
+            _ => true,
+        },
+        _ => true,
+    })
+}
+
+// Remove private tags from the items of a sequence element
+fn remove_private_in_sequence(element: InMemElement) -> InMemElement {
+    retain_in_sequences(element, |each_element| !is_private_tag(each_element.tag()))
+}
+
+// Sequence traversals use a stack of the open items instead of recursion
+// SR content trees nested hundreds of levels deep would overflow the stack otherwise
+
+// Visit the elements of the object and of its sequence items at any depth, depth first
+// depth is 0 for the top level elements. Stops at the first element visit returns false for
+// Returns true when every element was visited
+pub fn visit_elements<'a>(
+    dcm_obj: &'a InMemDicomObject,
+    mut visit: impl FnMut(&'a InMemElement, usize) -> bool,
+) -> bool {
+    let mut stack = vec![(dcm_obj.iter(), 0)];
+    while let Some((elements, depth)) = stack.last_mut() {
+        let depth = *depth;
+        let Some(element) = elements.next() else {
+            stack.pop();
+            continue;
+        };
+        if !visit(element, depth) {
+            return false;
+        }
+        if let Some(items) = element.items() {
+            // Reversed so the first item is visited first
+            stack.extend(items.iter().rev().map(|item| (item.iter(), depth + 1)));
+        }
+    }
+    true
+}
+
+// Items of a sequence being rebuilt by rebuild_in_sequences
+struct OpenSequence {
+    tag: Tag,
+    items: std::vec::IntoIter<InMemDicomObject>,
+    rebuilt_items: Vec<InMemDicomObject>,
+    // Elements of the current item left to check and the ones kept so far
+    elements: Option<std::vec::IntoIter<InMemElement>>,
+    kept: Vec<InMemElement>,
+}
+
+impl OpenSequence {
+    // The element back when it is not a sequence
+    fn open(element: InMemElement) -> std::result::Result<Self, InMemElement> {
+        if element.vr() != VR::SQ || element.items().is_none() {
+            return Err(element);
+        }
+        let tag = element.tag();
+        match element.into_value() {
+            Value::Sequence(sequence) => Ok(OpenSequence {
+                tag,
+                items: sequence
+                    .into_items()
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+                rebuilt_items: Vec::new(),
+                elements: None,
+                kept: Vec::new(),
+            }),
+            value => Err(DataElement::new(tag, VR::SQ, value)),
+        }
+    }
+}
+
+// Rebuild an element with only the elements of its sequence items at any depth that keep
+// returns true for, the element itself is always kept
+pub fn retain_in_sequences(
+    element: InMemElement,
+    keep: impl Fn(&InMemElement) -> bool,
+) -> InMemElement {
+    rebuild_in_sequences(element, |each_element| {
+        keep(&each_element).then_some(each_element)
+    })
+}
+
+// Rebuild an element with the elements of its sequence items at any depth replaced by what
+// rebuild returns for them, None drops the element. Sequences returned are opened in turn
+// and the element itself is always kept as is
+pub fn rebuild_in_sequences(
+    element: InMemElement,
+    mut rebuild: impl FnMut(InMemElement) -> Option<InMemElement>,
+) -> InMemElement {
+    let mut stack = match OpenSequence::open(element) {
+        Ok(sequence) => vec![sequence],
+        Err(element) => return element,
+    };
+    loop {
+        let sequence = stack.last_mut().expect("A sequence is open");
+        match sequence
+            .elements
+            .as_mut()
+            .and_then(|elements| elements.next())
+        {
+            Some(each_element) => match rebuild(each_element).map(OpenSequence::open) {
+                Some(Ok(nested)) => stack.push(nested),
+                Some(Err(each_element)) => sequence.kept.push(each_element),
+                None => (),
+            },
+            None => {
+                if sequence.elements.take().is_some() {
+                    let item =
+                        InMemDicomObject::from_element_iter(std::mem::take(&mut sequence.kept));
+                    sequence.rebuilt_items.push(item);
+                }
+                if let Some(item) = sequence.items.next() {
+                    sequence.elements = Some(item.into_iter().collect::<Vec<_>>().into_iter());
+                    continue;
+                }
+                let sequence = stack.pop().expect("A sequence is open");
+                let rebuilt = DataElement::new(
+                    sequence.tag,
+                    VR::SQ,
+                    Value::Sequence(DataSetSequence::from(sequence.rebuilt_items)),
+                );
+                match stack.last_mut() {
+                    Some(parent) => parent.kept.push(rebuilt),
+                    None => return rebuilt,
+                }
+            }
+        }
+    }
+}
+
+// Distinct private tags and private creators tracked, later ones go to the overflow count
+static PRIVATE_TAG_STATS_LIMIT: usize = 10_000;
+
+// Rows of the private tags removed table of the run summary, the rest is summed into other
+pub static PRIVATE_TAG_REPORT_TOP: usize = 50;
+
+// Private tags removed in a run, counted once per file for each tag and private creator
+// The data elements are counted on their element byte as (gggg,xxEE) as the block
+// reserved by a creator differs from file to file
+#[derive(Debug, Clone, Default)]
+pub struct PrivateTagStats {
+    counts: Arc<Mutex<PrivateTagCounts>>,
+}
+
+#[derive(Debug, Default)]
+struct PrivateTagCounts {
+    files: HashMap<(String, String), u64>,
+    // Files of the tags past PRIVATE_TAG_STATS_LIMIT
+    overflow: u64,
+}
+
+// A row of the private tags removed table
+#[derive(Debug, Clone, Serialize)]
+pub struct PrivateTagCount {
+    pub tag: String,
+    pub creator: String,
+    pub files: u64,
+}
+
+impl PrivateTagStats {
+    // Count the private tags of a file at any depth, kept tags are not removed and not counted
+    pub fn record(&self, dcm_obj: &InMemDicomObject, keep: &KeepList) {
+        let mut found: BTreeSet<(String, String)> = BTreeSet::new();
+        collect_private_tags(dcm_obj, keep, &mut found);
+        if found.is_empty() {
+            return;
+        }
+        let mut counts = self.counts.lock().expect("Failed to lock mutex");
+        for key in found {
+            let tracked = counts.files.len();
+            match counts.files.get_mut(&key) {
+                Some(files) => *files += 1,
+                None if tracked < PRIVATE_TAG_STATS_LIMIT => {
+                    counts.files.insert(key, 1);
+                }
+                None => counts.overflow += 1,
+            }
+        }
+    }
+
+    // The top tags by files affected, the files of the others are summed into an other row
+    pub fn summary(&self, top: usize) -> Vec<PrivateTagCount> {
+        let counts = self.counts.lock().expect("Failed to lock mutex");
+        let mut rows: Vec<PrivateTagCount> = counts
+            .files
+            .iter()
+            .map(|((tag, creator), files)| PrivateTagCount {
+                tag: tag.clone(),
+                creator: creator.clone(),
+                files: *files,
+            })
+            .collect();
+        rows.sort_by(|a, b| {
+            b.files
+                .cmp(&a.files)
+                .then_with(|| (&a.tag, &a.creator).cmp(&(&b.tag, &b.creator)))
+        });
+        let other: u64 = rows.iter().skip(top).map(|row| row.files).sum::<u64>() + counts.overflow;
+        rows.truncate(top);
+        if other > 0 {
+            rows.push(PrivateTagCount {
+                tag: "other".to_string(),
+                creator: String::new(),
+                files: other,
+            });
+        }
+        rows
+    }
+}
+
+fn collect_private_tags(
+    dataset: &InMemDicomObject,
+    keep: &KeepList,
+    found: &mut BTreeSet<(String, String)>,
+) {
+    for element in dataset.iter() {
+        let tag = element.tag();
+        if keep.contains(tag) {
+            continue;
+        }
+        if is_private_tag(tag) {
+            found.insert(private_tag_key(dataset, tag));
+        }
+        if let Value::Sequence(sequence) = element.value() {
+            for item in sequence.items() {
+                collect_private_tags(item, keep, found);
+            }
+        }
+    }
+}
+
+// Tag and private creator of a private element, creator elements (gggg,0010-00FF) are their own creator
+fn private_tag_key(dataset: &InMemDicomObject, tag: Tag) -> (String, String) {
+    let creator_of = |creator_tag: Tag| {
+        dataset
+            .element(creator_tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .unwrap_or_default()
+    };
+    match tag.element() {
+        0x0000..=0x000F => (
+            format!("({:04X},{:04X})", tag.group(), tag.element()),
+            String::new(),
+        ),
+        0x0010..=0x00FF => (format!("({:04X},00xx)", tag.group()), creator_of(tag)),
+        element => (
+            format!("({:04X},xx{:02X})", tag.group(), element & 0xFF),
+            creator_of(Tag(tag.group(), element >> 8)),
+        ),
+    }
+}
+
+pub fn delete_private_tags(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    keep: &KeepList,
+    private_tag_stats: &PrivateTagStats,
+) -> Result<(FileDicomObject<InMemDicomObject>, u64)> {
+    private_tag_stats.record(&dcm_obj, &KeepList::default());
+    fn is_private(tag: Tag) -> bool {
+        tag.group() % 2 == 1
+    }
+
+    let mut private_tags: Vec<Tag> = vec![];
+    visit_elements(&dcm_obj, |each_element, _depth| {
+        if is_private(each_element.tag()) {
+            private_tags.push(each_element.tag());
+        }
+        true
+    });
+
+    let mut changed = 0;
+    for each in private_tags {
+        if dcm_obj.remove_element(each) {
+            changed += 1;
+        }
+    }
+
+    if !(dcm_obj.element(ORIGINAL_ATTRIBUTES_SEQUENCE).is_ok()
+        && keep.protects(ORIGINAL_ATTRIBUTES_SEQUENCE, "private tag deletion"))
+        && dcm_obj.remove_element(ORIGINAL_ATTRIBUTES_SEQUENCE)
+    {
+        changed += 1;
+    }
+
+    Ok((dcm_obj, changed))
+}
+
+// UI values are padded to even length with a NUL, some vendors pad with spaces
+// The encoder adds the padding again on write
+pub fn trim_uid(uid: &str) -> &str {
+    uid.trim_matches(|c: char| c == '\0' || c.is_whitespace())
+}
+
+// The first 8 components of the UIDs are replaced with the UID root
+// The same UIDs in the sequence items are remapped too, so the references of KO and
+// presentation state objects still point at the remapped images
+pub fn anon_dicom_uids(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    uid_root: &str,
+    length_policy: &LengthPolicy,
+    keep: &KeepList,
+) -> Result<(FileDicomObject<InMemDicomObject>, u64)> {
+    let mut changed = 0;
+    let uid_tag_list = [
+        "SOPInstanceUID".to_string(),
+        "StudyInstanceUID".to_string(),
+        "SeriesInstanceUID".to_string(),
+        "FrameOfReferenceUID".to_string(),
+    ];
+
+    for each_uid in uid_tag_list {
+        let (each_tag, each_vr) = extract_tag_vr_from_str(&each_uid)?;
+        if keep.protects(each_tag, "the UID remapping") {
+            continue;
+        }
+        let org_uid_val = dcm_obj.element(each_tag)?.to_str()?;
+        let new_uid_val = remap_uid(&org_uid_val, uid_root).ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "{} is too short to remap: {}",
+                each_uid,
+                trim_uid(&org_uid_val)
+            ))
+        })?;
+        let value = dicom_vr_corrected_value(each_vr, &new_uid_val, length_policy)?;
+        dcm_obj.put(DataElement::new(each_tag, each_vr, value));
+        changed += 1;
+    }
+    let (dcm_obj, referenced_changed) =
+        anon_referenced_uids(dcm_obj, uid_root, length_policy, keep)?;
+    Ok((dcm_obj, changed + referenced_changed))
+}
+
+// UIDs of the sequence items remapped like the top level UIDs
+// ReferencedSOPClassUID is a standard UID and is kept like SOPClassUID
+static REFERENCED_UID_TAGS: [Tag; 4] = [
+    tags::REFERENCED_SOP_INSTANCE_UID,
+    tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID,
+    tags::FRAME_OF_REFERENCE_UID,
+];
+
+// Remap the UIDs of the sequence items at any depth eg the ReferencedSOPSequence items of
+// the CurrentRequestedProcedureEvidenceSequence of a KO or the ReferencedSeriesSequence of
+// a presentation state. Empty values are left as they are
+fn anon_referenced_uids(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+    uid_root: &str,
+    length_policy: &LengthPolicy,
+    keep: &KeepList,
+) -> Result<(FileDicomObject<InMemDicomObject>, u64)> {
+    let mut changed = 0;
+    let sequence_tags: Vec<Tag> = dcm_obj
+        .iter()
+        .filter(|e| e.items().is_some())
+        .map(|e| e.tag())
+        .collect();
+    for sequence_tag in sequence_tags {
+        let Some(sequence) = dcm_obj.take_element(sequence_tag).ok() else {
+            continue;
+        };
+        let mut failed = None;
+        let rebuilt = rebuild_in_sequences(sequence, |each_element| {
+            let tag = each_element.tag();
+            if failed.is_some()
+                || !REFERENCED_UID_TAGS.contains(&tag)
+                || keep.protects(tag, "the UID remapping")
+            {
+                return Some(each_element);
+            }
+            let org_uid_val = each_element.to_str().unwrap_or_default().to_string();
+            if trim_uid(&org_uid_val).is_empty() {
+                return Some(each_element);
+            }
+            let new_value = remap_uid(&org_uid_val, uid_root)
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!(
+                        "Referenced {} is too short to remap: {}",
+                        tag_keyword(tag),
+                        trim_uid(&org_uid_val)
+                    ))
+                })
+                .and_then(|new_uid_val| {
+                    dicom_vr_corrected_value(VR::UI, &new_uid_val, length_policy)
+                });
+            match new_value {
+                Ok(value) => {
+                    changed += 1;
+                    Some(DataElement::new(tag, VR::UI, value))
+                }
+                Err(e) => {
+                    failed = Some(e);
+                    Some(each_element)
+                }
+            }
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        dcm_obj.put(rebuilt);
+    }
+    Ok((dcm_obj, changed))
+}
+
+// The UID with its first 8 components replaced with the UID root, None when it has no more
+// than 8 components. Vendor padding would end up as an empty or NUL component of the new UID
+fn remap_uid(uid: &str, uid_root: &str) -> Option<String> {
+    let org_uid_vec: Vec<_> = trim_uid(uid)
+        .split(".")
+        .filter(|part| !part.is_empty())
+        .collect();
+    if org_uid_vec.len() <= 8 {
+        return None;
+    }
+    let mut new_uid_parts: Vec<_> = uid_root.split(".").collect();
+    new_uid_parts.extend_from_slice(&org_uid_vec[8..]);
+    Some(new_uid_parts.join("."))
+}
+
+// Root of the storage SOP class UIDs eg CT Image Storage 1.2.840.10008.5.1.4.1.1.2
+const STORAGE_SOP_CLASS_PREFIX: &str = "1.2.840.10008.5.1.4.1.1.";
+
+// Instances of storage SOP classes referenced by the sequence items at any depth, eg the
+// images of a KO or a presentation state. References to other SOP classes like a
+// performed procedure step are left out, they are never files of the run
+pub fn referenced_instance_uids(dcm_obj: &InMemDicomObject) -> Vec<String> {
+    let item_uid = |item: &InMemDicomObject, tag: Tag| {
+        item.element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|v| trim_uid(&v).to_string())
+    };
+    let mut referenced = Vec::new();
+    visit_elements(dcm_obj, |element, _depth| {
+        for item in element.items().unwrap_or_default() {
+            let class_uid = item_uid(item, tags::REFERENCED_SOP_CLASS_UID).unwrap_or_default();
+            match item_uid(item, tags::REFERENCED_SOP_INSTANCE_UID) {
+                Some(instance_uid)
+                    if !instance_uid.is_empty()
+                        && class_uid.starts_with(STORAGE_SOP_CLASS_PREFIX) =>
+                {
+                    referenced.push(instance_uid)
+                }
+                _ => (),
+            }
+        }
+        true
+    });
+    referenced
+}
+
+// Returns the object and the number of elements masked
 pub fn mask_all_vr(
     mut dcm_obj: FileDicomObject<InMemDicomObject>,
     vr: VR,
     val: PrimitiveValue,
-) -> Result<FileDicomObject<InMemDicomObject>> {
+    keep: &KeepList,
+) -> Result<(FileDicomObject<InMemDicomObject>, u64)> {
+    let mut changed = 0;
     for each_element in dcm_obj.clone() {
-        if each_element.header().vr() == vr {
+        if each_element.header().vr() == vr
+            && !keep.protects(each_element.tag(), &format!("masking of {}", vr))
+        {
             dcm_obj.put(DataElement::new(
                 each_element.tag(),
                 each_element.vr(),
                 val.clone(),
             ));
+            changed += 1;
+        }
+    }
+    Ok((dcm_obj, changed))
+}
+
+// Generate the Dicom filename based on the dicom tags
+pub fn generate_dicom_file_name(
+    dicom_tags_values: &HashMap<String, String>,
+    prefix: String,
+    pad_width: usize,
+) -> Result<String> {
+    let file_name = format!(
+        "{}_{}_{}_{}T{}_{}_{}_{}.dcm",
+        prefix,
+        replace_non_alphanumeric(
+            dicom_tags_values
+                .get("PatientID")
+                .expect("Failed to extract value")
+                .trim()
+        ),
+        replace_non_alphanumeric(
+            dicom_tags_values
+                .get("Modality")
+                .expect("Failed to extract value")
+        ),
+        dicom_tags_values
+            .get("StudyDate")
+            .expect("Failed to extract value"),
+        dicom_tags_values
+            .get("StudyTime")
+            .expect("Failed to extract value")
+            .split(".")
+            .next()
+            .expect("Failed to extract value"),
+        dicom_tags_values
+            .get("SeriesNumber")
+            .expect("Failed to extract value"),
+        dicom_tags_values
+            .get("SeriesInstanceUID")
+            .expect("Failed to extract value"),
+        pad_number(
+            dicom_tags_values
+                .get("InstanceNumber")
+                .expect("Failed to extract value"),
+            pad_width
+        )
+    );
+    Ok(file_name)
+}
+
+// Directory of the files of a series under the destination
+// The study directory is <StudyDate>T<StudyTime>_<UID tail> unless a study_dir is given
+// Only computes the path, the directories are created by the writer with EnsuredDirs so the
+// layout can be checked without a destination. A missing tag value gives an empty component
+pub fn compute_dicom_dir_path(
+    dicom_tags_values: &HashMap<String, String>,
+    destination_path: &Path,
+    series_derived: &DerivedValues,
+    study_dir: Option<String>,
+) -> PathBuf {
+    let study_dir = match study_dir {
+        Some(study_dir) => study_dir,
+        None => date_study_dir(dicom_tags_values),
+    };
+    let series_dir = format!(
+        "{}_{}{}",
+        pad_number(tag_value(dicom_tags_values, "SeriesNumber"), 4),
+        replace_non_alphanumeric(tag_value(dicom_tags_values, "SeriesDescription").trim())
+            .to_uppercase(),
+        series_derived.suffix(dicom_tags_values)
+    );
+    PathBuf::from(format!(
+        "{}/{}/{}/{}",
+        destination_path.display(),
+        replace_non_alphanumeric(tag_value(dicom_tags_values, "PatientID").trim()),
+        study_dir,
+        series_dir
+    ))
+}
+
+// Study directory <StudyDate>T<StudyTime>_<last 5 digits of the StudyInstanceUID>
+fn date_study_dir(dicom_tags_values: &HashMap<String, String>) -> String {
+    let final_trimmed_uid = study_uid_tail(dicom_tags_values);
+    format!(
+        "{}T{}_{:0>5}",
+        replace_non_alphanumeric(tag_value(dicom_tags_values, "StudyDate").trim()),
+        replace_non_alphanumeric(
+            tag_value(dicom_tags_values, "StudyTime")
+                .split('.')
+                .next()
+                .unwrap_or_default()
+        ),
+        replace_non_alphanumeric(&final_trimmed_uid)
+    )
+}
+
+// Sanitized value of a tag, empty when it wasn't extracted
+fn tag_value<'a>(dicom_tags_values: &'a HashMap<String, String>, tag_name: &str) -> &'a str {
+    dicom_tags_values
+        .get(tag_name)
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+// Files of a run that were not processed, by reason
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunCounts {
+    pub failed: u64,
+    pub non_dicom: u64,
+    pub skipped: u64,
+    // Excluded by SOP class
+    pub filtered: u64,
+    // Entries of the source that could not be read, not part of the total files
+    pub walk_errors: u64,
+    // Empty or smaller than a preamble and DICM marker, never opened
+    pub too_small: u64,
+    // Over the file size limit of the destination filesystem, never opened
+    pub too_large: u64,
+    // Left out after the run was interrupted
+    pub interrupted: u64,
+    // Processed with an AnonID of their own as none of the anon keys had a value
+    pub keyless: u64,
+    // Headers readable but not the PixelData, only with --tolerate-truncated-pixeldata
+    pub truncated_pixel_data: u64,
+    // Dropped by anon --drop-unsafe-references, referencing instances not in the run
+    pub unsafe_references: u64,
+    // Identical to a file written under the same name
+    pub duplicates: u64,
+    // Over --max-file-size, never opened
+    pub oversize: u64,
+    // Instances already processed from another file with --dedupe-global
+    pub global_duplicates: u64,
+}
+
+impl RunCounts {
+    pub fn not_processed(&self) -> u64 {
+        self.failed
+            + self.non_dicom
+            + self.skipped
+            + self.filtered
+            + self.too_small
+            + self.too_large
+            + self.truncated_pixel_data
+            + self.unsafe_references
+            + self.duplicates
+            + self.oversize
+            + self.global_duplicates
+            + self.interrupted
+    }
+}
+
+// Result of a single source file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum FileStatus {
+    Written,
+    Failed,
+    NonDicom,
+    // Unmapped by deid
+    Skipped,
+    // Excluded by SOP class
+    Filtered,
+    // Copied to FAILED_CASES as the PixelData can't be read
+    TruncatedPixelData,
+    // Not written as it references instances not in the run
+    UnsafeReferences,
+    // Not written as a file with its name and content is in the destination
+    Duplicate,
+    // Skipped with --dedupe-global as a file of its SOPInstanceUID was processed
+    GlobalDuplicate,
+}
+
+// Outcome of a single source file, recorded as soon as the file is complete
+#[derive(Debug, Clone, Serialize)]
+pub struct FileOutcome {
+    pub source_path: PathBuf,
+    // Written file, or the copy in NON_DICOM, FAILED_CASES or EXCLUDED_SOP
+    pub destination_path: Option<PathBuf>,
+    // Sanitized tag values of the written file, empty for the other outcomes
+    pub tag_values: HashMap<String, String>,
+    pub status: FileStatus,
+    pub error: Option<String>,
+}
+
+impl FileOutcome {
+    pub fn new(source_path: &std::path::Path, status: FileStatus) -> Self {
+        FileOutcome {
+            source_path: source_path.to_path_buf(),
+            destination_path: None,
+            tag_values: HashMap::new(),
+            status,
+            error: None,
+        }
+    }
+}
+
+pub type OutcomeCallback = Arc<dyn Fn(&FileOutcome) + Send + Sync>;
+
+// Outcome callback of a library caller, set as RunOptions::on_outcome
+#[derive(Clone)]
+pub struct OutcomeSink(OutcomeCallback);
+
+impl OutcomeSink {
+    pub fn new(callback: impl Fn(&FileOutcome) + Send + Sync + 'static) -> Self {
+        OutcomeSink(Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for OutcomeSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OutcomeSink")
+    }
+}
+
+// Collects the file outcomes of a run, the run counts are built from the outcomes only
+// Callbacks are called from the worker threads as each file completes
+pub struct OutcomeTracker {
+    counts: Mutex<RunCounts>,
+    written: Mutex<u64>,
+    callbacks: Vec<OutcomeCallback>,
+}
+
+impl OutcomeTracker {
+    // Walk errors, too small and too large files are known before any file is opened
+    pub fn new(initial_counts: RunCounts) -> Self {
+        OutcomeTracker {
+            counts: Mutex::new(initial_counts),
+            written: Mutex::new(0),
+            callbacks: Vec::new(),
+        }
+    }
+
+    pub fn with_callback(mut self, callback: OutcomeCallback) -> Self {
+        self.callbacks.push(callback);
+        self
+    }
+
+    // Callbacks asked for by the run options, the DICOMDIR collector of --write-dicomdir,
+    // the status counters of --status-port, the directory progress of --progress-by and the
+    // outcome callback of a library caller
+    pub fn with_run_callbacks(self, run_options: &RunOptions) -> Self {
+        let tracker = self.with_callback(run_options.outcome_details.callback());
+        let tracker = match &run_options.dicomdir {
+            Some(dicomdir) => tracker.with_callback(dicomdir.callback()),
+            None => tracker,
+        };
+        let tracker = match &run_options.status_board {
+            Some(status_board) => tracker.with_callback(status_board.callback()),
+            None => tracker,
+        };
+        let tracker = match &run_options.top_dir_progress {
+            Some(top_dir_progress) => tracker.with_callback(top_dir_progress.callback()),
+            None => tracker,
+        };
+        match &run_options.on_outcome {
+            Some(OutcomeSink(callback)) => tracker.with_callback(Arc::clone(callback)),
+            None => tracker,
+        }
+    }
+
+    // One line with the status and the written path or error of a single file source
+    pub fn with_single_file_result(self, run_dirs: &RunDirs) -> Self {
+        if !run_dirs.single_file {
+            return self;
+        }
+        self.with_callback(Arc::new(|outcome: &FileOutcome| {
+            let detail = match (&outcome.destination_path, &outcome.error) {
+                (_, Some(error)) => error.clone(),
+                (Some(destination_path), None) => destination_path.display().to_string(),
+                (None, None) => "not written".to_string(),
+            };
+            println!(
+                "{} {:?}: {}",
+                phi(outcome.source_path.display()),
+                outcome.status,
+                detail
+            );
+        }))
+    }
+
+    pub fn record(&self, outcome: FileOutcome) {
+        {
+            let mut counts = self.counts.lock().expect("Failed to lock mutex");
+            match outcome.status {
+                FileStatus::Written => *self.written.lock().expect("Failed to lock mutex") += 1,
+                FileStatus::Failed => counts.failed += 1,
+                FileStatus::NonDicom => counts.non_dicom += 1,
+                FileStatus::Skipped => counts.skipped += 1,
+                FileStatus::Filtered => counts.filtered += 1,
+                FileStatus::TruncatedPixelData => counts.truncated_pixel_data += 1,
+                FileStatus::UnsafeReferences => counts.unsafe_references += 1,
+                FileStatus::Duplicate => counts.duplicates += 1,
+                FileStatus::GlobalDuplicate => counts.global_duplicates += 1,
+            }
+        }
+        for callback in &self.callbacks {
+            callback(&outcome);
+        }
+    }
+
+    pub fn counts(&self) -> RunCounts {
+        self.counts.lock().expect("Failed to lock mutex").clone()
+    }
+
+    pub fn written(&self) -> u64 {
+        *self.written.lock().expect("Failed to lock mutex")
+    }
+}
+
+// Elements changed per rule in a single file, eg "mask PatientName" or "anon dates"
+pub type RuleCounts = BTreeMap<String, u64>;
+
+// Files and elements changed per rule of the anon passes and the cookbook tag actions, kept
+// whatever the logging so a rule that never changed anything shows at the end of the run
+#[derive(Debug, Clone, Default)]
+pub struct RuleStats {
+    rules: Arc<Mutex<BTreeMap<String, RuleEffect>>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleEffect {
+    pub rule: String,
+    // Files with at least one element changed by the rule
+    pub files: u64,
+    pub elements: u64,
+}
+
+impl RuleStats {
+    pub fn record(&self, rule_counts: &RuleCounts) {
+        let mut rules = self.rules.lock().expect("Failed to lock mutex");
+        for (rule, changed) in rule_counts {
+            let effect = rules.entry(rule.clone()).or_insert_with(|| RuleEffect {
+                rule: rule.clone(),
+                ..Default::default()
+            });
+            if *changed > 0 {
+                effect.files += 1;
+                effect.elements += changed;
+            }
+        }
+    }
+
+    pub fn summary(&self) -> Vec<RuleEffect> {
+        let rules = self.rules.lock().expect("Failed to lock mutex");
+        rules.values().cloned().collect()
+    }
+}
+
+// Distinct failure reasons and patients kept in a run, later ones are counted as other
+static OUTCOME_DETAILS_LIMIT: usize = 100_000;
+
+// Rows of the failure reasons and patients of the run summary
+pub static FAILURE_REASONS_TOP: usize = 20;
+pub static PATIENTS_TOP: usize = 1000;
+
+// Files per failure reason and the written studies, series and files per PatientID
+// The reason is the first line of the error, the UIDs are kept as hashes
+#[derive(Debug, Clone, Default)]
+pub struct OutcomeDetails {
+    state: Arc<Mutex<OutcomeDetailsState>>,
+}
+
+#[derive(Debug, Default)]
+struct OutcomeDetailsState {
+    failure_reasons: HashMap<String, u64>,
+    patients: HashMap<String, PatientFiles>,
+}
+
+#[derive(Debug, Default)]
+struct PatientFiles {
+    studies: HashSet<String>,
+    series: HashSet<String>,
+    files: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailureReason {
+    pub reason: String,
+    pub files: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PatientSummary {
+    pub patient_id: String,
+    pub studies: u64,
+    pub series: u64,
+    pub files: u64,
+}
+
+impl OutcomeDetails {
+    pub fn callback(&self) -> OutcomeCallback {
+        let state = Arc::clone(&self.state);
+        Arc::new(move |outcome: &FileOutcome| {
+            let mut state = state.lock().expect("Failed to lock mutex");
+            match (&outcome.status, &outcome.error) {
+                (FileStatus::Written, _) => {
+                    let tag_value =
+                        |name: &str| outcome.tag_values.get(name).cloned().unwrap_or_default();
+                    let patient_id = tag_value("PatientID");
+                    if !state.patients.contains_key(&patient_id)
+                        && state.patients.len() >= OUTCOME_DETAILS_LIMIT
+                    {
+                        return;
+                    }
+                    let patient = state.patients.entry(patient_id).or_default();
+                    patient
+                        .studies
+                        .insert(short_hash(&tag_value("StudyInstanceUID")));
+                    patient
+                        .series
+                        .insert(short_hash(&tag_value("SeriesInstanceUID")));
+                    patient.files += 1;
+                }
+                (_, Some(error)) => {
+                    let reason: String = error
+                        .lines()
+                        .next()
+                        .unwrap_or_default()
+                        .chars()
+                        .take(120)
+                        .collect();
+                    let reason = match state.failure_reasons.contains_key(&reason)
+                        || state.failure_reasons.len() < OUTCOME_DETAILS_LIMIT
+                    {
+                        true => reason,
+                        false => "other".to_string(),
+                    };
+                    *state.failure_reasons.entry(reason).or_insert(0) += 1;
+                }
+                (_, None) => (),
+            }
+        })
+    }
+
+    // The most frequent reasons, the files of the others are summed into an other row
+    pub fn failure_reasons(&self, top: usize) -> Vec<FailureReason> {
+        let state = self.state.lock().expect("Failed to lock mutex");
+        let mut reasons: Vec<FailureReason> = state
+            .failure_reasons
+            .iter()
+            .map(|(reason, files)| FailureReason {
+                reason: reason.clone(),
+                files: *files,
+            })
+            .collect();
+        reasons.sort_by(|a, b| b.files.cmp(&a.files).then_with(|| a.reason.cmp(&b.reason)));
+        let other: u64 = reasons.iter().skip(top).map(|reason| reason.files).sum();
+        reasons.truncate(top);
+        if other > 0 {
+            reasons.push(FailureReason {
+                reason: "other".to_string(),
+                files: other,
+            });
         }
+        reasons
+    }
+
+    // The first patients in PatientID order
+    pub fn patients(&self, top: usize) -> Vec<PatientSummary> {
+        let state = self.state.lock().expect("Failed to lock mutex");
+        let mut patients: Vec<PatientSummary> = state
+            .patients
+            .iter()
+            .map(|(patient_id, patient)| PatientSummary {
+                patient_id: patient_id.clone(),
+                studies: patient.studies.len() as u64,
+                series: patient.series.len() as u64,
+                files: patient.files,
+            })
+            .collect();
+        patients.sort_by(|a, b| a.patient_id.cmp(&b.patient_id));
+        patients.truncate(top);
+        patients
     }
-    Ok(dcm_obj)
 }
 
-pub fn mask_vr(
-    mut dcm_obj: FileDicomObject<InMemDicomObject>,
-    vr_list: Vec<VR>,
-    val: String,
-) -> Result<FileDicomObject<InMemDicomObject>> {
-    let p_value = dicom_value!(Strs, [val]);
-    for each_vr in vr_list {
-        dcm_obj = mask_all_vr(dcm_obj.clone(), each_vr, p_value.clone())?;
+// Expected instances per StudyInstanceUID of sort --expected-manifest, from the sender of the data
+// The written instances are counted from the outcomes and compared at the end of the run
+#[derive(Debug, Clone)]
+pub struct ExpectedManifest {
+    expected: Arc<BTreeMap<String, u64>>,
+    written: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+// Studies listed on the console, the CSV lists all of them
+static MANIFEST_LOG_LIMIT: usize = 20;
+
+impl ExpectedManifest {
+    // Lines of StudyInstanceUID,expected instance count, a first line that isn't a count is a header
+    pub fn load(manifest_path: &PathBuf) -> Result<Self> {
+        let manifest = fs::read_to_string(manifest_path).map_err(|e| {
+            anyhow::Error::msg(format!(
+                "Can't read the manifest {}: {}",
+                manifest_path.display(),
+                e
+            ))
+        })?;
+        let mut expected = BTreeMap::new();
+        for (line_index, each_line) in manifest.lines().enumerate() {
+            let each_line = each_line.trim();
+            if each_line.is_empty() {
+                continue;
+            }
+            let (study_uid, count) = each_line.split_once(',').unwrap_or((each_line, ""));
+            let study_uid = trim_uid(study_uid.trim().trim_matches('"')).to_string();
+            let count = match count.trim().trim_matches('"').parse::<u64>() {
+                Ok(count) => count,
+                Err(_) if line_index == 0 => continue,
+                Err(_) => {
+                    return Err(anyhow::Error::msg(format!(
+                        "Line {} of the manifest {} is not StudyInstanceUID,count",
+                        line_index + 1,
+                        manifest_path.display()
+                    )))
+                }
+            };
+            if expected.insert(study_uid.clone(), count).is_some() {
+                return Err(anyhow::Error::msg(format!(
+                    "StudyInstanceUID {} is listed twice in the manifest {}",
+                    study_uid,
+                    manifest_path.display()
+                )));
+            }
+        }
+        info!(
+            "Expected manifest with {} studies and {} instances",
+            expected.len(),
+            expected.values().sum::<u64>()
+        );
+        Ok(ExpectedManifest {
+            expected: Arc::new(expected),
+            written: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn callback(&self) -> OutcomeCallback {
+        let written = Arc::clone(&self.written);
+        Arc::new(move |outcome: &FileOutcome| {
+            if outcome.status != FileStatus::Written {
+                return;
+            }
+            let study_uid = outcome
+                .tag_values
+                .get("StudyInstanceUID")
+                .map(|uid| trim_uid(uid).to_string())
+                .unwrap_or_default();
+            *written
+                .lock()
+                .expect("Failed to lock mutex")
+                .entry(study_uid)
+                .or_insert(0) += 1;
+        })
+    }
+
+    // Write <destination>/manifest_check.csv with a row per study of the manifest and per study
+    // written but not listed, returns the studies that don't match
+    pub fn report(&self, destination_path: &Path) -> Result<usize> {
+        let written = self.written.lock().expect("Failed to lock mutex");
+        let mut studies: BTreeSet<&String> = self.expected.keys().collect();
+        studies.extend(written.keys());
+        let mut report_csv = "study_instance_uid,expected,written,missing,status\n".to_string();
+        let mut mismatched = 0;
+        for each_study in studies {
+            let expected = self.expected.get(each_study);
+            let written = written.get(each_study).copied().unwrap_or(0);
+            let status = match expected {
+                None => "unexpected",
+                Some(_) if written == 0 => "absent",
+                Some(expected) if written < *expected => "incomplete",
+                Some(expected) if written > *expected => "over",
+                Some(_) => "complete",
+            };
+            let missing = expected.map_or(0, |expected| expected.saturating_sub(written));
+            writeln!(
+                report_csv,
+                "{},{},{},{},{}",
+                csv_field(each_study),
+                expected.map_or(String::new(), |expected| expected.to_string()),
+                written,
+                missing,
+                status
+            )?;
+            if status == "complete" {
+                continue;
+            }
+            if mismatched < MANIFEST_LOG_LIMIT {
+                warn!(
+                    "!! Study {} is {}: {} of {} instances written",
+                    each_study,
+                    status,
+                    written,
+                    expected.map_or("no".to_string(), |expected| expected.to_string())
+                );
+            }
+            mismatched += 1;
+        }
+        let report_path = destination_path.join("manifest_check.csv");
+        fs::write(&report_path, report_csv)?;
+        match mismatched {
+            0 => info!(
+                "All {} studies of the manifest are complete",
+                self.expected.len()
+            ),
+            _ => warn!(
+                "{} studies don't match the manifest, see {}",
+                mismatched,
+                report_path.display()
+            ),
+        }
+        Ok(mismatched)
     }
-    Ok(dcm_obj)
 }
 
-// Generate the Dicom filename based on the dicom tags
-pub fn generate_dicom_file_name(
-    dicom_tags_values: &HashMap<String, String>,
-    prefix: String,
-) -> Result<String> {
-    let file_name = format!(
-        "{}_{}_{}_{}T{}_{}_{}_{:0>5}.dcm",
-        prefix,
-        dicom_tags_values
-            .get("PatientID")
-            .expect("Failed to extract value")
-            .trim(),
-        dicom_tags_values
-            .get("Modality")
-            .expect("Failed to extract value"),
-        dicom_tags_values
-            .get("StudyDate")
-            .expect("Failed to extract value"),
-        dicom_tags_values
-            .get("StudyTime")
-            .expect("Failed to extract value")
-            .split(".")
-            .next()
-            .expect("Failed to extract value"),
-        dicom_tags_values
-            .get("SeriesNumber")
-            .expect("Failed to extract value"),
-        dicom_tags_values
-            .get("SeriesInstanceUID")
-            .expect("Failed to extract value"),
-        dicom_tags_values
-            .get("InstanceNumber")
-            .expect("Failed to extract value")
+// A table of the report command, eg the device inventory
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportSection {
+    pub name: String,
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Complete,
+    Interrupted,
+}
+
+// Outcome of a single sort, anon or deid run
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub action: String,
+    pub status: RunStatus,
+    pub destination: PathBuf,
+    pub total_files: u64,
+    #[serde(flatten)]
+    pub counts: RunCounts,
+    pub processed: u64,
+    pub duration_secs: f64,
+    // Average wait for a read or write permit, only with --read-concurrency or --write-concurrency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_wait_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_wait_ms: Option<f64>,
+    // Files per route with --route, default for the files without a matching rule
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub routes: BTreeMap<String, u64>,
+    // Private tags removed by anon and deid with the files affected
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub private_tags_removed: Vec<PrivateTagCount>,
+    // Files and elements changed per anon pass and cookbook rule
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleEffect>,
+    // Most frequent first lines of the errors of the failed files
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failure_reasons: Vec<FailureReason>,
+    // Written studies, series and files per PatientID of the written files
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub patients: Vec<PatientSummary>,
+    // Tables of the report command
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub report_sections: Vec<ReportSection>,
+    // Files re-encoded with --compress and the bytes saved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionSummary>,
+    // Selection of a --limit or --sample run, only part of the source was processed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial: Option<PartialRunSummary>,
+}
+
+impl RunSummary {
+    pub fn new(
+        action: String,
+        destination: PathBuf,
+        total_files: u64,
+        counts: RunCounts,
+        duration: Duration,
+    ) -> Self {
+        RunSummary {
+            action,
+            status: match interrupted() {
+                true => RunStatus::Interrupted,
+                false => RunStatus::Complete,
+            },
+            destination,
+            total_files,
+            processed: total_files.saturating_sub(counts.not_processed()),
+            counts,
+            duration_secs: duration.as_secs_f64(),
+            read_wait_ms: None,
+            write_wait_ms: None,
+            routes: BTreeMap::new(),
+            private_tags_removed: Vec::new(),
+            rules: Vec::new(),
+            failure_reasons: Vec::new(),
+            patients: Vec::new(),
+            report_sections: Vec::new(),
+            compression: None,
+            partial: None,
+        }
+    }
+}
+
+pub fn print_status(summary: &RunSummary) -> Result<()> {
+    info!("Total Files: {}", summary.total_files);
+    info!("Failed Cases: {}", summary.counts.failed);
+    info!("NON-DCM files: {}", summary.counts.non_dicom);
+    info!("Unmapped/Skipped files: {}", summary.counts.skipped);
+    info!("Excluded SOP class files: {}", summary.counts.filtered);
+    info!("Empty or too small files: {}", summary.counts.too_small);
+    if summary.counts.too_large > 0 {
+        warn!(
+            "Files too large for the destination filesystem: {}",
+            summary.counts.too_large
+        );
+    }
+    if summary.counts.walk_errors > 0 {
+        warn!("Unreadable source entries: {}", summary.counts.walk_errors);
+    }
+    if summary.counts.oversize > 0 {
+        warn!(
+            "Files over --max-file-size, not opened: {}",
+            summary.counts.oversize
+        );
+    }
+    if summary.counts.truncated_pixel_data > 0 {
+        warn!(
+            "Files with truncated pixel data: {}",
+            summary.counts.truncated_pixel_data
+        );
+    }
+    if summary.counts.unsafe_references > 0 {
+        warn!(
+            "Dropped files with unsafe references: {}",
+            summary.counts.unsafe_references
+        );
+    }
+    if summary.counts.duplicates > 0 {
+        info!(
+            "Duplicates of a written file, not written again: {}",
+            summary.counts.duplicates
+        );
+    }
+    if summary.counts.global_duplicates > 0 {
+        info!(
+            "Instances already processed from another file, listed in {}: {}",
+            GLOBAL_DUPLICATES_CSV_NAME, summary.counts.global_duplicates
+        );
+    }
+    if summary.counts.keyless > 0 {
+        warn!(
+            "Files without an anon key, each with its own AnonID: {}",
+            summary.counts.keyless
+        );
+    }
+    if summary.status == RunStatus::Interrupted {
+        warn!(
+            "Run interrupted, files not processed: {}",
+            summary.counts.interrupted
+        );
+    }
+    if let Some(read_wait_ms) = summary.read_wait_ms {
+        info!("Average read queue wait: {:.2} ms", read_wait_ms);
+    }
+    if let Some(write_wait_ms) = summary.write_wait_ms {
+        info!("Average write queue wait: {:.2} ms", write_wait_ms);
+    }
+    for (route, files) in &summary.routes {
+        info!("Files routed to {}: {}", route, files);
+    }
+    if !summary.private_tags_removed.is_empty() {
+        info!("Private tags removed:");
+        info!("{:<14} {:<40} {:>8}", "Tag", "Creator", "Files");
+        for row in &summary.private_tags_removed {
+            info!("{:<14} {:<40} {:>8}", row.tag, row.creator, row.files);
+        }
+    }
+    if !summary.rules.is_empty() {
+        info!("Rule effectiveness:");
+        info!("{:<48} {:>8} {:>10}", "Rule", "Files", "Elements");
+        for row in &summary.rules {
+            info!("{:<48} {:>8} {:>10}", row.rule, row.files, row.elements);
+        }
+        for row in summary.rules.iter().filter(|row| row.elements == 0) {
+            warn!(
+                "!! Rule {} changed nothing, check the configuration",
+                row.rule
+            );
+        }
+    }
+    if let Some(compression) = &summary.compression {
+        info!(
+            "Compressed with {:?}: {} files, {} MB saved, {} files written uncompressed",
+            compression.codec,
+            compression.files_compressed,
+            compression.bytes_saved / 1_000_000,
+            compression.files_uncompressed
+        );
+    }
+    info!("Total {}: {}", summary.action, summary.processed);
+    if let Some(partial) = &summary.partial {
+        warn!("!! PARTIAL RUN with {}", partial.selection);
+        warn!(
+            "!! {} files of the source were not selected, the destination is not a complete delivery",
+            partial.not_selected
+        );
+    }
+    Ok(())
+}
+
+// Log the effective configuration at the start of a run and optionally write it to a file
+// Values of secret keys like salts are redacted
+// Returns the redacted configuration
+pub fn emit_effective_config(
+    mut config: serde_json::Value,
+    dump_path: Option<&PathBuf>,
+) -> Result<serde_json::Value> {
+    redact_config_secrets(&mut config);
+    info!(
+        "Effective configuration:\n{}",
+        serde_json::to_string_pretty(&config)?
     );
-    Ok(file_name)
+    if let Some(dump_path) = dump_path {
+        let is_toml = match dump_path.extension() {
+            Some(ext) => ext.eq_ignore_ascii_case("toml"),
+            None => false,
+        };
+        let config_str = match is_toml {
+            true => {
+                // TOML has no null, unset options are left out
+                let mut toml_config = config.clone();
+                remove_config_nulls(&mut toml_config);
+                toml::to_string_pretty(&toml_config)?
+            }
+            false => serde_json::to_string_pretty(&config)?,
+        };
+        fs::write(dump_path, config_str)?;
+        info!(
+            "Effective configuration written to: {}",
+            dump_path.display()
+        );
+    }
+    Ok(config)
+}
+
+fn redact_config_secrets(config: &mut serde_json::Value) {
+    match config {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if key.contains("salt") || key.contains("secret") {
+                    *value = serde_json::Value::String("<redacted>".to_string());
+                } else {
+                    redact_config_secrets(value);
+                }
+            }
+        }
+        serde_json::Value::Array(list) => list.iter_mut().for_each(redact_config_secrets),
+        _ => (),
+    }
+}
+
+fn remove_config_nulls(config: &mut serde_json::Value) {
+    match config {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_config_nulls);
+        }
+        serde_json::Value::Array(list) => list.iter_mut().for_each(remove_config_nulls),
+        _ => (),
+    }
+}
+
+// Quote a CSV field when it has a comma, quote or line break
+pub fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+// Write the run summary as a JSON file
+pub fn write_summary_json(summary: &RunSummary, summary_path: &PathBuf) -> Result<()> {
+    let summary_json = serde_json::to_string_pretty(summary)?;
+    fs::write(summary_path, summary_json)?;
+    info!("Summary written to: {}", summary_path.display());
+    Ok(())
+}
+
+// Page of --html-report, self contained so it can be sent as an attachment
+static HTML_REPORT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>dcmrig {action} report</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.5em; } h2 { font-size: 1.15em; margin-top: 1.5em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.6em; text-align: left; }
+th { background: #f0f0f0; } td.n { text-align: right; }
+.interrupted, .partial { color: #b00; font-weight: bold; }
+pre { background: #f7f7f7; padding: 1em; overflow-x: auto; font-size: 0.85em; }
+</style>
+</head>
+<body>
+<h1>dcmrig {version} {action} report</h1>
+{body}
+</body>
+</html>
+"#;
+
+// Write the run summary, the report tables and the effective configuration as a single HTML page
+// Built from the same summary as --summary-json
+pub fn write_html_report(
+    summary: &RunSummary,
+    config: &serde_json::Value,
+    html_path: &PathBuf,
+) -> Result<()> {
+    let mut body = String::new();
+    let status = match summary.status {
+        RunStatus::Complete => "complete".to_string(),
+        RunStatus::Interrupted => "<span class=\"interrupted\">interrupted</span>".to_string(),
+    };
+    writeln!(
+        body,
+        "<p>Status: {} | Destination: {} | Duration: {:.1} s</p>",
+        status,
+        html_escape(&summary.destination.display().to_string()),
+        summary.duration_secs
+    )?;
+    if let Some(partial) = &summary.partial {
+        writeln!(
+            body,
+            "<p class=\"partial\">Partial run with {}, {} files of the source were not selected</p>",
+            html_escape(&partial.selection),
+            partial.not_selected
+        )?;
+    }
+    let counts = &summary.counts;
+    let count_rows: Vec<Vec<String>> = [
+        ("Total files", summary.total_files),
+        ("Processed", summary.processed),
+        ("Failed", counts.failed),
+        ("Non DICOM", counts.non_dicom),
+        ("Unmapped or skipped", counts.skipped),
+        ("Excluded SOP class", counts.filtered),
+        ("Empty or too small", counts.too_small),
+        ("Too large", counts.too_large),
+        ("Oversize skipped", counts.oversize),
+        ("Truncated pixel data", counts.truncated_pixel_data),
+        ("Unsafe references", counts.unsafe_references),
+        ("Duplicates", counts.duplicates),
+        ("Global duplicates", counts.global_duplicates),
+        ("Unreadable source entries", counts.walk_errors),
+        ("Interrupted", counts.interrupted),
+        ("Keyless", counts.keyless),
+    ]
+    .into_iter()
+    .map(|(name, count)| vec![name.to_string(), count.to_string()])
+    .collect();
+    html_table(&mut body, "Counts", &["", "files"], &count_rows)?;
+    html_table(
+        &mut body,
+        "Rule effectiveness",
+        &["rule", "files", "elements"],
+        &summary
+            .rules
+            .iter()
+            .map(|r| vec![r.rule.clone(), r.files.to_string(), r.elements.to_string()])
+            .collect::<Vec<_>>(),
+    )?;
+    html_table(
+        &mut body,
+        "Failure reasons",
+        &["reason", "files"],
+        &summary
+            .failure_reasons
+            .iter()
+            .map(|r| vec![r.reason.clone(), r.files.to_string()])
+            .collect::<Vec<_>>(),
+    )?;
+    html_table(
+        &mut body,
+        "Patients",
+        &["PatientID", "studies", "series", "files"],
+        &summary
+            .patients
+            .iter()
+            .map(|p| {
+                vec![
+                    p.patient_id.clone(),
+                    p.studies.to_string(),
+                    p.series.to_string(),
+                    p.files.to_string(),
+                ]
+            })
+            .collect::<Vec<_>>(),
+    )?;
+    html_table(
+        &mut body,
+        "Files per route",
+        &["route", "files"],
+        &summary
+            .routes
+            .iter()
+            .map(|(route, files)| vec![route.clone(), files.to_string()])
+            .collect::<Vec<_>>(),
+    )?;
+    html_table(
+        &mut body,
+        "Private tags removed",
+        &["tag", "creator", "files"],
+        &summary
+            .private_tags_removed
+            .iter()
+            .map(|p| vec![p.tag.clone(), p.creator.clone(), p.files.to_string()])
+            .collect::<Vec<_>>(),
+    )?;
+    if let Some(compression) = &summary.compression {
+        html_table(
+            &mut body,
+            "Compression",
+            &["codec", "compressed", "uncompressed", "bytes saved"],
+            &[vec![
+                format!("{:?}", compression.codec),
+                compression.files_compressed.to_string(),
+                compression.files_uncompressed.to_string(),
+                compression.bytes_saved.to_string(),
+            ]],
+        )?;
+    }
+    for section in &summary.report_sections {
+        let headers: Vec<&str> = section.headers.iter().map(|h| h.as_str()).collect();
+        html_table(&mut body, &section.name, &headers, &section.rows)?;
+    }
+    writeln!(
+        body,
+        "<h2>Configuration</h2>\n<pre>{}</pre>",
+        html_escape(&serde_json::to_string_pretty(config)?)
+    )?;
+    let html = HTML_REPORT_TEMPLATE
+        .replace("{action}", &html_escape(&summary.action))
+        .replace("{version}", env!("CARGO_PKG_VERSION"))
+        .replace("{body}", &body);
+    fs::write(html_path, html)?;
+    info!("HTML report written to: {}", html_path.display());
+    Ok(())
+}
+
+// A titled table, left out when there are no rows. Numbers are right aligned
+fn html_table(
+    body: &mut String,
+    title: &str,
+    headers: &[&str],
+    rows: &[Vec<String>],
+) -> Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    writeln!(body, "<h2>{}</h2>\n<table>\n<tr>", html_escape(title))?;
+    for header in headers {
+        write!(body, "<th>{}</th>", html_escape(header))?;
+    }
+    writeln!(body, "</tr>")?;
+    for row in rows {
+        write!(body, "<tr>")?;
+        for cell in row {
+            match !cell.is_empty() && cell.chars().all(|c| c.is_ascii_digit()) {
+                true => write!(body, "<td class=\"n\">{}</td>", cell)?,
+                false => write!(body, "<td>{}</td>", html_escape(cell))?,
+            }
+        }
+        writeln!(body, "</tr>")?;
+    }
+    writeln!(body, "</table>")?;
+    Ok(())
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
-// Generate the path for the dicom files
-pub fn generate_dicom_file_path(
-    dicom_tags_values: HashMap<String, String>,
+static STARTED_MARKER: &str = ".dcmrig_started";
+static COMPLETE_MARKER: &str = ".dcmrig_complete";
+static PARTIAL_MARKER: &str = ".dcmrig_partial";
+
+// Mark the destination as in progress so orchestrators can tell a finished run from a crashed one
+// A started marker left by a previous run means it never completed, only continue with force
+// or skip existing
+pub fn write_started_marker(
     destination_path: &PathBuf,
-) -> Result<String> {
-    let temp_trimmed_study_uid = dicom_tags_values
-        .get("StudyInstanceUID")
-        .expect("Failed to extract value")
-        .split(".")
-        .last()
-        .expect("Failed to extract value");
+    force: bool,
+    skip_existing: bool,
+) -> Result<()> {
+    create_dir_all(destination_path)?;
+    let started_path = destination_path.join(STARTED_MARKER);
+    if started_path.exists() {
+        warn!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+        warn!(
+            "!! A previous run on {} did not complete",
+            destination_path.display()
+        );
+        warn!("!! Its partial output would be mixed with the output of this run");
+        warn!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+        match (force, skip_existing) {
+            (false, false) => {
+                return Err(anyhow::Error::msg(format!(
+                "Stale {} found, use --force to run anyway or --skip-existing to keep its files",
+                started_path.display()
+            )))
+            }
+            (_, true) => {
+                warn!("!! The files it wrote are kept, only the missing files are written")
+            }
+            (true, false) => (),
+        }
+    }
+    for each_marker in [COMPLETE_MARKER, PARTIAL_MARKER] {
+        let marker_path = destination_path.join(each_marker);
+        if marker_path.exists() {
+            fs::remove_file(&marker_path)?;
+        }
+    }
+    let started = serde_json::json!({
+        "command": std::env::args().collect::<Vec<_>>(),
+        "pid": std::process::id(),
+        "started_at": chrono::Local::now().to_rfc3339(),
+    });
+    fs::write(&started_path, serde_json::to_string_pretty(&started)?)?;
+    Ok(())
+}
 
-    let final_trimmed_uid = if temp_trimmed_study_uid.len() > 5 {
-        temp_trimmed_study_uid[temp_trimmed_study_uid.len() - 5..].to_string()
-    } else {
-        temp_trimmed_study_uid.to_string()
+// Keep the started marker of an interrupted run so the next run on the destination needs force
+// The summary of the partial run is added to it
+pub fn write_interrupted_marker(destination_path: &Path, summary: &RunSummary) -> Result<()> {
+    let started_path = destination_path.join(STARTED_MARKER);
+    let mut started: serde_json::Value = match fs::read_to_string(&started_path) {
+        Ok(started) => serde_json::from_str(&started)?,
+        Err(_) => serde_json::json!({}),
     };
-    let dir_path = format!(
-        "{}/{}/{}T{}_{:0>5}/{:0>4}_{}_{}",
-        destination_path.display(),
-        dicom_tags_values
-            .get("PatientID")
-            .expect("Failed to extract value")
-            .trim()
-            .replace(" ", "_")
-            .replace("^", "_"),
-        dicom_tags_values
-            .get("StudyDate")
-            .expect("Failed to extract value")
-            .trim(),
-        dicom_tags_values
-            .get("StudyTime")
-            .expect("Failed to extract value")
-            .trim(),
-        final_trimmed_uid,
-        dicom_tags_values
-            .get("SeriesNumber")
-            .expect("Failed to extract value"),
-        replace_non_alphanumeric(
-            dicom_tags_values
-                .get("SeriesDescription")
-                .expect("Failed to extract value")
-                .trim()
-        )
-        .to_uppercase(),
-        dicom_tags_values
-            .get("ImagePlane")
-            .expect("Failed to extract value")
-            .trim()
+    started["summary"] = serde_json::to_value(summary)?;
+    started["interrupted_at"] = serde_json::Value::String(chrono::Local::now().to_rfc3339());
+    fs::write(&started_path, serde_json::to_string_pretty(&started)?)?;
+    Ok(())
+}
+
+// Replace the started marker once all files and summaries are written
+// A --limit or --sample run gets a partial marker so it isn't taken for a complete delivery
+pub fn write_complete_marker(
+    destination_path: &Path,
+    summary: &RunSummary,
+    exit_code: i32,
+) -> Result<()> {
+    let complete = serde_json::json!({
+        "summary": summary,
+        "exit_code": exit_code,
+        "completed_at": chrono::Local::now().to_rfc3339(),
+    });
+    let marker = match summary.partial {
+        Some(_) => PARTIAL_MARKER,
+        None => COMPLETE_MARKER,
+    };
+    fs::write(
+        destination_path.join(marker),
+        serde_json::to_string_pretty(&complete)?,
+    )?;
+    fs::remove_file(destination_path.join(STARTED_MARKER))?;
+    Ok(())
+}
+
+static LOCK_FILE: &str = ".dcmrig.lock";
+
+// Advisory lock on the destination so two runs never write into it at the same time
+// The OS releases the lock when the process ends, also after a crash or a second Ctrl-C
+pub struct DestinationLock {
+    lock_path: PathBuf,
+    _lock_file: fs::File,
+}
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+// Take the lock of the destination, the lock file holds the PID, start time and command of the run
+// A lock held by another process is only taken over with force unlock once its PID is gone
+pub fn lock_destination(destination_path: &PathBuf, force_unlock: bool) -> Result<DestinationLock> {
+    create_dir_all(destination_path)?;
+    let lock_path = destination_path.join(LOCK_FILE);
+    let mut lock_file = open_lock_file(&lock_path)?;
+    match lock_file.try_lock() {
+        Ok(()) => (),
+        Err(fs::TryLockError::WouldBlock) => {
+            let holder = fs::read_to_string(&lock_path).unwrap_or_default();
+            let holder: serde_json::Value = serde_json::from_str(&holder).unwrap_or_default();
+            let holder_pid = holder["pid"].as_u64();
+            let holder_run = format!(
+                "PID {} started at {}: {}",
+                holder_pid.map_or("unknown".to_string(), |pid| pid.to_string()),
+                holder["started_at"].as_str().unwrap_or("unknown"),
+                holder["command"]
+                    .as_array()
+                    .map(|command| {
+                        command
+                            .iter()
+                            .filter_map(|arg| arg.as_str())
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    .unwrap_or_default()
+            );
+            match (force_unlock, holder_pid.and_then(process_alive)) {
+                (true, Some(false)) => {
+                    warn!("Removing the stale lock {} of {}", lock_path.display(), holder_run);
+                    fs::remove_file(&lock_path)?;
+                    lock_file = open_lock_file(&lock_path)?;
+                    lock_file.try_lock().map_err(|e| {
+                        anyhow::Error::msg(format!(
+                            "Could not lock {} after removing the stale lock: {}",
+                            lock_path.display(),
+                            e
+                        ))
+                    })?;
+                }
+                (true, _) => {
+                    return Err(anyhow::Error::msg(format!(
+                        "{} is locked by a run that may still be alive ({}), not unlocking",
+                        destination_path.display(),
+                        holder_run
+                    )))
+                }
+                (false, _) => {
+                    return Err(anyhow::Error::msg(format!(
+                        "{} is in use by another dcmrig run ({}). Wait for it to finish, or use --force-unlock if that run crashed",
+                        destination_path.display(),
+                        holder_run
+                    )))
+                }
+            }
+        }
+        Err(fs::TryLockError::Error(e)) if e.kind() == std::io::ErrorKind::Unsupported => {
+            warn!(
+                "The filesystem of {} doesn't support file locks, concurrent runs are not detected",
+                destination_path.display()
+            );
+        }
+        Err(fs::TryLockError::Error(e)) => return Err(e.into()),
+    }
+    let holder = serde_json::json!({
+        "command": std::env::args().collect::<Vec<_>>(),
+        "pid": std::process::id(),
+        "started_at": chrono::Local::now().to_rfc3339(),
+    });
+    lock_file.set_len(0)?;
+    std::io::Write::write_all(
+        &mut lock_file,
+        serde_json::to_string_pretty(&holder)?.as_bytes(),
+    )?;
+    Ok(DestinationLock {
+        lock_path,
+        _lock_file: lock_file,
+    })
+}
+
+// Opened without truncating, the holder of the lock is read from it when the lock is busy
+fn open_lock_file(lock_path: &PathBuf) -> Result<fs::File> {
+    Ok(fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?)
+}
+
+// None where the process table can't be read
+fn process_alive(pid: u64) -> Option<bool> {
+    match cfg!(target_os = "linux") {
+        true => Some(PathBuf::from("/proc").join(pid.to_string()).exists()),
+        false => None,
+    }
+}
+
+pub fn extract_tag_vr_from_str(tag_name: &String) -> Result<(Tag, VR)> {
+    match resolve_tag_name(tag_name) {
+        Some(v) => Ok((v.tag.inner(), v.vr.relaxed())),
+        None => {
+            warn!("Tag: {} is not valid!{}", tag_name, tag_name_hint(tag_name));
+            Err(anyhow::Error::msg("Tag Not Valid, VR not found!!"))
+        }
+    }
+}
+
+// Groups of the standard dictionary, scanned once to index the keywords for the lookups
+// that are not an exact keyword
+static STANDARD_DICTIONARY_GROUPS: [u16; 66] = [
+    0x0000, 0x0002, 0x0004, 0x0008, 0x0010, 0x0012, 0x0014, 0x0016, 0x0018, 0x0020, 0x0022, 0x0024,
+    0x0028, 0x0032, 0x0034, 0x0038, 0x003A, 0x0040, 0x0042, 0x0044, 0x0046, 0x0048, 0x0050, 0x0052,
+    0x0054, 0x0060, 0x0062, 0x0064, 0x0066, 0x0068, 0x006A, 0x0070, 0x0072, 0x0074, 0x0076, 0x0078,
+    0x0080, 0x0082, 0x0088, 0x0100, 0x0400, 0x2000, 0x2010, 0x2020, 0x2030, 0x2040, 0x2050, 0x2100,
+    0x2110, 0x2120, 0x2130, 0x2200, 0x3002, 0x3004, 0x3006, 0x3008, 0x300A, 0x300C, 0x300E, 0x4000,
+    0x4008, 0x4010, 0x4FFE, 0x5200, 0x5400, 0x7FE0,
+];
+
+// Keywords of the standard dictionary by their normalized form
+static TAG_NAME_INDEX: OnceLock<HashMap<String, &'static str>> = OnceLock::new();
+
+fn tag_name_index() -> &'static HashMap<String, &'static str> {
+    TAG_NAME_INDEX.get_or_init(|| {
+        let dictionary = StandardDataDictionary;
+        let mut index = HashMap::new();
+        for group in STANDARD_DICTIONARY_GROUPS {
+            for element in 0..=u16::MAX {
+                let Some(entry) = dictionary.by_tag(Tag(group, element)) else {
+                    continue;
+                };
+                // Range entries like the overlays are found for every element they cover
+                if entry.tag.inner() == Tag(group, element) {
+                    index.insert(normalize_tag_name(entry.alias), entry.alias);
+                }
+            }
+        }
+        index
+    })
+}
+
+// Lowercase without spaces, underscores and hyphens, eg "Patient ID" and "patient_id" are "patientid"
+fn normalize_tag_name(tag_name: &str) -> String {
+    tag_name
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != '-')
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+// Dictionary entry of a tag name of a configuration, an exact keyword like PatientID,
+// the keyword in any case and with spaces like "patient id", or the (0010,0020) form
+pub fn resolve_tag_name(tag_name: &str) -> Option<DataDictionaryEntryRef<'static>> {
+    let tag_name = tag_name.trim();
+    if let Some(entry) = StandardDataDictionary.by_name(tag_name) {
+        return Some(entry.clone());
+    }
+    let hex = tag_name.trim_start_matches('(').trim_end_matches(')');
+    if let Some((group, element)) = hex.split_once(',') {
+        let group = u16::from_str_radix(group.trim(), 16).ok()?;
+        let element = u16::from_str_radix(element.trim(), 16).ok()?;
+        return StandardDataDictionary
+            .by_tag(Tag(group, element))
+            .filter(|entry| entry.tag.inner() == Tag(group, element))
+            .cloned();
+    }
+    let alias = tag_name_index().get(&normalize_tag_name(tag_name))?;
+    StandardDataDictionary.by_name(alias).cloned()
+}
+
+// Closest keyword by edit distance of the normalized names, for the names that don't resolve
+pub fn closest_tag_name(tag_name: &str) -> Option<&'static str> {
+    let normalized = normalize_tag_name(tag_name);
+    let max_distance = (normalized.chars().count() / 4).max(2);
+    tag_name_index()
+        .iter()
+        .map(|(each, alias)| (edit_distance(&normalized, each), *alias))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(b.1)))
+        .map(|(_, alias)| alias)
+}
+
+// " Did you mean X?" for a tag name that doesn't resolve, empty without a close keyword
+pub fn tag_name_hint(tag_name: &str) -> String {
+    match closest_tag_name(tag_name) {
+        Some(alias) => format!(" Did you mean {}?", alias),
+        None => String::new(),
+    }
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Parse a numeric tag value like " 23 " or "1.0" and zero pad it to the given width
+// Non numeric values fall back to an ordinal derived from the hash of the value
+pub fn pad_number(value: &str, width: usize) -> String {
+    let trimmed = value.trim();
+    let number = match trimmed.parse::<u64>() {
+        Ok(v) => Some(v),
+        Err(_) => trimmed
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite() && *v >= 0.0)
+            .map(|v| v.trunc() as u64),
+    };
+    match number {
+        Some(v) => format!("{:0>width$}", v, width = width),
+        None => {
+            let ordinal_limit = 10u64.checked_pow(width as u32).unwrap_or(u64::MAX);
+            let ordinal =
+                u64::from_str_radix(&short_hash(trimmed), 16).unwrap_or(0) % ordinal_limit;
+            debug!(
+                "Non numeric value {} replaced with ordinal {}",
+                trimmed, ordinal
+            );
+            format!("{:0>width$}", ordinal, width = width)
+        }
+    }
+}
+
+// Short stable hash of a value, the first 8 hex characters of its SHA-256
+// With --redact-logs the values that may identify a patient are logged as a short hash
+// The hash to value lookup is kept in memory and only written to the local key file
+static LOG_REDACTION: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+pub fn enable_log_redaction() {
+    LOG_REDACTION.get_or_init(|| Mutex::new(HashMap::new()));
+}
+
+// Value for a log message or error, like a PatientID, a PatientName, a match value or a path
+// Logged as is, or as <phi:1a2b3c4d> with --redact-logs
+pub fn phi(value: impl std::fmt::Display) -> String {
+    let value = value.to_string();
+    match LOG_REDACTION.get() {
+        Some(redaction_keys) => {
+            let hash = short_hash(&value);
+            redaction_keys
+                .lock()
+                .expect("Failed to lock mutex")
+                .insert(hash.clone(), value);
+            format!("<phi:{}>", hash)
+        }
+        None => value,
+    }
+}
+
+// Write the hash,value lookup of the redacted log values, readable by the owner only
+pub fn write_log_redaction_keys(key_path: &PathBuf) -> Result<()> {
+    use std::io::Write as _;
+    let Some(redaction_keys) = LOG_REDACTION.get() else {
+        return Ok(());
+    };
+    let redaction_keys = redaction_keys.lock().expect("Failed to lock mutex");
+    let mut key_list: Vec<_> = redaction_keys.iter().collect();
+    key_list.sort();
+    let mut key_options = fs::OpenOptions::new();
+    key_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut key_options, 0o600);
+    let mut key_file = std::io::BufWriter::new(key_options.open(key_path)?);
+    writeln!(key_file, "hash,value")?;
+    for (hash, value) in key_list {
+        writeln!(key_file, "{},{}", hash, csv_field(value))?;
+    }
+    key_file.flush()?;
+    info!(
+        "{} redacted log values written to: {}",
+        redaction_keys.len(),
+        key_path.display()
     );
+    Ok(())
+}
+
+pub fn short_hash(value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    digest
+        .iter()
+        .take(4)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Normalization steps of the PatientIDs before the AnonID and mapping table lookups
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatientIdStep {
+    // Remove all whitespace
+    Spaces,
+    Hyphens,
+    Uppercase,
+    // A value of only zeros keeps a single 0
+    LeadingZeros,
+}
+
+// Steps of --normalize-patient-id without a [normalize] section in the cookbook
+pub static DEFAULT_PATIENT_ID_STEPS: [PatientIdStep; 3] = [
+    PatientIdStep::Spaces,
+    PatientIdStep::Hyphens,
+    PatientIdStep::Uppercase,
+];
 
-    create_target_dir(&dir_path)?;
-    Ok(dir_path)
+// PatientID normalization of anon and deid, the values are kept as they are without steps
+// eg "u 012-345" is U012345 with the default steps
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PatientIdNormalizer {
+    pub steps: Vec<PatientIdStep>,
 }
 
-pub fn print_status(
-    total_len: u64,
-    total_proc_failed_files: u64,
-    total_non_dcm_files: u64,
-    action: String,
-) -> Result<()> {
-    let total_processed = total_len - { total_proc_failed_files + total_non_dcm_files };
-    info!("Total Files: {}", total_len);
-    info!("Failed Cases: {}", total_proc_failed_files);
-    info!("NON-DCM files: {}", total_non_dcm_files);
-    info!("Total {}: {}", action, total_processed);
-    Ok(())
+impl PatientIdNormalizer {
+    pub fn normalize(&self, patient_id: &str) -> String {
+        let mut value = patient_id.to_string();
+        for step in &self.steps {
+            value = match step {
+                PatientIdStep::Spaces => value.chars().filter(|c| !c.is_whitespace()).collect(),
+                PatientIdStep::Hyphens => value.replace('-', ""),
+                PatientIdStep::Uppercase => value.to_uppercase(),
+                PatientIdStep::LeadingZeros => match value.trim_start_matches('0') {
+                    "" if !value.is_empty() => "0".to_string(),
+                    trimmed => trimmed.to_string(),
+                },
+            };
+        }
+        value
+    }
 }
 
-pub fn extract_tag_vr_from_str(tag_name: &String) -> Result<(Tag, VR)> {
-    match DataDictionary::by_name(&StandardDataDictionary, &tag_name) {
-        Some(v) => return Ok((v.tag.inner(), v.vr.relaxed())),
-        None => {
-            warn!("Tag: {} is not valid!", tag_name);
-            return Err(anyhow::Error::msg("Tag Not Valid, VR not found!!"));
-        }
+// Parse an interval like 90s, 10m or 2h, a plain number is in seconds
+pub fn parse_interval(interval: &str) -> Result<Duration> {
+    let interval = interval.trim();
+    let (number, unit_secs) = match interval.char_indices().last() {
+        Some((i, 's')) => (&interval[..i], 1),
+        Some((i, 'm')) => (&interval[..i], 60),
+        Some((i, 'h')) => (&interval[..i], 3600),
+        _ => (interval, 1),
     };
+    match number.trim().parse::<u64>() {
+        Ok(number) if number > 0 => Ok(Duration::from_secs(number * unit_secs)),
+        _ => Err(anyhow::Error::msg(format!(
+            "Invalid interval {:?}, expected eg 90s, 10m or 2h",
+            interval
+        ))),
+    }
+}
+
+// Parse the size of --max-file-size in bytes or with a K, M, G or T suffix in powers of 1024
+// eg 500M, 2G or 1.5GiB
+pub fn parse_file_size(size: &str) -> Result<u64> {
+    let upper = size.trim().to_uppercase();
+    let number_end = upper
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(upper.len());
+    let (number, unit) = upper.split_at(number_end);
+    let multiplier: Option<u64> = match unit.trim().trim_end_matches("IB").trim_end_matches('B') {
+        "" => Some(1),
+        "K" => Some(1 << 10),
+        "M" => Some(1 << 20),
+        "G" => Some(1 << 30),
+        "T" => Some(1 << 40),
+        _ => None,
+    };
+    let bytes = match (number.parse::<f64>(), multiplier) {
+        (Ok(number), Some(multiplier)) => (number * multiplier as f64) as u64,
+        _ => 0,
+    };
+    match bytes {
+        0 => Err(anyhow::Error::msg(format!(
+            "Invalid file size {:?}, expected bytes or eg 500M, 2G or 1.5GiB",
+            size
+        ))),
+        bytes => Ok(bytes),
+    }
+}
+
+// Parse the root of the UIDs dcmrig writes eg 1.2.999.999999, the ImplementationClassUID
+// appends .1 to it and has to fit the 64 characters of UI
+pub fn parse_uid_root(uid_root: &str) -> Result<String> {
+    let uid_root = uid_root.trim().trim_end_matches('.');
+    let valid = !uid_root.is_empty()
+        && uid_root.split('.').all(|component| {
+            !component.is_empty()
+                && component.chars().all(|c| c.is_ascii_digit())
+                && (component == "0" || !component.starts_with('0'))
+        });
+    if !valid {
+        return Err(anyhow::Error::msg(format!(
+            "Invalid UID root {:?}, expected dot separated numbers without leading zeros",
+            uid_root
+        )));
+    }
+    if uid_root.len() > 62 {
+        return Err(anyhow::Error::msg(format!(
+            "UID root {:?} is {} characters, at most 62 fit the ImplementationClassUID",
+            uid_root,
+            uid_root.len()
+        )));
+    }
+    Ok(uid_root.to_string())
+}
+
+// Parse an AE title, at most 16 characters without backslash or control characters
+pub fn parse_ae_title(ae_title: &str) -> Result<String> {
+    let ae_title = ae_title.trim();
+    if ae_title.is_empty()
+        || ae_title.len() > 16
+        || ae_title
+            .chars()
+            .any(|c| c == '\\' || !(' '..='~').contains(&c))
+    {
+        return Err(anyhow::Error::msg(format!(
+            "Invalid AE title {:?}, expected 1 to 16 characters without backslash",
+            ae_title
+        )));
+    }
+    Ok(ae_title.to_string())
+}
+
+// Parse the anon prefix, it is part of every AnonID and of the destination paths
+// Only letters, digits, underscore and hyphen, surrounding whitespace is trimmed
+// The AnonID of prefix, '_' and generated ID must fit the 64 characters of LO and PN
+pub fn parse_anon_prefix(prefix: &str) -> Result<String> {
+    let prefix = prefix.trim();
+    if let Some(invalid) = prefix
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        return Err(anyhow::Error::msg(format!(
+            "Invalid character {:?} in anon prefix {:?}, only letters, digits, underscore and hyphen are allowed",
+            invalid, prefix
+        )));
+    }
+    let max_len = 64 - 1 - gen_id().len();
+    if prefix.len() > max_len {
+        return Err(anyhow::Error::msg(format!(
+            "Anon prefix {:?} is {} characters, at most {} fit LO and PN tags",
+            prefix,
+            prefix.len(),
+            max_len
+        )));
+    }
+    Ok(prefix.to_string())
+}
+
+// Parse a tag keyword of the standard dictionary eg PatientID
+pub fn parse_tag_keyword(keyword: &str) -> Result<String> {
+    let keyword = keyword.trim();
+    match resolve_tag_name(keyword) {
+        Some(entry) => Ok(entry.alias.to_string()),
+        None => Err(anyhow::Error::msg(format!(
+            "Unknown tag keyword {:?}, expected a keyword like PatientID.{}",
+            keyword,
+            tag_name_hint(keyword)
+        ))),
+    }
 }
 
 // Generate ANON ID
@@ -538,6 +6674,21 @@ pub fn gen_id() -> String {
     nanoid!(10, &alpha_numeric)
 }
 
+// ID of the same length and alphabet as gen_id, derived from the seed and a key
+// The same seed and key always give the same ID regardless of the processing order
+pub fn seeded_id(seed: u64, key: &str) -> String {
+    let alpha_numeric = &nanoid::alphabet::SAFE[2..];
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(key.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .take(10)
+        .map(|b| alpha_numeric[*b as usize % alpha_numeric.len()])
+        .collect()
+}
+
 fn determine_plane(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<String> {
     let orientation: Vec<f64> = match dcm_obj.element_by_name("ImageOrientationPatient") {
         Ok(value) => value.to_multi_float64()?,
@@ -559,7 +6710,220 @@ fn determine_plane(dcm_obj: &FileDicomObject<InMemDicomObject>) -> Result<String
     Ok(plane)
 }
 
-pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue> {
+// Parse a UTC offset like +0200 or -0530 as used by DT and TimezoneOffsetFromUTC
+fn parse_utc_offset(offset: &str) -> Option<FixedOffset> {
+    if offset.len() != 5 || !offset[1..].chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = offset[1..3].parse().ok()?;
+    let minutes: i32 = offset[3..5].parse().ok()?;
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    let seconds = hours * 3600 + minutes * 60;
+    match &offset[..1] {
+        "+" => FixedOffset::east_opt(seconds),
+        "-" => FixedOffset::west_opt(seconds),
+        _ => None,
+    }
+}
+
+// What to do with a value over the length limit of its VR
+#[derive(Debug, Clone, Default, PartialEq, clap::ValueEnum, Serialize)]
+pub enum LengthPolicy {
+    // Fail the value, a file with it goes to FAILED_CASES
+    #[default]
+    Error,
+    // Warn and cut the value to the limit
+    Truncate,
+    // Warn and write the value as is
+    Keep,
+}
+
+// Maximum characters of a single value, PN limits each component group
+pub fn vr_max_length(vr: VR) -> Option<usize> {
+    match vr {
+        VR::AE | VR::CS | VR::DS | VR::SH => Some(16),
+        VR::AS => Some(4),
+        VR::IS => Some(12),
+        VR::LO | VR::PN | VR::UI => Some(64),
+        VR::ST => Some(1024),
+        VR::LT => Some(10240),
+        _ => None,
+    }
+}
+
+// Check every value of a multi-valued string against the length limit of the VR
+fn check_vr_length(vr: VR, value: &String, length_policy: &LengthPolicy) -> Result<String> {
+    let max_len = match vr_max_length(vr) {
+        Some(max_len) => max_len,
+        None => return Ok(value.clone()),
+    };
+    // PN component groups are separated by '=', every other VR only has the value separator
+    let separators: &[char] = match vr {
+        VR::PN => &['\\', '='],
+        VR::ST | VR::LT => &[],
+        _ => &['\\'],
+    };
+    let too_long = value
+        .split(|c| separators.contains(&c))
+        .any(|part| part.chars().count() > max_len);
+    if !too_long {
+        return Ok(value.clone());
+    }
+    match length_policy {
+        LengthPolicy::Error => Err(anyhow::Error::msg(format!(
+            "Value {:?} is over the {} character limit of {}",
+            phi(value),
+            max_len,
+            vr
+        ))),
+        LengthPolicy::Keep => {
+            warn!(
+                "Value {:?} is over the {} character limit of {}, Keeping it",
+                phi(value),
+                max_len,
+                vr
+            );
+            Ok(value.clone())
+        }
+        LengthPolicy::Truncate => {
+            let mut truncated = String::new();
+            let mut part_len = 0;
+            for c in value.chars() {
+                if separators.contains(&c) {
+                    part_len = 0;
+                } else if part_len == max_len {
+                    continue;
+                } else {
+                    part_len += 1;
+                }
+                truncated.push(c);
+            }
+            // A cut UID must not end on a component separator
+            if vr == VR::UI {
+                truncated = truncated.trim_end_matches('.').to_string();
+            }
+            warn!(
+                "Value {:?} is over the {} character limit of {}, Truncated to {:?}",
+                phi(value),
+                max_len,
+                vr,
+                phi(&truncated)
+            );
+            Ok(truncated)
+        }
+    }
+}
+
+// CS only allows uppercase letters, digits, space and underscore
+// Lowercase letters are uppercased and any other character replaced with an underscore
+pub fn normalize_cs_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9' | ' ' | '_' | '\\') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+// A single PN value split into its alphabetic, ideographic and phonetic component groups
+// and each group into family, given, middle, prefix and suffix components
+// eg "Yamada^Tarou=山田^太郎=やまだ^たろう"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PersonName {
+    pub groups: Vec<Vec<String>>,
+}
+
+impl PersonName {
+    // Padding is trimmed, at most 3 groups of 5 components are kept
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        PersonName {
+            groups: value
+                .splitn(3, '=')
+                .map(|group| {
+                    group
+                        .splitn(5, '^')
+                        .map(|component| component.trim().to_string())
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    pub fn family_name(&self) -> &str {
+        self.groups
+            .first()
+            .and_then(|group| group.first())
+            .map(|family| family.as_str())
+            .unwrap_or("")
+    }
+
+    // Alphabetic group without the trailing empty components eg "Yamada^Tarou"
+    pub fn alphabetic(&self) -> String {
+        let mut components: Vec<&str> = match self.groups.first() {
+            Some(group) => group.iter().map(|c| c.as_str()).collect(),
+            None => vec![],
+        };
+        while components.last().is_some_and(|c| c.is_empty()) {
+            components.pop();
+        }
+        components.join("^")
+    }
+
+    // Every component of every group, for matching a name anywhere in a text
+    pub fn components(&self) -> Vec<&str> {
+        self.groups
+            .iter()
+            .flatten()
+            .map(|c| c.as_str())
+            .filter(|c| !c.is_empty())
+            .collect()
+    }
+
+    pub fn has_other_groups(&self) -> bool {
+        self.groups
+            .iter()
+            .skip(1)
+            .any(|group| group.iter().any(|c| !c.is_empty()))
+    }
+}
+
+// Rebuild each value of a PN with only its alphabetic group
+// The ideographic and phonetic groups would otherwise keep stale content next to a new name
+pub fn single_group_pn(value: &str) -> String {
+    value
+        .split('\\')
+        .map(|each| PersonName::parse(each).alphabetic())
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+pub fn dicom_vr_corrected_value(
+    vr: VR,
+    value: &String,
+    length_policy: &LengthPolicy,
+) -> Result<PrimitiveValue> {
+    let value = &match vr {
+        VR::CS => {
+            let cs_value = normalize_cs_value(value);
+            if &cs_value != value {
+                warn!("CS value {:?} normalized to {:?}", value, cs_value);
+            }
+            cs_value
+        }
+        VR::PN => {
+            let pn_value = single_group_pn(value);
+            if &pn_value != value && value.contains('=') {
+                warn!("PN value {:?} written as {:?}", phi(value), phi(&pn_value));
+            }
+            pn_value
+        }
+        _ => value.clone(),
+    };
+    let value = &check_vr_length(vr, value, length_policy)?;
     let r_value = match vr {
         VR::AE | VR::AS | VR::PN | VR::SH | VR::CS | VR::LO | VR::UI | VR::UC => {
             dicom_value!(Strs, [value.clone()])
@@ -569,22 +6933,20 @@ pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue
         }
         VR::DA => {
             if value.len() != 8 {
-                error!(
+                return Err(anyhow::Error::msg(format!(
                     "Issue With Date value Does it follow this format YYYYMMDD: {}",
-                    value
-                );
-                exit(1);
+                    phi(value)
+                )));
             }
-            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(&value, "%Y%m%d")?)?;
+            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(value, "%Y%m%d")?)?;
             dicom_value!(Date, d_date)
         }
         VR::TM => {
             if value.len() != 6 {
-                error!(
+                return Err(anyhow::Error::msg(format!(
                     "Issue With Time value Does it follow this format HHMMSS: {}",
-                    value
-                );
-                exit(1);
+                    phi(value)
+                )));
             }
             let hr: u8 = value[0..2].to_string().parse()?;
             let min: u8 = value[2..4].to_string().parse()?;
@@ -594,32 +6956,1074 @@ pub fn dicom_vr_corrected_value(vr: VR, value: &String) -> Result<PrimitiveValue
         }
         VR::DT => {
             let split_value: Vec<&str> = value.split("T").collect();
+            if split_value.len() != 2 {
+                return Err(anyhow::Error::msg(format!(
+                    "Issue With DateTime value Does it follow this format YYYYMMDDTHHMMSS: {}",
+                    phi(value)
+                )));
+            }
             let t_date = split_value[0];
             let t_time = split_value[1];
 
             if t_date.len() != 8 {
-                error!(
+                return Err(anyhow::Error::msg(format!(
                     "Issue With Date value Does it follow this format YYYYMMDD: {}",
-                    value
-                );
-                exit(1);
+                    phi(value)
+                )));
             }
-            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(&t_date, "%Y%m%d")?)?;
+            let d_date = DicomDate::try_from(&NaiveDate::parse_from_str(t_date, "%Y%m%d")?)?;
 
+            // Optional UTC offset eg 090000+0200
+            let (t_time, t_offset) = match t_time.find(['+', '-']) {
+                Some(i) => (&t_time[..i], Some(&t_time[i..])),
+                None => (t_time, None),
+            };
             if t_time.len() != 6 {
-                error!(
+                return Err(anyhow::Error::msg(format!(
                     "Issue With Time value Does it follow this format HHMMSS: {}",
-                    value
-                );
-                exit(1);
+                    phi(value)
+                )));
             }
             let hr: u8 = t_time[0..2].to_string().parse()?;
             let min: u8 = t_time[2..4].to_string().parse()?;
             let sec: u8 = t_time[4..6].to_string().parse()?;
             let d_time = DicomTime::from_hms(hr, min, sec)?;
-            dicom_value!(DateTime, DicomDateTime::from_date_and_time(d_date, d_time)?)
+            match t_offset {
+                Some(t_offset) => {
+                    let offset = parse_utc_offset(t_offset).ok_or_else(|| {
+                        anyhow::Error::msg(format!(
+                            "Issue With UTC offset Does it follow this format +HHMM: {}",
+                            phi(value)
+                        ))
+                    })?;
+                    dicom_value!(
+                        DateTime,
+                        DicomDateTime::from_date_and_time_with_time_zone(d_date, d_time, offset)?
+                    )
+                }
+                None => dicom_value!(DateTime, DicomDateTime::from_date_and_time(d_date, d_time)?),
+            }
         }
         _ => dicom_value!(Str, value.clone()),
     };
     Ok(r_value)
 }
+
+// Expected value format for a VR, used when reporting invalid values
+pub fn vr_value_format(vr: VR) -> &'static str {
+    match vr {
+        VR::DA => "YYYYMMDD",
+        VR::TM => "HHMMSS",
+        VR::DT => "YYYYMMDDTHHMMSS with an optional +HHMM offset",
+        _ => "text",
+    }
+}
+
+// Keyword of a tag eg PatientID, the tag number for private and unknown tags
+pub fn tag_keyword(tag: Tag) -> String {
+    match StandardDataDictionary.by_tag(tag) {
+        Some(entry) => entry.alias.to_string(),
+        None => tag.to_string(),
+    }
+}
+
+// An element whose VR differs from the dictionary VR
+#[derive(Debug, Clone)]
+pub struct VrCorrection {
+    pub tag: Tag,
+    pub found: VR,
+    pub expected: VR,
+    // Why the value could not be converted, None when the element was rewritten
+    pub error: Option<String>,
+}
+
+// Rewrite the top level elements with a VR other than the dictionary VR to the dictionary VR
+// when their value converts cleanly, the others are left alone and returned with the error
+// Only UN, text and number VRs are rewritten. Private tags, group lengths, sequences,
+// bulk data and tags with a VR that depends on the context like xs or ox are skipped
+pub fn normalize_vrs(dcm_obj: &mut FileDicomObject<InMemDicomObject>) -> Vec<VrCorrection> {
+    let mismatched: Vec<(Tag, VR, VR)> = dcm_obj
+        .iter()
+        .filter_map(|element| {
+            let tag = element.tag();
+            if tag.group() % 2 == 1 || tag.element() == 0 || tag == tags::PIXEL_DATA {
+                return None;
+            }
+            let expected = match StandardDataDictionary.by_tag(tag)?.vr {
+                VirtualVr::Exact(vr) => vr,
+                _ => return None,
+            };
+            let found = element.vr();
+            match found != expected && is_fixable_vr(found) && is_fixable_vr(expected) {
+                true => Some((tag, found, expected)),
+                false => None,
+            }
+        })
+        .collect();
+    let mut corrections = Vec::new();
+    for (tag, found, expected) in mismatched {
+        let converted = match dcm_obj
+            .element(tag)
+            .ok()
+            .and_then(|e| e.value().primitive())
+        {
+            Some(value) => convert_vr_value(value, found, expected),
+            None => Err(anyhow::Error::msg("Not a primitive value")),
+        };
+        let error = match converted {
+            Ok(value) => {
+                dcm_obj.put(DataElement::new(tag, expected, value));
+                None
+            }
+            Err(e) => Some(e.to_string()),
+        };
+        corrections.push(VrCorrection {
+            tag,
+            found,
+            expected,
+            error,
+        });
+    }
+    corrections
+}
+
+fn is_fixable_vr(vr: VR) -> bool {
+    !matches!(
+        vr,
+        VR::SQ | VR::OB | VR::OW | VR::OF | VR::OD | VR::OL | VR::OV | VR::AT | VR::SV | VR::UV
+    )
+}
+
+// Value of an element read with the found VR as a value of the expected VR
+// UN values are their raw little endian bytes, the other VRs are read as text
+fn convert_vr_value(value: &PrimitiveValue, found: VR, expected: VR) -> Result<PrimitiveValue> {
+    let converted = match expected {
+        VR::US => PrimitiveValue::U16(vr_numbers(value, found, u16::from_le_bytes)?.into()),
+        VR::SS => PrimitiveValue::I16(vr_numbers(value, found, i16::from_le_bytes)?.into()),
+        VR::UL => PrimitiveValue::U32(vr_numbers(value, found, u32::from_le_bytes)?.into()),
+        VR::SL => PrimitiveValue::I32(vr_numbers(value, found, i32::from_le_bytes)?.into()),
+        VR::FL => PrimitiveValue::F32(vr_numbers(value, found, f32::from_le_bytes)?.into()),
+        VR::FD => PrimitiveValue::F64(vr_numbers(value, found, f64::from_le_bytes)?.into()),
+        _ => {
+            let text = match (found, value) {
+                (VR::UN, PrimitiveValue::U8(bytes)) => String::from_utf8(bytes.to_vec())
+                    .map_err(|_| anyhow::Error::msg("UN value is not text"))?,
+                _ => value.to_str().to_string(),
+            };
+            let values: Vec<String> = match expected {
+                // A backslash is part of the text of these VRs
+                VR::ST | VR::LT | VR::UT | VR::UR => {
+                    vec![text.trim_end_matches(['\0', ' ']).to_string()]
+                }
+                _ => text
+                    .trim_end_matches(['\0', ' '])
+                    .split('\\')
+                    .map(|v| v.trim().to_string())
+                    .collect(),
+            };
+            if let Some(invalid) = values.iter().find(|v| !is_valid_vr_value(expected, v)) {
+                return Err(anyhow::Error::msg(format!(
+                    "{} is not a valid {} value",
+                    phi(invalid),
+                    expected
+                )));
+            }
+            match expected {
+                VR::ST | VR::LT | VR::UT | VR::UR => PrimitiveValue::Str(values.concat()),
+                _ => PrimitiveValue::Strs(values.into()),
+            }
+        }
+    };
+    Ok(converted)
+}
+
+// Numbers of a binary VR from the raw bytes of UN or the text of the other VRs
+fn vr_numbers<T: std::str::FromStr, const N: usize>(
+    value: &PrimitiveValue,
+    found: VR,
+    from_le_bytes: fn([u8; N]) -> T,
+) -> Result<Vec<T>> {
+    match (found, value) {
+        (VR::UN, PrimitiveValue::U8(bytes)) => {
+            if bytes.len() % N != 0 {
+                return Err(anyhow::Error::msg(format!(
+                    "{} bytes are not a multiple of {}",
+                    bytes.len(),
+                    N
+                )));
+            }
+            Ok(bytes
+                .chunks_exact(N)
+                .map(|chunk| from_le_bytes(chunk.try_into().expect("Chunk of N bytes")))
+                .collect())
+        }
+        _ => value
+            .to_str()
+            .trim_end_matches(['\0', ' '])
+            .split('\\')
+            .map(|v| {
+                v.trim()
+                    .parse::<T>()
+                    .map_err(|_| anyhow::Error::msg(format!("{} is not a number", phi(v))))
+            })
+            .collect(),
+    }
+}
+
+// Format and length of a single value of a text VR, empty values are valid
+pub fn is_valid_vr_value(vr: VR, value: &str) -> bool {
+    let digits = |v: &str| v.chars().all(|c| c.is_ascii_digit());
+    if value.is_empty() {
+        return true;
+    }
+    // Date, time, number and code VRs are plain ASCII
+    if matches!(
+        vr,
+        VR::DA | VR::TM | VR::DT | VR::IS | VR::DS | VR::UI | VR::AS | VR::CS
+    ) && !value.is_ascii()
+    {
+        return false;
+    }
+    if let Some(max_length) = vr_max_length(vr) {
+        if value.chars().count() > max_length {
+            return false;
+        }
+    }
+    match vr {
+        VR::DA => {
+            value.len() == 8 && digits(value) && NaiveDate::parse_from_str(value, "%Y%m%d").is_ok()
+        }
+        VR::TM => {
+            let (hms, fraction) = value.split_once('.').unwrap_or((value, "0"));
+            [2, 4, 6].contains(&hms.len())
+                && digits(hms)
+                && (1..=6).contains(&fraction.len())
+                && digits(fraction)
+        }
+        VR::DT => {
+            let value = match value.len() > 5 && value[value.len() - 5..].starts_with(['+', '-']) {
+                true => &value[..value.len() - 5],
+                false => value,
+            };
+            let (date_time, fraction) = value.split_once('.').unwrap_or((value, "0"));
+            [4, 6, 8, 10, 12, 14].contains(&date_time.len())
+                && digits(date_time)
+                && (1..=6).contains(&fraction.len())
+                && digits(fraction)
+        }
+        VR::IS => value.parse::<i64>().is_ok(),
+        VR::DS => value.parse::<f64>().is_ok(),
+        VR::UI => value.chars().all(|c| c.is_ascii_digit() || c == '.'),
+        VR::AS => value.len() == 4 && digits(&value[..3]) && value.ends_with(['D', 'W', 'M', 'Y']),
+        VR::CS => value
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == ' ' || c == '_'),
+        _ => !value.contains(|c: char| c.is_control() && !matches!(c, '\r' | '\n' | '\t')),
+    }
+}
+
+// Corrections of --fix-vrs of all files of a run, written to vr_fixes.csv
+#[derive(Debug, Clone, Default)]
+pub struct VrFixLog {
+    rows: Arc<Mutex<Vec<(PathBuf, VrCorrection)>>>,
+}
+
+impl VrFixLog {
+    pub fn write(&self, destination_path: &Path) -> Result<()> {
+        let rows = self.rows.lock().expect("Failed to lock mutex");
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut fixes_csv =
+            "source_path,tag,keyword,found_vr,dictionary_vr,status,error\n".to_string();
+        for (source_path, correction) in rows.iter() {
+            fixes_csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                csv_field(&source_path.display().to_string()),
+                csv_field(&correction.tag.to_string()),
+                tag_keyword(correction.tag),
+                correction.found,
+                correction.expected,
+                match correction.error {
+                    Some(_) => "unconverted",
+                    None => "fixed",
+                },
+                csv_field(correction.error.as_deref().unwrap_or(""))
+            ));
+        }
+        let fixes_path = destination_path.join("vr_fixes.csv");
+        fs::write(&fixes_path, fixes_csv)?;
+        let unconverted = rows.iter().filter(|(_, c)| c.error.is_some()).count();
+        info!(
+            "VR corrections written to: {} | {} fixed | {} unconverted",
+            fixes_path.display(),
+            rows.len() - unconverted,
+            unconverted
+        );
+        Ok(())
+    }
+}
+
+// Fix the VRs of a file with --fix-vrs, true when an element was rewritten
+pub fn fix_file_vrs(
+    dcm_obj: &mut FileDicomObject<InMemDicomObject>,
+    each_file: &DirEntry,
+    run_options: &RunOptions,
+) -> bool {
+    if !run_options.fix_vrs {
+        return false;
+    }
+    let corrections = normalize_vrs(dcm_obj);
+    for correction in &corrections {
+        match &correction.error {
+            None => info!(
+                "VR of {} in {:?} fixed from {} to {}",
+                tag_keyword(correction.tag),
+                phi(each_file.file_name().to_string_lossy()),
+                correction.found,
+                correction.expected
+            ),
+            Some(e) => warn!(
+                "VR {} of {} in {:?} left as is, expected {}: {}",
+                correction.found,
+                tag_keyword(correction.tag),
+                phi(each_file.file_name().to_string_lossy()),
+                correction.expected,
+                e
+            ),
+        }
+    }
+    let fixed = corrections.iter().any(|c| c.error.is_none());
+    run_options
+        .vr_fix_log
+        .rows
+        .lock()
+        .expect("Failed to lock mutex")
+        .extend(
+            corrections
+                .into_iter()
+                .map(|c| (each_file.path().to_path_buf(), c)),
+        );
+    fixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CT object with the given elements and the meta group of its SOPInstanceUID
+    fn object_with(elements: &[(Tag, VR, &str)]) -> FileDicomObject<InMemDicomObject> {
+        let mut dcm_obj = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.840.10008.5.1.4.1.1.2"),
+            ),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.840.113619.2.55.3.1.2.3.4.5"),
+            ),
+        ]);
+        for (tag, vr, value) in elements {
+            dcm_obj.put(DataElement::new(*tag, *vr, dicom_value!(Str, *value)));
+        }
+        dcm_obj
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+                    .media_storage_sop_instance_uid("1.2.840.113619.2.55.3.1.2.3.4.5")
+                    .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN.uid()),
+            )
+            .expect("Failed to build the file meta group")
+    }
+
+    // Write the object to a file named after the test and open it again
+    fn round_trip(
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+        test_name: &str,
+    ) -> FileDicomObject<InMemDicomObject> {
+        let full_path = std::env::temp_dir().join(format!(
+            "dcmrig_test_{}_{}.dcm",
+            test_name,
+            std::process::id()
+        ));
+        write_dicom_file(
+            dcm_obj,
+            &full_path.to_string_lossy(),
+            &IoRetryPolicy::default(),
+        )
+        .expect("Failed to write the file");
+        let read_back = dicom::object::open_file(&full_path).expect("Failed to read the file");
+        let _ = fs::remove_file(&full_path);
+        read_back
+    }
+
+    fn element_str(dcm_obj: &InMemDicomObject, tag: Tag) -> String {
+        dcm_obj.element(tag).unwrap().to_str().unwrap().to_string()
+    }
+
+    fn tag_values(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn series_values() -> HashMap<String, String> {
+        tag_values(&[
+            ("PatientID", "PAT0001"),
+            ("StudyDate", "20240115"),
+            ("StudyTime", "101500.123"),
+            ("StudyInstanceUID", "1.2.840.113619.2.55.3.48213"),
+            ("SeriesInstanceUID", "1.2.840.113619.2.55.3.48213.7"),
+            ("SeriesNumber", "3"),
+            ("SeriesDescription", "T2 Ax"),
+            ("ImagePlane", "AX"),
+            ("EchoTimeMs", "TE30"),
+        ])
+    }
+
+    #[test]
+    fn computes_the_series_dir_path() {
+        let dir_path = compute_dicom_dir_path(
+            &series_values(),
+            Path::new("/dest"),
+            &DerivedValues::default(),
+            None,
+        );
+        assert_eq!(
+            dir_path,
+            PathBuf::from("/dest/PAT0001/20240115T101500_48213/0003_T2_AX_AX")
+        );
+    }
+
+    #[test]
+    fn joins_the_multiple_values_of_a_tag_with_an_underscore() {
+        let mut values = series_values();
+        values.insert("PatientID".to_string(), "PAT0001\\PAT0002".to_string());
+        values.insert("SeriesDescription".to_string(), "T2\\FLAIR ".to_string());
+        let dir_path = compute_dicom_dir_path(
+            &values,
+            Path::new("/dest"),
+            &DerivedValues(Vec::new()),
+            None,
+        );
+        assert_eq!(
+            dir_path,
+            PathBuf::from("/dest/PAT0001_PAT0002/20240115T101500_48213/0003_T2_FLAIR")
+        );
+    }
+
+    #[test]
+    fn gives_empty_components_for_the_missing_tags() {
+        let values = tag_values(&[
+            ("StudyInstanceUID", "1.2.3.4.5"),
+            ("SeriesInstanceUID", "1.2.3.4.5.6"),
+        ]);
+        let dir_path = compute_dicom_dir_path(
+            &values,
+            Path::new("/dest"),
+            &DerivedValues(Vec::new()),
+            None,
+        );
+        // The empty SeriesNumber takes the ordinal of pad_number like any non numeric value
+        assert_eq!(
+            dir_path,
+            PathBuf::from(format!("/dest//T_00005/{}_", pad_number("", 4)))
+        );
+    }
+
+    #[test]
+    fn suffixes_the_series_dir_with_the_derived_values() {
+        let derived_values = parse_derived_values("ImagePlane,EchoTimeMs").unwrap();
+        let dir_path = compute_dicom_dir_path(
+            &series_values(),
+            Path::new("/dest"),
+            &derived_values,
+            Some("STUDY_01".to_string()),
+        );
+        assert_eq!(
+            dir_path,
+            PathBuf::from("/dest/PAT0001/STUDY_01/0003_T2_AX_AX_TE30")
+        );
+        assert_eq!(parse_derived_values("none").unwrap().0, Vec::new());
+        assert!(parse_derived_values("ImagePlane,Colour").is_err());
+    }
+
+    #[test]
+    fn diverts_a_second_series_of_the_same_dir_with_strict_layout() {
+        let first = series_values();
+        let mut second = series_values();
+        second.insert(
+            "SeriesInstanceUID".to_string(),
+            "1.2.840.113619.2.55.3.48213.8".to_string(),
+        );
+        let destination = Path::new("/dest");
+        let derived_values = DerivedValues::default();
+        let first_dir = compute_dicom_dir_path(&first, destination, &derived_values, None);
+        let second_dir = compute_dicom_dir_path(&second, destination, &derived_values, None);
+        // Series number and description alone give the two series one directory
+        assert_eq!(first_dir, second_dir);
+        let dir_path = first_dir.to_string_lossy().to_string();
+
+        let series_dirs = SeriesDirs::new(true);
+        let resolve = |values: &HashMap<String, String>| {
+            series_dirs.resolve(dir_path.clone(), &values["SeriesInstanceUID"])
+        };
+        assert_eq!(resolve(&first), dir_path);
+        assert_eq!(resolve(&second), format!("{}_8", dir_path));
+        assert_eq!(resolve(&first), dir_path);
+
+        // Without --strict-layout the series share the directory
+        let series_dirs = SeriesDirs::new(false);
+        series_dirs.resolve(dir_path.clone(), &first["SeriesInstanceUID"]);
+        assert_eq!(
+            series_dirs.resolve(dir_path.clone(), &second["SeriesInstanceUID"]),
+            dir_path
+        );
+    }
+
+    #[test]
+    fn trims_the_uid_padding() {
+        assert_eq!(trim_uid("1.2.840.10008.1.2.1\0"), "1.2.840.10008.1.2.1");
+        assert_eq!(trim_uid(" 1.2.3 "), "1.2.3");
+        assert_eq!(trim_uid("1.2.3"), "1.2.3");
+        assert_eq!(trim_uid("\0"), "");
+    }
+
+    #[test]
+    fn remaps_a_padded_uid_without_an_empty_component() {
+        let uid_root = "1.2.999";
+        for padded in [
+            "1.2.840.113619.2.55.3.4.12345\0",
+            "1.2.840.113619.2.55.3.4.12345 ",
+            "1.2.840.113619.2.55.3.4.12345.",
+        ] {
+            assert_eq!(
+                remap_uid(padded, uid_root).as_deref(),
+                Some("1.2.999.12345"),
+                "{:?}",
+                padded
+            );
+        }
+        // Padding doesn't make a component
+        assert_eq!(remap_uid("1.2.840.113619.2.55.3.4\0", uid_root), None);
+    }
+
+    #[test]
+    fn round_trips_the_remapped_padded_uids() {
+        let dcm_obj = object_with(&[
+            (
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                "1.2.840.113619.2.55.3.4.1234\0",
+            ),
+            (
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                "1.2.840.113619.2.55.3.4.1234.5\0",
+            ),
+            (
+                tags::FRAME_OF_REFERENCE_UID,
+                VR::UI,
+                "1.2.840.113619.2.55.3.4.1234.6 ",
+            ),
+        ]);
+        let (dcm_obj, changed) = anon_dicom_uids(
+            dcm_obj,
+            DEFAULT_UID_ROOT,
+            &LengthPolicy::default(),
+            &KeepList::default(),
+        )
+        .unwrap();
+        assert_eq!(changed, 4);
+        let read_back = round_trip(&dcm_obj, "round_trips_the_remapped_padded_uids");
+        for (tag, suffix) in [
+            (tags::STUDY_INSTANCE_UID, "1234"),
+            (tags::SERIES_INSTANCE_UID, "1234.5"),
+            (tags::FRAME_OF_REFERENCE_UID, "1234.6"),
+        ] {
+            let uid = element_str(&read_back, tag);
+            // The encoder pads the odd length UIDs with a NUL, the value itself has none
+            assert_eq!(trim_uid(&uid), format!("{}.{}", DEFAULT_UID_ROOT, suffix));
+            assert!(!trim_uid(&uid).split('.').any(|part| part.is_empty()));
+        }
+    }
+
+    // The value as it is encoded in a file
+    fn corrected_str(vr: VR, value: &str) -> Result<String> {
+        dicom_vr_corrected_value(vr, &value.to_string(), &LengthPolicy::default()).map(|value| {
+            match value {
+                PrimitiveValue::DateTime(values) => values[0].to_encoded(),
+                value => value.to_str().to_string(),
+            }
+        })
+    }
+
+    #[test]
+    fn parses_a_datetime_with_and_without_an_offset() {
+        assert_eq!(
+            corrected_str(VR::DT, "20240101T090000").unwrap(),
+            "20240101090000"
+        );
+        assert_eq!(
+            corrected_str(VR::DT, "20240101T090000+0200").unwrap(),
+            "20240101090000+0200"
+        );
+        assert_eq!(
+            corrected_str(VR::DT, "20240101T090000-0530").unwrap(),
+            "20240101090000-0530"
+        );
+    }
+
+    #[test]
+    fn refuses_a_malformed_datetime_offset() {
+        for value in [
+            "20240101T090000+2500",
+            "20240101T090000+02",
+            "20240101T090000+02:00",
+            "20240101T0900+0200",
+            "20240101090000+0200",
+        ] {
+            assert!(corrected_str(VR::DT, value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn sanitizes_visually_identical_descriptions_the_same() {
+        let plain = replace_non_alphanumeric("Series T1");
+        assert_eq!(plain, "Series_T1");
+        for lookalike in [
+            "Series\u{a0}T1",
+            "Series\u{202f}T1",
+            "Series \u{200b}T1",
+            "Se\u{200b}ries T1\u{feff}",
+            "Series\u{3000}T1",
+        ] {
+            assert_eq!(
+                replace_non_alphanumeric(lookalike),
+                plain,
+                "{:?}",
+                lookalike
+            );
+        }
+    }
+
+    #[test]
+    fn strips_the_accents_precomposed_or_combining() {
+        // U+00E9 and e followed by the combining acute accent U+0301
+        assert_eq!(replace_non_alphanumeric("S\u{e9}ries"), "Series");
+        assert_eq!(replace_non_alphanumeric("Se\u{301}ries"), "Series");
+        assert_eq!(transliterate_to_ascii("Se\u{301}ries\u{a0}T1"), "Series T1");
+        // Letters without an ASCII base are left for the replacement
+        assert_eq!(replace_non_alphanumeric("\u{5c71}T1"), "_T1");
+    }
+
+    #[test]
+    fn checks_each_limited_vr_at_and_over_its_limit() {
+        let limits = [
+            (VR::AE, 16),
+            (VR::CS, 16),
+            (VR::DS, 16),
+            (VR::SH, 16),
+            (VR::AS, 4),
+            (VR::IS, 12),
+            (VR::LO, 64),
+            (VR::PN, 64),
+            (VR::UI, 64),
+            (VR::ST, 1024),
+            (VR::LT, 10240),
+        ];
+        for (vr, max_len) in limits {
+            assert_eq!(vr_max_length(vr), Some(max_len));
+            let at_limit = "1".repeat(max_len);
+            let over_limit = "1".repeat(max_len + 1);
+            assert_eq!(
+                check_vr_length(vr, &at_limit, &LengthPolicy::Error).unwrap(),
+                at_limit
+            );
+            assert!(check_vr_length(vr, &over_limit, &LengthPolicy::Error).is_err());
+            assert_eq!(
+                check_vr_length(vr, &over_limit, &LengthPolicy::Truncate).unwrap(),
+                at_limit,
+                "{}",
+                vr
+            );
+            assert_eq!(
+                check_vr_length(vr, &over_limit, &LengthPolicy::Keep).unwrap(),
+                over_limit
+            );
+        }
+        for vr in [VR::UT, VR::UC, VR::DA, VR::OB] {
+            assert_eq!(vr_max_length(vr), None);
+            let long = "1".repeat(20000);
+            assert_eq!(
+                check_vr_length(vr, &long, &LengthPolicy::Error).unwrap(),
+                long
+            );
+        }
+    }
+
+    #[test]
+    fn limits_each_value_and_pn_component_group() {
+        // Each of the multiple values has its own limit
+        let values = format!("{}\\{}", "A".repeat(16), "B".repeat(16));
+        assert!(check_vr_length(VR::SH, &values, &LengthPolicy::Error).is_ok());
+        let values = format!("{}\\{}", "A".repeat(16), "B".repeat(17));
+        assert_eq!(
+            check_vr_length(VR::SH, &values, &LengthPolicy::Truncate).unwrap(),
+            format!("{}\\{}", "A".repeat(16), "B".repeat(16))
+        );
+        // And so has each PN component group
+        let name = format!("{}={}", "A".repeat(64), "B".repeat(64));
+        assert!(check_vr_length(VR::PN, &name, &LengthPolicy::Error).is_ok());
+        // ST and LT are a single value, the backslash is text
+        let text = format!("{}\\{}", "A".repeat(1000), "B".repeat(1000));
+        assert!(check_vr_length(VR::ST, &text, &LengthPolicy::Error).is_err());
+    }
+
+    #[test]
+    fn truncates_a_uid_without_a_trailing_separator() {
+        let uid = format!("{}.123", "1".repeat(63));
+        assert_eq!(
+            check_vr_length(VR::UI, &uid, &LengthPolicy::Truncate).unwrap(),
+            "1".repeat(63)
+        );
+    }
+
+    // VR of the element and its correction, None when normalize_vrs left it out
+    fn normalized(element: DataElement<InMemDicomObject>) -> (InMemElement, Option<VrCorrection>) {
+        let tag = element.tag();
+        let mut dcm_obj = object_with(&[]);
+        dcm_obj.put(element);
+        let mut corrections = normalize_vrs(&mut dcm_obj);
+        assert!(corrections.len() <= 1);
+        (dcm_obj.element(tag).unwrap().clone(), corrections.pop())
+    }
+
+    #[test]
+    fn fixes_the_vrs_of_broken_converters() {
+        let fixtures = [
+            // Dates sent as UN, numbers and codes written as LO
+            (
+                DataElement::new(
+                    tags::STUDY_DATE,
+                    VR::UN,
+                    PrimitiveValue::U8(b"20240115".as_slice().into()),
+                ),
+                VR::DA,
+                "20240115",
+            ),
+            (
+                DataElement::new(tags::SERIES_NUMBER, VR::LO, dicom_value!(Str, " 3 ")),
+                VR::IS,
+                "3",
+            ),
+            (
+                DataElement::new(tags::ACCESSION_NUMBER, VR::LO, dicom_value!(Str, "ACC0001")),
+                VR::SH,
+                "ACC0001",
+            ),
+            (
+                DataElement::new(
+                    tags::STUDY_TIME,
+                    VR::UN,
+                    PrimitiveValue::U8(b"101500".as_slice().into()),
+                ),
+                VR::TM,
+                "101500",
+            ),
+            (
+                DataElement::new(
+                    tags::ROWS,
+                    VR::UN,
+                    PrimitiveValue::U8([0, 2].as_slice().into()),
+                ),
+                VR::US,
+                "512",
+            ),
+            (
+                DataElement::new(tags::ROWS, VR::LO, dicom_value!(Str, "256")),
+                VR::US,
+                "256",
+            ),
+            (
+                DataElement::new(
+                    tags::IMAGE_POSITION_PATIENT,
+                    VR::LO,
+                    dicom_value!(
+                        Strs,
+                        ["-125".to_string(), "-125".to_string(), "10.5".to_string()]
+                    ),
+                ),
+                VR::DS,
+                "-125\\-125\\10.5",
+            ),
+        ];
+        for (element, expected, value) in fixtures {
+            let (tag, found) = (element.tag(), element.vr());
+            let (fixed, correction) = normalized(element);
+            let correction = correction.expect("No correction");
+            assert_eq!(
+                (correction.tag, correction.found, correction.expected),
+                (tag, found, expected)
+            );
+            assert_eq!(correction.error, None, "{}", tag_keyword(tag));
+            assert_eq!(fixed.vr(), expected);
+            assert_eq!(fixed.to_str().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn leaves_the_values_that_do_not_convert() {
+        let fixtures = [
+            DataElement::new(tags::STUDY_DATE, VR::LO, dicom_value!(Str, "yesterday")),
+            DataElement::new(tags::STUDY_DATE, VR::LO, dicom_value!(Str, "20241315")),
+            DataElement::new(tags::SERIES_NUMBER, VR::LO, dicom_value!(Str, "three")),
+            DataElement::new(
+                tags::ROWS,
+                VR::UN,
+                PrimitiveValue::U8([2, 0, 0].as_slice().into()),
+            ),
+            DataElement::new(
+                tags::STUDY_DATE,
+                VR::UN,
+                PrimitiveValue::U8([0xff, 0xfe, 0x00, 0x01].as_slice().into()),
+            ),
+        ];
+        for element in fixtures {
+            let original = element.clone();
+            let (left, correction) = normalized(element);
+            let correction = correction.expect("No correction");
+            assert!(correction.error.is_some(), "{:?}", original);
+            assert_eq!(left, original);
+        }
+    }
+
+    #[test]
+    fn skips_the_private_structural_and_matching_elements() {
+        let fixtures = [
+            DataElement::new(
+                Tag(0x0009, 0x1001),
+                VR::UN,
+                PrimitiveValue::U8([1, 2].as_slice().into()),
+            ),
+            DataElement::new(
+                tags::PIXEL_DATA,
+                VR::OB,
+                PrimitiveValue::U8([0, 0, 0, 0].as_slice().into()),
+            ),
+            DataElement::new(tags::STUDY_DATE, VR::DA, dicom_value!(Str, "20240115")),
+            DataElement::new(
+                tags::ACCESSION_NUMBER,
+                VR::OB,
+                PrimitiveValue::U8([65, 66].as_slice().into()),
+            ),
+        ];
+        for element in fixtures {
+            let original = element.clone();
+            let (left, correction) = normalized(element);
+            assert!(correction.is_none(), "{:?}", original);
+            assert_eq!(left, original);
+        }
+    }
+
+    #[test]
+    fn takes_the_last_5_digits_of_the_study_uid() {
+        let tail = |study_uid: &str| {
+            study_uid_tail(&tag_values(&[
+                ("StudyInstanceUID", study_uid),
+                ("SeriesInstanceUID", "1.2.3.4.5.6"),
+            ]))
+        };
+        assert_eq!(tail("1.2.840.113619.2.55.3.48213"), "48213");
+        assert_eq!(tail("1.2.840.113619.2.55.3.1234567\0"), "34567");
+        // A short last component is left short, the directory name pads it
+        assert_eq!(tail("1.2.840.113619.2.55.3.42"), "42");
+        assert_eq!(tail("7"), "7");
+    }
+
+    #[test]
+    fn hashes_the_series_uid_for_an_unusable_study_uid() {
+        let series_hash = short_hash("1.2.3.4.5.6")[..5].to_string();
+        for study_uid in ["", "\0", "NoValue_StudyInstanceUID", "1.2.3.", "1.2.3.4x"] {
+            let tail = study_uid_tail(&tag_values(&[
+                ("StudyInstanceUID", study_uid),
+                ("SeriesInstanceUID", "1.2.3.4.5.6"),
+            ]));
+            assert_eq!(tail, series_hash, "{:?}", study_uid);
+        }
+        let tail = study_uid_tail(&tag_values(&[("SeriesInstanceUID", "1.2.3.4.5.6")]));
+        assert_eq!(tail, series_hash);
+    }
+
+    #[test]
+    fn hashes_the_patient_and_study_date_without_any_uid() {
+        let values = tag_values(&[
+            ("StudyInstanceUID", "NoValue_StudyInstanceUID"),
+            ("SeriesInstanceUID", "NoValue_SeriesInstanceUID"),
+            ("PatientID", "PAT0001"),
+            ("StudyDate", "20240115"),
+            ("StudyTime", "101500"),
+        ]);
+        assert_eq!(
+            study_uid_tail(&values),
+            short_hash("PAT0001_20240115_101500")[..5]
+        );
+        // Nothing to hash at all still gives a name
+        assert_eq!(study_uid_tail(&HashMap::new()).chars().count(), 5);
+        assert_eq!(
+            date_study_dir(&tag_values(&[
+                ("StudyDate", "20240115"),
+                ("StudyTime", "101500"),
+                ("StudyInstanceUID", "1.2.42"),
+            ])),
+            "20240115T101500_00042"
+        );
+    }
+
+    #[test]
+    fn reads_back_the_dcmrig_file_meta() {
+        let mut dcm_obj = object_with(&[]);
+        {
+            let meta = dcm_obj.meta_mut();
+            meta.implementation_class_uid = "1.2.276.0.7230010.3.0.3.6.4".to_string();
+            meta.implementation_version_name = Some("OFFIS_DCMTK_364".to_string());
+            meta.source_application_entity_title = Some("MODALITY01".to_string());
+        }
+        // A remapped instance the meta group must follow
+        dcm_obj.put(DataElement::new(
+            tags::SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, "1.2.999.12345"),
+        ));
+        update_file_meta(
+            &mut dcm_obj,
+            &FileMetaIdentity::new("1.2.826.0.1.3680043", "TRIAL"),
+        );
+
+        let read_back = round_trip(&dcm_obj, "reads_back_the_dcmrig_file_meta");
+        let meta = read_back.meta();
+        assert_eq!(
+            trim_uid(&meta.implementation_class_uid),
+            "1.2.826.0.1.3680043.1"
+        );
+        assert_eq!(
+            meta.implementation_version_name.as_deref().map(str::trim),
+            Some(format!("DCMRIG_{}", env!("CARGO_PKG_VERSION")).as_str())
+        );
+        assert_eq!(
+            meta.source_application_entity_title
+                .as_deref()
+                .map(str::trim),
+            Some("TRIAL")
+        );
+        assert_eq!(
+            trim_uid(meta.media_storage_sop_instance_uid()),
+            "1.2.999.12345"
+        );
+        assert_eq!(
+            trim_uid(meta.media_storage_sop_class_uid()),
+            "1.2.840.10008.5.1.4.1.1.2"
+        );
+    }
+
+    #[test]
+    fn collapses_the_padded_and_repeated_values() {
+        let values =
+            |values: &[&str]| -> Vec<String> { values.iter().map(|v| v.to_string()).collect() };
+        assert_eq!(collapsed_values(&values(&["MR", "MR"])), values(&["MR"]));
+        assert_eq!(
+            collapsed_values(&values(&["PAT0001\0", " PAT0001 ", ""])),
+            values(&["PAT0001"])
+        );
+        assert_eq!(
+            collapsed_values(&values(&["MR", "PT ", "MR"])),
+            values(&["MR", "PT"])
+        );
+        assert!(collapsed_values(&values(&["\0", " "])).is_empty());
+        assert_eq!(trim_padding(" CT\0"), "CT");
+    }
+
+    #[test]
+    fn reads_one_routing_value_and_keeps_the_raw_one() {
+        let naming = NamingOptions {
+            multi_value: MultiValueMode::Join,
+            pad_width: 4,
+            series_derived: DerivedValues(Vec::new()),
+            sanitized_tags: SanitizedTags::default(),
+        };
+        let mut dcm_obj = object_with(&[(tags::PATIENT_ID, VR::LO, "PAT0001\0")]);
+        dcm_obj.put(DataElement::new(
+            tags::MODALITY,
+            VR::CS,
+            dicom_value!(Strs, ["MR".to_string(), "MR".to_string()]),
+        ));
+        let values = get_sanitized_tag_values(&dcm_obj, &naming).unwrap();
+        assert_eq!(values["PatientID"], "PAT0001");
+        assert_eq!(values["Modality"], "MR");
+        assert_eq!(values["Modality_raw"], "MR\\MR");
+
+        // Other multi-valued tags keep all their values
+        let mut dcm_obj = object_with(&[(tags::PATIENT_ID, VR::LO, "PAT0001")]);
+        dcm_obj.put(DataElement::new(
+            tags::SERIES_DESCRIPTION,
+            VR::LO,
+            dicom_value!(Strs, ["T2".to_string(), "T2".to_string()]),
+        ));
+        let values = get_sanitized_tag_values(&dcm_obj, &naming).unwrap();
+        assert_eq!(values["PatientID"], "PAT0001");
+        assert!(!values.contains_key("PatientID_raw"));
+        assert_eq!(values["SeriesDescription"], "T2_T2");
+    }
+
+    #[test]
+    fn status_server_answers_on_the_bind_address() {
+        use std::io::{Read, Write as _};
+        use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server =
+            StatusServer::start(Ipv4Addr::LOCALHOST.into(), port, StatusBoard::new("sort"))
+                .unwrap();
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("\"sort\""));
+        drop(server);
+    }
+
+    #[test]
+    fn run_callbacks_include_the_outcome_sink() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink_seen = Arc::clone(&seen);
+        let run_options = RunOptions {
+            on_outcome: Some(OutcomeSink::new(move |outcome: &FileOutcome| {
+                sink_seen
+                    .lock()
+                    .unwrap()
+                    .push((outcome.source_path.clone(), outcome.status.clone()));
+            })),
+            ..Default::default()
+        };
+        let outcomes = OutcomeTracker::new(RunCounts::default()).with_run_callbacks(&run_options);
+        outcomes.record(FileOutcome::new(Path::new("a.dcm"), FileStatus::Written));
+        outcomes.record(FileOutcome::new(Path::new("b.dcm"), FileStatus::Failed));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (PathBuf::from("a.dcm"), FileStatus::Written),
+                (PathBuf::from("b.dcm"), FileStatus::Failed)
+            ]
+        );
+    }
+}
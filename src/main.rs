@@ -3,23 +3,52 @@ The main entry point into dcmrig.
 */
 mod anon;
 mod args;
+mod batch;
+mod check;
 mod cookbook_parser;
 mod deid;
+mod report;
+mod report_db;
 mod sort;
 
-use crate::args::EntityType;
+use crate::args::{CookbookAction, EntityType, FilenameMode, ProgressBy};
+use crate::cookbook_parser::parse_toml_cookbook;
 
-use anon::dicom_anon;
+use anon::{check_anon_prefix, dicom_anon};
+use batch::run_batch;
+use check::dicom_check;
 use deid::dicom_deid;
+use report::dicom_report;
 use sort::dicom_sort;
 
 use anyhow::{Ok, Result};
 use args::ArgsParser;
 use clap::Parser;
-use dcmrig_rs::print_logo;
+use dcmrig_rs::{
+    emit_effective_config, enable_log_redaction, install_interrupt_handler, lock_destination,
+    print_logo, print_status, probe_destination_fs, resolve_sop_classes, write_complete_marker,
+    write_html_report, write_interrupted_marker, write_log_redaction_keys, write_started_marker,
+    write_summary_json, DicomdirBuilder, DicomdirRecords, DirBuckets, EnsuredDirs,
+    ExpectedManifest, FileMetaIdentity, FsLimits, GlobalDedupe, IdentityPolicy, IoGate,
+    IoRetryPolicy, KeepList, MediaNames, NamingOptions, OutcomeDetails, PartialRun,
+    PartialSelection, PatientIdNormalizer, PixelCompression, PrivateTagStats, Routes, RuleStats,
+    RunOptions, RunStatus, RunSummary, SanitizedTags, SeriesDirs, StatusBoard, StatusServer,
+    StudyOrdinals, TopDirProgress, VrFixLog, DEFAULT_PATIENT_ID_STEPS, EXIT_INTERRUPTED,
+    FAILURE_REASONS_TOP, PATIENTS_TOP, PRIVATE_TAG_REPORT_TOP,
+};
+use serde_json::json;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    path::PathBuf,
+    process::exit,
+};
 use tracing::{error, info, warn, Level};
 
-fn app() -> Result<()> {
+// Exit code when --fail-on-errors is set and some files failed
+const EXIT_FILES_FAILED: i32 = 2;
+
+fn app() -> Result<i32> {
     let start_time = std::time::Instant::now();
     let args = ArgsParser::parse();
 
@@ -33,39 +62,448 @@ fn app() -> Result<()> {
             })
             .finish(),
     )?;
+    if args.redact_logs {
+        enable_log_redaction();
+    }
     print_logo();
-    // Only executes if one of the 4 subcommands are provided
-    match args.action_type {
-        EntityType::Sort(sort_command) => dicom_sort(
+    let destination_path = destination_of(&args.action_type);
+    let redact_logs = args.redact_logs;
+    let log_key_out = args.log_key_out.clone();
+    let exit_code = match &args.action_type {
+        EntityType::Batch(batch_command) => run_batch(&batch_command.runfile, run)?,
+        _ => run(args)?.0,
+    };
+
+    if redact_logs {
+        let key_path = match (log_key_out, &destination_path) {
+            (Some(key_path), _) => key_path,
+            (None, Some(destination_path)) => destination_path.join("log_redaction_keys.csv"),
+            (None, None) => PathBuf::from("log_redaction_keys.csv"),
+        };
+        write_log_redaction_keys(&key_path)?;
+    }
+
+    let elapsed_time = std::time::Instant::now() - start_time;
+    info!(
+        "Total time: {}.{:03} seconds",
+        elapsed_time.as_secs(),
+        elapsed_time.subsec_millis()
+    );
+    Ok(exit_code)
+}
+
+fn destination_of(action_type: &EntityType) -> Option<PathBuf> {
+    match action_type {
+        EntityType::Sort(sort_command) => Some(sort_command.destination.clone()),
+        EntityType::Deid(deid_command) => Some(deid_command.destination.clone()),
+        EntityType::Anon(anon_command) => Some(anon_command.destination.clone()),
+        EntityType::Report(report_command) => Some(report_command.destination.clone()),
+        EntityType::Check(_) | EntityType::Cookbook(_) | EntityType::Batch(_) => None,
+    }
+}
+
+// A single sort, anon, deid, report or cookbook run, the steps of a batch are run one by one
+// Returns the exit code and the summary of the run
+fn run(args: ArgsParser) -> Result<(i32, Option<RunSummary>)> {
+    let naming = NamingOptions {
+        multi_value: args.multi_value.clone(),
+        pad_width: args.pad_width,
+        series_derived: args.series_derived.clone(),
+        sanitized_tags: SanitizedTags::default()
+            .with(&MediaNames::TAGS)
+            .and_then(|sanitized_tags| sanitized_tags.with(&DicomdirBuilder::TAGS))
+            .unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            }),
+    };
+    // Only deid and cookbook check read the cookbook
+    let cookbook = match &args.action_type {
+        EntityType::Deid(deid_command) => Some(parse_toml_cookbook(
+            deid_command.strict_cookbook,
+            args.allow_risky_keep,
+            args.allow_breaking_rules,
+            &args.vr_length,
+            args.cookbook.as_ref(),
+        )?),
+        EntityType::Cookbook(cookbook_command) => match &cookbook_command.action {
+            CookbookAction::Check { strict_cookbook } => Some(parse_toml_cookbook(
+                *strict_cookbook,
+                args.allow_risky_keep,
+                args.allow_breaking_rules,
+                &args.vr_length,
+                args.cookbook.as_ref(),
+            )?),
+        },
+        _ => None,
+    };
+    // Routes are only read from the cookbook when a route destination is given
+    let routes = match &args.action_type {
+        EntityType::Sort(_) | EntityType::Anon(_) | EntityType::Deid(_)
+            if !args.route.is_empty() =>
+        {
+            let route_rules = match &cookbook {
+                Some(cookbook) => cookbook.routes.clone(),
+                None => {
+                    parse_toml_cookbook(
+                        false,
+                        args.allow_risky_keep,
+                        args.allow_breaking_rules,
+                        &args.vr_length,
+                        args.cookbook.as_ref(),
+                    )?
+                    .routes
+                }
+            };
+            Routes::new(route_rules, &args.route).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        }
+        _ => Routes::default(),
+    };
+    // PatientIDs are normalized with the steps of the cookbook, or the default steps without them
+    let patient_id = match &args.action_type {
+        EntityType::Anon(_) | EntityType::Deid(_) if args.normalize_patient_id => {
+            let patient_id_steps = match &cookbook {
+                Some(cookbook) => cookbook.patient_id_steps.clone(),
+                None => {
+                    parse_toml_cookbook(
+                        false,
+                        args.allow_risky_keep,
+                        args.allow_breaking_rules,
+                        &args.vr_length,
+                        args.cookbook.as_ref(),
+                    )?
+                    .patient_id_steps
+                }
+            };
+            let normalizer = PatientIdNormalizer {
+                steps: patient_id_steps.unwrap_or_else(|| DEFAULT_PATIENT_ID_STEPS.to_vec()),
+            };
+            info!("PatientID normalization: {:?}", normalizer.steps);
+            normalizer
+        }
+        _ => PatientIdNormalizer::default(),
+    };
+    // Anon only reads the [keep] list of a cookbook given with --cookbook
+    let keep_tags = match (&args.action_type, &cookbook) {
+        (EntityType::Deid(_), Some(cookbook)) => cookbook.keep_tags.clone(),
+        (EntityType::Anon(_), _) if args.cookbook.is_some() => {
+            parse_toml_cookbook(
+                false,
+                args.allow_risky_keep,
+                args.allow_breaking_rules,
+                &args.vr_length,
+                args.cookbook.as_ref(),
+            )?
+            .keep_tags
+        }
+        _ => vec![],
+    };
+    // A sample without a seed selects other files on every run, the seed is logged to repeat it
+    let partial_run = match (args.limit, args.sample) {
+        (Some(limit), _) => Some(PartialRun::new(PartialSelection::Limit(limit))),
+        (None, Some(fraction)) => Some(PartialRun::new(PartialSelection::Sample {
+            fraction,
+            seed: match (args.seed, args.deterministic) {
+                (Some(seed), _) => seed,
+                (None, true) => 0,
+                (None, false) => RandomState::new().build_hasher().finish(),
+            },
+        })),
+        (None, None) => None,
+    };
+    // The cookbook filter adds to the SOP classes given on the command line
+    let mut exclude_sop_list = args.exclude_sop_class.clone();
+    if let Some(cookbook) = &cookbook {
+        exclude_sop_list.extend(cookbook.exclude_sop_classes.clone());
+    }
+    let destination_path = destination_of(&args.action_type);
+    // The status is only served while a source is processed
+    let status_action = match &args.action_type {
+        EntityType::Sort(_) => Some("sort"),
+        EntityType::Anon(_) => Some("anon"),
+        EntityType::Deid(_) => Some("deid"),
+        EntityType::Report(_) => Some("report"),
+        EntityType::Check(_) => Some("check"),
+        EntityType::Cookbook(_) | EntityType::Batch(_) => None,
+    };
+    let mut run_options = RunOptions {
+        exclude_sop_classes: resolve_sop_classes(&exclude_sop_list),
+        keep_excluded: args.keep_excluded,
+        run_subdir: args.run_subdir,
+        run_id: chrono::Local::now().format("%Y-%m-%dT%H%M").to_string(),
+        file_timeout: args.file_timeout,
+        io_retry: IoRetryPolicy {
+            attempts: args.io_retries,
+            delay_ms: args.io_retry_delay_ms,
+        },
+        fail_on_walk_errors: args.fail_on_walk_errors,
+        parallelism: args.parallelism.clone(),
+        read_gate: IoGate::new(args.read_concurrency),
+        write_gate: IoGate::new(args.write_concurrency),
+        ensured_dirs: EnsuredDirs::default(),
+        dir_buckets: DirBuckets::new(args.max_files_per_dir, args.skip_existing),
+        series_dirs: SeriesDirs::new(args.strict_layout),
+        study_dir_style: args.study_dir_style.clone(),
+        study_ordinals: StudyOrdinals::default(),
+        routes,
+        private_tag_stats: PrivateTagStats::default(),
+        outcome_details: OutcomeDetails::default(),
+        rule_stats: RuleStats::default(),
+        compression: args.compress.clone().map(PixelCompression::new),
+        fix_vrs: args.fix_vrs,
+        tolerate_truncated_pixeldata: args.tolerate_truncated_pixeldata,
+        post_check: args.post_check,
+        keep: KeepList::new(keep_tags.iter().map(|t| t.tag.inner())),
+        uid_root: args.uid_root.clone(),
+        file_meta: FileMetaIdentity::new(&args.uid_root, &args.source_ae_title),
+        vr_fix_log: VrFixLog::default(),
+        status_board: args.status_port.and(status_action).map(StatusBoard::new),
+        // The report has no outcome for the files it read, so a directory would never be done
+        top_dir_progress: match (&args.progress_by, &args.action_type) {
+            (
+                ProgressBy::TopDir,
+                EntityType::Sort(_) | EntityType::Anon(_) | EntityType::Deid(_),
+            ) => Some(TopDirProgress::default()),
+            _ => None,
+        },
+        on_outcome: None,
+        use_dicomdir: args.use_dicomdir,
+        dicomdir_records: DicomdirRecords::default(),
+        media_names: args.media_compatible.then(MediaNames::default),
+        dicomdir: match (args.write_dicomdir, &args.action_type) {
+            (true, EntityType::Sort(_) | EntityType::Anon(_) | EntityType::Deid(_)) => {
+                if !args.media_compatible {
+                    warn!("The DICOMDIR will reference paths that are not valid on media without --media-compatible");
+                }
+                Some(DicomdirBuilder::default())
+            }
+            _ => None,
+        },
+        copy_non_dicom_all: args.copy_non_dicom_all,
+        too_small_csv: args.too_small_csv.clone(),
+        max_file_size: args.max_file_size,
+        // Set once the destination is locked
+        global_dedupe: None,
+        vr_length: args.vr_length.clone(),
+        deterministic: args.deterministic,
+        // Deterministic runs need seeded IDs, 0 when no seed is given
+        seed: match args.deterministic {
+            true => Some(args.seed.unwrap_or(0)),
+            false => args.seed,
+        },
+        partial_run,
+        fs_limits: match (args.ignore_fs_limits, &destination_path) {
+            (false, Some(destination_path)) => probe_destination_fs(destination_path),
+            _ => FsLimits::default(),
+        },
+        identity: IdentityPolicy {
+            institution: args.institution.clone().or(cookbook
+                .as_ref()
+                .and_then(|c| c.identity.institution.clone())),
+            staff_names: args.staff_names.clone().or(cookbook
+                .as_ref()
+                .and_then(|c| c.identity.staff_names.clone())),
+        },
+        patient_id,
+    };
+    let effective_config = emit_effective_config(
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "threads": rayon::current_num_threads(),
+            "args": serde_json::to_value(&args)?,
+            "cookbook": serde_json::to_value(cookbook.as_ref())?,
+        }),
+        args.dump_config.as_ref(),
+    )?;
+    // Refused before the destination is locked and marked as started
+    if let EntityType::Anon(anon_command) = &args.action_type {
+        check_anon_prefix(&anon_command.prefix, &args.vr_length);
+    }
+    // Held until the run returns
+    let _destination_lock = destination_path.as_ref().map(|destination_path| {
+        lock_destination(destination_path, args.force_unlock).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        })
+    });
+    if let Some(destination_path) = &destination_path {
+        write_started_marker(destination_path, args.force, args.skip_existing).unwrap_or_else(
+            |e| {
+                error!("{}", e);
+                exit(1)
+            },
+        );
+    }
+    // Opened once the destination is locked, the instances are shared with a later run into it
+    if let (
+        true,
+        EntityType::Sort(_) | EntityType::Anon(_) | EntityType::Deid(_),
+        Some(destination_path),
+    ) = (args.dedupe_global, &args.action_type, &destination_path)
+    {
+        run_options.global_dedupe =
+            Some(GlobalDedupe::open(destination_path).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            }));
+    }
+    install_interrupt_handler()?;
+    let status_server = match (args.status_port, &run_options.status_board) {
+        (Some(status_port), Some(status_board)) => Some(StatusServer::start(
+            args.status_bind,
+            status_port,
+            status_board.clone(),
+        )?),
+        _ => None,
+    };
+    // Errors found by check fail the run like failed files with --fail-on-errors
+    let conformance_check = matches!(args.action_type, EntityType::Check(_));
+    // Only executes if one of the 6 subcommands are provided
+    let mut run_summary = match args.action_type {
+        EntityType::Sort(sort_command) => Some(dicom_sort(
             sort_command.source,
             sort_command.destination,
             sort_command.sort_order,
-        )?,
-        EntityType::Deid(deid_command) => dicom_deid(
+            sort_command.hash_dirs,
+            sort_command.hash_key_out,
+            naming.clone(),
+            match sort_command.keep_filename {
+                true => FilenameMode::Original,
+                false => sort_command.filename,
+            },
+            sort_command.split_by,
+            sort_command.expected_manifest.map(|manifest_path| {
+                ExpectedManifest::load(&manifest_path).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    exit(1)
+                })
+            }),
+            run_options.clone(),
+        )?),
+        EntityType::Deid(deid_command) => Some(dicom_deid(
             deid_command.source,
             deid_command.destination,
             deid_command.mapping_table,
-        )?,
-        EntityType::Anon(anon_command) => dicom_anon(
+            deid_command.map_keys,
+            deid_command.map_level,
+            deid_command.match_normalize,
+            deid_command.study_table,
+            deid_command.study_map_keys,
+            cookbook.expect("Cookbook is parsed for deid"),
+            deid_command.charset,
+            deid_command.strict,
+            deid_command.debug_failed,
+            deid_command.reload_mapping_every,
+            naming.clone(),
+            run_options.clone(),
+        )?),
+        EntityType::Anon(anon_command) => Some(dicom_anon(
             anon_command.source,
             anon_command.destination,
             anon_command.prefix,
-        )?,
-        EntityType::Report(_report_command) => {
-            warn!("Report function Not setup yet");
+            anon_command.anon_key,
+            anon_command.mapping_out,
+            anon_command.charset,
+            anon_command.drop_unsafe_references,
+            naming.clone(),
+            run_options.clone(),
+        )?),
+        EntityType::Report(report_command) => Some(dicom_report(
+            report_command.source,
+            report_command.destination,
+            report_command.devices,
+            match report_command.per_instance {
+                true => Some(report_command.columns),
+                false => None,
+            },
+            report_command.distinct,
+            report_command.distinct_cap as usize,
+            report_command.format,
+            report_command.out,
+            report_command.append,
+            run_options.clone(),
+        )?),
+        EntityType::Check(check_command) => Some(dicom_check(
+            check_command.conformance,
+            check_command.findings,
+            run_options.clone(),
+        )?),
+        EntityType::Cookbook(_cookbook_command) => {
+            let cookbook = cookbook.expect("Cookbook is parsed for cookbook check");
+            println!("{}", serde_json::to_string_pretty(&cookbook)?);
+            for issue in cookbook.all_tag_name_issues() {
+                warn!("!! Tag name {}", issue);
+            }
+            None
         }
+        EntityType::Batch(_) => return Err(anyhow::Error::msg("A batch can't run another batch")),
+    };
+
+    drop(status_server);
+    if let Some(top_dir_progress) = &run_options.top_dir_progress {
+        top_dir_progress.finish();
     }
 
-    let elapsed_time = std::time::Instant::now() - start_time;
-    info!(
-        "Total time: {}.{:03} seconds",
-        elapsed_time.as_secs(),
-        elapsed_time.subsec_millis()
-    );
-    Ok(())
+    let mut exit_code = 0;
+    if let Some(summary) = run_summary.as_mut() {
+        summary.read_wait_ms = run_options.read_gate.average_wait_ms();
+        summary.write_wait_ms = run_options.write_gate.average_wait_ms();
+        summary.routes = run_options.routes.counts();
+        summary.private_tags_removed = run_options
+            .private_tag_stats
+            .summary(PRIVATE_TAG_REPORT_TOP);
+        summary.failure_reasons = run_options
+            .outcome_details
+            .failure_reasons(FAILURE_REASONS_TOP);
+        summary.patients = run_options.outcome_details.patients(PATIENTS_TOP);
+        summary.rules = run_options.rule_stats.summary();
+        summary.compression = run_options
+            .compression
+            .as_ref()
+            .map(PixelCompression::summary);
+        summary.partial = run_options.partial_run.as_ref().map(PartialRun::summary);
+        print_status(summary)?;
+        if let Some(summary_path) = &args.summary_json {
+            write_summary_json(summary, summary_path)?;
+        }
+        if let Some(html_path) = &args.html_report {
+            write_html_report(summary, &effective_config, html_path)?;
+        }
+        if (args.fail_on_errors || conformance_check) && summary.counts.failed > 0 {
+            error!("{} files failed to process", summary.counts.failed);
+            exit_code = EXIT_FILES_FAILED;
+        }
+        if summary.status == RunStatus::Interrupted {
+            exit_code = EXIT_INTERRUPTED;
+        }
+        if let (Some(dicomdir), Some(destination_path)) = (&run_options.dicomdir, &destination_path)
+        {
+            dicomdir.write(destination_path, &run_options)?;
+        }
+        if let (true, Some(destination_path)) = (run_options.fix_vrs, &destination_path) {
+            run_options.vr_fix_log.write(destination_path)?;
+        }
+        if let Some(destination_path) = &destination_path {
+            match summary.status {
+                RunStatus::Complete => write_complete_marker(destination_path, summary, exit_code)?,
+                RunStatus::Interrupted => write_interrupted_marker(destination_path, summary)?,
+            }
+        }
+    }
+
+    Ok((exit_code, run_summary))
 }
 
 fn main() -> Result<()> {
-    app().unwrap_or_else(|_| error!("Unexpected error during execution!"));
+    let exit_code = app().unwrap_or_else(|_| {
+        error!("Unexpected error during execution!");
+        1
+    });
+    if exit_code != 0 {
+        exit(exit_code)
+    }
     Ok(())
 }
@@ -0,0 +1,721 @@
+use crate::args::{InstanceColumn, ReportFormat};
+use crate::report_db::ReportDb;
+use anyhow::Result;
+use crossbeam::channel::{bounded, Sender};
+use dcmrig_rs::*;
+use dicom::{
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject, OpenFileOptions, Tag},
+};
+use rusqlite::types::Value;
+use rust_xlsxwriter::{Workbook, Worksheet};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs::{canonicalize, File},
+    hash::{Hash, Hasher},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+
+/// Attributes that identify a single device, missing attributes are blank
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct DeviceKey {
+    manufacturer: String,
+    model_name: String,
+    serial_number: String,
+    station_name: String,
+    software_versions: String,
+    field_strength: String,
+}
+
+/// Studies and series seen for a device
+/// UIDs are kept as hashes so the memory stays bounded on very large archives
+#[derive(Debug, Default)]
+struct DeviceStats {
+    studies: HashSet<u64>,
+    series: HashSet<u64>,
+    files: u64,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn dicom_report(
+    source_path: PathBuf,
+    destination_path: PathBuf,
+    devices: bool,
+    per_instance: Option<Vec<InstanceColumn>>,
+    distinct: Vec<String>,
+    distinct_cap: usize,
+    format: ReportFormat,
+    db_path: Option<PathBuf>,
+    append: bool,
+    run_options: RunOptions,
+) -> Result<RunSummary> {
+    let start_time = std::time::Instant::now();
+    info!(
+        "Generating report for >> SOURCE: {} | DESTINATION: {}",
+        source_path.display(),
+        destination_path.display()
+    );
+
+    // Nothing is copied by the report, only the source is indexed
+    check_given_path_exists(&source_path, &destination_path)?;
+    let IndexSetup {
+        all_files,
+        too_small,
+        oversize,
+        total_len,
+        pb,
+        walk_errors,
+    } = index_setup(&source_path, &run_options)?;
+    let outcomes = OutcomeTracker::new(RunCounts {
+        walk_errors,
+        too_small: too_small.len() as u64,
+        oversize: oversize.len() as u64,
+        ..Default::default()
+    });
+    if !oversize.is_empty() {
+        write_oversize_csv(&oversize, &destination_path.join(OVERSIZE_CSV_NAME))?;
+    }
+    // Opened before indexing so an existing database is refused straight away
+    let mut report_db = match format {
+        ReportFormat::Sqlite => Some(ReportDb::open(
+            &db_path.unwrap_or_else(|| destination_path.join("index.db")),
+            append,
+            &source_path,
+        )?),
+        _ => None,
+    };
+    let device_inventory: Arc<Mutex<HashMap<DeviceKey, DeviceStats>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let distinct_values = match distinct.is_empty() {
+        true => None,
+        false => Some(DistinctValues::new(&distinct, distinct_cap)?),
+    };
+    let instance_index = match per_instance {
+        Some(columns) => Some(InstanceIndex::create(
+            &source_path,
+            &destination_path,
+            columns,
+            report_db.take(),
+        )?),
+        None => None,
+    };
+
+    // Main Loop
+    let not_dispatched = for_each_source_file(&all_files, &run_options, |working_path| {
+        match run_options.read_gate.run(|| {
+            OpenFileOptions::new()
+                .read_until(tags::PIXEL_DATA)
+                .open_file(working_path.path())
+        }) {
+            Ok(dcm_obj) => {
+                check_dicomdir_record(&dcm_obj, working_path, &run_options);
+                if devices {
+                    add_device(&dcm_obj, &device_inventory);
+                }
+                if let Some(distinct_values) = &distinct_values {
+                    distinct_values.add(&dcm_obj);
+                }
+                if let Some(instance_index) = &instance_index {
+                    instance_index.write_row(working_path.path(), &dcm_obj);
+                }
+            }
+            Err(_) => match unreadable_transfer_syntax(working_path.path()) {
+                Some(transfer_syntax) => {
+                    error!(
+                        "Can't open {:?} with transfer syntax {}",
+                        phi(working_path.file_name().to_string_lossy()),
+                        transfer_syntax
+                    );
+                    outcomes.record(FileOutcome {
+                        error: Some(format!("Unsupported transfer syntax {}", transfer_syntax)),
+                        ..FileOutcome::new(working_path.path(), FileStatus::Failed)
+                    });
+                }
+                None => {
+                    debug!(
+                        "Not a DICOM file {:?}",
+                        phi(working_path.file_name().to_string_lossy())
+                    );
+                    outcomes.record(FileOutcome::new(working_path.path(), FileStatus::NonDicom));
+                }
+            },
+        }
+        pb.inc(1);
+    });
+    pb.finish();
+    if let Some(instance_index) = instance_index {
+        if let Some(instance_db) = instance_index.finish()? {
+            report_db = Some(instance_db);
+        }
+    }
+    if let Some(report_db) = &report_db {
+        let series = report_db.insert_series()?;
+        info!("Series table with {} rows written", series);
+    }
+    let mut report_output = ReportOutput::new(&destination_path, format, report_db);
+    if devices {
+        write_device_inventory(
+            &mut report_output,
+            &device_inventory.lock().expect("Failed to lock mutex"),
+        )?;
+    }
+    if let Some(distinct_values) = distinct_values {
+        distinct_values.write(&mut report_output)?;
+    }
+    let report_sections = report_output.finish()?;
+    info!("DICOM Report complete!");
+    Ok(RunSummary {
+        report_sections,
+        ..RunSummary::new(
+            "Report".to_string(),
+            destination_path,
+            total_len,
+            RunCounts {
+                interrupted: not_dispatched,
+                ..outcomes.counts()
+            },
+            start_time.elapsed(),
+        )
+    })
+}
+
+// Instance rows waiting for the SQLite writer thread
+const INSTANCE_QUEUE_SIZE: usize = 10_000;
+
+/// Row per file written as the files are read
+/// Only the buffered writer or the queue of the database writer is kept in memory,
+/// whatever the number of files
+struct InstanceIndex {
+    columns: Vec<InstanceColumn>,
+    source_path: PathBuf,
+    // Absolute source path for the path column
+    source_root: PathBuf,
+    sink: InstanceSink,
+}
+
+/// Where the instance rows go
+/// CSV rows are written to <destination>/instances.csv under a lock, SQLite rows are sent to a
+/// single writer thread that inserts them in batched transactions
+enum InstanceSink {
+    Csv {
+        index_path: PathBuf,
+        writer: Mutex<(BufWriter<File>, u64)>,
+    },
+    Sqlite {
+        sender: Sender<Vec<Value>>,
+        db_writer: JoinHandle<Result<(ReportDb, u64)>>,
+    },
+}
+
+impl InstanceIndex {
+    fn create(
+        source_path: &Path,
+        destination_path: &Path,
+        columns: Vec<InstanceColumn>,
+        report_db: Option<ReportDb>,
+    ) -> Result<Self> {
+        let sink = match report_db {
+            Some(mut report_db) => {
+                let (sender, receiver) = bounded::<Vec<Value>>(INSTANCE_QUEUE_SIZE);
+                let db_writer = std::thread::spawn(move || {
+                    let rows = report_db.insert_instances(receiver.into_iter())?;
+                    Ok((report_db, rows))
+                });
+                InstanceSink::Sqlite { sender, db_writer }
+            }
+            None => {
+                let index_path = destination_path.join("instances.csv");
+                let mut writer = BufWriter::new(File::create(&index_path)?);
+                let header: Vec<&str> = columns.iter().map(instance_column_name).collect();
+                writeln!(writer, "{}", header.join(","))?;
+                InstanceSink::Csv {
+                    index_path,
+                    writer: Mutex::new((writer, 0)),
+                }
+            }
+        };
+        Ok(InstanceIndex {
+            columns,
+            source_path: source_path.to_path_buf(),
+            source_root: canonicalize(source_path)?,
+            sink,
+        })
+    }
+
+    fn write_row(&self, file_path: &Path, dcm_obj: &FileDicomObject<InMemDicomObject>) {
+        match &self.sink {
+            InstanceSink::Csv { writer, .. } => {
+                let row: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|column| csv_field(&self.column_value(column, file_path, dcm_obj)))
+                    .collect();
+                let mut writer = writer.lock().expect("Failed to lock mutex");
+                match writeln!(writer.0, "{}", row.join(",")) {
+                    Ok(_) => writer.1 += 1,
+                    Err(e) => error!("Can't write the row of {}: {}", phi(file_path.display()), e),
+                }
+            }
+            InstanceSink::Sqlite { sender, .. } => {
+                // Every column of the instances table, the columns not asked for are NULL
+                let row: Vec<Value> = InstanceColumn::all()
+                    .iter()
+                    .map(|column| match self.columns.contains(column) {
+                        true => sql_value(column, self.column_value(column, file_path, dcm_obj)),
+                        false => Value::Null,
+                    })
+                    .collect();
+                if sender.send(row).is_err() {
+                    error!(
+                        "Can't queue the row of {}, the database writer stopped",
+                        phi(file_path.display())
+                    );
+                }
+            }
+        }
+    }
+
+    fn column_value(
+        &self,
+        column: &InstanceColumn,
+        file_path: &Path,
+        dcm_obj: &FileDicomObject<InMemDicomObject>,
+    ) -> String {
+        match column {
+            InstanceColumn::Path => match file_path.strip_prefix(&self.source_path) {
+                Ok(relative_path) => self.source_root.join(relative_path),
+                Err(_) => file_path.to_path_buf(),
+            }
+            .display()
+            .to_string(),
+            InstanceColumn::Size => file_path
+                .metadata()
+                .map(|m| m.len().to_string())
+                .unwrap_or_default(),
+            InstanceColumn::SopInstanceUid => uid_value(dcm_obj, tags::SOP_INSTANCE_UID),
+            InstanceColumn::SeriesInstanceUid => uid_value(dcm_obj, tags::SERIES_INSTANCE_UID),
+            InstanceColumn::StudyInstanceUid => uid_value(dcm_obj, tags::STUDY_INSTANCE_UID),
+            InstanceColumn::PatientId => text_attribute(dcm_obj, tags::PATIENT_ID),
+            InstanceColumn::Modality => text_attribute(dcm_obj, tags::MODALITY),
+            InstanceColumn::StudyDate => text_attribute(dcm_obj, tags::STUDY_DATE),
+            InstanceColumn::InstanceNumber => text_attribute(dcm_obj, tags::INSTANCE_NUMBER),
+            InstanceColumn::TransferSyntax => {
+                trim_uid(dcm_obj.meta().transfer_syntax()).to_string()
+            }
+        }
+    }
+
+    /// Flush the rows, the database is handed back once the writer thread is done
+    fn finish(self) -> Result<Option<ReportDb>> {
+        match self.sink {
+            InstanceSink::Csv { index_path, writer } => {
+                let (mut writer, rows) = writer.into_inner().expect("Failed to lock mutex");
+                writer.flush()?;
+                info!(
+                    "Instance index with {} rows written to {}",
+                    rows,
+                    index_path.display()
+                );
+                Ok(None)
+            }
+            InstanceSink::Sqlite { sender, db_writer } => {
+                // Closing the channel ends the writer thread after the last batch
+                drop(sender);
+                let (report_db, rows) = db_writer
+                    .join()
+                    .map_err(|_| anyhow::Error::msg("The database writer thread panicked"))??;
+                info!(
+                    "Instance index with {} rows written to {}",
+                    rows,
+                    report_db.path.display()
+                );
+                Ok(Some(report_db))
+            }
+        }
+    }
+}
+
+/// Typed value of a column for the instances table, blank values are NULL
+fn sql_value(column: &InstanceColumn, value: String) -> Value {
+    match (column, value.is_empty()) {
+        (_, true) => Value::Null,
+        (InstanceColumn::Size | InstanceColumn::InstanceNumber, false) => value
+            .parse::<i64>()
+            .map(Value::Integer)
+            .unwrap_or(Value::Text(value)),
+        (_, false) => Value::Text(value),
+    }
+}
+
+fn instance_column_name(column: &InstanceColumn) -> &'static str {
+    match column {
+        InstanceColumn::Path => "path",
+        InstanceColumn::Size => "size",
+        InstanceColumn::SopInstanceUid => "SOPInstanceUID",
+        InstanceColumn::SeriesInstanceUid => "SeriesInstanceUID",
+        InstanceColumn::StudyInstanceUid => "StudyInstanceUID",
+        InstanceColumn::PatientId => "PatientID",
+        InstanceColumn::Modality => "Modality",
+        InstanceColumn::StudyDate => "StudyDate",
+        InstanceColumn::InstanceNumber => "InstanceNumber",
+        InstanceColumn::TransferSyntax => "TransferSyntaxUID",
+    }
+}
+
+fn uid_value(dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag) -> String {
+    dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|uid| trim_uid(&uid).to_string())
+        .unwrap_or_default()
+}
+
+/// Text value of a tag, blank when missing, multiple values are joined with ';'
+fn text_attribute(dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag) -> String {
+    dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|e| e.to_multi_str().ok())
+        .map(|values| {
+            values
+                .iter()
+                .map(|v| v.trim_matches(|c: char| c == '\0' || c.is_whitespace()))
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+                .join(";")
+        })
+        .unwrap_or_default()
+}
+
+fn uid_hash(dcm_obj: &FileDicomObject<InMemDicomObject>, tag: Tag) -> Option<u64> {
+    let uid = dcm_obj.element(tag).ok()?.to_str().ok()?;
+    let mut hasher = DefaultHasher::new();
+    trim_uid(&uid).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Add the device of a single file to the inventory
+fn add_device(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    device_inventory: &Mutex<HashMap<DeviceKey, DeviceStats>>,
+) {
+    let device_key = DeviceKey {
+        manufacturer: text_attribute(dcm_obj, tags::MANUFACTURER),
+        model_name: text_attribute(dcm_obj, tags::MANUFACTURER_MODEL_NAME),
+        serial_number: text_attribute(dcm_obj, tags::DEVICE_SERIAL_NUMBER),
+        station_name: text_attribute(dcm_obj, tags::STATION_NAME),
+        software_versions: text_attribute(dcm_obj, tags::SOFTWARE_VERSIONS),
+        field_strength: text_attribute(dcm_obj, tags::MAGNETIC_FIELD_STRENGTH),
+    };
+    let study_hash = uid_hash(dcm_obj, tags::STUDY_INSTANCE_UID);
+    let series_hash = uid_hash(dcm_obj, tags::SERIES_INSTANCE_UID);
+
+    let mut inventory = device_inventory.lock().expect("Failed to lock mutex");
+    let stats = inventory.entry(device_key).or_default();
+    stats.files += 1;
+    if let Some(study_hash) = study_hash {
+        stats.studies.insert(study_hash);
+    }
+    if let Some(series_hash) = series_hash {
+        stats.series.insert(series_hash);
+    }
+}
+
+/// Write the device inventory section sorted on the device attributes
+fn write_device_inventory(
+    report_output: &mut ReportOutput,
+    device_inventory: &HashMap<DeviceKey, DeviceStats>,
+) -> Result<()> {
+    let mut device_list: Vec<_> = device_inventory.iter().collect();
+    device_list.sort_by(|a, b| a.0.cmp(b.0));
+    let rows = device_list.iter().map(|(device_key, stats)| {
+        vec![
+            ReportCell::Text(device_key.manufacturer.clone()),
+            ReportCell::Text(device_key.model_name.clone()),
+            ReportCell::Text(device_key.serial_number.clone()),
+            ReportCell::Text(device_key.station_name.clone()),
+            ReportCell::Text(device_key.software_versions.clone()),
+            ReportCell::Text(device_key.field_strength.clone()),
+            ReportCell::Number(stats.studies.len() as u64),
+            ReportCell::Number(stats.series.len() as u64),
+            ReportCell::Number(stats.files),
+        ]
+    });
+    report_output.write_section(
+        "devices",
+        &[
+            "Manufacturer",
+            "ManufacturerModelName",
+            "DeviceSerialNumber",
+            "StationName",
+            "SoftwareVersions",
+            "MagneticFieldStrength",
+            "studies",
+            "series",
+            "files",
+        ],
+        rows,
+    )?;
+    info!(
+        "Device inventory with {} devices written",
+        device_list.len()
+    );
+    Ok(())
+}
+
+/// Files per raw value of the tags of --distinct, the values are not sanitized so PHI in
+/// unexpected tags shows up. Each tag keeps at most cap values to bound the memory, the files
+/// of the values seen after that are counted as other
+struct DistinctValues {
+    tags: Vec<(String, Tag)>,
+    cap: usize,
+    counts: Mutex<Vec<DistinctCounts>>,
+}
+
+#[derive(Debug, Default)]
+struct DistinctCounts {
+    values: HashMap<String, u64>,
+    other: u64,
+    absent: u64,
+}
+
+impl DistinctValues {
+    fn new(keywords: &[String], cap: usize) -> Result<Self> {
+        let mut tags = Vec::new();
+        for each_keyword in keywords {
+            let (tag, _) = extract_tag_vr_from_str(each_keyword)?;
+            if !tags.iter().any(|(_, each_tag)| *each_tag == tag) {
+                tags.push((each_keyword.clone(), tag));
+            }
+        }
+        let counts = tags.iter().map(|_| DistinctCounts::default()).collect();
+        Ok(DistinctValues {
+            tags,
+            cap,
+            counts: Mutex::new(counts),
+        })
+    }
+
+    fn add(&self, dcm_obj: &FileDicomObject<InMemDicomObject>) {
+        // Multiple values stay joined with '\', only the padding is removed
+        let values: Vec<Option<String>> = self
+            .tags
+            .iter()
+            .map(|(_, tag)| {
+                dcm_obj
+                    .element(*tag)
+                    .ok()
+                    .and_then(|e| e.to_str().ok())
+                    .map(|v| v.trim_end_matches(['\0', ' ']).to_string())
+            })
+            .collect();
+        let mut counts = self.counts.lock().expect("Failed to lock mutex");
+        for (tag_counts, value) in counts.iter_mut().zip(values) {
+            match value {
+                None => tag_counts.absent += 1,
+                Some(value) => {
+                    let tracked = tag_counts.values.len();
+                    match tag_counts.values.get_mut(&value) {
+                        Some(files) => *files += 1,
+                        None if tracked < self.cap => {
+                            tag_counts.values.insert(value, 1);
+                        }
+                        None => tag_counts.other += 1,
+                    }
+                }
+            }
+        }
+    }
+
+    /// A section per tag with the most common values first, then other and absent
+    fn write(self, report_output: &mut ReportOutput) -> Result<()> {
+        let counts = self.counts.into_inner().expect("Failed to lock mutex");
+        for ((keyword, _), tag_counts) in self.tags.iter().zip(counts) {
+            let mut value_list: Vec<_> = tag_counts.values.into_iter().collect();
+            value_list.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let distinct = value_list.len();
+            if tag_counts.other > 0 {
+                warn!(
+                    "{} has more than {} distinct values, {} files are counted as other",
+                    keyword, self.cap, tag_counts.other
+                );
+            }
+            let rows = value_list
+                .into_iter()
+                .map(|(value, files)| ("value", value, files))
+                .chain([
+                    ("other", String::new(), tag_counts.other),
+                    ("absent", String::new(), tag_counts.absent),
+                ])
+                .filter(|(_, _, files)| *files > 0)
+                .map(|(kind, value, files)| {
+                    vec![
+                        ReportCell::Text(kind.to_string()),
+                        ReportCell::Text(value),
+                        ReportCell::Number(files),
+                    ]
+                });
+            report_output.write_section(
+                &format!("distinct_{}", keyword),
+                &["kind", "value", "files"],
+                rows,
+            )?;
+            info!("{} distinct values of {} written", distinct, keyword);
+        }
+        Ok(())
+    }
+}
+
+/// A single cell of a report row
+/// Text is always written as a string so UIDs and IDs are not converted by Excel
+enum ReportCell {
+    Text(String),
+    Number(u64),
+}
+
+impl ReportCell {
+    fn to_text(&self) -> String {
+        match self {
+            ReportCell::Text(value) => value.clone(),
+            ReportCell::Number(value) => value.to_string(),
+        }
+    }
+
+    fn to_sql(&self) -> Value {
+        match self {
+            ReportCell::Text(value) => Value::Text(value.clone()),
+            ReportCell::Number(value) => Value::Integer(*value as i64),
+        }
+    }
+}
+
+// Rows of a section kept for the run summary and --html-report
+const SUMMARY_SECTION_ROWS: usize = 1000;
+
+// Widest column set when auto sizing the xlsx columns
+const XLSX_MAX_COLUMN_WIDTH: usize = 80;
+
+/// Destination of the report sections
+/// CSV writes <destination>/<section>.csv, xlsx a sheet per section in <destination>/report.xlsx
+/// and sqlite a table per section in the report database
+/// Rows are streamed, the xlsx sheets use the constant memory mode of the writer
+/// The first rows of each section are kept for the run summary
+struct ReportOutput {
+    destination_path: PathBuf,
+    target: ReportTarget,
+    sections: Vec<ReportSection>,
+}
+
+enum ReportTarget {
+    Csv,
+    Xlsx(Box<Workbook>),
+    Sqlite(ReportDb),
+}
+
+impl ReportOutput {
+    fn new(destination_path: &Path, format: ReportFormat, report_db: Option<ReportDb>) -> Self {
+        ReportOutput {
+            destination_path: destination_path.to_path_buf(),
+            target: match (format, report_db) {
+                (ReportFormat::Sqlite, Some(report_db)) => ReportTarget::Sqlite(report_db),
+                (ReportFormat::Xlsx, _) => ReportTarget::Xlsx(Box::new(Workbook::new())),
+                _ => ReportTarget::Csv,
+            },
+            sections: Vec::new(),
+        }
+    }
+
+    fn write_section(
+        &mut self,
+        name: &str,
+        header: &[&str],
+        rows: impl Iterator<Item = Vec<ReportCell>>,
+    ) -> Result<()> {
+        let mut summary_rows: Vec<Vec<String>> = Vec::new();
+        let rows = rows.inspect(|row| {
+            if summary_rows.len() < SUMMARY_SECTION_ROWS {
+                summary_rows.push(row.iter().map(ReportCell::to_text).collect());
+            }
+        });
+        let written = match &mut self.target {
+            ReportTarget::Xlsx(workbook) => {
+                let worksheet = workbook.add_worksheet_with_constant_memory();
+                // Sheet names are limited to 31 characters
+                worksheet.set_name(name.chars().take(31).collect::<String>())?;
+                write_xlsx_section(worksheet, header, rows)
+            }
+            ReportTarget::Sqlite(report_db) => {
+                report_db.insert_section(
+                    name,
+                    header,
+                    rows.map(|row| row.iter().map(ReportCell::to_sql).collect()),
+                )?;
+                info!("Report section written to table {}", name);
+                Ok(())
+            }
+            ReportTarget::Csv => {
+                let section_path = self.destination_path.join(format!("{}.csv", name));
+                let mut section_csv = BufWriter::new(File::create(&section_path)?);
+                writeln!(section_csv, "{}", header.join(","))?;
+                for row in rows {
+                    let line: Vec<String> = row.iter().map(|c| csv_field(&c.to_text())).collect();
+                    writeln!(section_csv, "{}", line.join(","))?;
+                }
+                section_csv.flush()?;
+                info!("Report section written to {}", section_path.display());
+                Ok(())
+            }
+        };
+        self.sections.push(ReportSection {
+            name: name.to_string(),
+            headers: header.iter().map(|h| h.to_string()).collect(),
+            rows: summary_rows,
+        });
+        written
+    }
+
+    fn finish(self) -> Result<Vec<ReportSection>> {
+        if let ReportTarget::Xlsx(mut workbook) = self.target {
+            let report_path = self.destination_path.join("report.xlsx");
+            workbook.save(&report_path)?;
+            info!("Report written to {}", report_path.display());
+        }
+        Ok(self.sections)
+    }
+}
+
+// Header row frozen, columns sized to the widest value seen
+fn write_xlsx_section(
+    worksheet: &mut Worksheet,
+    header: &[&str],
+    rows: impl Iterator<Item = Vec<ReportCell>>,
+) -> Result<()> {
+    let mut column_widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for (col, title) in header.iter().enumerate() {
+        worksheet.write_string(0, col as u16, *title)?;
+    }
+    worksheet.set_freeze_panes(1, 0)?;
+    for (row_index, row) in rows.enumerate() {
+        let row_num = row_index as u32 + 1;
+        for (col, cell) in row.iter().enumerate() {
+            match cell {
+                ReportCell::Text(value) => worksheet.write_string(row_num, col as u16, value)?,
+                ReportCell::Number(value) => {
+                    worksheet.write_number(row_num, col as u16, *value as f64)?
+                }
+            };
+            if let Some(width) = column_widths.get_mut(col) {
+                *width = (*width).max(cell.to_text().chars().count());
+            }
+        }
+    }
+    for (col, width) in column_widths.iter().enumerate() {
+        worksheet.set_column_width(col as u16, (width.min(&XLSX_MAX_COLUMN_WIDTH) + 2) as f64)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use anyhow::Result;
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+// Rows per transaction when inserting
+const INSERT_BATCH_SIZE: usize = 10_000;
+
+// Every row carries the run_id of the report run that wrote it
+const REPORT_DB_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at TEXT NOT NULL,
+    source TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS instances (
+    run_id INTEGER NOT NULL REFERENCES runs(run_id),
+    path TEXT,
+    size INTEGER,
+    sop_instance_uid TEXT,
+    series_instance_uid TEXT,
+    study_instance_uid TEXT,
+    patient_id TEXT,
+    modality TEXT,
+    study_date TEXT,
+    instance_number INTEGER,
+    transfer_syntax_uid TEXT
+);
+CREATE INDEX IF NOT EXISTS instances_sop_instance_uid ON instances(sop_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_series_instance_uid ON instances(series_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_study_instance_uid ON instances(study_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_patient_id ON instances(patient_id, study_date);
+CREATE TABLE IF NOT EXISTS series (
+    run_id INTEGER NOT NULL REFERENCES runs(run_id),
+    series_instance_uid TEXT,
+    study_instance_uid TEXT,
+    patient_id TEXT,
+    modality TEXT,
+    study_date TEXT,
+    instances INTEGER,
+    size INTEGER
+);
+CREATE INDEX IF NOT EXISTS series_series_instance_uid ON series(series_instance_uid);
+CREATE INDEX IF NOT EXISTS series_study_instance_uid ON series(study_instance_uid);
+CREATE INDEX IF NOT EXISTS series_patient_id ON series(patient_id, study_date);
+";
+
+const INSERT_INSTANCE: &str = "INSERT INTO instances (run_id, path, size, sop_instance_uid,
+    series_instance_uid, study_instance_uid, patient_id, modality, study_date, instance_number,
+    transfer_syntax_uid) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
+
+/// SQLite database of the report
+pub struct ReportDb {
+    conn: Connection,
+    run_id: i64,
+    pub path: PathBuf,
+}
+
+impl ReportDb {
+    /// Open or create the database and add a run to it
+    /// A database with earlier runs is only added to with --append
+    pub fn open(db_path: &Path, append: bool, source_path: &Path) -> Result<Self> {
+        let conn = Connection::open(db_path)?;
+        let earlier_runs: i64 = conn.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'runs'",
+            [],
+            |row| row.get(0),
+        )?;
+        if earlier_runs > 0 && !append {
+            return Err(anyhow::Error::msg(format!(
+                "Report database {} already exists, use --append to add this run to it",
+                db_path.display()
+            )));
+        }
+        conn.execute_batch(REPORT_DB_SCHEMA)?;
+        conn.execute(
+            "INSERT INTO runs (started_at, source) VALUES (?1, ?2)",
+            params![
+                chrono::Local::now().to_rfc3339(),
+                source_path.display().to_string()
+            ],
+        )?;
+        let run_id = conn.last_insert_rowid();
+        info!("Report run {} in {}", run_id, db_path.display());
+        Ok(ReportDb {
+            conn,
+            run_id,
+            path: db_path.to_path_buf(),
+        })
+    }
+
+    /// Insert the instance rows in the column order of the instances table without run_id
+    /// Rows are committed in batches, the iterator may block until the next row is ready
+    pub fn insert_instances(&mut self, rows: impl Iterator<Item = Vec<Value>>) -> Result<u64> {
+        let run_id = self.run_id;
+        let mut count = 0;
+        let mut rows = rows.peekable();
+        while rows.peek().is_some() {
+            let tx = self.conn.transaction()?;
+            {
+                let mut insert = tx.prepare_cached(INSERT_INSTANCE)?;
+                for row in rows.by_ref().take(INSERT_BATCH_SIZE) {
+                    insert.execute(params_from_iter(
+                        std::iter::once(Value::Integer(run_id)).chain(row),
+                    ))?;
+                    count += 1;
+                }
+            }
+            tx.commit()?;
+        }
+        Ok(count)
+    }
+
+    /// Series rows aggregated from the instances of this run
+    pub fn insert_series(&self) -> Result<usize> {
+        Ok(self.conn.execute(
+            "INSERT INTO series SELECT run_id, series_instance_uid, min(study_instance_uid),
+                min(patient_id), min(modality), min(study_date), count(*), sum(size)
+            FROM instances WHERE run_id = ?1 GROUP BY series_instance_uid",
+            params![self.run_id],
+        )?)
+    }
+
+    /// Table of a report section named after the section, the columns after the header
+    pub fn insert_section(
+        &mut self,
+        name: &str,
+        header: &[&str],
+        rows: impl Iterator<Item = Vec<Value>>,
+    ) -> Result<()> {
+        let columns: Vec<String> = header.iter().map(|h| format!("\"{}\"", h)).collect();
+        let placeholders: Vec<String> = (1..=header.len() + 1).map(|i| format!("?{}", i)).collect();
+        let run_id = self.run_id;
+        let tx = self.conn.transaction()?;
+        tx.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (run_id INTEGER NOT NULL REFERENCES runs(run_id), {})",
+            name,
+            columns.join(", ")
+        ))?;
+        {
+            let mut insert = tx.prepare(&format!(
+                "INSERT INTO \"{}\" (run_id, {}) VALUES ({})",
+                name,
+                columns.join(", "),
+                placeholders.join(", ")
+            ))?;
+            for row in rows {
+                insert.execute(params_from_iter(
+                    std::iter::once(Value::Integer(run_id)).chain(row),
+                ))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
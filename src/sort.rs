@@ -1,11 +1,11 @@
-use anyhow::Result;
+use crate::args::{FilenameMode, SplitBy};
+use anyhow::{Context, Result};
 use crossbeam::sync::WaitGroup;
 use dcmrig_rs::*;
 use dicom::{
-    dictionary_std::tags::PIXEL_DATA,
+    dictionary_std::tags::{self, PIXEL_DATA},
     object::{FileDicomObject, InMemDicomObject},
 };
-use rayon::prelude::*;
 use std::{
     collections::HashMap,
     fs,
@@ -15,11 +15,20 @@ use std::{
 use tracing::{debug, error, info, warn};
 use walkdir::DirEntry;
 
+#[allow(clippy::too_many_arguments)]
 pub fn dicom_sort(
     source_path: PathBuf,
     destination_path: PathBuf,
     sort_order: String,
-) -> Result<()> {
+    hash_dirs: bool,
+    hash_key_out: Option<PathBuf>,
+    naming: NamingOptions,
+    filename_mode: FilenameMode,
+    split_by: Option<SplitBy>,
+    expected_manifest: Option<ExpectedManifest>,
+    run_options: RunOptions,
+) -> Result<RunSummary> {
+    let start_time = std::time::Instant::now();
     info!(
         "Sorting the data for >> SOURCE: {} | DESTINATION: {}",
         source_path.display(),
@@ -27,73 +36,160 @@ pub fn dicom_sort(
     );
 
     // Set up required variables
-    let (all_files, total_len, pb) = preprocessing_setup(&source_path, &destination_path)?;
+    let RunSetup {
+        all_files,
+        total_len,
+        pb,
+        run_dirs,
+        walk_errors,
+        too_small,
+        too_large,
+        oversize,
+    } = preprocessing_setup(&source_path, &destination_path, &run_options)?;
     let sort_order_vec = generate_sort_order(sort_order)?;
-    let failed_case: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-    let non_dcm_cases: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
+    let outcomes = OutcomeTracker::new(RunCounts {
+        walk_errors,
+        too_small,
+        too_large,
+        oversize,
+        ..Default::default()
+    })
+    .with_run_callbacks(&run_options)
+    .with_single_file_result(&run_dirs);
+    let outcomes: Arc<OutcomeTracker> = Arc::new(match &expected_manifest {
+        Some(expected_manifest) => outcomes.with_callback(expected_manifest.callback()),
+        None => outcomes,
+    });
+    let hash_keys: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
     info!("Sort Order {:?}", sort_order_vec);
+    info!("File name: {:?}", filename_mode);
+    if let Some(split_by) = &split_by {
+        info!("Split series by: {:?}", split_by);
+    }
 
     let wg = WaitGroup::new();
     // Main loop
-    all_files
-        .par_iter()
-        .enumerate()
-        .for_each(|(_index, working_path)| {
-            if let Ok(dcm_obj) = dicom::object::OpenFileOptions::new()
-                .read_until(PIXEL_DATA)
-                .open_file(working_path.path())
-            {
-                sort_each_dcm_file(
-                    working_path,
-                    &dcm_obj,
-                    &destination_path,
-                    &sort_order_vec,
-                    wg.clone(),
-                )
-                .unwrap_or_else(|_| {
-                    let mut map = failed_case.lock().expect("Failed to lock mutex");
-                    *map += 1;
-                    error!(
-                        "Can't SORT {:#?} Copying to FAILED_CASES directory",
-                        &working_path.file_name()
-                    );
-                    failed_case_copy(&working_path.clone().into_path(), &destination_path)
-                        .expect("Failed to copy file to FAILED_CASES directory");
-                });
-            } else {
-                let nwg = wg.clone();
-                let mut map = non_dcm_cases.lock().expect("Failed to lock mutex");
-                *map += 1;
-                copy_non_dicom_files(&working_path, &destination_path).unwrap_or_else(|_| {
-                    error!("Can't copy non dicom file {:#?}", &working_path.file_name())
-                });
-                drop(nwg);
-            }
+    let not_dispatched = for_each_source_file(&all_files, &run_options, |working_path| {
+        // Fixed files are written from the object so the pixel data has to be read
+        let fix_vrs = run_options.fix_vrs;
+        let Some(read) = run_options.read_gate.run(|| {
+            with_file_timeout(working_path.path(), &run_options, move |path| {
+                match fix_vrs {
+                    true => dicom::object::OpenFileOptions::new().read_all(),
+                    false => dicom::object::OpenFileOptions::new().read_until(PIXEL_DATA),
+                }
+                .open_file(path)
+            })
+        }) else {
+            record_file_timeout(working_path, &run_dirs, &run_options, &outcomes);
             pb.inc(1);
+            return;
+        };
+        let read = read.ok().map(|dcm_obj| (dcm_obj, false)).or_else(|| {
+            truncated_pixel_data_headers(working_path.path(), &run_options)
+                .map(|dcm_obj| (dcm_obj, true))
         });
+        if let Some((mut dcm_obj, truncated)) = read {
+            check_dicomdir_record(&dcm_obj, working_path, &run_options);
+            // The original bytes of a truncated file are copied as they are
+            let vrs_fixed = !truncated && fix_file_vrs(&mut dcm_obj, working_path, &run_options);
+            if skip_excluded_file(&dcm_obj, working_path, &run_dirs, &run_options, &outcomes) {
+                pb.inc(1);
+                return;
+            }
+            if skip_global_duplicate(&dcm_obj, working_path, &run_options, &outcomes) {
+                pb.inc(1);
+                return;
+            }
+            sort_each_dcm_file(
+                working_path,
+                &dcm_obj,
+                vrs_fixed,
+                &destination_path,
+                &sort_order_vec,
+                hash_dirs,
+                &hash_keys,
+                &naming,
+                &filename_mode,
+                split_by.as_ref(),
+                &run_options,
+                &run_dirs,
+                Arc::clone(&outcomes),
+                wg.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!(
+                    "Can't SORT {:?} Copying to FAILED_CASES directory",
+                    phi(working_path.file_name().to_string_lossy())
+                );
+                record_failed_case(
+                    working_path,
+                    e.to_string(),
+                    &run_dirs,
+                    &run_options,
+                    &outcomes,
+                );
+            });
+        } else {
+            let nwg = wg.clone();
+            record_unopened_file(working_path, &run_dirs, &run_options, &outcomes);
+            drop(nwg);
+        }
+        pb.inc(1);
+    });
     pb.finish();
-    print_status(
-        total_len,
-        *failed_case.lock().expect("Failed to lock mutex"),
-        *non_dcm_cases.lock().expect("Failed to lock mutex"),
-        "Sorted".to_string(),
-    )?;
-    wg.wait();
+    wait_for_writes(wg);
+    if let Some(hash_key_path) = hash_key_out {
+        write_hash_keys(
+            &hash_keys.lock().expect("Failed to lock mutex"),
+            &hash_key_path,
+        )?;
+    }
+    if let Some(expected_manifest) = &expected_manifest {
+        expected_manifest.report(&destination_path)?;
+    }
     info!("DICOM Sort complete!");
-    Ok(())
+    Ok(RunSummary::new(
+        "Sorted".to_string(),
+        destination_path,
+        total_len,
+        RunCounts {
+            interrupted: not_dispatched,
+            ..outcomes.counts()
+        },
+        start_time.elapsed(),
+    ))
 }
 
 // DICOM SORT
+#[allow(clippy::too_many_arguments)]
 fn sort_each_dcm_file(
     source_path: &DirEntry,
     dcm_obj: &FileDicomObject<InMemDicomObject>,
+    vrs_fixed: bool,
     destination_path: &PathBuf,
     sort_order_vec: &Vec<String>,
+    hash_dirs: bool,
+    hash_keys: &Mutex<HashMap<String, String>>,
+    naming: &NamingOptions,
+    filename_mode: &FilenameMode,
+    split_by: Option<&SplitBy>,
+    run_options: &RunOptions,
+    run_dirs: &RunDirs,
+    outcomes: Arc<OutcomeTracker>,
     wg: WaitGroup,
 ) -> Result<()> {
-    let dicom_tags_values = get_sanitized_tag_values(&dcm_obj)?;
-    let order_level = generate_order_level(sort_order_vec, &dicom_tags_values, &dcm_obj)?;
-    let file_name = generate_dicom_file_name(
+    let dicom_tags_values = get_sanitized_tag_values(dcm_obj, naming)?;
+    // Files matching a cookbook route go to its destination root
+    let destination_path = run_options.routes.destination(dcm_obj, destination_path);
+    let order_level = generate_order_level(
+        sort_order_vec,
+        &dicom_tags_values,
+        dcm_obj,
+        hash_dirs,
+        hash_keys,
+    )?;
+    let generated_file_name = generate_dicom_file_name(
         &dicom_tags_values,
         replace_non_alphanumeric(
             dicom_tags_values
@@ -101,68 +197,239 @@ fn sort_each_dcm_file(
                 .expect("Failed to extract value")
                 .trim(),
         ),
+        naming.pad_width,
     )?;
+    let original_file_name = fs_safe_name(
+        &source_path.file_name().to_string_lossy(),
+        &run_options.fs_limits,
+    );
+    let file_name = match filename_mode {
+        FilenameMode::Generated => generated_file_name,
+        FilenameMode::Original => original_file_name,
+        FilenameMode::Both => format!(
+            "{}_{}",
+            generated_file_name.trim_end_matches(".dcm"),
+            original_file_name
+        ),
+    };
 
-    let temp_trimmed_study_uid = dicom_tags_values
-        .get("StudyInstanceUID")
-        .expect("Failed to extract value")
-        .split(".")
-        .last()
-        .expect("Failed to extract value");
-
-    let final_trimmed_uid = if temp_trimmed_study_uid.len() > 5 {
-        temp_trimmed_study_uid[temp_trimmed_study_uid.len() - 5..].to_string()
-    } else {
-        temp_trimmed_study_uid.to_string()
+    let study_dir = match (hash_dirs, &run_options.study_dir_style) {
+        (false, StudyDirStyle::Ordinal) => run_options.study_ordinals.study_dir(dcm_obj),
+        (true, _) => hash_dir_name(
+            dicom_tags_values
+                .get("StudyInstanceUID")
+                .expect("Failed to extract value")
+                .trim(),
+            hash_keys,
+        ),
+        (false, StudyDirStyle::Date) => {
+            let final_trimmed_uid = study_uid_tail(&dicom_tags_values);
+            format!(
+                "{}T{}_{}",
+                replace_non_alphanumeric(
+                    dicom_tags_values
+                        .get("StudyDate")
+                        .expect("Failed to extract value")
+                        .trim()
+                ),
+                replace_non_alphanumeric(
+                    dicom_tags_values
+                        .get("StudyTime")
+                        .expect("Failed to extract value")
+                        .split(".")
+                        .next()
+                        .expect("Failed to extract value")
+                ),
+                replace_non_alphanumeric(&final_trimmed_uid)
+            )
+        }
     };
 
+    // Files without the split attribute stay in the unsuffixed series directory
+    let split_suffix = split_by
+        .and_then(|split_by| series_split_suffix(dcm_obj, split_by))
+        .unwrap_or_default();
+
     let dir_path = format!(
-        "{}/{}{}T{}_{}/{:0>4}_{}_{}",
+        "{}/{}{}/{}_{}_{}{}",
         destination_path.display(),
         order_level,
-        dicom_tags_values
-            .get("StudyDate")
-            .expect("Failed to extract value")
-            .trim(),
-        dicom_tags_values
-            .get("StudyTime")
-            .expect("Failed to extract value")
-            .split(".")
-            .next()
-            .expect("Failed to extract value"),
-        final_trimmed_uid,
-        dicom_tags_values
-            .get("SeriesNumber")
-            .expect("Failed to extract value"),
+        study_dir,
+        pad_number(
+            dicom_tags_values
+                .get("SeriesNumber")
+                .expect("Failed to extract value"),
+            4
+        ),
         replace_non_alphanumeric(
             dicom_tags_values
                 .get("SeriesDescription")
                 .expect("Failed to extract value")
                 .trim()
         ),
-        dicom_tags_values
-            .get("ImagePlane")
-            .expect("Failed to extract value")
-            .trim()
+        naming.series_derived.suffix(&dicom_tags_values),
+        split_suffix
     );
+    // Media names replace the sort layout
+    let (dir_path, file_name) = match &run_options.media_names {
+        Some(media_names) => media_names.assign(&dicom_tags_values, destination_path),
+        None => (
+            run_options.series_dirs.resolve(
+                dir_path,
+                dicom_tags_values
+                    .get("SeriesInstanceUID")
+                    .expect("Failed to extract value"),
+            ),
+            file_name,
+        ),
+    };
 
     let c_source_path = source_path.clone();
-    rayon::spawn(move || {
-        create_target_dir(&dir_path).expect("Failed to created target dir");
-        let full_path = check_if_dup_exists(format!("{}/{}", dir_path, file_name));
-        debug!("Saving file: {} to: {}", file_name, dir_path);
-        fs::copy(c_source_path.into_path(), full_path)
-            .expect("Failed to copy file to sorted destination");
-        drop(wg);
-    });
+    // Files with fixed VRs are written from the object, the others are copied as is
+    let fixed_obj = vrs_fixed.then(|| dcm_obj.clone());
+    let io_retry = run_options.io_retry.clone();
+    let ensured_dirs = run_options.ensured_dirs.clone();
+    let dir_buckets = run_options.dir_buckets.clone();
+    let failed_source_path = source_path.clone();
+    let run_dirs = run_dirs.clone();
+    let c_run_options = run_options.clone();
+    let failed_outcomes = outcomes.clone();
+    spawn_write(
+        run_options,
+        wg,
+        move || {
+            // The content is the fixed object or the source file as copied
+            let content_hash = || match &fixed_obj {
+                Some(fixed_obj) => object_content_hash(fixed_obj),
+                None => file_content_hash(c_source_path.path()),
+            };
+            let (bucket_dir, full_path) = match dir_buckets.place(
+                &dir_path,
+                &file_name,
+                instance_order(&dicom_tags_values),
+                &content_hash,
+            ) {
+                Placement::New(bucket_dir, full_path) => (bucket_dir, full_path),
+                Placement::Duplicate(existing_path) => {
+                    debug!(
+                        "{} is identical to {}",
+                        phi(c_source_path.path().display()),
+                        phi(existing_path.display())
+                    );
+                    outcomes.record(FileOutcome {
+                        destination_path: Some(existing_path),
+                        ..FileOutcome::new(c_source_path.path(), FileStatus::Duplicate)
+                    });
+                    return Ok(());
+                }
+            };
+            ensured_dirs
+                .ensure(&bucket_dir)
+                .context("Create the destination directory")?;
+            debug!("Saving file: {} to: {}", phi(&file_name), phi(&bucket_dir));
+            match &fixed_obj {
+                Some(fixed_obj) => write_dicom_file(fixed_obj, &full_path, &io_retry)
+                    .context("Write to the destination")?,
+                None => {
+                    retry_io(&io_retry, &full_path, || {
+                        fs::copy(c_source_path.path(), &full_path)
+                    })
+                    .context("Copy to the destination")?;
+                }
+            }
+            outcomes.record(FileOutcome {
+                destination_path: Some(PathBuf::from(full_path)),
+                tag_values: dicom_tags_values,
+                ..FileOutcome::new(c_source_path.path(), FileStatus::Written)
+            });
+            Ok(())
+        },
+        move |e| {
+            error!(
+                "Can't write {:?} Copying to FAILED_CASES directory",
+                phi(failed_source_path.file_name().to_string_lossy())
+            );
+            record_failed_case(
+                &failed_source_path,
+                format!("{:#}", e),
+                &run_dirs,
+                &c_run_options,
+                &failed_outcomes,
+            );
+        },
+    );
     Ok(())
 }
 
+// Series directory suffix eg _E1, _B1000 or _T3
+fn series_split_suffix(
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+    split_by: &SplitBy,
+) -> Option<String> {
+    let (prefix, tag, frame_macro, frame_tag) = match split_by {
+        SplitBy::Echo => (
+            "E",
+            tags::ECHO_NUMBERS,
+            tags::MR_ECHO_SEQUENCE,
+            tags::EFFECTIVE_ECHO_TIME,
+        ),
+        SplitBy::Bvalue => (
+            "B",
+            tags::DIFFUSION_B_VALUE,
+            tags::MR_DIFFUSION_SEQUENCE,
+            tags::DIFFUSION_B_VALUE,
+        ),
+        SplitBy::Temporal => (
+            "T",
+            tags::TEMPORAL_POSITION_IDENTIFIER,
+            tags::FRAME_CONTENT_SEQUENCE,
+            tags::TEMPORAL_POSITION_INDEX,
+        ),
+    };
+    // Enhanced MR keeps the value in the shared or the first per frame functional group
+    let value = dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|e| e.to_float64().ok())
+        .or_else(|| {
+            [
+                tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+                tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            ]
+            .iter()
+            .find_map(|group_tag| {
+                dcm_obj
+                    .element(*group_tag)
+                    .ok()?
+                    .items()?
+                    .first()?
+                    .element(frame_macro)
+                    .ok()?
+                    .items()?
+                    .first()?
+                    .element(frame_tag)
+                    .ok()?
+                    .to_float64()
+                    .ok()
+            })
+        })?;
+    // Enhanced MR has no echo number, the echo time is used instead
+    let prefix = match (split_by, dcm_obj.element(tag).is_ok()) {
+        (SplitBy::Echo, false) => "TE",
+        _ => prefix,
+    };
+    let value = match value.fract() == 0.0 {
+        true => format!("{}", value as i64),
+        false => format!("{}", value).replace(".", "p"),
+    };
+    Some(format!("_{}{}", prefix, replace_non_alphanumeric(&value)))
+}
+
 // Generate the DIR order level from the given input
 // Any combination if I=PatientID, N=PatientName, or M=Modality PatientID is the default
 fn generate_sort_order(ord_input: String) -> Result<Vec<String>> {
     let mut order_level_vec: Vec<String> = vec![];
-    for each in ord_input.to_uppercase().chars().into_iter() {
+    for each in ord_input.to_uppercase().chars() {
         match each.to_string().as_str() {
             "I" => order_level_vec.push("PatientID".to_string()),
             "N" => order_level_vec.push("PatientName".to_string()),
@@ -181,21 +448,42 @@ fn generate_order_level(
     order_level_vec: &Vec<String>,
     dicom_tags_values: &HashMap<String, String>,
     dcm_obj: &FileDicomObject<InMemDicomObject>,
+    hash_dirs: bool,
+    hash_keys: &Mutex<HashMap<String, String>>,
 ) -> Result<String> {
     let mut order_level: String = "".to_string();
 
     for each in order_level_vec {
-        dcm_obj.element_by_name(&each)?;
-        order_level = format!(
-            "{}{}/",
-            order_level,
-            replace_non_alphanumeric(
-                dicom_tags_values
-                    .get(each.as_str())
-                    .expect("Failed to replace")
-                    .trim()
-            )
-        )
+        dcm_obj.element_by_name(each)?;
+        let each_value = dicom_tags_values
+            .get(each.as_str())
+            .expect("Failed to replace")
+            .trim();
+        let each_dir = match hash_dirs && each == "PatientID" {
+            true => hash_dir_name(each_value, hash_keys),
+            false => replace_non_alphanumeric(each_value),
+        };
+        order_level = format!("{}{}/", order_level, each_dir)
     }
     Ok(order_level)
 }
+
+// Hashed directory name, the original value is kept for the hash key CSV
+fn hash_dir_name(value: &str, hash_keys: &Mutex<HashMap<String, String>>) -> String {
+    let hash = short_hash(value);
+    let mut map = hash_keys.lock().expect("Failed to lock mutex");
+    map.insert(hash.clone(), value.to_string());
+    hash
+}
+
+fn write_hash_keys(hash_keys: &HashMap<String, String>, hash_key_path: &PathBuf) -> Result<()> {
+    let mut hash_key_list: Vec<_> = hash_keys.iter().collect();
+    hash_key_list.sort();
+    let mut hash_key_csv = "hash,original_value\n".to_string();
+    for (hash, value) in hash_key_list {
+        hash_key_csv.push_str(&format!("{},{}\n", hash, value));
+    }
+    fs::write(hash_key_path, hash_key_csv)?;
+    info!("Hash keys written to: {}", hash_key_path.display());
+    Ok(())
+}
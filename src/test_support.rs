@@ -0,0 +1,198 @@
+// Builders of small DICOM objects for tests, enabled with the test-support feature
+// The objects are Explicit VR Little Endian with a 2x2 MONOCHROME2 image, they write with
+// write_all and open again with dicom::object::open_file. Sort, anon and deid are subcommands
+// of the binary, end to end tests write the files with write_temp_dicom and run dcmrig on them
+use crate::{resolve_tag_name, write_dicom_file, IoRetryPolicy, DEFAULT_UID_ROOT};
+use anyhow::Result;
+use dicom::{
+    core::{
+        value::{DataSetSequence, Value},
+        DataElement, VR,
+    },
+    dicom_value,
+    dictionary_std::tags,
+    object::{FileDicomObject, FileMetaTableBuilder, InMemDicomObject},
+    transfer_syntax::entries::EXPLICIT_VR_LITTLE_ENDIAN,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub const CT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+// Study and series of every built object, so objects of one test sort into one series
+pub const TEST_STUDY_INSTANCE_UID: &str = "1.2.999.999999.9999.9.9.9.9999.1";
+pub const TEST_SERIES_INSTANCE_UID: &str = "1.2.999.999999.9999.9.9.9.9999.1.1";
+pub const TEST_FRAME_OF_REFERENCE_UID: &str = "1.2.999.999999.9999.9.9.9.9999.1.2";
+
+// Numbers the SOPInstanceUIDs, every built object is a distinct instance
+static INSTANCE_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+// A CT image with the patient, study, series and image tags sort, anon and deid read
+pub fn minimal_ct_object() -> FileDicomObject<InMemDicomObject> {
+    let instance_number = INSTANCE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let instance_uid = format!("{}.2.{}", DEFAULT_UID_ROOT, instance_number);
+    let dataset = InMemDicomObject::from_element_iter([
+        DataElement::new(
+            tags::SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, CT_IMAGE_STORAGE),
+        ),
+        DataElement::new(
+            tags::SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, instance_uid.clone()),
+        ),
+        DataElement::new(tags::STUDY_DATE, VR::DA, dicom_value!(Str, "20240115")),
+        DataElement::new(tags::STUDY_TIME, VR::TM, dicom_value!(Str, "101500")),
+        DataElement::new(tags::ACCESSION_NUMBER, VR::SH, dicom_value!(Str, "ACC0001")),
+        DataElement::new(tags::MODALITY, VR::CS, dicom_value!(Str, "CT")),
+        DataElement::new(
+            tags::INSTITUTION_NAME,
+            VR::LO,
+            dicom_value!(Str, "Test Hospital"),
+        ),
+        DataElement::new(
+            tags::STUDY_DESCRIPTION,
+            VR::LO,
+            dicom_value!(Str, "CT CHEST"),
+        ),
+        DataElement::new(tags::PATIENT_NAME, VR::PN, dicom_value!(Str, "Doe^John")),
+        DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, "PAT0001")),
+        DataElement::new(
+            tags::PATIENT_BIRTH_DATE,
+            VR::DA,
+            dicom_value!(Str, "19700101"),
+        ),
+        DataElement::new(tags::PATIENT_SEX, VR::CS, dicom_value!(Str, "M")),
+        DataElement::new(
+            tags::STUDY_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, TEST_STUDY_INSTANCE_UID),
+        ),
+        DataElement::new(
+            tags::SERIES_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, TEST_SERIES_INSTANCE_UID),
+        ),
+        DataElement::new(
+            tags::FRAME_OF_REFERENCE_UID,
+            VR::UI,
+            dicom_value!(Str, TEST_FRAME_OF_REFERENCE_UID),
+        ),
+        DataElement::new(tags::STUDY_ID, VR::SH, dicom_value!(Str, "1")),
+        DataElement::new(tags::SERIES_NUMBER, VR::IS, dicom_value!(Str, "1")),
+        DataElement::new(
+            tags::INSTANCE_NUMBER,
+            VR::IS,
+            dicom_value!(Str, instance_number.to_string()),
+        ),
+        DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, dicom_value!(U16, [1])),
+        DataElement::new(
+            tags::PHOTOMETRIC_INTERPRETATION,
+            VR::CS,
+            dicom_value!(Str, "MONOCHROME2"),
+        ),
+        DataElement::new(tags::ROWS, VR::US, dicom_value!(U16, [2])),
+        DataElement::new(tags::COLUMNS, VR::US, dicom_value!(U16, [2])),
+        DataElement::new(tags::BITS_ALLOCATED, VR::US, dicom_value!(U16, [16])),
+        DataElement::new(tags::BITS_STORED, VR::US, dicom_value!(U16, [12])),
+        DataElement::new(tags::HIGH_BIT, VR::US, dicom_value!(U16, [11])),
+        DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, dicom_value!(U16, [0])),
+        DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OW,
+            dicom_value!(U16, [0, 100, 200, 300]),
+        ),
+    ]);
+    dataset
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(CT_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid(instance_uid)
+                .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN.uid()),
+        )
+        .expect("Failed to build the file meta group")
+}
+
+// A minimal CT object with ReferencedStudySequence nested depth levels deep
+// Each item holds a referenced instance and a PatientName, to test the rules inside sequences
+pub fn object_with_sequences(depth: usize) -> FileDicomObject<InMemDicomObject> {
+    let mut dcm_obj = minimal_ct_object();
+    let mut nested: Option<InMemDicomObject> = None;
+    for level in (0..depth).rev() {
+        let mut item = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::REFERENCED_SOP_CLASS_UID,
+                VR::UI,
+                dicom_value!(Str, CT_IMAGE_STORAGE),
+            ),
+            DataElement::new(
+                tags::REFERENCED_SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, format!("{}.3.{}", DEFAULT_UID_ROOT, level + 1)),
+            ),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, dicom_value!(Str, "Doe^John")),
+        ]);
+        if let Some(inner) = nested.take() {
+            item.put(DataElement::new(
+                tags::REFERENCED_STUDY_SEQUENCE,
+                VR::SQ,
+                Value::Sequence(DataSetSequence::from(vec![inner])),
+            ));
+        }
+        nested = Some(item);
+    }
+    if let Some(item) = nested {
+        dcm_obj.put(DataElement::new(
+            tags::REFERENCED_STUDY_SEQUENCE,
+            VR::SQ,
+            Value::Sequence(DataSetSequence::from(vec![item])),
+        ));
+    }
+    dcm_obj
+}
+
+// A minimal CT object without the given tags, named by keyword or (gggg,eeee)
+// Panics on a name that isn't a tag, a typo would otherwise test nothing
+pub fn object_missing(tag_names: &[&str]) -> FileDicomObject<InMemDicomObject> {
+    let mut dcm_obj = minimal_ct_object();
+    for each_name in tag_names {
+        let entry = resolve_tag_name(each_name)
+            .unwrap_or_else(|| panic!("{} is not a DICOM tag", each_name));
+        dcm_obj.remove_element(entry.tag.inner());
+    }
+    dcm_obj
+}
+
+// Write the object to <dir>/<SOPInstanceUID>.dcm the way the subcommands write their files
+pub fn write_temp_dicom(
+    dir: &Path,
+    dcm_obj: &FileDicomObject<InMemDicomObject>,
+) -> Result<PathBuf> {
+    let file_name = format!(
+        "{}.dcm",
+        dcm_obj
+            .meta()
+            .media_storage_sop_instance_uid()
+            .trim_end_matches('\0')
+    );
+    let full_path = dir.join(file_name);
+    write_dicom_file(
+        dcm_obj,
+        &full_path.to_string_lossy(),
+        &IoRetryPolicy::default(),
+    )?;
+    Ok(full_path)
+}
+
+// Fresh empty directory named after the test under the system temp directory
+pub fn temp_test_dir(test_name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("dcmrig_test_{}_{}", test_name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Failed to create the test directory");
+    dir
+}
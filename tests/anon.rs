@@ -0,0 +1,238 @@
+mod common;
+
+use common::{dcmrig_output, run_dcmrig, tag_value, written_files};
+use dcmrig_rs::test_support::{minimal_ct_object, temp_test_dir, write_temp_dicom};
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dicom_value,
+    dictionary_std::tags,
+};
+use std::{collections::BTreeSet, fs};
+
+#[test]
+fn anonymizes_the_patient_and_remaps_the_uids() {
+    let test_dir = temp_test_dir("anonymizes_the_patient_and_remaps_the_uids");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    for _ in 0..2 {
+        write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    }
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "anon",
+            "--prefix",
+            "TRIAL",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 2);
+    assert_eq!(summary["failed"], 0);
+
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 2);
+    let anon_ids: BTreeSet<_> = written
+        .iter()
+        .filter_map(|path| tag_value(path, tags::PATIENT_ID))
+        .collect();
+    assert_eq!(anon_ids.len(), 1);
+    let anon_id = anon_ids.into_iter().next().unwrap();
+    assert!(anon_id.starts_with("TRIAL"), "AnonID {}", anon_id);
+    for path in &written {
+        assert_ne!(tag_value(path, tags::PATIENT_NAME).unwrap(), "Doe^John");
+        assert_ne!(
+            tag_value(path, tags::STUDY_INSTANCE_UID).unwrap(),
+            dcmrig_rs::test_support::TEST_STUDY_INSTANCE_UID
+        );
+    }
+    // The files of a study keep a shared remapped StudyInstanceUID
+    let study_uids: BTreeSet<_> = written
+        .iter()
+        .filter_map(|path| tag_value(path, tags::STUDY_INSTANCE_UID))
+        .collect();
+    assert_eq!(study_uids.len(), 1);
+}
+
+#[test]
+fn removes_the_timezone_offset() {
+    let test_dir = temp_test_dir("removes_the_timezone_offset");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    let mut dcm_obj = minimal_ct_object();
+    dcm_obj.put(DataElement::new(
+        tags::TIMEZONE_OFFSET_FROM_UTC,
+        VR::SH,
+        dicom_value!(Str, "+0200"),
+    ));
+    write_temp_dicom(&source, &dcm_obj).unwrap();
+
+    run_dcmrig(
+        &test_dir,
+        &[
+            "anon",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 1);
+    assert_eq!(tag_value(&written[0], tags::TIMEZONE_OFFSET_FROM_UTC), None);
+}
+
+#[test]
+fn refuses_a_prefix_too_long_for_the_sh_tags() {
+    let test_dir = temp_test_dir("refuses_a_prefix_too_long_for_the_sh_tags");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+
+    let anon = |prefix: &str, vr_length: &str| {
+        dcmrig_output(
+            &test_dir,
+            &[
+                "--vr-length",
+                vr_length,
+                "anon",
+                "--prefix",
+                prefix,
+                source.to_str().unwrap(),
+                destination.to_str().unwrap(),
+            ],
+        )
+    };
+    // The prefix, _ and the 10 character ID are over the 16 characters of AccessionNumber
+    assert!(!anon("TRIAL01", "error").status.success());
+    assert!(written_files(&destination).is_empty());
+    // Over the 64 characters of LO and PN whatever the length policy
+    assert!(!anon(&"P".repeat(60), "keep").status.success());
+    assert!(anon("TRIAL01", "keep").status.success());
+}
+
+#[test]
+fn writes_the_dcmrig_file_meta() {
+    let test_dir = temp_test_dir("writes_the_dcmrig_file_meta");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+
+    run_dcmrig(
+        &test_dir,
+        &[
+            "--uid-root",
+            "1.2.826.0.1.3680043.10.999",
+            "--source-ae-title",
+            "TRIAL",
+            "anon",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 1);
+    let dcm_obj = dicom::object::open_file(&written[0]).unwrap();
+    let meta = dcm_obj.meta();
+    assert_eq!(
+        meta.implementation_class_uid.trim_end_matches('\0'),
+        "1.2.826.0.1.3680043.10.999.1"
+    );
+    assert_eq!(
+        meta.implementation_version_name.as_deref().map(str::trim),
+        Some(format!("DCMRIG_{}", env!("CARGO_PKG_VERSION")).as_str())
+    );
+    assert_eq!(
+        meta.source_application_entity_title
+            .as_deref()
+            .map(str::trim),
+        Some("TRIAL")
+    );
+    // The meta group follows the remapped SOPInstanceUID
+    assert_eq!(
+        meta.media_storage_sop_instance_uid().trim_end_matches('\0'),
+        tag_value(&written[0], tags::SOP_INSTANCE_UID).unwrap()
+    );
+}
+
+#[test]
+fn gives_padded_and_repeated_patient_ids_one_anon_id() {
+    let test_dir = temp_test_dir("gives_padded_and_repeated_patient_ids_one_anon_id");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    for (patient_id, modality) in [
+        (vec!["PAT0001"], vec!["CT"]),
+        (vec!["PAT0001 "], vec!["CT", "CT"]),
+        (vec!["PAT0001", "PAT0001"], vec!["CT "]),
+    ] {
+        let mut dcm_obj = minimal_ct_object();
+        let strs = |values: Vec<&str>| values.into_iter().map(String::from).collect::<Vec<_>>();
+        dcm_obj.put(DataElement::new(
+            tags::PATIENT_ID,
+            VR::LO,
+            PrimitiveValue::Strs(strs(patient_id).into()),
+        ));
+        dcm_obj.put(DataElement::new(
+            tags::MODALITY,
+            VR::CS,
+            PrimitiveValue::Strs(strs(modality).into()),
+        ));
+        write_temp_dicom(&source, &dcm_obj).unwrap();
+    }
+
+    run_dcmrig(
+        &test_dir,
+        &[
+            "anon",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 3);
+    let anon_ids: BTreeSet<_> = written
+        .iter()
+        .filter_map(|path| tag_value(path, tags::PATIENT_ID))
+        .collect();
+    assert_eq!(anon_ids.len(), 1, "{:?}", anon_ids);
+    // One patient and series directory, named with a single CT
+    let series_dirs: BTreeSet<_> = written.iter().map(|path| path.parent()).collect();
+    assert_eq!(series_dirs.len(), 1);
+    for path in &written {
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        assert!(file_name.contains("_CT_"), "{}", file_name);
+        assert!(!file_name.contains("CT_CT"), "{}", file_name);
+    }
+}
+
+#[test]
+fn records_a_failed_write_and_completes_the_run() {
+    let test_dir = temp_test_dir("records_a_failed_write_and_completes_the_run");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    // A directory in place of the .part file makes the write fail, the media names are known
+    // before the run
+    fs::create_dir_all(destination.join("DICOM/P0000001/S0000001/SE000001/I0000001.part")).unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "--io-retries",
+            "1",
+            "--media-compatible",
+            "anon",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(written_files(&destination.join("FAILED_CASES")).len(), 1);
+    assert!(!destination.join(".dcmrig.lock").exists());
+    assert!(destination.join(".dcmrig_complete").exists());
+}
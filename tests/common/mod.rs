@@ -0,0 +1,89 @@
+// Runs of the dcmrig binary for the end to end tests
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+// Run dcmrig with the arguments, HOME is the test directory so deid creates its default
+// cookbook there
+pub fn dcmrig_output(test_dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dcmrig"))
+        .env("HOME", test_dir)
+        .arg("--summary-json")
+        .arg(test_dir.join("summary.json"))
+        .args(args)
+        .output()
+        .expect("Failed to run dcmrig")
+}
+
+// Run dcmrig, which must succeed. Returns the summary written with --summary-json
+pub fn run_dcmrig(test_dir: &Path, args: &[&str]) -> serde_json::Value {
+    let summary_path = test_dir.join("summary.json");
+    let output = dcmrig_output(test_dir, args);
+    assert!(
+        output.status.success(),
+        "dcmrig {:?} failed\n{}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let summary = fs::read_to_string(&summary_path).expect("Failed to read the summary");
+    serde_json::from_str(&summary).expect("Failed to parse the summary")
+}
+
+// Run dcmrig like run_dcmrig, failing the test instead of hanging when the run does not end
+// within the timeout
+pub fn run_dcmrig_within(test_dir: &Path, args: &[&str], timeout: Duration) -> serde_json::Value {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dcmrig"))
+        .env("HOME", test_dir)
+        .arg("--summary-json")
+        .arg(test_dir.join("summary.json"))
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to run dcmrig");
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("Failed to wait for dcmrig") {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            panic!("dcmrig {:?} did not end within {:?}", args, timeout);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "dcmrig {:?} failed with {}", args, status);
+    let summary =
+        fs::read_to_string(test_dir.join("summary.json")).expect("Failed to read the summary");
+    serde_json::from_str(&summary).expect("Failed to parse the summary")
+}
+
+// DICOM files written under the destination, the run directories and reports left out
+pub fn written_files(destination: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkDir::new(destination)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "dcm"))
+        .collect();
+    files.sort();
+    files
+}
+
+// Value of a tag of a written file, trimmed of its padding
+pub fn tag_value(path: &Path, tag: dicom::core::Tag) -> Option<String> {
+    let dcm_obj = dicom::object::open_file(path).expect("Failed to open the written file");
+    dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+}
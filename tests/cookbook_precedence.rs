@@ -0,0 +1,119 @@
+// Outcome of a tag listed in more than one cookbook action, the later stage of the order wins
+mod common;
+
+use common::{dcmrig_output, run_dcmrig, tag_value, written_files};
+use dcmrig_rs::test_support::{minimal_ct_object, temp_test_dir, write_temp_dicom};
+use dicom::{core::Tag, dictionary_std::tags};
+use std::fs;
+
+// Deidentify a single CT object of PAT0001 as DEID_001 with the cookbook
+// Returns the value of each tag of the written file
+fn deid_with_cookbook(test_name: &str, cookbook: &str, read_tags: &[Tag]) -> Vec<Option<String>> {
+    let test_dir = temp_test_dir(test_name);
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+    let cookbook_path = test_dir.join("cookbook.toml");
+    fs::write(&cookbook_path, cookbook).unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "--cookbook",
+            cookbook_path.to_str().unwrap(),
+            "deid",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 1);
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 1);
+    read_tags
+        .iter()
+        .map(|tag| tag_value(&written[0], *tag))
+        .collect()
+}
+
+#[test]
+fn delete_wins_over_mask_in_the_default_order() {
+    let values = deid_with_cookbook(
+        "delete_wins_over_mask_in_the_default_order",
+        "[mask]\ntags = [\"AccessionNumber\"]\n[delete]\ntags = [\"AccessionNumber\"]\n",
+        &[tags::ACCESSION_NUMBER],
+    );
+    assert_eq!(values, [None]);
+}
+
+#[test]
+fn mask_wins_over_delete_when_ordered_after_it() {
+    let values = deid_with_cookbook(
+        "mask_wins_over_delete_when_ordered_after_it",
+        "order = [\"delete_private\", \"delete\", \"mask\", \"add\"]\n\
+         [mask]\ntags = [\"AccessionNumber\"]\n[delete]\ntags = [\"AccessionNumber\"]\n",
+        &[tags::ACCESSION_NUMBER],
+    );
+    assert_eq!(values, [Some("DEID_001".to_string())]);
+}
+
+#[test]
+fn add_wins_over_mask_in_the_default_order() {
+    let values = deid_with_cookbook(
+        "add_wins_over_mask_in_the_default_order",
+        "[mask]\ntags = [\"InstitutionName\"]\nvrs = [\"PN\"]\n\
+         [add]\ntags.InstitutionName = \"SITE01\"\ntags.PatientName = \"SUBJECT\"\n",
+        &[tags::INSTITUTION_NAME, tags::PATIENT_NAME],
+    );
+    assert_eq!(
+        values,
+        [Some("SITE01".to_string()), Some("SUBJECT".to_string())]
+    );
+}
+
+#[test]
+fn delete_wins_over_add_in_the_default_order() {
+    let values = deid_with_cookbook(
+        "delete_wins_over_add_in_the_default_order",
+        "[add]\ntags.StudyDescription = \"TRIAL\"\n[delete]\ntags = [\"StudyDescription\"]\n",
+        &[tags::STUDY_DESCRIPTION],
+    );
+    assert_eq!(values, [None]);
+}
+
+#[test]
+fn strict_cookbook_refuses_a_tag_in_two_actions() {
+    let test_dir = temp_test_dir("strict_cookbook_refuses_a_tag_in_two_actions");
+    let source = test_dir.join("source");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+    let cookbook_path = test_dir.join("cookbook.toml");
+    fs::write(
+        &cookbook_path,
+        "[mask]\ntags = [\"AccessionNumber\"]\n[delete]\ntags = [\"AccessionNumber\"]\n",
+    )
+    .unwrap();
+
+    let output = dcmrig_output(
+        &test_dir,
+        &[
+            "--cookbook",
+            cookbook_path.to_str().unwrap(),
+            "deid",
+            "--strict-cookbook",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            test_dir.join("destination").to_str().unwrap(),
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("AccessionNumber is listed in mask and delete"));
+}
@@ -0,0 +1,230 @@
+mod common;
+
+use common::{run_dcmrig, run_dcmrig_within, tag_value, written_files};
+use dcmrig_rs::test_support::{minimal_ct_object, object_missing, temp_test_dir, write_temp_dicom};
+use dicom::dictionary_std::tags;
+use std::{fs, path::Path, time::Duration};
+
+#[test]
+fn deidentifies_with_the_mapping_table() {
+    let test_dir = temp_test_dir("deidentifies_with_the_mapping_table");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "deid",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 1);
+    assert_eq!(summary["failed"], 0);
+
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 1);
+    assert_eq!(
+        tag_value(&written[0], tags::PATIENT_ID).as_deref(),
+        Some("DEID_001")
+    );
+    assert_eq!(
+        tag_value(&written[0], tags::PATIENT_NAME).as_deref(),
+        Some("DEID_001")
+    );
+    // From the [add] section of the default cookbook
+    assert_eq!(
+        tag_value(&written[0], tags::PATIENT_IDENTITY_REMOVED).as_deref(),
+        Some("YES")
+    );
+    assert!(destination.join("deid_usage.csv").exists());
+}
+
+#[test]
+fn counts_a_file_without_the_match_tag_as_unmapped() {
+    let test_dir = temp_test_dir("counts_a_file_without_the_match_tag_as_unmapped");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &object_missing(&["PatientID"])).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "deid",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["skipped"], 1);
+    assert_eq!(summary["failed"], 0);
+    assert_eq!(summary["processed"], 1);
+    assert_eq!(written_files(&destination).len(), 1);
+    assert!(!destination
+        .join("FAILED_CASES")
+        .read_dir()
+        .unwrap()
+        .any(|entry| entry
+            .unwrap()
+            .path()
+            .extension()
+            .is_some_and(|ext| ext == "dcm")));
+}
+
+#[test]
+fn records_a_failed_write_with_a_single_write_permit() {
+    let test_dir = temp_test_dir("records_a_failed_write_with_a_single_write_permit");
+    let source = test_dir.join("source");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+    let deid_args = |destination: &Path| {
+        vec![
+            "--write-concurrency".to_string(),
+            "1".to_string(),
+            "--io-retries".to_string(),
+            "1".to_string(),
+            "deid".to_string(),
+            "-m".to_string(),
+            mapping_table.to_str().unwrap().to_string(),
+            source.to_str().unwrap().to_string(),
+            destination.to_str().unwrap().to_string(),
+        ]
+    };
+
+    // A first run finds the path of the written file
+    let first = test_dir.join("first");
+    let first_args = deid_args(&first);
+    run_dcmrig(
+        &test_dir,
+        &first_args.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    let written = written_files(&first);
+    assert_eq!(written.len(), 1);
+    let relative_path = written[0].strip_prefix(&first).unwrap();
+
+    // A directory in place of the .part file makes the write fail
+    let destination = test_dir.join("destination");
+    let mut part_path = destination.join(relative_path).into_os_string();
+    part_path.push(".part");
+    fs::create_dir_all(&part_path).unwrap();
+    let args = deid_args(&destination);
+    let summary = run_dcmrig_within(
+        &test_dir,
+        &args.iter().map(String::as_str).collect::<Vec<_>>(),
+        Duration::from_secs(60),
+    );
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(written_files(&destination.join("FAILED_CASES")).len(), 1);
+}
+
+#[test]
+fn records_a_file_whose_directory_cannot_be_created() {
+    let test_dir = temp_test_dir("records_a_file_whose_directory_cannot_be_created");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+    // A file in place of the patient directory of the media names
+    fs::create_dir_all(destination.join("DICOM")).unwrap();
+    fs::write(destination.join("DICOM/P0000001"), "").unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "--media-compatible",
+            "deid",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(written_files(&destination.join("FAILED_CASES")).len(), 1);
+    assert!(destination.join(".dcmrig_complete").exists());
+}
+
+#[test]
+fn deidentifies_a_file_without_study_and_series_uids() {
+    let test_dir = temp_test_dir("deidentifies_a_file_without_study_and_series_uids");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(
+        &source,
+        &object_missing(&["StudyInstanceUID", "SeriesInstanceUID"]),
+    )
+    .unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "deid",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["failed"], 0);
+    assert_eq!(written_files(&destination).len(), 1);
+}
+
+#[test]
+fn counts_a_study_missing_from_the_study_table_as_unmapped() {
+    let test_dir = temp_test_dir("counts_a_study_missing_from_the_study_table_as_unmapped");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    let dcm_obj = minimal_ct_object();
+    let study_uid = dcm_obj
+        .element(tags::STUDY_INSTANCE_UID)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .trim_end_matches('\0')
+        .to_string();
+    write_temp_dicom(&source, &dcm_obj).unwrap();
+    let mapping_table = test_dir.join("mapping.csv");
+    fs::write(&mapping_table, "DEID_001,PAT0001\n").unwrap();
+    let study_table = test_dir.join("studies.csv");
+    fs::write(&study_table, "V01,1.2.3.4\n").unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "deid",
+            "-m",
+            mapping_table.to_str().unwrap(),
+            "--map-level",
+            "study",
+            "--study-table",
+            study_table.to_str().unwrap(),
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["skipped"], 1);
+    assert!(written_files(&destination).is_empty());
+    let usage = fs::read_to_string(destination.join("deid_usage.csv")).unwrap();
+    assert!(usage
+        .lines()
+        .any(|line| line.starts_with(&format!(",{},", study_uid)) && line.contains(",unmapped,")));
+}
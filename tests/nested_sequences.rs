@@ -0,0 +1,92 @@
+// The sequence traversals on a ReferencedStudySequence nested 100 levels deep
+use dcmrig_rs::{
+    apply_tag_actions, is_private_tag, rebuild_in_sequences, test_support::object_with_sequences,
+    visit_elements, KeepList, TagActions, TagStage,
+};
+use dicom::{
+    core::{header::Header, DataElement, Tag, VR},
+    dicom_value,
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject},
+};
+use std::collections::{HashMap, HashSet};
+
+const DEPTH: usize = 100;
+
+// The nested object with the PatientName of every item replaced by a private element
+fn with_nested_private_tags() -> FileDicomObject<InMemDicomObject> {
+    let mut dcm_obj = object_with_sequences(DEPTH);
+    let sequence = dcm_obj
+        .take_element(tags::REFERENCED_STUDY_SEQUENCE)
+        .expect("Missing the nested sequence");
+    dcm_obj.put(rebuild_in_sequences(sequence, |each_element| {
+        match each_element.tag() == tags::PATIENT_NAME {
+            true => Some(DataElement::new(
+                Tag(0x0011, 0x1001),
+                VR::LO,
+                dicom_value!(Str, "nested"),
+            )),
+            false => Some(each_element),
+        }
+    }));
+    dcm_obj
+}
+
+#[test]
+fn visits_every_level() {
+    let dcm_obj = object_with_sequences(DEPTH);
+    let mut deepest = 0;
+    let mut referenced = 0;
+    assert!(visit_elements(&dcm_obj, |each_element, depth| {
+        deepest = deepest.max(depth);
+        if each_element.tag() == tags::REFERENCED_SOP_INSTANCE_UID {
+            referenced += 1;
+        }
+        true
+    }));
+    assert_eq!(deepest, DEPTH);
+    assert_eq!(referenced, DEPTH);
+}
+
+#[test]
+fn rebuilds_every_level() {
+    let dcm_obj = with_nested_private_tags();
+    let mut private_tags = 0;
+    let mut patient_names = 0;
+    visit_elements(&dcm_obj, |each_element, depth| {
+        match is_private_tag(each_element.tag()) {
+            true => private_tags += 1,
+            false if depth > 0 && each_element.tag() == tags::PATIENT_NAME => patient_names += 1,
+            false => (),
+        }
+        true
+    });
+    assert_eq!(private_tags, DEPTH);
+    assert_eq!(patient_names, 0);
+}
+
+#[test]
+fn deletes_the_private_tags_at_every_level() {
+    let actions = TagActions {
+        order: vec![TagStage::DeletePrivate],
+        mask_tags: HashMap::new(),
+        mask_vrs: HashMap::new(),
+        add: HashMap::new(),
+        add_modes: HashMap::new(),
+        delete: HashSet::new(),
+        keep: KeepList::default(),
+    };
+    let (dcm_obj, _) = apply_tag_actions(with_nested_private_tags(), &actions).unwrap();
+    let mut private_tags = 0;
+    let mut deepest = 0;
+    visit_elements(&dcm_obj, |each_element, depth| {
+        deepest = deepest.max(depth);
+        if is_private_tag(each_element.tag()) {
+            private_tags += 1;
+        }
+        true
+    });
+    assert_eq!(private_tags, 0);
+    // The items themselves are kept
+    assert_eq!(deepest, DEPTH);
+}
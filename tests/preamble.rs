@@ -0,0 +1,73 @@
+mod common;
+
+use common::{dcmrig_output, run_dcmrig, written_files};
+use dcmrig_rs::{
+    test_support::{minimal_ct_object, temp_test_dir, write_temp_dicom},
+    DICOM_PREAMBLE_LEN,
+};
+use std::{fs, path::Path};
+
+// Source file whose preamble starts like a little endian TIFF, as dual format files do
+fn write_tiff_preamble_file(source: &Path) {
+    let full_path = write_temp_dicom(source, &minimal_ct_object()).unwrap();
+    let mut bytes = fs::read(&full_path).unwrap();
+    bytes[..8].copy_from_slice(b"II*\0\x08\0\0\0");
+    bytes[64..72].copy_from_slice(b"VENDOR01");
+    fs::write(&full_path, bytes).unwrap();
+}
+
+#[test]
+fn anon_writes_a_zeroed_preamble() {
+    let test_dir = temp_test_dir("anon_writes_a_zeroed_preamble");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_tiff_preamble_file(&source);
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "anon",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 1);
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 1);
+    let bytes = fs::read(&written[0]).unwrap();
+    assert!(bytes[..DICOM_PREAMBLE_LEN].iter().all(|b| *b == 0));
+    assert_eq!(&bytes[DICOM_PREAMBLE_LEN..DICOM_PREAMBLE_LEN + 4], b"DICM");
+}
+
+#[test]
+fn check_flags_a_non_zero_preamble() {
+    let test_dir = temp_test_dir("check_flags_a_non_zero_preamble");
+    let source = test_dir.join("source");
+    fs::create_dir_all(&source).unwrap();
+    write_tiff_preamble_file(&source);
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    let findings_path = test_dir.join("findings.csv");
+
+    // The builder objects lack Type 1 attributes of the CT module, so check exits with an error
+    dcmrig_output(
+        &test_dir,
+        &[
+            "check",
+            "--conformance",
+            source.to_str().unwrap(),
+            "--findings",
+            findings_path.to_str().unwrap(),
+        ],
+    );
+    let findings = fs::read_to_string(&findings_path).unwrap();
+    let preamble_rows: Vec<_> = findings
+        .lines()
+        .filter(|line| line.contains(",preamble,"))
+        .collect();
+    // Only the file with the crafted preamble, 4 non-zero bytes of TIFF header and 8 of text
+    assert_eq!(preamble_rows.len(), 1, "{}", findings);
+    assert!(
+        preamble_rows[0].ends_with(",warning,Preamble,preamble,12 non-zero bytes in the preamble")
+    );
+}
@@ -0,0 +1,173 @@
+mod common;
+
+use common::{run_dcmrig, tag_value, written_files};
+use dcmrig_rs::test_support::{minimal_ct_object, temp_test_dir, write_temp_dicom};
+use dicom::{
+    core::{DataElement, VR},
+    dicom_value,
+    dictionary_std::tags,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+};
+
+#[test]
+fn sorts_a_series_into_one_directory() {
+    let test_dir = temp_test_dir("sorts_a_series_into_one_directory");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    let mut instance_uids = BTreeSet::new();
+    for _ in 0..3 {
+        let dcm_obj = minimal_ct_object();
+        instance_uids.insert(
+            dcm_obj
+                .meta()
+                .media_storage_sop_instance_uid()
+                .trim_end_matches('\0')
+                .to_string(),
+        );
+        write_temp_dicom(&source, &dcm_obj).unwrap();
+    }
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "sort",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 3);
+    assert_eq!(summary["failed"], 0);
+
+    let written = written_files(&destination);
+    assert_eq!(written.len(), 3);
+    let series_dirs: BTreeSet<_> = written.iter().map(|path| path.parent()).collect();
+    assert_eq!(series_dirs.len(), 1);
+    assert!(written[0].starts_with(destination.join("PAT0001")));
+    // Sort copies the files, the instances are the ones of the source
+    let written_uids: BTreeSet<_> = written
+        .iter()
+        .filter_map(|path| tag_value(path, tags::SOP_INSTANCE_UID))
+        .collect();
+    assert_eq!(written_uids, instance_uids);
+}
+
+#[test]
+fn copies_non_dicom_files_aside() {
+    let test_dir = temp_test_dir("copies_non_dicom_files_aside");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    fs::write(source.join("notes.txt"), "x".repeat(200)).unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "sort",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 1);
+    assert_eq!(summary["non_dicom"], 1);
+    assert!(destination.join("NON_DICOM").join("notes.txt").exists());
+}
+
+#[test]
+fn writes_the_files_of_a_series_in_instance_order() {
+    let test_dir = temp_test_dir("writes_the_files_of_a_series_in_instance_order");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    for series in 1..=2 {
+        // The source names run against the instance order
+        for instance_number in (1..=4).rev() {
+            let mut dcm_obj = minimal_ct_object();
+            dcm_obj.put(DataElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, format!("1.2.999.999999.9999.9.9.9.9999.1.1{}", series)),
+            ));
+            dcm_obj.put(DataElement::new(
+                tags::SERIES_NUMBER,
+                VR::IS,
+                dicom_value!(Str, series.to_string()),
+            ));
+            dcm_obj.put(DataElement::new(
+                tags::INSTANCE_NUMBER,
+                VR::IS,
+                dicom_value!(Str, instance_number.to_string()),
+            ));
+            write_temp_dicom(&source, &dcm_obj).unwrap();
+        }
+    }
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "--parallelism",
+            "series",
+            "sort",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["processed"], 8);
+
+    let mut series_files: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for each_path in written_files(&destination) {
+        let modified = fs::metadata(&each_path).unwrap().modified().unwrap();
+        let instance_number: i64 = tag_value(&each_path, tags::INSTANCE_NUMBER)
+            .unwrap()
+            .parse()
+            .unwrap();
+        series_files
+            .entry(each_path.parent().unwrap().to_path_buf())
+            .or_default()
+            .push((modified, instance_number));
+    }
+    assert_eq!(series_files.len(), 2);
+    // Written one after another, in the order of their InstanceNumber
+    for files in series_files.values_mut() {
+        files.sort();
+        let instance_numbers: Vec<_> = files.iter().map(|(_, number)| *number).collect();
+        assert_eq!(instance_numbers, vec![1, 2, 3, 4]);
+    }
+}
+
+#[test]
+fn records_a_failed_copy_and_completes_the_run() {
+    let test_dir = temp_test_dir("records_a_failed_copy_and_completes_the_run");
+    let source = test_dir.join("source");
+    let destination = test_dir.join("destination");
+    fs::create_dir_all(&source).unwrap();
+    write_temp_dicom(&source, &minimal_ct_object()).unwrap();
+    // A dangling link in place of the copy makes it fail, the media names are known before the run
+    let series_dir = destination.join("DICOM/P0000001/S0000001/SE000001");
+    fs::create_dir_all(&series_dir).unwrap();
+    std::os::unix::fs::symlink(
+        test_dir.join("missing/I0000001"),
+        series_dir.join("I0000001"),
+    )
+    .unwrap();
+
+    let summary = run_dcmrig(
+        &test_dir,
+        &[
+            "--io-retries",
+            "1",
+            "--media-compatible",
+            "sort",
+            source.to_str().unwrap(),
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(written_files(&destination.join("FAILED_CASES")).len(), 1);
+    assert!(!destination.join(".dcmrig.lock").exists());
+    assert!(destination.join(".dcmrig_complete").exists());
+}
@@ -0,0 +1,331 @@
+// apply_tag_actions against the outputs of the earlier deid pipeline, which ran the private
+// tag deletion, tag masks, VR masks, add values and deletions as separate passes in that order
+use dcmrig_rs::{
+    apply_tag_actions, dicom_vr_corrected_value, resolve_tag_name,
+    test_support::{minimal_ct_object, object_missing, object_with_sequences},
+    AddMode, KeepList, LengthPolicy, TagActions, TagStage, DEFAULT_TAG_STAGE_ORDER,
+};
+use dicom::{
+    core::{
+        value::{DataSetSequence, Value},
+        DataElement, PrimitiveValue, Tag, VR,
+    },
+    dicom_value,
+    dictionary_std::tags,
+    object::{FileDicomObject, InMemDicomObject},
+};
+use std::collections::HashMap;
+
+static MASK_TAGS: [&str; 4] = [
+    "PatientID",
+    "PatientName",
+    "InstitutionName",
+    "AccessionNumber",
+];
+static ADD_VALUES: [(&str, &str); 2] = [
+    ("PatientIdentityRemoved", "YES"),
+    ("ClinicalTrialSponsorName", "TrialName"),
+];
+static DELETE_TAGS: [&str; 2] = ["StudyDescription", "ImageComments"];
+
+fn corrected_value(vr: VR, value: &str) -> PrimitiveValue {
+    dicom_vr_corrected_value(vr, &value.to_string(), &LengthPolicy::default())
+        .expect("Failed to correct the value")
+}
+
+// Actions of a cookbook, the PN tags and VR take NAME and the others DEID like a mapping
+// table with a PatientName column
+fn actions(
+    mask: &[&str],
+    mask_vrs: &[VR],
+    add: &[(&str, &str)],
+    delete: &[&str],
+    private_tags: bool,
+) -> TagActions {
+    let entry = |name: &&str| resolve_tag_name(name).expect("Not a tag");
+    let mask_value = |vr: VR| match vr {
+        VR::PN => "NAME",
+        _ => "DEID",
+    };
+    TagActions {
+        order: DEFAULT_TAG_STAGE_ORDER
+            .iter()
+            .filter(|stage| private_tags || **stage != TagStage::DeletePrivate)
+            .copied()
+            .collect(),
+        mask_tags: mask
+            .iter()
+            .map(entry)
+            .map(|each_tag| {
+                let vr = each_tag.vr.relaxed();
+                (
+                    each_tag.tag.inner(),
+                    (vr, corrected_value(vr, mask_value(vr))),
+                )
+            })
+            .collect(),
+        mask_vrs: mask_vrs
+            .iter()
+            .map(|vr| (*vr, dicom_value!(Strs, [mask_value(*vr).to_string()])))
+            .collect(),
+        add: add
+            .iter()
+            .map(|(name, value)| {
+                let each_tag = entry(name);
+                let vr = each_tag.vr.relaxed();
+                (each_tag.tag.inner(), (vr, corrected_value(vr, value)))
+            })
+            .collect(),
+        add_modes: HashMap::new(),
+        delete: delete.iter().map(entry).map(|t| t.tag.inner()).collect(),
+        keep: KeepList::default(),
+    }
+}
+
+fn value(dcm_obj: &InMemDicomObject, tag: Tag) -> Option<String> {
+    dcm_obj
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+}
+
+// Private creator and element at the top level and in a ReferencedImageSequence item
+fn with_private_tags(
+    mut dcm_obj: FileDicomObject<InMemDicomObject>,
+) -> FileDicomObject<InMemDicomObject> {
+    dcm_obj.put(DataElement::new(
+        Tag(0x0009, 0x0010),
+        VR::LO,
+        dicom_value!(Str, "ACME"),
+    ));
+    dcm_obj.put(DataElement::new(
+        Tag(0x0009, 0x1001),
+        VR::LO,
+        dicom_value!(Str, "secret"),
+    ));
+    let item = InMemDicomObject::from_element_iter([
+        DataElement::new(Tag(0x0011, 0x0010), VR::LO, dicom_value!(Str, "ACME")),
+        DataElement::new(Tag(0x0011, 0x1001), VR::LO, dicom_value!(Str, "nested")),
+        DataElement::new(
+            tags::REFERENCED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, "1.2.840.10008.5.1.4.1.1.2"),
+        ),
+    ]);
+    dcm_obj.put(DataElement::new(
+        tags::REFERENCED_IMAGE_SEQUENCE,
+        VR::SQ,
+        Value::Sequence(DataSetSequence::from(vec![item])),
+    ));
+    dcm_obj
+}
+
+#[test]
+fn masks_adds_and_deletes_like_the_separate_passes() {
+    let (dcm_obj, _) = apply_tag_actions(
+        minimal_ct_object(),
+        &actions(&MASK_TAGS, &[VR::PN], &ADD_VALUES, &DELETE_TAGS, false),
+    )
+    .unwrap();
+    assert_eq!(value(&dcm_obj, tags::PATIENT_ID).as_deref(), Some("DEID"));
+    assert_eq!(value(&dcm_obj, tags::PATIENT_NAME).as_deref(), Some("NAME"));
+    assert_eq!(
+        value(&dcm_obj, tags::INSTITUTION_NAME).as_deref(),
+        Some("DEID")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::ACCESSION_NUMBER).as_deref(),
+        Some("DEID")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::PATIENT_IDENTITY_REMOVED).as_deref(),
+        Some("YES")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::CLINICAL_TRIAL_SPONSOR_NAME).as_deref(),
+        Some("TrialName")
+    );
+    assert!(dcm_obj.element(tags::STUDY_DESCRIPTION).is_err());
+    // Untouched tags keep their values
+    assert_eq!(value(&dcm_obj, tags::MODALITY).as_deref(), Some("CT"));
+    assert_eq!(
+        value(&dcm_obj, tags::STUDY_DATE).as_deref(),
+        Some("20240115")
+    );
+    assert_eq!(
+        dcm_obj.element(tags::PIXEL_DATA).unwrap().value(),
+        minimal_ct_object()
+            .element(tags::PIXEL_DATA)
+            .unwrap()
+            .value()
+    );
+}
+
+#[test]
+fn masked_tags_missing_from_the_file_are_added() {
+    let (dcm_obj, rule_counts) = apply_tag_actions(
+        object_missing(&["PatientID", "InstitutionName", "AccessionNumber"]),
+        &actions(&MASK_TAGS, &[], &[], &[], false),
+    )
+    .unwrap();
+    assert_eq!(value(&dcm_obj, tags::PATIENT_ID).as_deref(), Some("DEID"));
+    assert_eq!(
+        value(&dcm_obj, tags::INSTITUTION_NAME).as_deref(),
+        Some("DEID")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::ACCESSION_NUMBER).as_deref(),
+        Some("DEID")
+    );
+    assert_eq!(rule_counts["mask PatientID"], 1);
+}
+
+#[test]
+fn a_vr_mask_overrides_the_tag_mask_and_the_add_value_overrides_both() {
+    let (dcm_obj, _) = apply_tag_actions(
+        minimal_ct_object(),
+        &actions(
+            &["InstitutionName", "StudyID"],
+            &[VR::LO],
+            &[("StudyID", "S1")],
+            &[],
+            false,
+        ),
+    )
+    .unwrap();
+    // Masked by its tag with the DEID, then by the LO VR mask with the DEID
+    assert_eq!(
+        value(&dcm_obj, tags::INSTITUTION_NAME).as_deref(),
+        Some("DEID")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::STUDY_DESCRIPTION).as_deref(),
+        Some("DEID")
+    );
+    assert_eq!(value(&dcm_obj, tags::STUDY_ID).as_deref(), Some("S1"));
+}
+
+#[test]
+fn a_deleted_tag_is_deleted_whatever_masks_or_adds_it() {
+    let (dcm_obj, _) = apply_tag_actions(
+        minimal_ct_object(),
+        &actions(
+            &["AccessionNumber", "PatientName"],
+            &[VR::PN],
+            &[("AccessionNumber", "A1")],
+            &["AccessionNumber", "PatientName"],
+            false,
+        ),
+    )
+    .unwrap();
+    assert!(dcm_obj.element(tags::ACCESSION_NUMBER).is_err());
+    assert!(dcm_obj.element(tags::PATIENT_NAME).is_err());
+}
+
+#[test]
+fn the_masks_only_apply_to_the_top_level_elements() {
+    let (dcm_obj, _) = apply_tag_actions(
+        object_with_sequences(3),
+        &actions(&MASK_TAGS, &[VR::PN, VR::UI], &[], &[], false),
+    )
+    .unwrap();
+    assert_eq!(value(&dcm_obj, tags::PATIENT_NAME).as_deref(), Some("NAME"));
+    let item = &dcm_obj
+        .element(tags::REFERENCED_STUDY_SEQUENCE)
+        .unwrap()
+        .items()
+        .unwrap()[0];
+    assert_eq!(value(item, tags::PATIENT_NAME).as_deref(), Some("Doe^John"));
+    assert_ne!(
+        value(item, tags::REFERENCED_SOP_INSTANCE_UID).as_deref(),
+        Some("DEID")
+    );
+}
+
+#[test]
+fn the_if_missing_add_value_keeps_a_present_value() {
+    let mut tag_actions = actions(
+        &[],
+        &[],
+        &[
+            ("InstitutionName", "SITE01"),
+            ("ClinicalTrialSiteID", "SITE01"),
+        ],
+        &[],
+        false,
+    );
+    for each_tag in [tags::INSTITUTION_NAME, tags::CLINICAL_TRIAL_SITE_ID] {
+        tag_actions.add_modes.insert(
+            each_tag,
+            AddMode::IfMissing {
+                empty_is_missing: true,
+            },
+        );
+    }
+    let (dcm_obj, _) = apply_tag_actions(minimal_ct_object(), &tag_actions).unwrap();
+    assert_eq!(
+        value(&dcm_obj, tags::INSTITUTION_NAME).as_deref(),
+        Some("Test Hospital")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::CLINICAL_TRIAL_SITE_ID).as_deref(),
+        Some("SITE01")
+    );
+}
+
+#[test]
+fn private_tags_are_kept_without_the_private_tag_stage() {
+    let (dcm_obj, _) = apply_tag_actions(
+        with_private_tags(minimal_ct_object()),
+        &actions(&MASK_TAGS, &[VR::PN], &ADD_VALUES, &DELETE_TAGS, false),
+    )
+    .unwrap();
+    assert_eq!(
+        value(&dcm_obj, Tag(0x0009, 0x1001)).as_deref(),
+        Some("secret")
+    );
+}
+
+#[test]
+fn private_tags_are_deleted_at_the_top_level_and_in_the_sequence_items() {
+    let (dcm_obj, rule_counts) = apply_tag_actions(
+        with_private_tags(minimal_ct_object()),
+        &actions(&MASK_TAGS, &[VR::PN], &ADD_VALUES, &DELETE_TAGS, true),
+    )
+    .unwrap();
+    assert!(dcm_obj.element(Tag(0x0009, 0x0010)).is_err());
+    assert!(dcm_obj.element(Tag(0x0009, 0x1001)).is_err());
+    assert_eq!(rule_counts["delete_private"], 2);
+    // The separate pass only removed the top level private tags, the items lose theirs too
+    let item = &dcm_obj
+        .element(tags::REFERENCED_IMAGE_SEQUENCE)
+        .unwrap()
+        .items()
+        .unwrap()[0];
+    assert!(item.element(Tag(0x0011, 0x0010)).is_err());
+    assert!(item.element(Tag(0x0011, 0x1001)).is_err());
+    assert!(item.element(tags::REFERENCED_SOP_CLASS_UID).is_ok());
+}
+
+#[test]
+fn kept_tags_are_left_by_every_stage() {
+    let mut tag_actions = actions(
+        &MASK_TAGS,
+        &[VR::PN],
+        &[("InstitutionName", "SITE01")],
+        &["InstitutionName"],
+        false,
+    );
+    tag_actions.keep = KeepList::new([tags::INSTITUTION_NAME, tags::PATIENT_NAME]);
+    let (dcm_obj, _) = apply_tag_actions(minimal_ct_object(), &tag_actions).unwrap();
+    assert_eq!(
+        value(&dcm_obj, tags::INSTITUTION_NAME).as_deref(),
+        Some("Test Hospital")
+    );
+    assert_eq!(
+        value(&dcm_obj, tags::PATIENT_NAME).as_deref(),
+        Some("Doe^John")
+    );
+    assert_eq!(value(&dcm_obj, tags::PATIENT_ID).as_deref(), Some("DEID"));
+}
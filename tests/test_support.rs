@@ -0,0 +1,76 @@
+use dcmrig_rs::test_support::{
+    minimal_ct_object, object_missing, object_with_sequences, temp_test_dir, write_temp_dicom,
+    CT_IMAGE_STORAGE,
+};
+use dicom::{dictionary_std::tags, object::open_file};
+
+#[test]
+fn built_objects_write_and_open_again() {
+    let test_dir = temp_test_dir("built_objects_write_and_open_again");
+    for dcm_obj in [
+        minimal_ct_object(),
+        object_missing(&["PatientID", "(0008,0080)"]),
+        object_with_sequences(3),
+    ] {
+        let path = write_temp_dicom(&test_dir, &dcm_obj).unwrap();
+        let reopened = open_file(&path).unwrap();
+        assert_eq!(
+            reopened
+                .meta()
+                .media_storage_sop_class_uid()
+                .trim_end_matches('\0'),
+            CT_IMAGE_STORAGE
+        );
+        assert_eq!(
+            reopened.meta().media_storage_sop_instance_uid(),
+            dcm_obj.meta().media_storage_sop_instance_uid()
+        );
+        assert_eq!(reopened.iter().count(), dcm_obj.iter().count());
+    }
+}
+
+#[test]
+fn built_objects_are_distinct_instances() {
+    let first = minimal_ct_object();
+    let second = minimal_ct_object();
+    assert_ne!(
+        first
+            .element(tags::SOP_INSTANCE_UID)
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        second
+            .element(tags::SOP_INSTANCE_UID)
+            .unwrap()
+            .to_str()
+            .unwrap()
+    );
+}
+
+#[test]
+fn object_missing_leaves_out_the_tags() {
+    let dcm_obj = object_missing(&["PatientID", "(0008,0080)"]);
+    assert!(dcm_obj.element(tags::PATIENT_ID).is_err());
+    assert!(dcm_obj.element(tags::INSTITUTION_NAME).is_err());
+    assert!(dcm_obj.element(tags::PATIENT_NAME).is_ok());
+}
+
+#[test]
+fn object_with_sequences_nests_to_the_depth() {
+    let dcm_obj = object_with_sequences(5);
+    let mut depth = 0;
+    let mut items = dcm_obj
+        .element(tags::REFERENCED_STUDY_SEQUENCE)
+        .ok()
+        .and_then(|element| element.items())
+        .map(|items| items.to_vec());
+    while let Some(level) = items {
+        depth += 1;
+        items = level[0]
+            .element(tags::REFERENCED_STUDY_SEQUENCE)
+            .ok()
+            .and_then(|element| element.items())
+            .map(|items| items.to_vec());
+    }
+    assert_eq!(depth, 5);
+}